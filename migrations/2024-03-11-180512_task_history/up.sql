@@ -0,0 +1,11 @@
+CREATE TABLE task_history (
+    id UUID PRIMARY KEY,
+    task_id UUID NOT NULL REFERENCES tasks(id),
+    actor_id UUID NOT NULL REFERENCES auth.users(id),
+    created TIMESTAMP NOT NULL,
+    task_update JSONB NOT NULL,
+    previous JSONB,
+    reverted BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX task_history_task_id_idx ON task_history (task_id, created);
@@ -0,0 +1 @@
+ALTER TABLE jobs ADD COLUMN created TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc');
@@ -0,0 +1,10 @@
+CREATE TABLE task_comments (
+    id UUID PRIMARY KEY,
+    task_id UUID NOT NULL REFERENCES tasks(id),
+    author_id UUID NOT NULL REFERENCES auth.users(id),
+    created TIMESTAMP NOT NULL,
+    updated TIMESTAMP,
+    body TEXT NOT NULL
+);
+
+CREATE INDEX task_comments_task_id_idx ON task_comments (task_id, created);
@@ -0,0 +1 @@
+ALTER TABLE flow_node_connections DROP COLUMN guards;
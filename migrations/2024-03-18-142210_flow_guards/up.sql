@@ -0,0 +1 @@
+ALTER TABLE flow_node_connections ADD COLUMN guards JSONB NOT NULL DEFAULT '[]';
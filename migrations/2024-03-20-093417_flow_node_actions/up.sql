@@ -0,0 +1,10 @@
+CREATE TABLE flow_node_actions (
+    id UUID PRIMARY KEY,
+    flow_id UUID NOT NULL REFERENCES flows(id),
+    node_id UUID NOT NULL REFERENCES flow_nodes(id),
+    created TIMESTAMP NOT NULL,
+    on_enter BOOLEAN NOT NULL,
+    action JSONB NOT NULL
+);
+
+CREATE INDEX flow_node_actions_node_idx ON flow_node_actions (flow_id, node_id);
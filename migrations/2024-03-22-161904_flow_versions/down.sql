@@ -0,0 +1,13 @@
+ALTER TABLE flow_node_connections DROP CONSTRAINT flow_node_connections_pkey;
+DELETE FROM flow_node_connections c
+USING flow_node_connections d
+WHERE c.from_node_id = d.from_node_id
+  AND c.to_node_id = d.to_node_id
+  AND c.flow_id > d.flow_id;
+ALTER TABLE flow_node_connections DROP COLUMN flow_id;
+ALTER TABLE flow_node_connections ADD PRIMARY KEY (from_node_id, to_node_id);
+
+DROP INDEX flows_lineage_version_idx;
+ALTER TABLE flows DROP COLUMN superseded_by;
+ALTER TABLE flows DROP COLUMN lineage_id;
+ALTER TABLE flows DROP COLUMN version;
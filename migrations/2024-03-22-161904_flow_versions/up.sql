@@ -0,0 +1,20 @@
+-- Every change to a flow graph creates a new version sharing the lineage of the original flow
+ALTER TABLE flows ADD COLUMN version INT NOT NULL DEFAULT 1;
+ALTER TABLE flows ADD COLUMN lineage_id UUID;
+ALTER TABLE flows ADD COLUMN superseded_by UUID NULL REFERENCES flows(id);
+UPDATE flows SET lineage_id = id;
+ALTER TABLE flows ALTER COLUMN lineage_id SET NOT NULL;
+CREATE UNIQUE INDEX flows_lineage_version_idx ON flows(lineage_id, version);
+
+-- Connections belong to a single flow version so versions can differ in their edges and guards
+ALTER TABLE flow_node_connections ADD COLUMN flow_id UUID NULL REFERENCES flows(id);
+ALTER TABLE flow_node_connections DROP CONSTRAINT flow_node_connections_pkey;
+INSERT INTO flow_node_connections (from_node_id, to_node_id, guards, flow_id)
+SELECT c.from_node_id, c.to_node_id, c.guards, a.flow_id
+FROM flow_node_connections c
+JOIN flow_assignments a ON a.node_id = c.from_node_id
+JOIN flow_assignments b ON b.node_id = c.to_node_id AND b.flow_id = a.flow_id
+WHERE c.flow_id IS NULL;
+DELETE FROM flow_node_connections WHERE flow_id IS NULL;
+ALTER TABLE flow_node_connections ALTER COLUMN flow_id SET NOT NULL;
+ALTER TABLE flow_node_connections ADD PRIMARY KEY (flow_id, from_node_id, to_node_id);
@@ -0,0 +1,9 @@
+CREATE TABLE job_progress (
+    id UUID PRIMARY KEY,
+    job_id UUID NOT NULL REFERENCES jobs(id) ON DELETE CASCADE,
+    created TIMESTAMP NOT NULL,
+    percent REAL NOT NULL,
+    stage VARCHAR NOT NULL,
+    message TEXT NOT NULL
+);
+CREATE INDEX job_progress_job_id_idx ON job_progress(job_id, created);
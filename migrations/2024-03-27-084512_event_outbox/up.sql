@@ -0,0 +1,9 @@
+-- Events waiting to be emitted to Prism, replayed in seq order after a reconnect
+CREATE TABLE event_outbox (
+    seq BIGSERIAL PRIMARY KEY,
+    beam VARCHAR NOT NULL,
+    payload JSONB NOT NULL,
+    created TIMESTAMP NOT NULL,
+    emitted TIMESTAMP NULL
+);
+CREATE INDEX event_outbox_pending_idx ON event_outbox(seq) WHERE emitted IS NULL;
@@ -0,0 +1 @@
+DROP TABLE event_sink_cursors;
@@ -0,0 +1,6 @@
+-- How far each configured event sink has read the outbox
+CREATE TABLE event_sink_cursors (
+    sink_name VARCHAR PRIMARY KEY,
+    last_seq BIGINT NOT NULL,
+    updated TIMESTAMP NOT NULL
+);
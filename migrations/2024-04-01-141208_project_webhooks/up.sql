@@ -0,0 +1,24 @@
+CREATE TABLE project_webhooks (
+    id UUID PRIMARY KEY,
+    project_id UUID NOT NULL REFERENCES projects(id) ON DELETE CASCADE,
+    url VARCHAR NOT NULL,
+    secret VARCHAR NOT NULL,
+    events JSONB NOT NULL,
+    created TIMESTAMP NOT NULL
+);
+CREATE INDEX project_webhooks_project_id_idx ON project_webhooks(project_id);
+
+CREATE TABLE webhook_deliveries (
+    id UUID PRIMARY KEY,
+    webhook_id UUID NOT NULL REFERENCES project_webhooks(id) ON DELETE CASCADE,
+    event VARCHAR NOT NULL,
+    payload JSONB NOT NULL,
+    created TIMESTAMP NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    next_attempt TIMESTAMP NULL,
+    delivered TIMESTAMP NULL,
+    status_code INT NULL,
+    error TEXT NULL
+);
+CREATE INDEX webhook_deliveries_webhook_id_idx ON webhook_deliveries(webhook_id, created);
+CREATE INDEX webhook_deliveries_due_idx ON webhook_deliveries(next_attempt) WHERE next_attempt IS NOT NULL;
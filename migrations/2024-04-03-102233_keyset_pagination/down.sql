@@ -0,0 +1,6 @@
+DROP INDEX flows_created_id;
+DROP INDEX projects_created_id;
+DROP INDEX jobs_created_id;
+DROP INDEX tasks_updated_id;
+DROP INDEX tasks_created_id;
+ALTER TABLE tasks DROP COLUMN updated;
@@ -0,0 +1,13 @@
+ALTER TABLE tasks ADD COLUMN updated TIMESTAMP;
+UPDATE tasks SET updated = COALESCE(
+    (SELECT MAX(task_history.created) FROM task_history WHERE task_history.task_id = tasks.id),
+    tasks.created
+);
+ALTER TABLE tasks ALTER COLUMN updated SET NOT NULL;
+ALTER TABLE tasks ALTER COLUMN updated SET DEFAULT NOW();
+
+CREATE INDEX tasks_created_id ON tasks (created, id);
+CREATE INDEX tasks_updated_id ON tasks (updated, id);
+CREATE INDEX jobs_created_id ON jobs (created, id);
+CREATE INDEX projects_created_id ON projects (created, id);
+CREATE INDEX flows_created_id ON flows (created, id);
@@ -0,0 +1,8 @@
+ALTER TABLE tasks ADD COLUMN search_vector TSVECTOR;
+UPDATE tasks SET search_vector =
+    setweight(to_tsvector('english', title), 'A') ||
+    setweight(to_tsvector('english', description), 'B');
+ALTER TABLE tasks ALTER COLUMN search_vector SET NOT NULL;
+ALTER TABLE tasks ALTER COLUMN search_vector SET DEFAULT ''::tsvector;
+
+CREATE INDEX tasks_search_vector ON tasks USING GIN (search_vector);
@@ -0,0 +1,8 @@
+CREATE TABLE task_embeddings (
+    task_id UUID PRIMARY KEY REFERENCES tasks(id) ON DELETE CASCADE,
+    model VARCHAR NOT NULL,
+    embedding REAL[] NOT NULL,
+    updated TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX task_embeddings_model ON task_embeddings (model);
@@ -0,0 +1,12 @@
+CREATE TABLE routing_rules (
+    id UUID PRIMARY KEY,
+    project_id UUID NOT NULL REFERENCES projects(id) ON DELETE CASCADE,
+    priority INT NOT NULL,
+    pattern VARCHAR,
+    tag VARCHAR,
+    assignee_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created TIMESTAMP NOT NULL DEFAULT NOW(),
+    CONSTRAINT routing_rule_matches CHECK (pattern IS NOT NULL OR tag IS NOT NULL)
+);
+
+CREATE INDEX routing_rules_project_priority ON routing_rules (project_id, priority);
@@ -0,0 +1,2 @@
+DROP TABLE instruct_messages;
+DROP TABLE instruct_sessions;
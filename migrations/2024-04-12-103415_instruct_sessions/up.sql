@@ -0,0 +1,22 @@
+CREATE TABLE instruct_sessions (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    project_id UUID NOT NULL REFERENCES projects(id) ON DELETE CASCADE,
+    stream_id UUID,
+    title VARCHAR NOT NULL,
+    awaiting_reply BOOLEAN NOT NULL DEFAULT FALSE,
+    created TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated TIMESTAMP NOT NULL DEFAULT NOW(),
+    closed TIMESTAMP
+);
+
+CREATE INDEX instruct_sessions_user_created ON instruct_sessions (user_id, created);
+
+CREATE TABLE instruct_messages (
+    session_id UUID NOT NULL REFERENCES instruct_sessions(id) ON DELETE CASCADE,
+    seq INT NOT NULL,
+    kind VARCHAR NOT NULL,
+    payload JSONB NOT NULL,
+    created TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (session_id, seq)
+);
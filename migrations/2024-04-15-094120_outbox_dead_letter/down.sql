@@ -0,0 +1,4 @@
+DROP INDEX event_outbox_pending_idx;
+CREATE INDEX event_outbox_pending_idx ON event_outbox(seq) WHERE emitted IS NULL;
+ALTER TABLE event_outbox DROP COLUMN failed;
+ALTER TABLE event_outbox DROP COLUMN attempts;
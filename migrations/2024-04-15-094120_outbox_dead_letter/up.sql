@@ -0,0 +1,5 @@
+-- Events Prism keeps refusing are set aside instead of blocking the rest of the outbox
+ALTER TABLE event_outbox ADD COLUMN attempts INT NOT NULL DEFAULT 0;
+ALTER TABLE event_outbox ADD COLUMN failed TIMESTAMP NULL;
+DROP INDEX event_outbox_pending_idx;
+CREATE INDEX event_outbox_pending_idx ON event_outbox(seq) WHERE emitted IS NULL AND failed IS NULL;
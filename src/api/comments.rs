@@ -0,0 +1,219 @@
+use std::sync::Arc;
+
+use chrono::NaiveDateTime;
+use diesel::{Connection, PgConnection, QueryResult};
+use serde::{Deserialize, Serialize};
+use subseq_util::api::sessions::store_auth_cookie;
+use subseq_util::oidc::IdentityProvider;
+use subseq_util::{api::*, tables::{DbPool, UserTable}, Router};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+use warp_sessions::{MemoryStore, SessionWithStore};
+
+use crate::api::users::DenormalizedUser;
+use crate::events::TASK_COMMENT_BEAM;
+use crate::tables::{OutboxEvent, Task, TaskComment, User};
+
+#[derive(Deserialize)]
+pub struct CommentPayload {
+    body: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DenormalizedTaskComment {
+    pub id: Uuid,
+    pub author: DenormalizedUser,
+    pub created: NaiveDateTime,
+    pub updated: Option<NaiveDateTime>,
+    pub body: String,
+}
+
+impl DenormalizedTaskComment {
+    pub fn denormalize(conn: &mut PgConnection, comment: TaskComment) -> QueryResult<Self> {
+        let author =
+            User::get(conn, comment.author_id).ok_or_else(|| diesel::result::Error::NotFound)?;
+        let author = DenormalizedUser::denormalize(conn, author)?;
+        Ok(Self {
+            id: comment.id,
+            author,
+            created: comment.created,
+            updated: comment.updated,
+            body: comment.body,
+        })
+    }
+
+    pub fn list(conn: &mut PgConnection, task: &Task) -> QueryResult<Vec<Self>> {
+        let mut comments = vec![];
+        for comment in TaskComment::list_for_task(conn, task.id)? {
+            comments.push(Self::denormalize(conn, comment)?);
+        }
+        Ok(comments)
+    }
+}
+
+/// Fetches a comment on a task which the user is allowed to modify.
+fn authored_comment(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    task_id: Uuid,
+    comment_id: Uuid,
+) -> Result<TaskComment, Rejection> {
+    let comment = match TaskComment::get(conn, comment_id) {
+        Some(comment) if comment.task_id == task_id => comment,
+        _ => return Err(warp::reject::custom(NotFoundError {})),
+    };
+    if comment.author_id != user_id {
+        tracing::warn!("User {} cannot modify comment {}", user_id, comment_id);
+        return Err(warp::reject::custom(InvalidConfigurationError {}));
+    }
+    Ok(comment)
+}
+
+async fn create_comment_handler(
+    task_id: Uuid,
+    payload: CommentPayload,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+    sender: broadcast::Sender<TaskComment>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let task = match Task::get(&mut conn, task_id) {
+        Some(task) => task,
+        None => return Err(warp::reject::custom(NotFoundError {})),
+    };
+    let user = match User::get(&mut conn, auth.id()) {
+        Some(user) => user,
+        None => return Err(warp::reject::custom(NotFoundError {})),
+    };
+    let comment = conn
+        .transaction(|transact| {
+            let comment = TaskComment::create(transact, &task, &user, &payload.body)?;
+            OutboxEvent::record(transact, TASK_COMMENT_BEAM, &comment)?;
+            Ok::<_, diesel::result::Error>(comment)
+        })
+        .map_err(|_| warp::reject::custom(InvalidConfigurationError {}))?;
+    sender.send(comment.clone()).ok();
+
+    let comment = DenormalizedTaskComment::denormalize(&mut conn, comment)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::json(&comment), session))
+}
+
+async fn list_comments_handler(
+    task_id: Uuid,
+    _auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let task = match Task::get(&mut conn, task_id) {
+        Some(task) => task,
+        None => return Err(warp::reject::custom(NotFoundError {})),
+    };
+    let comments = DenormalizedTaskComment::list(&mut conn, &task)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::json(&comments), session))
+}
+
+async fn update_comment_handler(
+    task_id: Uuid,
+    comment_id: Uuid,
+    payload: CommentPayload,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let mut comment = authored_comment(&mut conn, auth.id(), task_id, comment_id)?;
+    comment
+        .set_body(&mut conn, &payload.body)
+        .map_err(|_| warp::reject::custom(InvalidConfigurationError {}))?;
+    let comment = DenormalizedTaskComment::denormalize(&mut conn, comment)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::json(&comment), session))
+}
+
+async fn delete_comment_handler(
+    task_id: Uuid,
+    comment_id: Uuid,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let comment = authored_comment(&mut conn, auth.id(), task_id, comment_id)?;
+    comment
+        .delete(&mut conn)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::reply(), session))
+}
+
+/// Routes under /task/{task_id}/comment
+pub fn routes(
+    idp: Option<Arc<IdentityProvider>>,
+    session: MemoryStore,
+    pool: Arc<DbPool>,
+    router: &mut Router,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let comment_tx: broadcast::Sender<TaskComment> = router.announce();
+
+    let create_comment = warp::path::param()
+        .and(warp::path("comment"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and(with_broadcast(comment_tx))
+        .and_then(create_comment_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let list_comments = warp::path::param()
+        .and(warp::path("comment"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(list_comments_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let update_comment = warp::path::param()
+        .and(warp::path!("comment" / Uuid))
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(update_comment_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let delete_comment = warp::path::param()
+        .and(warp::path!("comment" / Uuid))
+        .and(warp::delete())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(delete_comment_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    create_comment
+        .or(list_comments)
+        .or(update_comment)
+        .or(delete_comment)
+}
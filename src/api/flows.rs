@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use diesel::connection::{Connection, LoadConnection};
@@ -13,8 +14,10 @@ use uuid::Uuid;
 use warp::{Filter, Rejection, Reply};
 use warp_sessions::{MemoryStore, SessionWithStore};
 
+use crate::events::{FLOW_UPDATED_BEAM, TASK_STATE_BEAM};
 use crate::tables::*;
-use super::PAGE_SIZE;
+use super::tasks::TaskStateChanged;
+use super::ValidationError;
 
 #[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct FlowNodePayload {
@@ -22,6 +25,13 @@ pub struct FlowNodePayload {
     id: Option<Uuid>,
 }
 
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct FlowGuardPayload {
+    from: FlowNodePayload,
+    to: FlowNodePayload,
+    guards: Vec<FlowGuard>,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct NewFlowPayload {
     flow_name: String,
@@ -29,6 +39,15 @@ pub struct NewFlowPayload {
     entry: FlowNodePayload,
     exits: Vec<FlowNodePayload>,
     connections: Vec<(FlowNodePayload, FlowNodePayload)>,
+    #[serde(default)]
+    guards: Vec<FlowGuardPayload>,
+}
+
+/// Where tasks on a node missing from the new flow version should be moved to
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct NodeMappingPayload {
+    from: Uuid,
+    to: FlowNodePayload,
 }
 
 #[derive(Deserialize, Debug, Clone, Serialize)]
@@ -37,6 +56,31 @@ pub struct UpdateFlowPayload {
     entry: FlowNodePayload,
     exits: Vec<FlowNodePayload>,
     connections: Vec<(FlowNodePayload, FlowNodePayload)>,
+    #[serde(default)]
+    guards: Vec<FlowGuardPayload>,
+    /// Move tasks pinned to older versions onto the new version
+    #[serde(default)]
+    upgrade_tasks: bool,
+    #[serde(default)]
+    node_mapping: Vec<NodeMappingPayload>,
+    /// Report the new graph and the tasks which would move without committing anything
+    #[serde(default)]
+    preview: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct UpgradeFlowPayload {
+    #[serde(default)]
+    node_mapping: Vec<NodeMappingPayload>,
+    #[serde(default)]
+    preview: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FlowMigrationReply {
+    graph: Graph,
+    moved: Vec<TaskFlowMove>,
+    preview: bool,
 }
 
 fn find_node<'a>(nodes: &[&'a FlowNode], node_to_find: FlowNodePayload) -> Option<&'a FlowNode> {
@@ -52,6 +96,40 @@ fn find_node<'a>(nodes: &[&'a FlowNode], node_to_find: FlowNodePayload) -> Optio
     None
 }
 
+fn node_matches(node: &FlowNode, payload: &FlowNodePayload) -> bool {
+    match &payload.id {
+        Some(id) => &node.id == id,
+        None => node.node_name == payload.name,
+    }
+}
+
+/// Resolves each guarded edge to the (from, to) node ids of an edge in the graph.
+fn resolve_guards(
+    edges: &[(FlowNode, FlowNode)],
+    guards: Vec<FlowGuardPayload>,
+) -> QueryResult<Vec<(Uuid, Uuid, Vec<FlowGuard>)>> {
+    let mut resolved = vec![];
+    for FlowGuardPayload { from, to, guards } in guards {
+        let edge = edges
+            .iter()
+            .find(|(source, sink)| node_matches(source, &from) && node_matches(sink, &to));
+        let (source, sink) = match edge {
+            Some(edge) => edge,
+            None => {
+                let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+                let msg = Box::new(ValidationErrorMessage {
+                    message: format!("Guarded edge {} -> {} is not in the flow", from.name, to.name),
+                    column: "guards".to_string(),
+                    constraint_name: "flow_guard_edge".to_string(),
+                });
+                return Err(diesel::result::Error::DatabaseError(kind, msg));
+            }
+        };
+        resolved.push((source.id, sink.id, guards));
+    }
+    Ok(resolved)
+}
+
 // Handler for creating a new flow
 async fn create_flow_handler(
     payload: NewFlowPayload,
@@ -71,20 +149,23 @@ async fn create_flow_handler(
         entry,
         exits,
         connections,
+        guards,
     } = payload;
 
+    let mut known = vec![];
     let mut nodes: Vec<(FlowNode, FlowNode)> = vec![];
     for (node_from, node_to) in connections.into_iter() {
-        let source = match create_or_get_flow_node(&mut conn, node_from) {
+        let source = match resolve_flow_node(&mut conn, &mut known, node_from) {
             Ok(s) => s,
             Err(_) => return Err(warp::reject::custom(DatabaseError {})),
         };
-        let sink = match create_or_get_flow_node(&mut conn, node_to) {
+        let sink = match resolve_flow_node(&mut conn, &mut known, node_to) {
             Ok(s) => s,
             Err(_) => return Err(warp::reject::custom(DatabaseError {})),
         };
         nodes.push((source, sink));
     }
+    let guards = resolve_guards(&nodes, guards).map_err(ValidationError::reject)?;
     let mut sources: Vec<&FlowNode> = vec![];
     let mut sinks: Vec<&FlowNode> = vec![];
     let mut graph: Vec<(&FlowNode, &FlowNode)> = vec![];
@@ -112,18 +193,22 @@ async fn create_flow_handler(
         None => return Err(warp::reject::custom(NotFoundError {})),
     };
 
-    let flow = match Flow::create(
-        &mut conn,
-        &user,
-        flow_name,
-        description.unwrap_or_else(String::new),
-        entry,
-        graph,
-        exit_refs,
-    ) {
-        Ok(flow) => flow,
-        Err(_) => return Err(warp::reject::custom(ConflictError {})),
-    };
+    let flow = conn.transaction(|transaction| {
+        let flow = Flow::create(
+            transaction,
+            &user,
+            flow_name,
+            description.unwrap_or_else(String::new),
+            entry,
+            graph,
+            exit_refs,
+        )?;
+        for (from_node_id, to_node_id, guards) in guards {
+            FlowConnection::set_guards(transaction, flow.id, from_node_id, to_node_id, &guards)?;
+        }
+        Ok(flow)
+    });
+    let flow = flow.map_err(ValidationError::reject)?;
     sender.send(flow.clone()).ok();
 
     // Create a response with the created flow details
@@ -131,10 +216,19 @@ async fn create_flow_handler(
     Ok((reply, session))
 }
 
-fn create_or_get_flow_node<C>(conn: &mut C, node: FlowNodePayload) -> QueryResult<FlowNode>
+/// Finds the node a payload refers to among the `known` nodes, otherwise fetches it by id or
+/// creates it by name. Repeating a name within one request always refers to the same node.
+fn resolve_flow_node<C>(
+    conn: &mut C,
+    known: &mut Vec<FlowNode>,
+    node: FlowNodePayload,
+) -> QueryResult<FlowNode>
 where
     C: Connection<Backend = Pg> + LoadConnection,
 {
+    if let Some(flow_node) = known.iter().find(|known| node_matches(known, &node)) {
+        return Ok(flow_node.clone());
+    }
     let flow_node = match node.id {
         Some(id) => match FlowNode::get(conn, id) {
             Some(node) => node,
@@ -142,6 +236,7 @@ where
         },
         None => FlowNode::create(conn, &node.name)?,
     };
+    known.push(flow_node.clone());
     Ok(flow_node)
 }
 
@@ -164,7 +259,7 @@ async fn get_flow_graph_handler(
 }
 
 async fn list_flows_handler(
-    page: u32,
+    page: PageRequest,
     _auth: AuthenticatedUser,
     session: SessionWithStore<MemoryStore>,
     db_pool: Arc<DbPool>,
@@ -173,16 +268,106 @@ async fn list_flows_handler(
         Ok(conn) => conn,
         Err(_) => return Err(warp::reject::custom(DatabaseError {})),
     };
-    let flows = Flow::list(&mut conn, page, PAGE_SIZE);
+    let flows = Flow::page(&mut conn, &page).map_err(ValidationError::reject)?;
     Ok((warp::reply::json(&flows), session))
 }
 
+async fn list_flow_versions_handler(
+    flow_id: Uuid,
+    _auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let flow = match Flow::get(&mut conn, flow_id) {
+        Some(flow) => flow,
+        None => return Err(warp::reject::custom(NotFoundError {})),
+    };
+    let versions = Flow::versions(&mut conn, flow.lineage_id)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::json(&versions), session))
+}
+
+async fn get_flow_version_handler(
+    flow_id: Uuid,
+    version: i32,
+    _auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let flow = Flow::get(&mut conn, flow_id)
+        .and_then(|flow| Flow::get_version(&mut conn, flow.lineage_id, version));
+    let flow = match flow {
+        Some(flow) => flow,
+        None => return Err(warp::reject::custom(NotFoundError {})),
+    };
+    let graph =
+        Graph::fetch(&mut conn, flow.id).map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::json(&graph), session))
+}
+
+/// Resolves a node mapping against the nodes of the flow version tasks are moving onto
+fn resolve_node_mapping<C>(
+    conn: &mut C,
+    known: &mut Vec<FlowNode>,
+    node_mapping: Vec<NodeMappingPayload>,
+) -> QueryResult<HashMap<Uuid, Uuid>>
+where
+    C: Connection<Backend = Pg> + LoadConnection,
+{
+    let mut mapping = HashMap::new();
+    for NodeMappingPayload { from, to } in node_mapping {
+        let to = resolve_flow_node(conn, known, to)?;
+        mapping.insert(from, to.id);
+    }
+    Ok(mapping)
+}
+
+/// The state changes of the tasks a migration moved to another node
+fn task_moves(flow_id: Uuid, moved: &[TaskFlowMove], actor_id: Uuid) -> Vec<TaskStateChanged> {
+    moved
+        .iter()
+        .filter_map(|task_move| {
+            let new_node_id = match task_move.to_node_id {
+                Some(node_id) if task_move.from_node_id != Some(node_id) => node_id,
+                _ => return None,
+            };
+            Some(TaskStateChanged {
+                task_id: task_move.task_id,
+                flow_id,
+                old_node_id: task_move.from_node_id,
+                new_node_id,
+                actor_id,
+            })
+        })
+        .collect()
+}
+
+/// Records the state changes of a migration in the outbox, inside its transaction
+fn record_task_moves<C>(conn: &mut C, changes: &[TaskStateChanged]) -> QueryResult<()>
+where
+    C: Connection<Backend = Pg> + LoadConnection,
+{
+    for change in changes {
+        OutboxEvent::record(conn, TASK_STATE_BEAM, change)?;
+    }
+    Ok(())
+}
+
 async fn update_flow_graph_handler(
     payload: UpdateFlowPayload,
-    _auth: AuthenticatedUser,
+    auth: AuthenticatedUser,
     session: SessionWithStore<MemoryStore>,
     db_pool: Arc<DbPool>,
     sender: broadcast::Sender<Graph>,
+    state_sender: broadcast::Sender<TaskStateChanged>,
 ) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
     let mut conn = match db_pool.get() {
         Ok(conn) => conn,
@@ -193,47 +378,223 @@ async fn update_flow_graph_handler(
         entry,
         exits,
         connections,
+        guards,
+        upgrade_tasks,
+        node_mapping,
+        preview,
     } = payload;
+    if !upgrade_tasks && !node_mapping.is_empty() {
+        return Err(warp::reject::custom(ValidationError {
+            message: "A node mapping is only used when upgrading tasks".to_string(),
+            column: Some("node_mapping".to_string()),
+            constraint_name: None,
+            unmet_guards: vec![],
+        }));
+    }
 
-    conn.transaction(|transaction| {
+    let mut previewed = None;
+    let outcome = conn.transaction(|transaction| {
         let mut flow = match Flow::get(transaction, flow_id) {
             Some(f) => f,
             None => return Err(diesel::result::Error::NotFound),
         };
-        // Set Entry Node
-        let entry = create_or_get_flow_node(transaction, entry)?;
-        flow.set_flow_entry(transaction, &entry)?;
+        // Names refer to the flow's existing nodes before any new ones are created
+        let mut known = flow.nodes(transaction)?;
+        let entry = resolve_flow_node(transaction, &mut known, entry)?;
 
-        // Set Exit Nodes
         let mut exit_nodes = vec![];
         for exit in exits {
-            let exit_node = create_or_get_flow_node(transaction, exit)?;
+            let exit_node = resolve_flow_node(transaction, &mut known, exit)?;
             exit_nodes.push(exit_node);
         }
         let exit_refs: Vec<&FlowNode> = exit_nodes.iter().collect();
-        FlowExit::create_exits(transaction, flow_id, exit_refs)?;
 
-        // Set Edges
         let mut edges = vec![];
         for (edge_from, edge_to) in connections {
-            let edge_from = create_or_get_flow_node(transaction, edge_from)?;
-            let edge_to = create_or_get_flow_node(transaction, edge_to)?;
+            let edge_from = resolve_flow_node(transaction, &mut known, edge_from)?;
+            let edge_to = resolve_flow_node(transaction, &mut known, edge_to)?;
             edges.push((edge_from, edge_to));
         }
         let edge_refs: Vec<(&FlowNode, &FlowNode)> =
             edges.iter().map(|(source, sink)| (source, sink)).collect();
-        FlowConnection::connect_all(transaction, flow_id, edge_refs)?;
+        let next = flow.new_version(transaction, &entry, edge_refs, exit_refs)?;
 
-        Ok::<(), diesel::result::Error>(())
-    })
-    .map_err(|_| warp::reject::custom(DatabaseError {}))?;
-    let graph =
-        Graph::fetch(&mut conn, flow_id).map_err(|_| warp::reject::custom(DatabaseError {}))?;
-    sender.send(graph.clone()).ok();
-    let reply = warp::reply::json(&graph);
+        // Set Guards
+        let guards = resolve_guards(&edges, guards)?;
+        for (from_node_id, to_node_id, guards) in guards {
+            FlowConnection::set_guards(transaction, next.id, from_node_id, to_node_id, &guards)?;
+        }
+
+        // Tasks stay on the old version unless they are upgraded along with the graph
+        let moved = if upgrade_tasks {
+            let mapping = resolve_node_mapping(transaction, &mut known, node_mapping)?;
+            next.upgrade_tasks(transaction, &mapping)?
+        } else {
+            vec![]
+        };
+        let graph = Graph::fetch(transaction, next.id)?;
+        OutboxEvent::record(transaction, FLOW_UPDATED_BEAM, &graph)?;
+        let changes = task_moves(next.id, &moved, auth.id());
+        record_task_moves(transaction, &changes)?;
+
+        if preview {
+            previewed = Some((graph, moved, changes));
+            return Err(diesel::result::Error::RollbackTransaction);
+        }
+        Ok((graph, moved, changes))
+    });
+    let (graph, moved, changes) = match (outcome, previewed) {
+        (Ok(result), _) => result,
+        (Err(diesel::result::Error::RollbackTransaction), Some(result)) => result,
+        (Err(err), _) => return Err(ValidationError::reject(err)),
+    };
+
+    if !preview {
+        sender.send(graph.clone()).ok();
+        for change in changes {
+            state_sender.send(change).ok();
+        }
+    }
+    let reply = warp::reply::json(&FlowMigrationReply {
+        graph,
+        moved,
+        preview,
+    });
     Ok((reply, session))
 }
 
+async fn upgrade_flow_tasks_handler(
+    flow_id: Uuid,
+    payload: UpgradeFlowPayload,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+    state_sender: broadcast::Sender<TaskStateChanged>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let UpgradeFlowPayload {
+        node_mapping,
+        preview,
+    } = payload;
+
+    let mut previewed = None;
+    let outcome = conn.transaction(|transaction| {
+        let latest = match Flow::get(transaction, flow_id) {
+            Some(flow) => flow.latest_version(transaction)?,
+            None => return Err(diesel::result::Error::NotFound),
+        };
+        let mut known = latest.nodes(transaction)?;
+        let mapping = resolve_node_mapping(transaction, &mut known, node_mapping)?;
+        let moved = latest.upgrade_tasks(transaction, &mapping)?;
+        let graph = Graph::fetch(transaction, latest.id)?;
+        let changes = task_moves(latest.id, &moved, auth.id());
+        record_task_moves(transaction, &changes)?;
+
+        if preview {
+            previewed = Some((graph, moved, changes));
+            return Err(diesel::result::Error::RollbackTransaction);
+        }
+        Ok((graph, moved, changes))
+    });
+    let (graph, moved, changes) = match (outcome, previewed) {
+        (Ok(result), _) => result,
+        (Err(diesel::result::Error::RollbackTransaction), Some(result)) => result,
+        (Err(diesel::result::Error::NotFound), _) => {
+            return Err(warp::reject::custom(NotFoundError {}))
+        }
+        (Err(err), _) => return Err(ValidationError::reject(err)),
+    };
+
+    if !preview {
+        for change in changes {
+            state_sender.send(change).ok();
+        }
+    }
+    let reply = warp::reply::json(&FlowMigrationReply {
+        graph,
+        moved,
+        preview,
+    });
+    Ok((reply, session))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FlowActionPayload {
+    node_id: Uuid,
+    on_enter: bool,
+    action: FlowAction,
+}
+
+async fn list_flow_actions_handler(
+    flow_id: Uuid,
+    _auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let actions = FlowNodeAction::list_for_flow(&mut conn, flow_id)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::json(&actions), session))
+}
+
+/// Fetches a flow whose node actions the user is allowed to manage
+fn owned_flow(conn: &mut PgConnection, user_id: Uuid, flow_id: Uuid) -> Result<Flow, Rejection> {
+    let flow = match Flow::get(conn, flow_id) {
+        Some(flow) => flow,
+        None => return Err(warp::reject::custom(NotFoundError {})),
+    };
+    if flow.owner_id != user_id {
+        tracing::warn!("User {} cannot manage flow {}", user_id, flow_id);
+        return Err(warp::reject::custom(InvalidConfigurationError {}));
+    }
+    Ok(flow)
+}
+
+async fn create_flow_action_handler(
+    flow_id: Uuid,
+    payload: FlowActionPayload,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let FlowActionPayload {
+        node_id,
+        on_enter,
+        action,
+    } = payload;
+    owned_flow(&mut conn, auth.id(), flow_id)?;
+    let action = FlowNodeAction::create(&mut conn, flow_id, node_id, on_enter, &action)
+        .map_err(ValidationError::reject)?;
+    Ok((warp::reply::json(&action), session))
+}
+
+async fn delete_flow_action_handler(
+    flow_id: Uuid,
+    action_id: Uuid,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    owned_flow(&mut conn, auth.id(), flow_id)?;
+    FlowNodeAction::delete(&mut conn, flow_id, action_id)
+        .map_err(|_| warp::reject::custom(NotFoundError {}))?;
+    Ok((warp::reply::reply(), session))
+}
+
 // Add the route for creating a flow to your routes function
 pub fn routes(
     idp: Option<Arc<IdentityProvider>>,
@@ -243,6 +604,7 @@ pub fn routes(
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     let flow_tx: broadcast::Sender<Flow> = router.announce();
     let graph_tx: broadcast::Sender<Graph> = router.announce();
+    let task_state_tx: broadcast::Sender<TaskStateChanged> = router.announce();
 
     let create_flow = warp::post()
         .and(warp::body::json())
@@ -253,15 +615,76 @@ pub fn routes(
         .untuple_one()
         .and_then(store_auth_cookie);
 
-    let list_flows = warp::path("list")
-        .and(warp::path::param())
+    let list_flows = warp::path!("list")
         .and(warp::get())
+        .and(warp::query::<PageRequest>())
         .and(authenticate(idp.clone(), session.clone()))
         .and(with_db(pool.clone()))
         .and_then(list_flows_handler)
         .untuple_one()
         .and_then(store_auth_cookie);
 
+    let list_flow_actions = warp::get()
+        .and(warp::path::param())
+        .and(warp::path("action"))
+        .and(warp::path::end())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(list_flow_actions_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let create_flow_action = warp::post()
+        .and(warp::path::param())
+        .and(warp::path("action"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(create_flow_action_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let delete_flow_action = warp::delete()
+        .and(warp::path::param())
+        .and(warp::path!("action" / Uuid))
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(delete_flow_action_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let list_flow_versions = warp::get()
+        .and(warp::path::param())
+        .and(warp::path("versions"))
+        .and(warp::path::end())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(list_flow_versions_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let get_flow_version = warp::get()
+        .and(warp::path::param())
+        .and(warp::path!("version" / i32))
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(get_flow_version_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let upgrade_flow_tasks = warp::post()
+        .and(warp::path::param())
+        .and(warp::path("upgrade"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and(with_broadcast(task_state_tx.clone()))
+        .and_then(upgrade_flow_tasks_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
     let get_flow_graph = warp::get()
         .and(warp::path::param())
         .and(authenticate(idp.clone(), session.clone()))
@@ -276,12 +699,19 @@ pub fn routes(
         .and(authenticate(idp.clone(), session.clone()))
         .and(with_db(pool.clone()))
         .and(with_broadcast(graph_tx))
+        .and(with_broadcast(task_state_tx))
         .and_then(update_flow_graph_handler)
         .untuple_one()
         .and_then(store_auth_cookie);
 
     warp::path("flow").and(
-        create_flow
+        list_flow_actions
+            .or(create_flow_action)
+            .or(delete_flow_action)
+            .or(list_flow_versions)
+            .or(get_flow_version)
+            .or(upgrade_flow_tasks)
+            .or(create_flow)
             .or(list_flows)
             .or(get_flow_graph)
             .or(update_flow_graph),
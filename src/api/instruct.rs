@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use subseq_util::api::sessions::store_auth_cookie;
+use subseq_util::oidc::IdentityProvider;
+use subseq_util::{api::*, tables::DbPool};
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+use warp_sessions::{MemoryStore, SessionWithStore};
+
+use super::prompts::{session_transcript, TranscriptEntry};
+use super::ValidationError;
+use crate::tables::{InstructSession, PageRequest};
+
+#[derive(Serialize)]
+pub struct SessionTranscript {
+    session: InstructSession,
+    entries: Vec<TranscriptEntry>,
+}
+
+async fn list_sessions_handler(
+    page: PageRequest,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let sessions = InstructSession::page(&mut conn, auth.id(), &page)
+        .map_err(ValidationError::reject)?;
+    Ok((warp::reply::json(&sessions), session))
+}
+
+async fn get_transcript_handler(
+    session_id: Uuid,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let instruct_session = match InstructSession::get(&mut conn, session_id) {
+        Some(instruct_session) if instruct_session.user_id == auth.id() => instruct_session,
+        _ => return Err(warp::reject::custom(NotFoundError {})),
+    };
+    let entries = session_transcript(&mut conn, &instruct_session)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    let transcript = SessionTranscript {
+        session: instruct_session,
+        entries,
+    };
+    Ok((warp::reply::json(&transcript), session))
+}
+
+/// Routes under /instruct. Sessions are resumed by connecting to /ws?resume={session_id}.
+pub fn routes(
+    idp: Option<Arc<IdentityProvider>>,
+    session: MemoryStore,
+    pool: Arc<DbPool>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let list_sessions = warp::path!("sessions")
+        .and(warp::get())
+        .and(warp::query::<PageRequest>())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(list_sessions_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let get_transcript = warp::path!("sessions" / Uuid)
+        .and(warp::get())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(get_transcript_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    warp::path("instruct").and(list_sessions.or(get_transcript))
+}
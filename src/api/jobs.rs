@@ -1,18 +1,22 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use chrono::NaiveDateTime;
+use diesel::{Connection, QueryResult};
 use serde::{Serialize, Deserialize};
 use subseq_util::api::sessions::store_auth_cookie;
 use subseq_util::oidc::IdentityProvider;
 use subseq_util::{api::*, tables::{DbPool, UserTable}, Router};
 use tokio::spawn;
 use tokio::sync::{broadcast, mpsc};
+use tokio::time::timeout;
 use uuid::Uuid;
 use warp::{Filter, Rejection, Reply};
 use warp_sessions::{MemoryStore, SessionWithStore};
 
-use crate::interop::{JobResult, ActionTaken};
+use crate::events::{HELP_REQUESTED_BEAM, JOB_FINISHED_BEAM};
+use crate::interop::{JobProgress, JobResult, ActionTaken};
 use crate::tables::{HelpResolution, DenormalizedHelpAction};
 use crate::{
     api::tasks::DenormalizedTask,
@@ -27,7 +31,11 @@ use crate::{
     tables::{
         AwaitingHelp,
         Job,
+        JobProgress as JobProgressTable,
         JobResult as JobResultTable,
+        OutboxEvent,
+        Page,
+        PageRequest,
         Project,
         Task,
         User,
@@ -35,9 +43,37 @@ use crate::{
 };
 
 use super::prompts::{PromptRxPayload, PromptChannelHandle};
+use super::ValidationError;
+
+/// Sage created a job, acknowledging a task run
+#[derive(Clone, Debug, Serialize)]
+pub struct JobCreated {
+    pub job: Job,
+    pub run_id: Option<Uuid>,
+}
+
+/// Waits for Sage to create a job for the run, giving up after `wait`. Subscribe before the
+/// run is sent so the acknowledgement can't be missed.
+pub async fn wait_for_job(
+    job_created_rx: &mut broadcast::Receiver<JobCreated>,
+    run_id: Uuid,
+    wait: Duration,
+) -> Option<Job> {
+    let acknowledged = async {
+        loop {
+            match job_created_rx.recv().await {
+                Ok(created) if created.run_id == Some(run_id) => return Some(created.job),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    };
+    timeout(wait, acknowledged).await.ok().flatten()
+}
 
 pub fn handle_new_job(db_pool: Arc<DbPool>, router: &mut Router) {
     let mut job_rx: mpsc::Receiver<InteropDenormalizedJob> = router.create_channel();
+    let job_created_tx: broadcast::Sender<JobCreated> = router.announce();
 
     spawn(async move {
         while let Some(job) = job_rx.recv().await {
@@ -55,7 +91,8 @@ pub fn handle_new_job(db_pool: Arc<DbPool>, router: &mut Router) {
                 project_id,
                 task_id,
                 name,
-                job_owners
+                job_owners,
+                run_id,
             } = job;
 
             let task_id = match task_id {
@@ -75,7 +112,10 @@ pub fn handle_new_job(db_pool: Arc<DbPool>, router: &mut Router) {
                 job_owners.created_id,
                 job_owners.assignee_id,
             ) {
-                Ok(job) => tracing::info!("Job created {}", job.id),
+                Ok(job) => {
+                    tracing::info!("Job created {}", job.id);
+                    job_created_tx.send(JobCreated { job, run_id }).ok();
+                }
                 Err(err) => tracing::info!("Failed Job::create: {:?}", err),
             };
         }
@@ -83,8 +123,23 @@ pub fn handle_new_job(db_pool: Arc<DbPool>, router: &mut Router) {
     });
 }
 
+/// A job reported its result
+#[derive(Clone, Debug, Serialize)]
+pub struct JobFinished {
+    pub job: Job,
+    pub result: JobResultTable,
+}
+
+/// A running job asked its requester for help
+#[derive(Clone, Debug, Serialize)]
+pub struct HelpRequested {
+    pub job: Job,
+    pub help: AwaitingHelp,
+}
+
 pub fn handle_new_job_results(db_pool: Arc<DbPool>, router: &mut Router) {
     let mut job_result_rx: mpsc::Receiver<JobResult> = router.create_channel();
+    let job_finished_tx: broadcast::Sender<JobFinished> = router.announce();
 
     spawn(async move {
         while let Some(job_result) = job_result_rx.recv().await {
@@ -116,12 +171,21 @@ pub fn handle_new_job_results(db_pool: Arc<DbPool>, router: &mut Router) {
             }
 
             // Insert this result with that job
-            match JobResultTable::create(&mut conn,
-                                   &job,
-                                   completion_time,
-                                   succeeded,
-                                   job_log) {
-                Ok(job) => tracing::info!("JobResult created {}", job.job_id),
+            let finished = conn.transaction(|transact| {
+                let result = JobResultTable::create(transact,
+                                                    &job,
+                                                    completion_time,
+                                                    succeeded,
+                                                    job_log)?;
+                let finished = JobFinished { job, result };
+                OutboxEvent::record(transact, JOB_FINISHED_BEAM, &finished)?;
+                QueryResult::Ok(finished)
+            });
+            match finished {
+                Ok(finished) => {
+                    tracing::info!("JobResult created {}", finished.result.job_id);
+                    job_finished_tx.send(finished).ok();
+                }
                 Err(err) => tracing::info!("Failed JobResult::create: {:?}", err),
             }
         }
@@ -129,9 +193,50 @@ pub fn handle_new_job_results(db_pool: Arc<DbPool>, router: &mut Router) {
     });
 }
 
+pub fn handle_job_progress(db_pool: Arc<DbPool>, router: &mut Router) {
+    let mut job_progress_rx: mpsc::Receiver<JobProgress> = router.create_channel();
+
+    spawn(async move {
+        while let Some(progress) = job_progress_rx.recv().await {
+            let JobProgress {
+                job_id,
+                percent,
+                stage,
+                message
+            } = progress;
+            let mut conn = match db_pool.get() {
+                Ok(conn) => conn,
+                Err(_) => {
+                    tracing::warn!("Database connection failed");
+                    continue;
+                }
+            };
+            let (job, result) = match Job::get(&mut conn, job_id) {
+                Some(t) => t,
+                None => {
+                    tracing::warn!("No matching job {}", job_id);
+                    continue;
+                }
+            };
+
+            if result.is_some() {
+                tracing::warn!("Progress for finished job {}", job_id);
+                continue;
+            }
+
+            match JobProgressTable::create(&mut conn, &job, percent, stage, message) {
+                Ok(progress) => tracing::info!("JobProgress {}% on {}", progress.percent, job_id),
+                Err(err) => tracing::info!("Failed JobProgress::create: {:?}", err),
+            }
+        }
+        tracing::warn!("handle_job_progress exited");
+    });
+}
+
 pub fn handle_job_request(db_pool: Arc<DbPool>, router: &mut Router, prompt_channel: PromptChannelHandle) {
     let mut job_request_rx: mpsc::Receiver<JobRequest> = router.create_channel();
     let job_response_tx: broadcast::Sender<JobResponse> = router.announce();
+    let help_requested_tx: broadcast::Sender<HelpRequested> = router.announce();
 
     spawn(async move {
         while let Some(request) = job_request_rx.recv().await {
@@ -191,10 +296,16 @@ pub fn handle_job_request(db_pool: Arc<DbPool>, router: &mut Router, prompt_chan
             };
 
             // Add this request to the DB
-            match AwaitingHelp::create(&mut conn,
-                                        job.id,
-                                        request.clone()) {
-                Ok(request) => request,
+            let requested = conn.transaction(|transact| {
+                let help = AwaitingHelp::create(transact, job.id, request.clone())?;
+                let requested = HelpRequested { job, help };
+                OutboxEvent::record(transact, HELP_REQUESTED_BEAM, &requested)?;
+                QueryResult::Ok(requested)
+            });
+            match requested {
+                Ok(requested) => {
+                    help_requested_tx.send(requested).ok();
+                }
                 Err(_) => {
                     tracing::warn!("Couldnt't create request {}", job_id);
                     job_response_tx.send(failed).ok();
@@ -236,8 +347,8 @@ impl JobRequestCollection {
 
 #[derive(Deserialize)]
 pub struct QueryPayload {
-    pub page: u32,
-    pub page_size: u32,
+    #[serde(flatten)]
+    pub page: PageRequest,
     pub query: HashMap<String, String>,
 }
 
@@ -251,13 +362,13 @@ async fn filter_jobs_handler(
         Ok(conn) => conn,
         Err(_) => return Err(warp::reject::custom(DatabaseError {})),
     };
-    let QueryPayload { page, page_size, query } = payload;
+    let QueryPayload { page, query } = payload;
 
-    let result = Job::query(&mut conn, auth_user.id(), &query, page, page_size)
-        .map_err(|_| warp::reject::custom(DatabaseError{}))?;
+    let result = Job::query(&mut conn, auth_user.id(), &query, &page)
+        .map_err(ValidationError::reject)?;
 
 
-    let jobs: Vec<_> = result.into_iter()
+    let jobs: Vec<_> = result.items.into_iter()
         .filter_map(|(job, job_result)| {
             let task = Task::get(&mut conn, job.task_id)?;
             let project = Project::get(&mut conn, job.project_id)?;
@@ -272,6 +383,11 @@ async fn filter_jobs_handler(
         })
         .collect();
 
+    let jobs = Page {
+        items: jobs,
+        next_cursor: result.next_cursor,
+        total: result.total,
+    };
     Ok((warp::reply::json(&jobs), session))
 }
 
@@ -286,13 +402,13 @@ async fn filter_logs_handler(
         Err(_) => return Err(warp::reject::custom(DatabaseError {})),
     };
 
-    let QueryPayload { page, page_size, mut query } = payload;
+    let QueryPayload { page, mut query } = payload;
     query.insert("running".to_string(), "false".to_string());  // Guarantees all have results
 
-    let result = Job::query(&mut conn, auth_user.id(), &query, page, page_size)
-        .map_err(|_| warp::reject::custom(DatabaseError{}))?;
+    let result = Job::query(&mut conn, auth_user.id(), &query, &page)
+        .map_err(ValidationError::reject)?;
 
-    let jobs: Vec<_> = result.into_iter()
+    let jobs: Vec<_> = result.items.into_iter()
         .filter_map(|(job, job_result)| {
             let job_result = job_result?;
             let task = Task::get(&mut conn, job.task_id)?;
@@ -310,6 +426,11 @@ async fn filter_logs_handler(
         })
         .collect();
 
+    let jobs = Page {
+        items: jobs,
+        next_cursor: result.next_cursor,
+        total: result.total,
+    };
     Ok((warp::reply::json(&jobs), session))
 }
 
@@ -354,6 +475,7 @@ pub struct DenormalizedJobFull {
     pub task: DenormalizedTask,
     pub project: Project,
     pub result: Option<DenormalizedJobResult>,
+    pub progress: Vec<JobProgressTable>,
     pub help_request: Option<DenormalizedHelpRequest>,
     pub assigned_to: DenormalizedUser,
     pub requested_by: DenormalizedUser,
@@ -389,6 +511,9 @@ async fn get_job_handler(
     let requested_by = DenormalizedUser::denormalize(&mut conn, requested_by)
         .map_err(|_| warp::reject::custom(DatabaseError{}))?;
 
+    let progress = JobProgressTable::list_for_job(&mut conn, job.id)
+        .map_err(|_| warp::reject::custom(DatabaseError{}))?;
+
     let help = AwaitingHelp::next_open_help(&mut conn, &job);
     let help_request = if let Some(help) = help.as_ref() {
         let resolution = HelpResolution::get(&mut conn, help.id);
@@ -419,6 +544,7 @@ async fn get_job_handler(
         task,
         project,
         result: job_result,
+        progress,
         help_request,
         assigned_to,
         requested_by
@@ -644,3 +770,43 @@ pub fn routes(
             .or(finish_help)
     )
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn created(run_id: Option<Uuid>) -> JobCreated {
+        let job = Job {
+            id: Uuid::new_v4(),
+            project_id: Uuid::new_v4(),
+            task_id: Uuid::new_v4(),
+            name: "run".to_string(),
+            created_id: Uuid::new_v4(),
+            assignee_id: Uuid::new_v4(),
+            created: chrono::Utc::now().naive_utc(),
+        };
+        JobCreated { job, run_id }
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_job() {
+        let (job_created_tx, mut job_created_rx) = broadcast::channel(8);
+        let run_id = Uuid::new_v4();
+
+        // Jobs for other runs, or created without a run, aren't the acknowledgement
+        job_created_tx.send(created(None)).expect("send");
+        job_created_tx.send(created(Some(Uuid::new_v4()))).expect("send");
+        let ours = created(Some(run_id));
+        job_created_tx.send(ours.clone()).expect("send");
+        let job = wait_for_job(&mut job_created_rx, run_id, Duration::from_secs(1)).await;
+        assert_eq!(job, Some(ours.job));
+
+        job_created_tx.send(created(Some(Uuid::new_v4()))).expect("send");
+        let job = wait_for_job(&mut job_created_rx, run_id, Duration::from_millis(50)).await;
+        assert_eq!(job, None);
+
+        drop(job_created_tx);
+        let job = wait_for_job(&mut job_created_rx, run_id, Duration::from_secs(1)).await;
+        assert_eq!(job, None);
+    }
+}
@@ -1,17 +1,73 @@
+pub mod comments;
 pub mod flows;
+pub mod instruct;
 pub mod projects;
 pub mod prompts;
+pub mod routing;
 pub mod jobs;
 pub mod socket;
+pub mod subscriptions;
 pub mod tasks;
 pub mod users;
 pub mod voice;
+pub mod webhooks;
 
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use serde::Serialize;
+use subseq_util::api::{DatabaseError, NotFoundError};
 use tokio::sync::mpsc;
-use warp::Filter;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::tables::UnmetGuardsMessage;
 
 pub const PAGE_SIZE: u32 = 20;
 
+/// A request which the tables layer refused, returned to the caller with the reason.
+#[derive(Debug, Serialize)]
+pub struct ValidationError {
+    pub message: String,
+    pub column: Option<String>,
+    pub constraint_name: Option<String>,
+    /// Each guard a refused transition did not meet
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unmet_guards: Vec<String>,
+}
+
+impl warp::reject::Reject for ValidationError {}
+
+impl ValidationError {
+    /// Passes check violations through to the caller and missing rows as NotFound, anything
+    /// else is a DatabaseError.
+    pub fn reject(err: DieselError) -> Rejection {
+        match err {
+            DieselError::DatabaseError(DatabaseErrorKind::CheckViolation, info) => {
+                warp::reject::custom(ValidationError {
+                    message: info.message().to_string(),
+                    column: info.column_name().map(String::from),
+                    constraint_name: info.constraint_name().map(String::from),
+                    unmet_guards: UnmetGuardsMessage::unmet(info.as_ref()),
+                })
+            }
+            DieselError::NotFound => warp::reject::custom(NotFoundError {}),
+            err => {
+                tracing::warn!("Database error: {}", err);
+                warp::reject::custom(DatabaseError {})
+            }
+        }
+    }
+}
+
+pub async fn handle_validation_rejection(rej: Rejection) -> Result<impl Reply, Rejection> {
+    match rej.find::<ValidationError>() {
+        Some(err) => Ok(warp::reply::with_status(
+            warp::reply::json(err),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        )),
+        None => Err(rej),
+    }
+}
+
 pub fn with_channel<M: Send + Sync>(
     channel: mpsc::Sender<M>,
 ) -> impl Filter<Extract = (mpsc::Sender<M>,), Error = std::convert::Infallible> + Clone {
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use serde::Deserialize;
+use diesel::Connection;
+use serde::{Deserialize, Serialize};
 use subseq_util::api::sessions::store_auth_cookie;
 use subseq_util::oidc::IdentityProvider;
 use subseq_util::{api::*, tables::UserTable, Router};
@@ -10,8 +11,9 @@ use uuid::Uuid;
 use warp::{Filter, Rejection, Reply};
 use warp_sessions::{MemoryStore, SessionWithStore};
 
-use super::PAGE_SIZE;
-use crate::tables::{DbPool, Flow, Project, User};
+use super::ValidationError;
+use crate::events::PROJECT_UPDATED_BEAM;
+use crate::tables::{DbPool, Flow, OutboxEvent, PageRequest, Project, User};
 
 #[derive(Deserialize)]
 pub struct ProjectPayload {
@@ -64,8 +66,91 @@ pub async fn create_project_handler(
     Ok((warp::reply::json(&dict), session))
 }
 
+#[derive(Deserialize)]
+pub struct ProjectUpdatePayload {
+    name: Option<String>,
+    description: Option<String>,
+    default_flow: Option<Uuid>,
+    owner_id: Option<Uuid>,
+}
+
+/// A project was changed through PUT /project/{id}
+#[derive(Clone, Debug, Serialize)]
+pub struct ProjectUpdated {
+    pub project: Project,
+    pub actor_id: Uuid,
+}
+
+pub async fn update_project_handler(
+    project_id: Uuid,
+    payload: ProjectUpdatePayload,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+    sender: broadcast::Sender<ProjectUpdated>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let mut project = match Project::get(&mut conn, project_id) {
+        Some(project) => project,
+        None => return Err(warp::reject::custom(NotFoundError {})),
+    };
+    if project.owner_id != auth.id() {
+        tracing::warn!("User {} cannot modify project {}", auth.id(), project_id);
+        return Err(warp::reject::custom(InvalidConfigurationError {}));
+    }
+    let ProjectUpdatePayload {
+        name,
+        description,
+        default_flow,
+        owner_id,
+    } = payload;
+
+    let flow = match default_flow {
+        Some(flow_id) => match Flow::get(&mut conn, flow_id) {
+            Some(flow) => Some(flow),
+            None => return Err(warp::reject::custom(NotFoundError {})),
+        },
+        None => None,
+    };
+    let owner = match owner_id {
+        Some(owner_id) => match User::get(&mut conn, owner_id) {
+            Some(owner) => Some(owner),
+            None => return Err(warp::reject::custom(NotFoundError {})),
+        },
+        None => None,
+    };
+
+    let updated = conn.transaction(|transact| {
+        if let Some(name) = name {
+            project.set_name(transact, &name)?;
+        }
+        if let Some(description) = description {
+            project.set_description(transact, &description)?;
+        }
+        if let Some(flow) = flow {
+            project.set_default_flow(transact, &flow)?;
+        }
+        if let Some(owner) = owner {
+            project.set_owner(transact, &owner)?;
+        }
+        let updated = ProjectUpdated {
+            project: project.clone(),
+            actor_id: auth.id(),
+        };
+        OutboxEvent::record(transact, PROJECT_UPDATED_BEAM, &updated)?;
+        Ok(updated)
+    })
+    .map_err(ValidationError::reject)?;
+
+    sender.send(updated).ok();
+    Ok((warp::reply::json(&project), session))
+}
+
 pub async fn list_projects_handler(
-    page_number: u32,
+    page: PageRequest,
     _auth: AuthenticatedUser,
     session: SessionWithStore<MemoryStore>,
     db_pool: Arc<DbPool>,
@@ -74,7 +159,7 @@ pub async fn list_projects_handler(
         Ok(conn) => conn,
         Err(_) => return Err(warp::reject::custom(DatabaseError {})),
     };
-    let projects = Project::list(&mut conn, page_number, PAGE_SIZE);
+    let projects = Project::page(&mut conn, &page).map_err(ValidationError::reject)?;
     Ok((warp::reply::json(&projects), session))
 }
 
@@ -149,6 +234,7 @@ pub fn routes(
     router: &mut Router,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     let project_tx: broadcast::Sender<Project> = router.announce();
+    let project_update_tx: broadcast::Sender<ProjectUpdated> = router.announce();
 
     let create_project = warp::post()
         .and(warp::body::json())
@@ -159,9 +245,9 @@ pub fn routes(
         .untuple_one()
         .and_then(store_auth_cookie);
 
-    let list_projects = warp::path("list")
+    let list_projects = warp::path!("list")
         .and(warp::get())
-        .and(warp::path::param())
+        .and(warp::query::<PageRequest>())
         .and(authenticate(idp.clone(), session.clone()))
         .and(with_db(pool.clone()))
         .and_then(list_projects_handler)
@@ -185,6 +271,17 @@ pub fn routes(
         .untuple_one()
         .and_then(store_auth_cookie);
 
+    let update_project = warp::put()
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and(with_broadcast(project_update_tx))
+        .and_then(update_project_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
     let get_project = warp::get()
         .and(warp::path::param())
         .and(authenticate(idp.clone(), session.clone()))
@@ -198,6 +295,7 @@ pub fn routes(
             .or(list_projects)
             .or(set_active_project)
             .or(get_active_project)
+            .or(update_project)
             .or(get_project),
     )
 }
@@ -1,20 +1,35 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use diesel::PgConnection;
+use diesel::{PgConnection, QueryResult};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use subseq_util::api::AuthenticatedUser;
 use subseq_util::tables::{DbPool, UserTable};
 use subseq_util::Router;
-use tokio::{sync::{broadcast, mpsc}, select, task::spawn};
+use tokio::{sync::{broadcast, mpsc}, select, task::spawn, time::timeout};
 use uuid::Uuid;
 
+use super::jobs::{wait_for_job, JobCreated};
 use super::socket::FrontEndMessage;
-use super::tasks::{create_task, filter_tasks, update_task, QueryPayload, DenormalizedTask};
+use super::tasks::{
+    build_task_run, create_task, find_duplicates, query_tasks, update_task,
+    DenormalizedTask, TaskUpdateSenders,
+};
 use super::users::DenormalizedUser;
+use crate::embeddings::EmbedRequest;
+use crate::events::TASK_RUN_BEAM;
 use crate::interop::JobRequestType;
-use crate::tables::{ActiveProject, Flow, Project, Task, TaskLinkType, TaskUpdate, User};
+use crate::tables::{
+    ActiveProject, Flow, InstructMessage, InstructSession, OutboxEvent, Project, ProjectRef, Task,
+    TaskFilter, TaskLinkType, TaskQuery, TaskUpdate, User,
+};
+
+/// How long a run waits for Sage to create its job
+const RUN_ACK_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the stream may stay silent while the assistant has the turn before it is taken for dead
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -98,7 +113,11 @@ pub struct TaskSummary {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum ToolResult {
     RunTask(Uuid),
+    /// Sage has not acknowledged the run yet; it starts once Sage picks it up
+    RunQueued { run_id: Uuid },
     CreateTask(TaskSummary),
+    /// The task was not created because the user took it for a duplicate of these
+    Duplicates(Vec<TaskSummary>),
     UpdateTask(TaskSummary),
     FetchTasks(Vec<TaskSummary>),
     BeginProject { project_id: Uuid },
@@ -133,7 +152,12 @@ pub enum Tool {
         task_id: Uuid,
         update: TaskUpdate,
     },
+    /// Every task in the active project
     FetchTasks,
+    /// Tasks in the active project matching a task query
+    QueryTasks {
+        query: String,
+    },
     BeginProject {
         title: String,
         description: String,
@@ -157,6 +181,57 @@ pub enum PromptRxPayload {
     Close(PromptResponseType),
 }
 
+/// One step of an instruct session, as it is stored and replayed to the frontend
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", content = "payload", rename_all = "snake_case")]
+pub enum TranscriptEntry {
+    Chat(ChatCompletion),
+    ToolCall(Tool),
+    ToolResult(ToolResult),
+}
+
+impl TranscriptEntry {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Chat(_) => "chat",
+            Self::ToolCall(_) => "tool_call",
+            Self::ToolResult(_) => "tool_result",
+        }
+    }
+
+    fn payload(&self) -> Value {
+        match self {
+            Self::Chat(chat) => serde_json::to_value(chat),
+            Self::ToolCall(tool) => serde_json::to_value(tool),
+            Self::ToolResult(result) => serde_json::to_value(result),
+        }
+        .expect("Transcript entries serialize")
+    }
+
+    /// Reads back a stored step, or None if it no longer parses as one
+    pub fn from_message(message: &InstructMessage) -> Option<Self> {
+        let payload = message.payload.clone();
+        match message.kind.as_str() {
+            "chat" => serde_json::from_value(payload).ok().map(Self::Chat),
+            "tool_call" => serde_json::from_value(payload).ok().map(Self::ToolCall),
+            "tool_result" => serde_json::from_value(payload).ok().map(Self::ToolResult),
+            _ => None,
+        }
+    }
+}
+
+/// The steps of a session which still parse, in order
+pub fn session_transcript(
+    conn: &mut PgConnection,
+    session: &InstructSession,
+) -> QueryResult<Vec<TranscriptEntry>> {
+    Ok(session
+        .messages(conn)?
+        .iter()
+        .filter_map(TranscriptEntry::from_message)
+        .collect())
+}
+
 pub struct InitializePromptChannel (
     pub Uuid,
     pub mpsc::Receiver<PromptTx>,
@@ -166,15 +241,44 @@ pub struct InitializePromptChannel (
 #[derive(Clone, Debug)]
 pub struct PromptChannelHandle {
     user_to_prompt: Arc<Mutex<HashMap<Uuid, mpsc::UnboundedSender<PromptRxPayload>>>>,
+    live_sessions: Arc<Mutex<HashSet<Uuid>>>,
+}
+
+/// Marks a session as driven by one socket. The session is free again once this is dropped.
+#[derive(Debug)]
+pub struct SessionClaim {
+    live_sessions: Arc<Mutex<HashSet<Uuid>>>,
+    session_id: Uuid,
+}
+
+impl Drop for SessionClaim {
+    fn drop(&mut self) {
+        if let Ok(mut live) = self.live_sessions.lock() {
+            live.remove(&self.session_id);
+        }
+    }
 }
 
 impl PromptChannelHandle {
     pub fn new() -> Self {
         Self {
-            user_to_prompt: Arc::new(Mutex::new(HashMap::new()))
+            user_to_prompt: Arc::new(Mutex::new(HashMap::new())),
+            live_sessions: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
+    /// Claims a session for the calling socket, None when another socket already drives it
+    pub fn claim_session(&self, session_id: Uuid) -> Option<SessionClaim> {
+        let mut live = self.live_sessions.lock().unwrap();
+        if !live.insert(session_id) {
+            return None;
+        }
+        Some(SessionClaim {
+            live_sessions: self.live_sessions.clone(),
+            session_id,
+        })
+    }
+
     pub fn add_channel_for_user(&self, user_id: Uuid) -> (mpsc::UnboundedSender<PromptRxPayload>, mpsc::UnboundedReceiver<PromptRxPayload>) {
         let mut map = self.user_to_prompt.lock().unwrap();
         let (tx, rx) = mpsc::unbounded_channel();
@@ -248,11 +352,17 @@ enum AuthRequestPayload {
         title: String,
         description: String,
         components: Vec<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        duplicates: Vec<TaskSummary>,
     },
     TaskUpdate {
         task_id: Uuid,
         update: TaskUpdate,
     },
+    TaskRun {
+        task_id: Uuid,
+        title: String,
+    },
     Project {
         title: String,
         description: String,
@@ -262,50 +372,106 @@ enum AuthRequestPayload {
 #[derive(Debug)]
 pub struct InstructionState {
     auth_user: AuthenticatedUser,
-    assignee: Option<User>,
     stream_id: Option<Uuid>,
     project_id: Uuid,
-    check_auth: bool
+    check_auth: bool,
+    session: Option<InstructSession>,
+    claim: Option<SessionClaim>,
 }
 
 impl InstructionState {
+    /// Closes the stored session and lets go of it
+    fn end_session(&mut self, conn: &mut PgConnection) {
+        self.update_session(conn, |session, conn| session.close(conn));
+        self.claim.take();
+    }
+
+    /// Applies a change to the stored session. A session which can't be saved doesn't end the
+    /// conversation, it only can't be resumed.
+    fn update_session<F>(&mut self, conn: &mut PgConnection, update: F)
+    where
+        F: FnOnce(&mut InstructSession, &mut PgConnection) -> QueryResult<()>,
+    {
+        if let Some(session) = self.session.as_mut() {
+            if let Err(err) = update(session, conn) {
+                tracing::warn!("Could not update instruct session {}: {:?}", session.id, err);
+            }
+        }
+    }
+
+    /// Tasks in the session's active project matching a task query, the whole project when
+    /// the query is empty
+    fn fetch_tasks(&self, conn: &mut PgConnection, query: &str) -> ToolResult {
+        let task_query = match TaskQuery::parse(query) {
+            Ok(task_query) => task_query.and(TaskFilter::Project(ProjectRef::Active)),
+            Err(err) => return ToolResult::Error(format!("Invalid task query: {}", err)),
+        };
+        match query_tasks(self.auth_user, conn, &task_query, None, Some(50)) {
+            Ok(reply) => {
+                let summary_vec: Vec<_> = reply
+                    .tasks
+                    .into_iter()
+                    .map(|task| TaskSummary {
+                        task_id: task.id,
+                        title: task.title,
+                        description: task.description,
+                    })
+                    .collect();
+                ToolResult::FetchTasks(summary_vec)
+            }
+            Err(err) => ToolResult::Error(format!("Tool error: {:?}", err)),
+        }
+    }
+
     async fn run_tool(
         &mut self,
         tool: Tool,
-        conn: &mut PgConnection,
+        db_pool: &DbPool,
         connections: Connections<'_>,
         string_rx: &mut mpsc::Receiver<String>,
     ) -> ToolResult {
+        let mut pooled = match db_pool.get() {
+            Ok(conn) => conn,
+            Err(_) => return ToolResult::Error("Database connection failed".to_string()),
+        };
+        let conn: &mut PgConnection = &mut pooled;
         match tool {
-            Tool::RunTask { task_id: _ } => {
-                // TODO: This currently doesn't have an API
-                ToolResult::RunTask(Uuid::new_v4())
-            }
-            Tool::FetchTasks => {
-                let mut query = HashMap::new();
-                query.insert(String::from("project"), String::from("active"));
-                let payload = QueryPayload {
-                    page: 0,
-                    page_size: 50,
-                    query,
+            Tool::RunTask { task_id } => {
+                let task = match Task::get(conn, task_id) {
+                    Some(task) if task.in_project(conn, self.project_id).unwrap_or(false) => task,
+                    _ => return ToolResult::Error(format!("No task {} in this project", task_id)),
                 };
-                let result = filter_tasks(self.auth_user, conn, payload).await;
-                match result {
-                    Ok(reply) => {
-                        let summary_vec: Vec<_> = reply
-                            .tasks
-                            .into_iter()
-                            .map(|task| TaskSummary {
-                                task_id: task.id,
-                                title: task.title,
-                                description: task.description,
-                            })
-                            .collect();
-                        ToolResult::FetchTasks(summary_vec)
-                    }
-                    Err(err) => ToolResult::Error(format!("Tool error: {:?}", err)),
+                if task.assignee_id.is_none() {
+                    let message = "The task must be assigned before it is run".to_string();
+                    return ToolResult::Error(message);
+                }
+                let authed_run = AuthRequestPayload::TaskRun {
+                    task_id,
+                    title: task.title.clone(),
+                };
+                if self.check_auth && !connections.auth_request(conn, string_rx, authed_run).await {
+                    return ToolResult::Error("Change was rejected by the user".to_string());
+                }
+                let run = match build_task_run(conn, self.auth_user.id(), &task) {
+                    Ok(run) => run,
+                    Err(err) => return ToolResult::Error(format!("Could not run task: {:?}", err)),
+                };
+
+                let run_id = run.run_id;
+                let mut job_created_rx = connections.job_created_tx.subscribe();
+                if let Err(err) = OutboxEvent::record(conn, TASK_RUN_BEAM, &run) {
+                    return ToolResult::Error(format!("Database error: {:?}", err));
+                }
+                connections.update_senders.task_run_tx.send(run).ok();
+                // Sage can take a while to pick the run up; the connection isn't held meanwhile
+                drop(pooled);
+                match wait_for_job(&mut job_created_rx, run_id, RUN_ACK_TIMEOUT).await {
+                    Some(job) => ToolResult::RunTask(job.id),
+                    None => ToolResult::RunQueued { run_id },
                 }
             }
+            Tool::FetchTasks => self.fetch_tasks(conn, ""),
+            Tool::QueryTasks { query } => self.fetch_tasks(conn, &query),
             Tool::CreateTask {
                 title,
                 description,
@@ -314,21 +480,57 @@ impl InstructionState {
                 tags,
                 components,
             } => {
+                // Likely duplicates are always put to the user, even when changes are not
+                // otherwise checked
+                let duplicates: Vec<_> = find_duplicates(
+                    conn,
+                    connections.embed_tx,
+                    self.project_id,
+                    &title,
+                    &description,
+                )
+                .await
+                .into_iter()
+                .map(|similar| TaskSummary {
+                    task_id: similar.task.id,
+                    title: similar.task.title,
+                    description: similar.task.description,
+                })
+                .collect();
+                let ask = self.check_auth || !duplicates.is_empty();
                 let authed_task = AuthRequestPayload::Task {
                     title: title.clone(),
                     description: description.clone(),
                     components: components.clone(),
+                    duplicates: duplicates.clone(),
                 };
-                if self.check_auth && !connections.auth_request(string_rx, authed_task).await {
+                if ask && !connections.auth_request(conn, string_rx, authed_task).await {
+                    if !duplicates.is_empty() {
+                        return ToolResult::Duplicates(duplicates);
+                    }
                     return ToolResult::Error("Change was rejected by the user".to_string());
                 }
 
-                let mut task = match create_task(
+                let mut task_tags = vec![];
+                for tag in tags.unwrap_or_default() {
+                    let label = serde_json::to_string(&serde_json::json!({"label": tag}))
+                        .expect("is valid");
+                    task_tags.push(label);
+                }
+                for component in components {
+                    let component = serde_json::to_string(&serde_json::json!({"component": component}))
+                        .expect("is valid");
+                    task_tags.push(component);
+                }
+
+                let task = match create_task(
                     conn,
                     self.auth_user.id(),
                     self.project_id,
                     title,
                     description,
+                    &task_tags,
+                    connections.update_senders,
                 )
                 .await
                 {
@@ -350,23 +552,6 @@ impl InstructionState {
                     }
                 }
 
-                if let Some(tags) = tags {
-                    for tag in tags {
-                        let label = serde_json::to_string(&serde_json::json!({"label": tag}))
-                            .expect("is valid");
-                        task.add_tag(conn, &label).ok();
-                    }
-                }
-                for component in components {
-                    let component = serde_json::to_string(&serde_json::json!({"component": component}))
-                        .expect("is valid");
-                    task.add_tag(conn, &component).ok();
-                }
-
-                if let Some(user) = self.assignee.as_ref() {
-                    task.update(conn, self.auth_user.id(),
-                                TaskUpdate::AssignOther { user_id: user.id }).ok();
-                }
                 connections.task_tx.send(task.clone()).ok();
                 if let Ok(task) = DenormalizedTask::denormalize(conn, &task) {
                     connections.chat_tx.send(FrontEndMessage::AddTask(task)).await.ok();
@@ -382,10 +567,16 @@ impl InstructionState {
                     task_id,
                     update: update.clone(),
                 };
-                if self.check_auth && !connections.auth_request(string_rx, authed_task).await {
+                if self.check_auth
+                    && !connections.auth_request(conn, string_rx, authed_task).await
+                {
                     return ToolResult::Error("Change was rejected by the user".to_string());
                 }
-                let task = match update_task(conn, self.auth_user.id(), task_id, update).await {
+                let task = match update_task(conn,
+                                             self.auth_user.id(),
+                                             task_id,
+                                             update,
+                                             connections.update_senders).await {
                     Ok(task) => task,
                     Err(err) => return ToolResult::Error(format!("Database error: {:?}", err)),
                 };
@@ -400,7 +591,9 @@ impl InstructionState {
                     title: title.clone(),
                     description: description.clone(),
                 };
-                if self.check_auth && !connections.auth_request(string_rx, authed_project).await {
+                if self.check_auth
+                    && !connections.auth_request(conn, string_rx, authed_project).await
+                {
                     return ToolResult::Error("Change was rejected by the user".to_string());
                 }
                 let user = match User::get(conn, self.auth_user.id()) {
@@ -408,7 +601,7 @@ impl InstructionState {
                     None => return ToolResult::Error("Database error: missing user".to_string()),
                 };
 
-                let flow = match Flow::list(conn, 0, 1).into_iter().next() {
+                let flow = match Flow::default_flow(conn) {
                     Some(flow) => flow,
                     None => return ToolResult::Error("No flows defined in database".to_string()),
                 };
@@ -428,6 +621,7 @@ impl InstructionState {
                     return ToolResult::Error("Could not set the active project".to_string());
                 }
                 self.project_id = project.id;
+                self.update_session(conn, |session, conn| session.set_project(conn, project.id));
                 connections.project_tx.send(project.clone()).ok();
                 connections.chat_tx.send(FrontEndMessage::SetProject(project)).await.ok();
 
@@ -448,12 +642,39 @@ pub struct Connections<'a> {
     project_tx: &'a broadcast::Sender<Project>,
     task_tx: &'a broadcast::Sender<Task>,
     prompt_tx: &'a mpsc::Sender<PromptTx>,
+    update_senders: &'a TaskUpdateSenders,
+    embed_tx: &'a mpsc::Sender<EmbedRequest>,
+    job_created_tx: &'a broadcast::Sender<JobCreated>,
+    session_id: Option<Uuid>,
 }
 
 
 impl<'a> Connections<'a> {
+    /// Adds a step to the stored session, if there is one
+    fn record(&self, conn: &mut PgConnection, entry: &TranscriptEntry) {
+        if let Some(session_id) = self.session_id {
+            let appended = InstructMessage::append(conn, session_id, entry.kind(), entry.payload());
+            if let Err(err) = appended {
+                tracing::warn!("Could not record to instruct session {}: {:?}", session_id, err);
+            }
+        }
+    }
+
+    /// Records a chat message and shows it to the user. It is recorded first so it is kept
+    /// even when the user has gone.
+    async fn send_chat(
+        &self,
+        conn: &mut PgConnection,
+        chat: ChatCompletion,
+    ) -> Result<(), InstructError> {
+        self.record(conn, &TranscriptEntry::Chat(chat.clone()));
+        self.chat_tx.send(FrontEndMessage::InstructMessage(chat)).await
+            .map_err(|_| InstructError("ChatCompletion channel is closed"))
+    }
+
     async fn auth_request(
         &self,
+        conn: &mut PgConnection,
         string_rx: &mut mpsc::Receiver<String>,
         auth_request: AuthRequestPayload,
     ) -> bool {
@@ -461,7 +682,7 @@ impl<'a> Connections<'a> {
             role: ChatRole::System,
             content: Content::Object(serde_json::to_value(&auth_request).expect("Request")),
         };
-        if self.chat_tx.send(FrontEndMessage::InstructMessage(chat)).await.is_err() {
+        if self.send_chat(conn, chat).await.is_err() {
             return false;
         }
         let auth_response = match string_rx.recv().await {
@@ -477,75 +698,49 @@ impl<'a> Connections<'a> {
     }
 }
 
-fn reassign_user(conn: &mut PgConnection,
-                 update: &str) -> Option<Option<User>> {
-    if update.contains("Frontend") {
-        return Some(User::from_username(conn, "FRONTEND"));
-    }
-    if update.contains("Backend") {
-        return Some(User::from_username(conn, "BACKEND"));
-    }
-    if update.contains("Devops") {
-        return Some(User::from_username(conn, "DEVOPS"));
-    }
-    // Don't want to set assignee on single tasks
-    if update.contains("finished") {
-        return Some(None);
-    }
-    None
-}
-
 async fn instruction_channel_message(response: PromptRxPayload,
-                                     conn: &mut PgConnection,
+                                     db_pool: &DbPool,
                                      connections: Connections<'_>,
                                      state: &mut InstructionState,
                                      string_rx: &mut mpsc::Receiver<String>) -> Result<bool, InstructError> {
+    // Tools take their own connections, so they can let go of them while they wait
+    let conn = || db_pool.get().map_err(|_| InstructError("DbPool is errored"));
     match response {
         PromptRxPayload::Tool(tool) => {
             let stream_id = state.stream_id
                 .ok_or_else(|| InstructError("stream_id attempted to be used before being set"))?;
+            connections.record(&mut conn()?, &TranscriptEntry::ToolCall(tool.clone()));
             let tool_response = state.run_tool(
                 tool,
-                conn,
+                db_pool,
                 connections,
                 string_rx,
             )
             .await;
+            connections.record(&mut conn()?, &TranscriptEntry::ToolResult(tool_response.clone()));
             let response = PromptTx::tool_result(stream_id, tool_response);
             connections.prompt_tx.send(response).await
                 .map_err(|_| InstructError("PromptTx channel is closed"))?;
         }
         PromptRxPayload::Stream { update, response_expected } => {
-            // HACKY
-            if let Some(new_assignee) = reassign_user(conn, &update) {
-                state.assignee = new_assignee;
-            }
-
+            let conn = &mut conn()?;
             let chat = ChatCompletion {
                 role: ChatRole::Assistant,
                 content: Content::Text(update),
             };
-            connections.chat_tx.send(FrontEndMessage::InstructMessage(chat)).await
-                .map_err(|_| InstructError("ChatCompletion channel is closed"))?;
+            if response_expected {
+                // Marked before the message goes out so a user who drops now is asked again
+                // on resuming
+                state.update_session(conn, |session, conn| session.set_awaiting_reply(conn, true));
+            }
+            connections.send_chat(conn, chat).await?;
 
             if response_expected {
-                let stream_id = state.stream_id
-                    .ok_or_else(|| InstructError("stream_id attempted to be used before being set"))?;
-                let text = string_rx.recv().await
-                    .ok_or_else(|| InstructError("StringRx channel is closed"))?;
-                connections.chat_tx
-                    .send(FrontEndMessage::InstructMessage(ChatCompletion {
-                        role: ChatRole::User,
-                        content: Content::Text(text.clone()),
-                    }))
-                    .await
-                    .ok();
-                let response = PromptTx::stream_update(stream_id, text);
-                connections.prompt_tx.send(response).await
-                    .map_err(|_| InstructError("PromptTx channel is closed"))?;
+                forward_reply(conn, connections, state, string_rx).await?;
             }
         }
         PromptRxPayload::JobRequest(job_request_type, user) => {
+            let conn = &mut conn()?;
             let denorm_user = DenormalizedUser::denormalize(conn, user)
                 .map_err(|_| InstructError("Could not denormalize user"))?;
             let request = UIRequest { user: denorm_user,
@@ -555,10 +750,10 @@ async fn instruction_channel_message(response: PromptRxPayload,
                 content: Content::Object(serde_json::to_value(&request)
                     .map_err(|_| InstructError("serde serialization failed"))?),
             };
-            connections.chat_tx.send(FrontEndMessage::InstructMessage(chat)).await
-                .map_err(|_| InstructError("ChatCompletion channel is closed"))?;
+            connections.send_chat(conn, chat).await?;
         }
         PromptRxPayload::Close(last_update) => {
+            let conn = &mut conn()?;
             let chat = match last_update {
                 PromptResponseType::Json(json) => ChatCompletion {
                     role: ChatRole::System,
@@ -570,23 +765,89 @@ async fn instruction_channel_message(response: PromptRxPayload,
                     content: Content::Text(text),
                 },
             };
-            connections.chat_tx.send(FrontEndMessage::InstructMessage(chat)).await
-                .map_err(|_| InstructError("ChatCompletion channel is closed"))?;
+            state.end_session(conn);
+            connections.send_chat(conn, chat).await?;
 
             let chat = ChatCompletion {
                 role: ChatRole::System,
                 content: Content::Object(serde_json::json!({"state": "closed"})),
             };
-            connections.chat_tx.send(FrontEndMessage::InstructMessage(chat)).await
-                .map_err(|_| InstructError("ChatCompletion channel is closed"))?;
+            connections.send_chat(conn, chat).await?;
             return Ok(true);
         }
     }
     Ok(false)
 }
 
+/// Passes the user's next message on to the stream waiting for it
+async fn forward_reply(conn: &mut PgConnection,
+                       connections: Connections<'_>,
+                       state: &mut InstructionState,
+                       string_rx: &mut mpsc::Receiver<String>) -> Result<(), InstructError> {
+    let stream_id = state.stream_id
+        .ok_or_else(|| InstructError("stream_id attempted to be used before being set"))?;
+    let text = string_rx.recv().await
+        .ok_or_else(|| InstructError("StringRx channel is closed"))?;
+    let chat = ChatCompletion {
+        role: ChatRole::User,
+        content: Content::Text(text.clone()),
+    };
+    connections.send_chat(conn, chat).await.ok();
+    state.update_session(conn, |session, conn| session.set_awaiting_reply(conn, false));
+    let response = PromptTx::stream_update(stream_id, text);
+    connections.prompt_tx.send(response).await
+        .map_err(|_| InstructError("PromptTx channel is closed"))
+}
+
 pub struct InstructError(pub &'static str);
 
+/// Replays a session the user asked to resume, returning it if it can carry on: it is still open,
+/// its stream has started and no other socket is driving it.
+async fn replay_session(
+    conn: &mut PgConnection,
+    auth_user: AuthenticatedUser,
+    session_id: Uuid,
+    chat_tx: &mpsc::Sender<FrontEndMessage>,
+    prompt_channel: &PromptChannelHandle,
+) -> Option<(InstructSession, SessionClaim)> {
+    let session = match InstructSession::get(conn, session_id) {
+        Some(session) if session.user_id == auth_user.id() => session,
+        _ => {
+            tracing::warn!("{} can't resume instruct session {}", auth_user.id(), session_id);
+            return None;
+        }
+    };
+    let entries = match session_transcript(conn, &session) {
+        Ok(entries) => entries,
+        Err(err) => {
+            tracing::warn!("Could not load instruct session {}: {:?}", session_id, err);
+            return None;
+        }
+    };
+    chat_tx
+        .send(FrontEndMessage::InstructTranscript {
+            session: session.clone(),
+            entries,
+        })
+        .await
+        .ok()?;
+    if !session.is_open() || session.stream_id.is_none() {
+        return None;
+    }
+    match prompt_channel.claim_session(session.id) {
+        Some(claim) => Some((session, claim)),
+        None => {
+            tracing::warn!("Instruct session {} is already live on another socket", session.id);
+            let chat = ChatCompletion {
+                role: ChatRole::System,
+                content: Content::Object(serde_json::json!({"state": "in_use"})),
+            };
+            chat_tx.send(FrontEndMessage::InstructMessage(chat)).await.ok();
+            None
+        }
+    }
+}
+
 async fn new_instruction_channel(
     db_pool: Arc<DbPool>,
     auth_user: AuthenticatedUser,
@@ -594,8 +855,12 @@ async fn new_instruction_channel(
     chat_tx: mpsc::Sender<FrontEndMessage>,
     project_tx: broadcast::Sender<Project>,
     task_tx: broadcast::Sender<Task>,
+    update_senders: TaskUpdateSenders,
     prompt_channel: PromptChannelHandle,
     initialize_prompt_tx: mpsc::Sender<InitializePromptChannel>,
+    embed_tx: mpsc::Sender<EmbedRequest>,
+    job_created_tx: broadcast::Sender<JobCreated>,
+    mut resume: Option<Uuid>,
 ) -> Result<(), InstructError> {
     tracing::info!("New instruction channel");
 
@@ -606,6 +871,10 @@ async fn new_instruction_channel(
             project_tx: &project_tx,
             task_tx: &task_tx,
             prompt_tx: &prompt_tx,
+            update_senders: &update_senders,
+            embed_tx: &embed_tx,
+            job_created_tx: &job_created_tx,
+            session_id: None,
         };
 
         let project_id = {
@@ -616,57 +885,120 @@ async fn new_instruction_channel(
         };
         let mut state = InstructionState {
             auth_user,
-            assignee: None,
             stream_id: None,
             project_id,
             check_auth: true,
+            session: None,
+            claim: None,
         };
         let (prompt_response_tx, mut prompt_rx) = prompt_channel.add_channel_for_user(auth_user.id());
 
-        // The inital ask from the instruct stream
-        let initial_request = loop {
-            select! {
-                msg = prompt_rx.recv() => {
-                    let response = msg.ok_or_else(|| InstructError("PromptRx channel is closed"))?;
-                    let mut conn = db_pool.get().map_err(|_| InstructError("DbPool is errored"))?;
-                    instruction_channel_message(response,
-                                                &mut conn,
-                                                connections,
-                                                &mut state,
-                                                &mut string_rx).await?;
+        let resumed = match resume.take() {
+            Some(session_id) => {
+                let mut conn = db_pool.get().map_err(|_| InstructError("DbPool is errored"))?;
+                replay_session(&mut conn, auth_user, session_id, &chat_tx, &prompt_channel).await
+            }
+            None => None,
+        };
+
+        let connections = if let Some((session, claim)) = resumed {
+            tracing::info!("Resuming instruct session {}", session.id);
+            let stream_id = session.stream_id.expect("Resumed sessions have a stream");
+            let awaiting_reply = session.awaiting_reply;
+            let connections = Connections {
+                session_id: Some(session.id),
+                ..connections
+            };
+            state.stream_id = Some(stream_id);
+            state.project_id = session.project_id;
+            state.session = Some(session);
+            state.claim = Some(claim);
+
+            // The stream's responses come here from now on rather than to the dropped socket
+            initialize_prompt_tx
+                .send(InitializePromptChannel(stream_id, prompt_request_rx, prompt_response_tx))
+                .await
+                .map_err(|_| InstructError("InitializePromptChannel channel is closed"))?;
+            if awaiting_reply {
+                let mut conn = db_pool.get().map_err(|_| InstructError("DbPool is errored"))?;
+                forward_reply(&mut conn, connections, &mut state, &mut string_rx).await?;
+            }
+            connections
+        } else {
+            // The inital ask from the instruct stream
+            let initial_request = loop {
+                select! {
+                    msg = prompt_rx.recv() => {
+                        let response = msg.ok_or_else(|| InstructError("PromptRx channel is closed"))?;
+                        instruction_channel_message(response,
+                                                    &db_pool,
+                                                    connections,
+                                                    &mut state,
+                                                    &mut string_rx).await?;
+                    }
+                    msg = string_rx.recv() => {
+                        let msg = msg.ok_or_else(|| InstructError("string_rx channel is closed"))?;
+                        break msg;
+                    }
                 }
-                msg = string_rx.recv() => {
-                    let msg = msg.ok_or_else(|| InstructError("string_rx channel is closed"))?;
-                    break msg;
+            };
+
+            let mut conn = db_pool.get().map_err(|_| InstructError("DbPool is errored"))?;
+            let session =
+                InstructSession::create(&mut conn, auth_user.id(), state.project_id, &initial_request);
+            match session {
+                Ok(session) => {
+                    state.claim = prompt_channel.claim_session(session.id);
+                    state.session = Some(session);
                 }
+                Err(err) => tracing::warn!("Could not store instruct session: {:?}", err),
             }
-        };
+            let connections = Connections {
+                session_id: state.session.as_ref().map(|session| session.id),
+                ..connections
+            };
 
-        chat_tx
-            .send(FrontEndMessage::InstructMessage(ChatCompletion {
+            let chat = ChatCompletion {
                 role: ChatRole::User,
                 content: Content::Text(initial_request.clone()),
-            }))
-            .await
-            .ok();
-        tracing::info!("Initial request {}", initial_request);
-        let handshake = PromptTx::new_stream(initial_request);
-        state.stream_id = Some(handshake.stream_id);
-
-        initialize_prompt_tx
-            .send(InitializePromptChannel(state.stream_id.unwrap(),
-                                          prompt_request_rx,
-                                          prompt_response_tx))
-            .await
-            .map_err(|_| InstructError("InitializePromptChannel channel is closed"))?;
-        prompt_tx.send(handshake).await.map_err(|_| InstructError("PromptTx channel is closed"))?;
+            };
+            connections.send_chat(&mut conn, chat).await.ok();
+            tracing::info!("Initial request {}", initial_request);
+            let handshake = PromptTx::new_stream(initial_request);
+            let stream_id = handshake.stream_id;
+            state.stream_id = Some(stream_id);
+            state.update_session(&mut conn, |session, conn| session.set_stream(conn, stream_id));
+
+            initialize_prompt_tx
+                .send(InitializePromptChannel(stream_id,
+                                              prompt_request_rx,
+                                              prompt_response_tx))
+                .await
+                .map_err(|_| InstructError("InitializePromptChannel channel is closed"))?;
+            prompt_tx.send(handshake).await.map_err(|_| InstructError("PromptTx channel is closed"))?;
+            connections
+        };
 
         loop {
-            let response = prompt_rx.recv().await.ok_or_else(|| InstructError("PromptRx channel is closed"))?;
-            let mut conn = db_pool.get().map_err(|_| InstructError("DbPool is errored"))?;
+            let response = match timeout(STREAM_IDLE_TIMEOUT, prompt_rx.recv()).await {
+                Ok(response) => response.ok_or_else(|| InstructError("PromptRx channel is closed"))?,
+                Err(_) => {
+                    // A stream that went away with the last server, or stopped answering, can't
+                    // carry the session on
+                    tracing::warn!("Instruct stream {:?} stopped responding", state.stream_id);
+                    let mut conn = db_pool.get().map_err(|_| InstructError("DbPool is errored"))?;
+                    state.end_session(&mut conn);
+                    let chat = ChatCompletion {
+                        role: ChatRole::System,
+                        content: Content::Object(serde_json::json!({"state": "closed"})),
+                    };
+                    connections.send_chat(&mut conn, chat).await?;
+                    break;
+                }
+            };
             tracing::info!("Prompt rx {:?}", response);
             if instruction_channel_message(response,
-                                           &mut conn,
+                                           &db_pool,
                                            connections,
                                            &mut state,
                                            &mut string_rx).await? {
@@ -676,26 +1008,35 @@ async fn new_instruction_channel(
     }
 }
 
+/// Starts the instruct channel for a socket, resuming the given session if there is one
 pub struct InstructChannel(
     pub AuthenticatedUser,
     pub mpsc::Receiver<String>,
     pub mpsc::Sender<FrontEndMessage>,
+    pub Option<Uuid>,
 );
 
 pub fn instruction_channel_task(db_pool: Arc<DbPool>, router: &mut Router, prompt_channel: PromptChannelHandle) {
     let mut instruction_config_rx: mpsc::Receiver<InstructChannel> = router.create_channel();
     let prompt_request_tx: mpsc::Sender<InitializePromptChannel> =
         router.get_address().expect("Could't get address").clone();
+    let embed_tx: mpsc::Sender<EmbedRequest> =
+        router.get_address().expect("No embedding channel defined").clone();
     let project_tx = router.announce();
     let task_tx = router.announce();
+    let job_created_tx = router.announce();
+    let update_senders = TaskUpdateSenders::new(router);
 
     spawn(async move {
-        while let Some(InstructChannel(auth_user, rx, tx)) = instruction_config_rx.recv().await {
+        while let Some(InstructChannel(auth_user, rx, tx, resume)) = instruction_config_rx.recv().await {
             let db_pool = db_pool.clone();
             let project_tx = project_tx.clone();
             let task_tx = task_tx.clone();
+            let update_senders = update_senders.clone();
             let prompt_channel = prompt_channel.clone();
             let prompt_request_tx = prompt_request_tx.clone();
+            let embed_tx = embed_tx.clone();
+            let job_created_tx = job_created_tx.clone();
 
             spawn(async move {
                 if let Err(err) = new_instruction_channel(
@@ -705,8 +1046,12 @@ pub fn instruction_channel_task(db_pool: Arc<DbPool>, router: &mut Router, promp
                         tx,
                         project_tx,
                         task_tx,
+                        update_senders,
                         prompt_channel,
                         prompt_request_tx,
+                        embed_tx,
+                        job_created_tx,
+                        resume,
                     ).await {
                     tracing::warn!("InsructError({})", err.0);
                 }
@@ -714,3 +1059,103 @@ pub fn instruction_channel_task(db_pool: Arc<DbPool>, router: &mut Router, promp
         }
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tables::test::MIGRATIONS;
+    use crate::tables::FlowNode;
+    use function_name::named;
+    use subseq_util::tables::harness::{to_pg_db_name, DbHarness};
+
+    #[test]
+    fn test_fetch_tasks_wire_forms() {
+        let tool: Tool = serde_json::from_str("\"FetchTasks\"").expect("unit form");
+        assert!(matches!(tool, Tool::FetchTasks));
+        assert_eq!(serde_json::to_string(&tool).expect("serialize"), "\"FetchTasks\"");
+
+        let tool: Tool = serde_json::from_str(r#"{"QueryTasks": {"query": "tag:bug"}}"#)
+            .expect("query form");
+        assert!(matches!(tool, Tool::QueryTasks { query } if query == "tag:bug"));
+
+        let payload: PromptRxPayload = serde_json::from_str(r#"{"Tool": "FetchTasks"}"#)
+            .expect("payload");
+        assert!(matches!(payload, PromptRxPayload::Tool(Tool::FetchTasks)));
+    }
+
+    #[test]
+    #[named]
+    fn test_session_transcript() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let user = User::create(&mut conn, Uuid::new_v4(), "test@example.com", None).expect("user");
+        let open = FlowNode::create(&mut conn, "OPEN").expect("open");
+        let closed = FlowNode::create(&mut conn, "CLOSED").expect("closed");
+        let flow = Flow::create(
+            &mut conn,
+            &user,
+            "Default".to_string(),
+            "This is the default flow".to_string(),
+            &open,
+            vec![(&open, &closed)],
+            vec![&closed],
+        ).expect("flow");
+        let proj = Project::create(&mut conn, Uuid::new_v4(), &user, "proj", "", &flow)
+            .expect("proj");
+        let session = InstructSession::create(&mut conn, user.id, proj.id, "Plan the release")
+            .expect("session");
+
+        let task_id = Uuid::new_v4();
+        let entries = vec![
+            TranscriptEntry::Chat(ChatCompletion {
+                role: ChatRole::User,
+                content: Content::Text("Plan the release".to_string()),
+            }),
+            TranscriptEntry::ToolCall(Tool::RunTask { task_id }),
+            TranscriptEntry::ToolResult(ToolResult::RunTask(task_id)),
+        ];
+        for (i, entry) in entries.iter().enumerate() {
+            InstructMessage::append(&mut conn, session.id, entry.kind(), entry.payload())
+                .expect("append");
+            // Steps which no longer parse are left out of the transcript
+            if i == 0 {
+                InstructMessage::append(&mut conn, session.id, "chat", serde_json::json!(1))
+                    .expect("bad chat");
+                InstructMessage::append(&mut conn, session.id, "retired", serde_json::json!({}))
+                    .expect("retired");
+            }
+        }
+
+        let messages = session.messages(&mut conn).expect("messages");
+        assert_eq!(messages.len(), 5);
+        assert!(TranscriptEntry::from_message(&messages[1]).is_none());
+        assert!(TranscriptEntry::from_message(&messages[2]).is_none());
+        let round_trip = TranscriptEntry::from_message(&messages[3]).expect("tool call");
+        assert!(matches!(round_trip, TranscriptEntry::ToolCall(Tool::RunTask { task_id: id }) if id == task_id));
+
+        let transcript = session_transcript(&mut conn, &session).expect("transcript");
+        let as_json = |entries: &[TranscriptEntry]| {
+            entries
+                .iter()
+                .map(|entry| serde_json::to_value(entry).expect("serialize"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(as_json(&transcript), as_json(&entries));
+        assert_eq!(
+            serde_json::to_value(&transcript[0]).expect("serialize")["kind"],
+            serde_json::json!("chat")
+        );
+    }
+
+    #[test]
+    fn test_claim_session() {
+        let prompt_channel = PromptChannelHandle::new();
+        let session_id = Uuid::new_v4();
+        let claim = prompt_channel.claim_session(session_id).expect("claim");
+        assert!(prompt_channel.claim_session(session_id).is_none());
+        assert!(prompt_channel.claim_session(Uuid::new_v4()).is_some());
+        drop(claim);
+        assert!(prompt_channel.claim_session(session_id).is_some());
+    }
+}
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use diesel::PgConnection;
+use subseq_util::api::sessions::store_auth_cookie;
+use subseq_util::oidc::IdentityProvider;
+use subseq_util::{api::*, tables::DbPool};
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+use warp_sessions::{MemoryStore, SessionWithStore};
+
+use super::webhooks::owned_project;
+use super::ValidationError;
+use crate::tables::{RoutingRule, RoutingRuleFields};
+
+/// Fetches a routing rule on a project the user owns.
+fn owned_rule(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    project_id: Uuid,
+    rule_id: Uuid,
+) -> Result<RoutingRule, Rejection> {
+    owned_project(conn, user_id, project_id)?;
+    match RoutingRule::get(conn, rule_id) {
+        Some(rule) if rule.project_id == project_id => Ok(rule),
+        _ => Err(warp::reject::custom(NotFoundError {})),
+    }
+}
+
+async fn create_rule_handler(
+    project_id: Uuid,
+    payload: RoutingRuleFields,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let project = owned_project(&mut conn, auth.id(), project_id)?;
+    let rule = RoutingRule::create(&mut conn, &project, &payload).map_err(ValidationError::reject)?;
+    Ok((warp::reply::json(&rule), session))
+}
+
+async fn list_rules_handler(
+    project_id: Uuid,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    owned_project(&mut conn, auth.id(), project_id)?;
+    let rules = RoutingRule::list_for_project(&mut conn, project_id)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::json(&rules), session))
+}
+
+async fn update_rule_handler(
+    project_id: Uuid,
+    rule_id: Uuid,
+    payload: RoutingRuleFields,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let mut rule = owned_rule(&mut conn, auth.id(), project_id, rule_id)?;
+    rule.update(&mut conn, &payload).map_err(ValidationError::reject)?;
+    Ok((warp::reply::json(&rule), session))
+}
+
+async fn delete_rule_handler(
+    project_id: Uuid,
+    rule_id: Uuid,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let rule = owned_rule(&mut conn, auth.id(), project_id, rule_id)?;
+    rule.delete(&mut conn)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::reply(), session))
+}
+
+/// Routes under /project/{project_id}/routing
+pub fn routes(
+    idp: Option<Arc<IdentityProvider>>,
+    session: MemoryStore,
+    pool: Arc<DbPool>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let create_rule = warp::path!("project" / Uuid / "routing")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(create_rule_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let list_rules = warp::path!("project" / Uuid / "routing")
+        .and(warp::get())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(list_rules_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let update_rule = warp::path!("project" / Uuid / "routing" / Uuid)
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(update_rule_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let delete_rule = warp::path!("project" / Uuid / "routing" / Uuid)
+        .and(warp::delete())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(delete_rule_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    create_rule
+        .or(list_rules)
+        .or(update_rule)
+        .or(delete_rule)
+}
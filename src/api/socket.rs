@@ -9,15 +9,22 @@ use subseq_util::api::sessions::store_auth_cookie;
 use subseq_util::api::{authenticate, AuthenticatedUser};
 use subseq_util::oidc::IdentityProvider;
 use subseq_util::Router;
-use tokio::{sync::mpsc, task::spawn};
+use tokio::sync::mpsc;
+use tokio::task::{spawn, spawn_blocking};
+use uuid::Uuid;
 use warp::filters::ws::{Message, WebSocket};
 use warp::{Filter, Rejection, Reply};
 use warp_sessions::{MemoryStore, SessionWithStore};
 
-use crate::tables::Project;
+use crate::tables::{
+    ActiveProject, DbPool, InstructSession, Project, QueryError, Task, TaskComment, TaskSearchHit,
+    MAX_SEARCH_RESULTS,
+};
 
-use super::prompts::{ChatCompletion, InstructChannel};
-use super::tasks::DenormalizedTask;
+use super::jobs::JobFinished;
+use super::prompts::{ChatCompletion, InstructChannel, TranscriptEntry};
+use super::subscriptions::{run_subscriptions, Subscription, SubscriptionChannels, SubscriptionCommand};
+use super::tasks::{DenormalizedTask, TaskStateChanged, TaskStatePayload};
 use super::voice::{create_audio_timing_task, AudioContext, AudioData, AudioEventChannel};
 
 const WEBSOCKET_BUFFER_SIZE: usize = 1024;
@@ -26,14 +33,37 @@ const WEBSOCKET_BUFFER_SIZE: usize = 1024;
 pub enum WebSocketMessage {
     Instruct(String),
     SetAudioContext(AudioContext),
+    Subscribe(Subscription),
+    Unsubscribe(Subscription),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SocketParams {
+    /// An instruct session to pick up again
+    #[serde(default)]
+    resume: Option<Uuid>,
 }
 
 #[derive(Clone, Debug)]
 pub enum FrontEndMessage {
     InstructMessage(ChatCompletion),
+    /// Everything said so far in a session being resumed
+    InstructTranscript {
+        session: InstructSession,
+        entries: Vec<TranscriptEntry>,
+    },
     InstructClear,
     SetProject(Project),
     AddTask(DenormalizedTask),
+    TaskState(TaskStatePayload),
+    TaskTransition(TaskStateChanged),
+    TaskComment(TaskComment),
+    JobResult(JobFinished),
+    QueryError(QueryError),
+    SearchResults {
+        query: String,
+        hits: Vec<TaskSearchHit>,
+    },
     Ping,
 }
 
@@ -64,6 +94,52 @@ impl Serialize for FrontEndMessage {
                 state.serialize_field("task", &task)?;
                 state
             }
+            FrontEndMessage::TaskState(task_state) => {
+                let mut state = serializer.serialize_struct("FrontEndMessage", 2)?;
+                state.serialize_field("channel", "TASK-STATE")?;
+                state.serialize_field("state", &task_state)?;
+                state
+            }
+            FrontEndMessage::TaskTransition(change) => {
+                let mut state = serializer.serialize_struct("FrontEndMessage", 2)?;
+                state.serialize_field("channel", "TASK-TRANSITION")?;
+                state.serialize_field("transition", &change)?;
+                state
+            }
+            FrontEndMessage::TaskComment(comment) => {
+                let mut state = serializer.serialize_struct("FrontEndMessage", 2)?;
+                state.serialize_field("channel", "TASK-COMMENT")?;
+                state.serialize_field("comment", &comment)?;
+                state
+            }
+            FrontEndMessage::JobResult(finished) => {
+                let mut state = serializer.serialize_struct("FrontEndMessage", 3)?;
+                state.serialize_field("channel", "JOB-RESULT")?;
+                state.serialize_field("job", &finished.job)?;
+                state.serialize_field("result", &finished.result)?;
+                state
+            }
+            FrontEndMessage::QueryError(err) => {
+                let mut state = serializer.serialize_struct("FrontEndMessage", 3)?;
+                state.serialize_field("channel", "QUERY-ERROR")?;
+                state.serialize_field("message", &err.message)?;
+                state.serialize_field("position", &err.position)?;
+                state
+            }
+            FrontEndMessage::SearchResults { query, hits } => {
+                let mut state = serializer.serialize_struct("FrontEndMessage", 3)?;
+                state.serialize_field("channel", "TASK-SEARCH")?;
+                state.serialize_field("query", &query)?;
+                state.serialize_field("hits", &hits)?;
+                state
+            }
+            FrontEndMessage::InstructTranscript { session, entries } => {
+                let mut state = serializer.serialize_struct("FrontEndMessage", 3)?;
+                state.serialize_field("channel", "INSTRUCT-TRANSCRIPT")?;
+                state.serialize_field("session", &session)?;
+                state.serialize_field("entries", &entries)?;
+                state
+            }
             FrontEndMessage::InstructMessage(completion) => {
                 let mut state = serializer.serialize_struct("FrontEndMessage", 3)?;
                 state.serialize_field("channel", "INSTRUCT-MESSAGE")?;
@@ -78,9 +154,12 @@ impl Serialize for FrontEndMessage {
 
 async fn client_websocket(
     auth_user: AuthenticatedUser,
+    resume: Option<Uuid>,
     ws: WebSocket,
     audio_event: mpsc::Sender<AudioEventChannel>,
     instruct_config_tx: mpsc::Sender<InstructChannel>,
+    db_pool: Arc<DbPool>,
+    subscription_channels: SubscriptionChannels,
 ) {
     let user_id = auth_user.id();
     tracing::info!("Client {} ws started", user_id);
@@ -92,7 +171,7 @@ async fn client_websocket(
 
     let instruct_out_tx = output_tx.clone();
     if instruct_config_tx
-        .send(InstructChannel(auth_user, instruct_in_rx, instruct_out_tx))
+        .send(InstructChannel(auth_user, instruct_in_rx, instruct_out_tx, resume))
         .await
         .is_err()
     {
@@ -100,15 +179,64 @@ async fn client_websocket(
         return;
     }
 
+    let (subscription_tx, subscription_rx) = mpsc::channel(WEBSOCKET_BUFFER_SIZE);
+    run_subscriptions(
+        user_id,
+        db_pool.clone(),
+        subscription_channels,
+        subscription_rx,
+        output_tx.clone(),
+    );
+
     let (audio_text_tx, mut audio_text_rx) = mpsc::channel(WEBSOCKET_BUFFER_SIZE);
     create_audio_timing_task(audio_text_tx, audio_rx, audio_event);
 
-    // TODO: handle context -> send message to context to fill in text for feedback
     // TODO: handle streaming messages -> check finalized bit
     let instruct_tx = instruct_in_tx.clone();
+    let search_tx = output_tx.clone();
     spawn(async move {
-        while let Some(audio_response) = audio_text_rx.recv().await {
-            instruct_tx.send(audio_response.payload).await.ok();
+        while let Some((context, audio_response)) = audio_text_rx.recv().await {
+            match context {
+                AudioContext::Search => {
+                    let query = audio_response.payload;
+                    if query.trim().is_empty() {
+                        continue;
+                    }
+                    // Voice has no project picker, so search the project the user is working in
+                    let search_pool = db_pool.clone();
+                    let search_query = query.clone();
+                    let hits = spawn_blocking(move || {
+                        let mut conn = search_pool.get().ok()?;
+                        let project_id =
+                            ActiveProject::get(&mut conn, user_id).map(|active| active.project_id);
+                        Some(Task::search(
+                            &mut conn,
+                            &search_query,
+                            project_id,
+                            MAX_SEARCH_RESULTS,
+                        ))
+                    })
+                    .await;
+                    let hits = match hits {
+                        Ok(Some(Ok(hits))) => hits,
+                        Ok(Some(Err(err))) => {
+                            tracing::warn!("Voice search failed: {}", err);
+                            continue;
+                        }
+                        Ok(None) | Err(_) => continue,
+                    };
+                    if search_tx
+                        .send(FrontEndMessage::SearchResults { query, hits })
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                AudioContext::Discover | AudioContext::Instruct => {
+                    instruct_tx.send(audio_response.payload).await.ok();
+                }
+            }
         }
         tracing::info!("{} audio text handler exited", user_id);
     });
@@ -152,6 +280,18 @@ async fn client_websocket(
                             context = new_context;
                             msg_counter = 0;
                         }
+                        WebSocketMessage::Subscribe(subscription) => {
+                            let command = SubscriptionCommand::Subscribe(subscription);
+                            if subscription_tx.send(command).await.is_err() {
+                                break;
+                            }
+                        }
+                        WebSocketMessage::Unsubscribe(subscription) => {
+                            let command = SubscriptionCommand::Unsubscribe(subscription);
+                            if subscription_tx.send(command).await.is_err() {
+                                break;
+                            }
+                        }
                     }
                 } else if message.is_binary() {
                     let audio = AudioData {
@@ -189,8 +329,10 @@ async fn client_websocket(
 pub fn routes(
     idp: Option<Arc<IdentityProvider>>,
     session: MemoryStore,
+    pool: Arc<DbPool>,
     router: &mut Router,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let subscription_channels = SubscriptionChannels::new(router);
     let audio_stream: mpsc::Sender<AudioEventChannel> = router
         .get_address()
         .expect("Could not get AudioDataChannel")
@@ -201,17 +343,29 @@ pub fn routes(
         .clone();
 
     warp::path("ws")
+        .and(warp::query::<SocketParams>())
         .and(authenticate(idp, session.clone()))
         .and(warp::ws())
         .map(
-            move |auth: AuthenticatedUser,
+            move |params: SocketParams,
+                  auth: AuthenticatedUser,
                   session: SessionWithStore<MemoryStore>,
                   ws: warp::ws::Ws| {
                 let audio_stream = audio_stream.clone();
                 let instruction_config_tx = instruction_config_tx.clone();
+                let pool = pool.clone();
+                let subscription_channels = subscription_channels.clone();
                 (
                     ws.on_upgrade(move |socket| {
-                        client_websocket(auth, socket, audio_stream, instruction_config_tx)
+                        client_websocket(
+                            auth,
+                            params.resume,
+                            socket,
+                            audio_stream,
+                            instruction_config_tx,
+                            pool,
+                            subscription_channels,
+                        )
                     }),
                     session,
                 )
@@ -0,0 +1,249 @@
+use std::sync::Arc;
+
+use diesel::PgConnection;
+use serde::{Deserialize, Serialize};
+use subseq_util::{tables::DbPool, Router};
+use tokio::spawn;
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+use super::jobs::JobFinished;
+use super::socket::FrontEndMessage;
+use super::tasks::{TaskStateChanged, TaskStatePayload};
+use crate::tables::{Task, TaskComment, TaskQuery};
+
+/// What a websocket client wants live task changes for
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Subscription {
+    Project(Uuid),
+    Task(Uuid),
+    /// A query in the task query language, checked when subscribing
+    Query(String),
+}
+
+/// A subscription held by a socket, with its query parsed once when subscribing
+struct Watched {
+    subscription: Subscription,
+    query: Option<TaskQuery>,
+}
+
+impl Watched {
+    fn wants(&self, conn: &mut PgConnection, user_id: Uuid, task: &Task) -> bool {
+        match (&self.subscription, &self.query) {
+            (Subscription::Task(task_id), _) => *task_id == task.id,
+            (Subscription::Project(project_id), _) => {
+                task.in_project(conn, *project_id).unwrap_or(false)
+            }
+            (Subscription::Query(_), Some(query)) => task.matches_query(conn, user_id, query),
+            (Subscription::Query(_), None) => false,
+        }
+    }
+}
+
+/// Adds or removes a subscription on a socket
+#[derive(Clone, Debug)]
+pub enum SubscriptionCommand {
+    Subscribe(Subscription),
+    Unsubscribe(Subscription),
+}
+
+/// The broadcasts a socket can subscribe to, each socket taking its own receivers
+#[derive(Clone)]
+pub struct SubscriptionChannels {
+    task_tx: broadcast::Sender<Task>,
+    task_update_tx: broadcast::Sender<TaskStatePayload>,
+    task_state_tx: broadcast::Sender<TaskStateChanged>,
+    task_comment_tx: broadcast::Sender<TaskComment>,
+    job_finished_tx: broadcast::Sender<JobFinished>,
+}
+
+impl SubscriptionChannels {
+    pub fn new(router: &mut Router) -> Self {
+        Self {
+            task_tx: router.announce(),
+            task_update_tx: router.announce(),
+            task_state_tx: router.announce(),
+            task_comment_tx: router.announce(),
+            job_finished_tx: router.announce(),
+        }
+    }
+}
+
+/// A task change received from one of the broadcasts
+enum Event {
+    Task(Task),
+    TaskUpdate(TaskStatePayload),
+    TaskState(TaskStateChanged),
+    TaskComment(TaskComment),
+    JobFinished(JobFinished),
+}
+
+impl Event {
+    fn task_id(&self) -> Uuid {
+        match self {
+            Self::Task(task) => task.id,
+            Self::TaskUpdate(state) => state.task.id,
+            Self::TaskState(change) => change.task_id,
+            Self::TaskComment(comment) => comment.task_id,
+            Self::JobFinished(finished) => finished.job.task_id,
+        }
+    }
+
+    fn task(&self) -> Option<&Task> {
+        match self {
+            Self::Task(task) => Some(task),
+            Self::TaskUpdate(state) => Some(&state.task),
+            _ => None,
+        }
+    }
+}
+
+/// The most events checked against the subscriptions with one connection
+const MAX_BATCH: usize = 64;
+
+/// Moves whatever is already waiting on a broadcast into the batch without blocking
+fn drain<T: Clone>(rx: &mut broadcast::Receiver<T>, batch: &mut Vec<Event>, wrap: fn(T) -> Event) {
+    while batch.len() < MAX_BATCH {
+        match rx.try_recv() {
+            Ok(msg) => batch.push(wrap(msg)),
+            Err(TryRecvError::Lagged(_)) => continue,
+            Err(_) => break,
+        }
+    }
+}
+
+/// Whether any of the subscriptions want changes to this task. Looks the task up when only
+/// its id is known.
+fn wanted(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    subscriptions: &[Watched],
+    task_id: Uuid,
+    task: Option<&Task>,
+) -> bool {
+    if subscriptions.is_empty() {
+        return false;
+    }
+    if subscriptions
+        .iter()
+        .any(|watched| watched.subscription == Subscription::Task(task_id))
+    {
+        return true;
+    }
+    let fetched;
+    let task = match task {
+        Some(task) => task,
+        None => match Task::get(conn, task_id) {
+            Some(task) => {
+                fetched = task;
+                &fetched
+            }
+            None => return false,
+        },
+    };
+    subscriptions
+        .iter()
+        .any(|watched| watched.wants(conn, user_id, task))
+}
+
+/// Forwards task changes matching a socket's subscriptions to its output until either side
+/// closes
+pub fn run_subscriptions(
+    user_id: Uuid,
+    db_pool: Arc<DbPool>,
+    channels: SubscriptionChannels,
+    mut command_rx: mpsc::Receiver<SubscriptionCommand>,
+    output_tx: mpsc::Sender<FrontEndMessage>,
+) {
+    let mut task_rx = channels.task_tx.subscribe();
+    let mut task_update_rx = channels.task_update_tx.subscribe();
+    let mut task_state_rx = channels.task_state_tx.subscribe();
+    let mut task_comment_rx = channels.task_comment_tx.subscribe();
+    let mut job_finished_rx = channels.job_finished_tx.subscribe();
+
+    spawn(async move {
+        let mut subscriptions: Vec<Watched> = vec![];
+        'events: loop {
+            let event = tokio::select!(
+                command = command_rx.recv() => {
+                    match command {
+                        Some(SubscriptionCommand::Subscribe(subscription)) => {
+                            let query = match &subscription {
+                                Subscription::Query(query) => match TaskQuery::parse(query) {
+                                    Ok(query) => Some(query),
+                                    Err(err) => {
+                                        if output_tx.send(FrontEndMessage::QueryError(err)).await.is_err() {
+                                            break;
+                                        }
+                                        continue;
+                                    }
+                                },
+                                _ => None,
+                            };
+                            if !subscriptions.iter().any(|watched| watched.subscription == subscription) {
+                                subscriptions.push(Watched { subscription, query });
+                            }
+                        }
+                        Some(SubscriptionCommand::Unsubscribe(subscription)) => {
+                            subscriptions.retain(|watched| watched.subscription != subscription);
+                        }
+                        None => break,
+                    }
+                    continue;
+                }
+                Ok(task) = task_rx.recv() => Event::Task(task),
+                Ok(state) = task_update_rx.recv() => Event::TaskUpdate(state),
+                Ok(change) = task_state_rx.recv() => Event::TaskState(change),
+                Ok(comment) = task_comment_rx.recv() => Event::TaskComment(comment),
+                Ok(finished) = job_finished_rx.recv() => Event::JobFinished(finished),
+            );
+
+            // Take everything else already waiting so the batch shares one connection
+            let mut batch = vec![event];
+            drain(&mut task_rx, &mut batch, Event::Task);
+            drain(&mut task_update_rx, &mut batch, Event::TaskUpdate);
+            drain(&mut task_state_rx, &mut batch, Event::TaskState);
+            drain(&mut task_comment_rx, &mut batch, Event::TaskComment);
+            drain(&mut job_finished_rx, &mut batch, Event::JobFinished);
+
+            if subscriptions.is_empty() {
+                continue;
+            }
+            let mut messages = vec![];
+            {
+                let mut conn = match db_pool.get() {
+                    Ok(conn) => conn,
+                    Err(_) => continue,
+                };
+                for event in batch {
+                    if !wanted(
+                        &mut conn,
+                        user_id,
+                        &subscriptions,
+                        event.task_id(),
+                        event.task(),
+                    ) {
+                        continue;
+                    }
+                    messages.push(match event {
+                        Event::Task(task) => match TaskStatePayload::build(&mut conn, task) {
+                            Ok(state) => FrontEndMessage::TaskState(state),
+                            Err(_) => continue,
+                        },
+                        Event::TaskUpdate(state) => FrontEndMessage::TaskState(state),
+                        Event::TaskState(change) => FrontEndMessage::TaskTransition(change),
+                        Event::TaskComment(comment) => FrontEndMessage::TaskComment(comment),
+                        Event::JobFinished(finished) => FrontEndMessage::JobResult(finished),
+                    });
+                }
+            }
+            for message in messages {
+                if output_tx.send(message).await.is_err() {
+                    break 'events;
+                }
+            }
+        }
+        tracing::info!("{} subscription handler exited", user_id);
+    });
+}
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use chrono::NaiveDateTime;
-use diesel::{PgConnection, QueryResult};
+use diesel::{Connection, PgConnection, QueryResult};
 use serde::{Deserialize, Serialize};
 use subseq_util::api::sessions::store_auth_cookie;
 use subseq_util::oidc::IdentityProvider;
@@ -11,23 +11,39 @@ use subseq_util::{api::*, tables::{DbPool, UserTable}, Router};
 use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio::time::timeout;
 use uuid::Uuid;
+use warp::http::StatusCode;
 use warp::{Filter, Rejection, Reply};
 use warp_sessions::{MemoryStore, SessionWithStore};
 
 use super::prompts::{InitializePromptChannel, PromptResponseType, PromptRxPayload, PromptTx};
-use super::with_channel;
+use super::comments::{self, DenormalizedTaskComment};
+use super::{with_channel, ValidationError};
 use crate::api::users::DenormalizedUser;
+use crate::embeddings::{embed, task_text, EmbedRequest, DUPLICATE_THRESHOLD};
+use crate::events::{TASK_ASSIGNEE_BEAM, TASK_RUN_BEAM, TASK_STATE_BEAM, TASK_UPDATED_BEAM};
 use crate::tables::{
     ActiveProject,
-    FlowConnection,
+    FlowAction,
     FlowNode,
+    FlowNodeAction,
+    Job,
+    OutboxEvent,
     Project,
+    QueryError,
+    RoutingRule,
+    SimilarTask,
     Task,
+    TaskEmbedding,
     TaskFlow,
+    TaskFlowState,
+    TaskHistory,
     TaskLink,
     TaskLinkType,
+    TaskQuery,
+    TaskSearchHit,
     TaskUpdate,
     User,
+    MAX_SEARCH_RESULTS,
 };
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -35,6 +51,12 @@ pub struct TaskPayload {
     project_id: Uuid,
     title: Option<String>,
     description: String,
+    /// Reply with likely duplicates instead of creating the task when there are any
+    #[serde(default)]
+    check_duplicates: bool,
+    /// Added before the project's routing rules are applied
+    #[serde(default)]
+    tags: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -112,12 +134,17 @@ async fn title_from_description(
     }
 }
 
+/// Creates a task with its tags and routes it, all in one transaction, so the routing rules
+/// see the tags and the assignment is recorded with the creation. The assignee change is
+/// broadcast once committed.
 pub async fn create_task(
     conn: &mut PgConnection,
     user_id: Uuid,
     project_id: Uuid,
     title: String,
     description: String,
+    tags: &[String],
+    senders: &TaskUpdateSenders,
 ) -> Result<Task, Rejection> {
     let mut project = match Project::get(conn, project_id) {
         Some(project) => project,
@@ -129,33 +156,108 @@ pub async fn create_task(
         None => return Err(warp::reject::custom(NotFoundError {})),
     };
 
-    Task::create(
+    let mut tags = tags.to_vec();
+    tags.sort();
+    tags.dedup();
+    let (task, assignee) = conn
+        .transaction(|transact| {
+            let mut task = Task::create(
+                transact,
+                Uuid::new_v4(),
+                &mut project,
+                &title,
+                &description,
+                &user,
+            )?;
+            for tag in &tags {
+                task.add_tag(transact, tag)?;
+            }
+            let assignee = route_task(transact, user_id, project_id, &mut task)?;
+            QueryResult::Ok((task, assignee))
+        })
+        .map_err(|err| {
+            tracing::error!("Task creation failed: {:?}", err);
+            warp::reject::custom(ConflictError {})
+        })?;
+    if let Some(assignee) = assignee {
+        senders.task_assignee_tx.send(assignee).ok();
+    }
+    Ok(task)
+}
+
+/// Assigns a new task to whoever the project's routing rules pick, leaving it as it is when
+/// no rule matches. Call this inside the transaction creating the task: the assignment is
+/// recorded in the outbox like any other update.
+pub fn route_task(
+    conn: &mut PgConnection,
+    actor_id: Uuid,
+    project_id: Uuid,
+    task: &mut Task,
+) -> QueryResult<Option<TaskAssigneeChanged>> {
+    let assignee_id = match RoutingRule::route(conn, project_id, task)? {
+        Some(assignee_id) => assignee_id,
+        None => return Ok(None),
+    };
+    let old_assignee_id = task.assignee_id;
+    task.update(conn, actor_id, TaskUpdate::AssignOther { user_id: assignee_id })?;
+    let changes = TaskChanges::diff(task, actor_id, old_assignee_id, &[], &[]);
+    changes.record(conn)?;
+    let state = TaskStatePayload::build(conn, task.clone())?;
+    OutboxEvent::record(conn, TASK_UPDATED_BEAM, &state)?;
+    Ok(changes.assignee)
+}
+
+const DUPLICATE_LIMIT: usize = 5;
+
+/// Tasks in the project which look like the task described, most similar first
+pub async fn find_duplicates(
+    conn: &mut PgConnection,
+    embed_tx: &mpsc::Sender<EmbedRequest>,
+    project_id: Uuid,
+    title: &str,
+    description: &str,
+) -> Vec<SimilarTask> {
+    let text_embedding = match embed(embed_tx, task_text(title, description)).await {
+        Some(text_embedding) => text_embedding,
+        None => return vec![],
+    };
+    Task::similar(
         conn,
-        Uuid::new_v4(),
-        &mut project,
-        &title,
-        &description,
-        &user,
+        &text_embedding.embedding,
+        &text_embedding.model,
+        Some(&[project_id]),
+        None,
+        DUPLICATE_THRESHOLD,
+        DUPLICATE_LIMIT,
     )
-    .map_err(|err| {
-        tracing::error!("Task creation failed: {:?}", err);
-        warp::reject::custom(ConflictError {})
+    .unwrap_or_else(|err| {
+        tracing::warn!("Duplicate check failed: {:?}", err);
+        vec![]
     })
 }
 
+#[derive(Serialize)]
+struct DuplicatesReply {
+    duplicates: Vec<SimilarTask>,
+}
+
 async fn create_task_handler(
     payload: TaskPayload,
     auth: AuthenticatedUser,
     session: SessionWithStore<MemoryStore>,
     db_pool: Arc<DbPool>,
     mut prompt_request_tx: mpsc::Sender<InitializePromptChannel>,
+    embed_tx: mpsc::Sender<EmbedRequest>,
     sender: broadcast::Sender<Task>,
+    update_senders: TaskUpdateSenders,
 ) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
     // We explicitly ignore setting the task id from the api.
     let TaskPayload {
         project_id,
         title,
         description,
+        check_duplicates,
+        tags,
     } = payload;
     let title = if let Some(title) = title {
         title
@@ -168,7 +270,24 @@ async fn create_task_handler(
         Ok(conn) => conn,
         Err(_) => return Err(warp::reject::custom(DatabaseError {})),
     };
-    let task = create_task(&mut conn, auth.id(), project_id, title, description).await?;
+    if check_duplicates {
+        let duplicates =
+            find_duplicates(&mut conn, &embed_tx, project_id, &title, &description).await;
+        if !duplicates.is_empty() {
+            let reply = warp::reply::json(&DuplicatesReply { duplicates });
+            return Ok((warp::reply::with_status(reply, StatusCode::CONFLICT), session));
+        }
+    }
+    let task = create_task(
+        &mut conn,
+        auth.id(),
+        project_id,
+        title,
+        description,
+        &tags,
+        &update_senders,
+    )
+    .await?;
     sender.send(task.clone()).ok();
 
     let mut conn = match db_pool.get() {
@@ -179,7 +298,10 @@ async fn create_task_handler(
         Ok(task) => task,
         Err(_) => return Err(warp::reject::custom(DatabaseError {})),
     };
-    Ok((warp::reply::json(&payload), session))
+    Ok((
+        warp::reply::with_status(warp::reply::json(&payload), StatusCode::OK),
+        session,
+    ))
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -187,10 +309,9 @@ pub struct TaskStatePayload {
     pub task: Task,
     pub tags: Vec<String>,
     pub watchers: Vec<User>,
-    pub state: FlowNode,
+    pub flows: Vec<TaskFlowState>,
     pub links_out: Vec<TaskLink>,
     pub links_in: Vec<TaskLink>,
-    pub valid_transitions: Vec<FlowNode>,
 }
 
 impl TaskStatePayload {
@@ -198,27 +319,224 @@ impl TaskStatePayload {
         let flows = task.flows(conn)?;
         let tags = task.tags(conn).ok().unwrap_or_default();
         let watchers = task.watchers(conn).ok().unwrap_or_default();
-        let state = TaskFlow::get_active_node(conn, &flows)?;
-        let valid_transitions = FlowConnection::edges(conn, state.id)?;
+        let flows = TaskFlow::states(conn, &flows)?;
         let links_out = TaskLink::get_outgoing(conn, &task)?;
         let links_in = TaskLink::get_incoming(conn, &task)?;
         Ok(Self {
             task,
             tags,
             watchers,
-            state,
+            flows,
             links_out,
             links_in,
-            valid_transitions,
         })
     }
 }
 
+/// A task moved from one node to another within a flow
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskStateChanged {
+    pub task_id: Uuid,
+    pub flow_id: Uuid,
+    pub old_node_id: Option<Uuid>,
+    pub new_node_id: Uuid,
+    pub actor_id: Uuid,
+}
+
+/// A task was assigned to someone else, or unassigned
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskAssigneeChanged {
+    pub task_id: Uuid,
+    pub old_assignee_id: Option<Uuid>,
+    pub new_assignee_id: Option<Uuid>,
+    pub actor_id: Uuid,
+}
+
+/// Where task updates send the typed events describing what they changed
+#[derive(Clone, Debug)]
+pub struct TaskUpdateSenders {
+    pub task_run_tx: broadcast::Sender<TaskRun>,
+    pub task_state_tx: broadcast::Sender<TaskStateChanged>,
+    pub task_assignee_tx: broadcast::Sender<TaskAssigneeChanged>,
+}
+
+impl TaskUpdateSenders {
+    pub fn new(router: &mut Router) -> Self {
+        Self {
+            task_run_tx: router.announce(),
+            task_state_tx: router.announce(),
+            task_assignee_tx: router.announce(),
+        }
+    }
+
+    /// Broadcasts the typed events of a committed update to the rest of the service
+    fn send_changes(&self, changes: TaskChanges) {
+        if let Some(assignee) = changes.assignee {
+            self.task_assignee_tx.send(assignee).ok();
+        }
+        for state in changes.states {
+            self.task_state_tx.send(state).ok();
+        }
+    }
+}
+
+/// The typed assignee and state events for whatever an update (and the flow actions it
+/// triggered) changed on a task
+struct TaskChanges {
+    assignee: Option<TaskAssigneeChanged>,
+    states: Vec<TaskStateChanged>,
+}
+
+impl TaskChanges {
+    fn diff(
+        task: &Task,
+        actor_id: Uuid,
+        old_assignee_id: Option<Uuid>,
+        old_flows: &[TaskFlow],
+        new_flows: &[TaskFlow],
+    ) -> Self {
+        let assignee = (task.assignee_id != old_assignee_id).then(|| TaskAssigneeChanged {
+            task_id: task.id,
+            old_assignee_id,
+            new_assignee_id: task.assignee_id,
+            actor_id,
+        });
+        let mut states = vec![];
+        for flow in new_flows {
+            let new_node_id = match flow.current_node_id {
+                Some(node_id) => node_id,
+                None => continue,
+            };
+            let old_node_id = old_flows
+                .iter()
+                .find(|old| old.flow_id == flow.flow_id)
+                .and_then(|old| old.current_node_id);
+            if old_node_id == Some(new_node_id) {
+                continue;
+            }
+            states.push(TaskStateChanged {
+                task_id: task.id,
+                flow_id: flow.flow_id,
+                old_node_id,
+                new_node_id,
+                actor_id,
+            });
+        }
+        Self { assignee, states }
+    }
+
+    fn record(&self, conn: &mut PgConnection) -> QueryResult<()> {
+        if let Some(assignee) = &self.assignee {
+            OutboxEvent::record(conn, TASK_ASSIGNEE_BEAM, assignee)?;
+        }
+        for state in &self.states {
+            OutboxEvent::record(conn, TASK_STATE_BEAM, state)?;
+        }
+        Ok(())
+    }
+}
+
+fn with_update_senders(
+    senders: TaskUpdateSenders,
+) -> impl Filter<Extract = (TaskUpdateSenders,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || senders.clone())
+}
+
+/// Why a flow action did not run
+#[derive(Debug)]
+enum FlowActionError {
+    Query(diesel::result::Error),
+    Rejected(Rejection),
+}
+
+impl From<diesel::result::Error> for FlowActionError {
+    fn from(err: diesel::result::Error) -> Self {
+        Self::Query(err)
+    }
+}
+
+impl From<Rejection> for FlowActionError {
+    fn from(rej: Rejection) -> Self {
+        Self::Rejected(rej)
+    }
+}
+
+/// Runs one action. Runs and beams are recorded in the outbox, so they are only emitted if
+/// the update which triggered them commits.
+fn run_flow_action(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    task: &mut Task,
+    action: FlowAction,
+) -> Result<(), FlowActionError> {
+    match action {
+        FlowAction::AssignUser { user_id: assignee_id } => {
+            task.update(conn, user_id, TaskUpdate::AssignOther { user_id: assignee_id })?;
+        }
+        FlowAction::AddTag { name } => {
+            task.update(conn, user_id, TaskUpdate::Tag { name })?;
+        }
+        FlowAction::RemoveTag { name } => {
+            task.update(conn, user_id, TaskUpdate::Untag { name })?;
+        }
+        FlowAction::AddWatcher { user_id: watcher_id } => {
+            task.add_watcher(conn, watcher_id)?;
+        }
+        FlowAction::RunTask => {
+            let run = build_task_run(conn, user_id, task)?;
+            OutboxEvent::record(conn, TASK_RUN_BEAM, &run)?;
+        }
+        FlowAction::EmitBeam { beam } => {
+            let state = TaskStatePayload::build(conn, task.clone())?;
+            OutboxEvent::record(conn, &beam, &state)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs the on-exit actions of the node a task left, then the on-enter actions of the node it
+/// entered. Each action runs in its own savepoint, so a failed action is logged and rolled back
+/// without stopping the rest or the update.
+fn run_flow_actions(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    task: &mut Task,
+    entry: &TaskHistory,
+) -> QueryResult<()> {
+    let (flow_id, from, to) = match entry.transition() {
+        Some(transition) => transition,
+        None => return Ok(()),
+    };
+    let mut actions = vec![];
+    if let Some(from) = from {
+        actions.extend(FlowNodeAction::triggered(conn, flow_id, from, false)?);
+    }
+    actions.extend(FlowNodeAction::triggered(conn, flow_id, to, true)?);
+
+    for node_action in actions {
+        let action = match node_action.decode_action() {
+            Some(action) => action,
+            None => {
+                tracing::warn!("Undecodable flow action {}", node_action.id);
+                continue;
+            }
+        };
+        let before = task.clone();
+        let outcome = conn.transaction(|savepoint| run_flow_action(savepoint, user_id, task, action));
+        if let Err(err) = outcome {
+            tracing::warn!("Flow action {} failed: {:?}", node_action.id, err);
+            *task = before;
+        }
+    }
+    Ok(())
+}
+
 pub async fn update_task(
     conn: &mut PgConnection,
     user_id: Uuid,
     task_id: Uuid,
     update: TaskUpdate,
+    senders: &TaskUpdateSenders,
 ) -> Result<Task, Rejection> {
     let mut task = match Task::get(conn, task_id) {
         Some(task) => task,
@@ -226,13 +544,24 @@ pub async fn update_task(
             return Err(warp::reject::custom(NotFoundError {}));
         }
     };
-    match task.update(conn, user_id, update) {
-        Ok(state) => state,
-        Err(err) => {
-            tracing::warn!("Update error: {}", err);
-            return Err(warp::reject::custom(DatabaseError {}));
-        }
-    };
+    let old_assignee_id = task.assignee_id;
+    let old_flows = task
+        .flows(conn)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    // The update, its flow actions and the events describing them commit together
+    let changes = conn
+        .transaction(|transact| {
+            let entry = task.update(transact, user_id, update)?;
+            run_flow_actions(transact, user_id, &mut task, &entry)?;
+            let new_flows = task.flows(transact)?;
+            let changes = TaskChanges::diff(&task, user_id, old_assignee_id, &old_flows, &new_flows);
+            changes.record(transact)?;
+            let state = TaskStatePayload::build(transact, task.clone())?;
+            OutboxEvent::record(transact, TASK_UPDATED_BEAM, &state)?;
+            QueryResult::Ok(changes)
+        })
+        .map_err(ValidationError::reject)?;
+    senders.send_changes(changes);
     Ok(task)
 }
 
@@ -243,12 +572,13 @@ async fn update_task_handler(
     session: SessionWithStore<MemoryStore>,
     db_pool: Arc<DbPool>,
     sender: broadcast::Sender<TaskStatePayload>,
+    update_senders: TaskUpdateSenders,
 ) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
     let mut conn = match db_pool.get() {
         Ok(conn) => conn,
         Err(_) => return Err(warp::reject::custom(DatabaseError {})),
     };
-    let task = update_task(&mut conn, auth.id(), task_id, payload).await?;
+    let task = update_task(&mut conn, auth.id(), task_id, payload, &update_senders).await?;
     let task_denorm = DenormalizedTask::denormalize(&mut conn, &task)
         .map_err(|_| warp::reject::custom(DatabaseError {}))?;
     let task_state = TaskStatePayload::build(&mut conn, task)
@@ -299,11 +629,33 @@ async fn get_task_handler(
     }
 }
 
+/// A task query as sent by clients, either in the query language or as the older map of
+/// field to substring
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum QueryInput {
+    Text(String),
+    Fields(HashMap<String, String>),
+}
+
+impl QueryInput {
+    pub fn parse(&self) -> Result<TaskQuery, QueryError> {
+        match self {
+            Self::Text(text) => TaskQuery::parse(text),
+            Self::Fields(fields) => TaskQuery::from_fields(fields),
+        }
+    }
+}
+
+/// A task query and the page of its results to return. Pages are continued with the
+/// `next_cursor` of the previous reply.
 #[derive(Deserialize)]
 pub struct QueryPayload {
-    pub page: u32,
-    pub page_size: u32,
-    pub query: HashMap<String, String>,
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub page_size: Option<u32>,
+    pub query: QueryInput,
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -311,6 +663,7 @@ pub struct DenormalizedTask {
     pub id: Uuid,
     pub slug: String,
     pub created: NaiveDateTime,
+    pub updated: NaiveDateTime,
     pub title: String,
     pub description: String,
     pub author: DenormalizedUser,
@@ -332,18 +685,20 @@ impl DenormalizedTask {
             None => None,
         };
         let flows = task.flows(conn)?;
-        let state = TaskFlow::get_active_node(conn, &flows)?;
-        let valid_transitions = FlowConnection::edges(conn, state.id)?;
+        let open = TaskFlow::states(conn, &flows)?
+            .iter()
+            .any(|flow| !flow.valid_transitions.is_empty());
 
         Ok(Self {
             id: task.id,
             slug: task.slug.clone(),
             created: task.created,
+            updated: task.updated,
             title: task.title.clone(),
             description: task.description.clone(),
             author,
             assignee,
-            open: !valid_transitions.is_empty(),
+            open,
         })
     }
 }
@@ -380,10 +735,10 @@ impl DenormalizedTaskLink {
 pub struct DenormalizedTaskDetails {
     pub tags: Vec<String>,
     pub watchers: Vec<DenormalizedUser>,
-    pub state: FlowNode,
+    pub flows: Vec<TaskFlowState>,
     pub links_out: Vec<DenormalizedTaskLink>,
     pub links_in: Vec<DenormalizedTaskLink>,
-    pub valid_transitions: Vec<FlowNode>,
+    pub comments: Vec<DenormalizedTaskComment>,
 }
 
 impl DenormalizedTaskDetails {
@@ -396,8 +751,7 @@ impl DenormalizedTaskDetails {
             .into_iter()
             .filter_map(|user| DenormalizedUser::denormalize(conn, user).ok())
             .collect();
-        let state = TaskFlow::get_active_node(conn, &flows)?;
-        let valid_transitions = FlowConnection::edges(conn, state.id)?;
+        let flows = TaskFlow::states(conn, &flows)?;
 
         let links_out: Vec<DenormalizedTaskLink> = TaskLink::get_outgoing(conn, task)?
             .into_iter()
@@ -411,14 +765,15 @@ impl DenormalizedTaskDetails {
                 DenormalizedTaskLink::denormalize(conn, DirectionalLink::From(task_link)).ok()
             })
             .collect();
+        let comments = DenormalizedTaskComment::list(conn, task)?;
 
         Ok(Self {
             tags,
             watchers,
-            state,
+            flows,
             links_out,
             links_in,
-            valid_transitions,
+            comments,
         })
     }
 }
@@ -448,6 +803,8 @@ impl DenormalizedTaskState {
 #[derive(Serialize)]
 pub struct QueryReply {
     pub tasks: Vec<DenormalizedTask>,
+    pub next_cursor: Option<String>,
+    pub total: i64,
 }
 
 pub type QueryChannel = (QueryPayload, oneshot::Sender<Result<QueryReply, Rejection>>);
@@ -457,22 +814,43 @@ pub async fn filter_tasks(
     conn: &mut PgConnection,
     payload: QueryPayload,
 ) -> Result<QueryReply, Rejection> {
-    let tasks = Task::query(
+    let task_query = payload.query.parse().map_err(|err| {
+        warp::reject::custom(ValidationError {
+            message: err.to_string(),
+            column: Some("query".to_string()),
+            constraint_name: Some("query_syntax".to_string()),
+            unmet_guards: vec![],
+        })
+    })?;
+    query_tasks(
+        auth,
         conn,
-        auth.id(),
-        &payload.query,
-        payload.page,
+        &task_query,
+        payload.cursor.as_deref(),
         payload.page_size,
-    );
+    )
+}
+
+pub fn query_tasks(
+    auth: AuthenticatedUser,
+    conn: &mut PgConnection,
+    task_query: &TaskQuery,
+    cursor: Option<&str>,
+    page_size: Option<u32>,
+) -> Result<QueryReply, Rejection> {
+    let page = Task::query(conn, auth.id(), task_query, cursor, page_size)
+        .map_err(ValidationError::reject)?;
     let mut denorm_tasks = vec![];
-    for task in tasks {
-        let denorm_task = DenormalizedTask::denormalize(conn, &task)
+    for task in &page.items {
+        let denorm_task = DenormalizedTask::denormalize(conn, task)
             .map_err(|_| warp::reject::custom(DatabaseError {}))?;
         denorm_tasks.push(denorm_task);
     }
 
     Ok(QueryReply {
         tasks: denorm_tasks,
+        next_cursor: page.next_cursor,
+        total: page.total,
     })
 }
 
@@ -492,17 +870,163 @@ async fn filter_tasks_handler(
     ))
 }
 
-#[derive(Clone, Debug)]
-pub struct TaskRun {
-    pub state: DenormalizedTaskState,
+#[derive(Deserialize, Debug, Clone)]
+pub struct SearchParams {
+    q: String,
+    #[serde(default)]
+    project_id: Option<Uuid>,
+    #[serde(default)]
+    limit: Option<u32>,
 }
 
-async fn run_task_handler(
+/// Ranked full-text search over task titles and descriptions
+pub fn search_tasks(
+    conn: &mut PgConnection,
+    text: &str,
+    project_id: Option<Uuid>,
+    limit: Option<u32>,
+) -> Result<Vec<TaskSearchHit>, Rejection> {
+    if text.trim().is_empty() {
+        return Err(warp::reject::custom(ValidationError {
+            message: "Search text is required".to_string(),
+            column: Some("q".to_string()),
+            constraint_name: Some("search_text".to_string()),
+            unmet_guards: vec![],
+        }));
+    }
+    let limit = limit.map(i64::from).unwrap_or(MAX_SEARCH_RESULTS);
+    Task::search(conn, text, project_id, limit).map_err(|_| warp::reject::custom(DatabaseError {}))
+}
+
+async fn search_tasks_handler(
+    params: SearchParams,
+    _auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let hits = search_tasks(&mut conn, &params.q, params.project_id, params.limit)?;
+    Ok((warp::reply::json(&hits), session))
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum TaskActivityEvent {
+    Created,
+    Updated {
+        update: TaskUpdate,
+        reverted: bool,
+    },
+    Transition {
+        flow_id: Uuid,
+        from: Option<FlowNode>,
+        to: FlowNode,
+        reverted: bool,
+    },
+    JobRun {
+        job_id: Uuid,
+        name: String,
+    },
+    JobResult {
+        job_id: Uuid,
+        succeeded: bool,
+    },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TaskActivity {
+    pub timestamp: NaiveDateTime,
+    pub actor: Option<DenormalizedUser>,
+    pub event: TaskActivityEvent,
+}
+
+impl TaskActivity {
+    /// Builds the chronological activity feed of a task from its history and jobs.
+    pub fn feed(conn: &mut PgConnection, task: &Task) -> QueryResult<Vec<Self>> {
+        let mut users: HashMap<Uuid, DenormalizedUser> = HashMap::new();
+        let mut actor = |conn: &mut PgConnection, user_id: Uuid| -> QueryResult<DenormalizedUser> {
+            if let Some(user) = users.get(&user_id) {
+                return Ok(user.clone());
+            }
+            let user = User::get(conn, user_id).ok_or_else(|| diesel::result::Error::NotFound)?;
+            let user = DenormalizedUser::denormalize(conn, user)?;
+            users.insert(user_id, user.clone());
+            Ok(user)
+        };
+
+        let mut feed = vec![Self {
+            timestamp: task.created,
+            actor: Some(actor(conn, task.author_id)?),
+            event: TaskActivityEvent::Created,
+        }];
+
+        for entry in TaskHistory::list(conn, task.id)? {
+            let update = match entry.decode_update() {
+                Some(update) => update,
+                None => {
+                    tracing::warn!("Undecodable history entry {}", entry.id);
+                    continue;
+                }
+            };
+            let event = match entry.transition() {
+                Some((flow_id, from, node_id)) => {
+                    let to = match FlowNode::get(conn, node_id) {
+                        Some(node) => node,
+                        None => continue,
+                    };
+                    let from = from.and_then(|from| FlowNode::get(conn, from));
+                    TaskActivityEvent::Transition {
+                        flow_id,
+                        from,
+                        to,
+                        reverted: entry.reverted,
+                    }
+                }
+                None => TaskActivityEvent::Updated {
+                    update,
+                    reverted: entry.reverted,
+                },
+            };
+            feed.push(Self {
+                timestamp: entry.created,
+                actor: Some(actor(conn, entry.actor_id)?),
+                event,
+            });
+        }
+
+        for (job, result) in Job::list_for_task(conn, task.id)? {
+            feed.push(Self {
+                timestamp: job.created,
+                actor: Some(actor(conn, job.created_id)?),
+                event: TaskActivityEvent::JobRun {
+                    job_id: job.id,
+                    name: job.name,
+                },
+            });
+            if let Some(result) = result {
+                feed.push(Self {
+                    timestamp: result.completion_time,
+                    actor: Some(actor(conn, job.assignee_id)?),
+                    event: TaskActivityEvent::JobResult {
+                        job_id: job.id,
+                        succeeded: result.succeeded,
+                    },
+                });
+            }
+        }
+
+        feed.sort_by_key(|activity| activity.timestamp);
+        Ok(feed)
+    }
+}
+
+async fn task_history_handler(
     task_id: Uuid,
-    auth: AuthenticatedUser,
+    _auth: AuthenticatedUser,
     session: SessionWithStore<MemoryStore>,
     db_pool: Arc<DbPool>,
-    task_run_tx: broadcast::Sender<TaskRun>,
 ) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
     let mut conn = match db_pool.get() {
         Ok(conn) => conn,
@@ -514,20 +1038,115 @@ async fn run_task_handler(
             return Err(warp::reject::custom(NotFoundError {}));
         }
     };
+    let feed = TaskActivity::feed(&mut conn, &task)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::json(&feed), session))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SimilarParams {
+    #[serde(default)]
+    limit: Option<u32>,
+    #[serde(default)]
+    min_score: Option<f32>,
+}
+
+const DEFAULT_SIMILAR_LIMIT: u32 = 10;
+
+async fn similar_tasks_handler(
+    task_id: Uuid,
+    params: SimilarParams,
+    _auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+    embed_tx: mpsc::Sender<EmbedRequest>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let (task, stored, project_ids) = {
+        let mut conn = db_pool
+            .get()
+            .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+        let task = Task::get(&mut conn, task_id)
+            .ok_or_else(|| warp::reject::custom(NotFoundError {}))?;
+        let stored = TaskEmbedding::for_task(&mut conn, task.id);
+        let project_ids = task
+            .project_ids(&mut conn)
+            .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+        (task, stored, project_ids)
+    };
+    // Tasks are embedded as they change, so only ones the embedder hasn't reached yet are
+    // embedded here
+    let (embedding, model) = match stored {
+        Some(stored) => (stored.embedding, stored.model),
+        None => {
+            let text_embedding = embed(&embed_tx, task_text(&task.title, &task.description))
+                .await
+                .ok_or_else(|| warp::reject::custom(InvalidConfigurationError {}))?;
+            (text_embedding.embedding, text_embedding.model)
+        }
+    };
+    let mut conn = db_pool
+        .get()
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    let limit = params.limit.unwrap_or(DEFAULT_SIMILAR_LIMIT).clamp(1, MAX_SEARCH_RESULTS as u32);
+    let similar = Task::similar(
+        &mut conn,
+        &embedding,
+        &model,
+        Some(&project_ids),
+        Some(task.id),
+        params.min_score.unwrap_or(0.0),
+        limit as usize,
+    )
+    .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::json(&similar), session))
+}
+
+/// A request for Sage to run a task. Sage puts the run id on the job it creates so the
+/// requester can tell which job is theirs.
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskRun {
+    pub run_id: Uuid,
+    #[serde(flatten)]
+    pub state: DenormalizedTaskState,
+}
+
+/// Packages an assigned task with the submitting user's context for Sage to run.
+pub fn build_task_run(conn: &mut PgConnection, user_id: Uuid, task: &Task) -> Result<TaskRun, Rejection> {
     if task.assignee_id.is_none() {
         return Err(warp::reject::custom(InvalidConfigurationError {}));
     }
-    let submitted_user = User::get(&mut conn, auth.id())
+    let submitted_user = User::get(conn, user_id)
         .ok_or_else(|| warp::reject::custom(NotFoundError {}))?;
-    let active_project = ActiveProject::get(&mut conn, submitted_user.id)
+    let active_project = ActiveProject::get(conn, submitted_user.id)
         .ok_or_else(|| warp::reject::custom(NotFoundError {}))?;
-    let state = DenormalizedTaskState::denormalize(&mut conn,
+    let state = DenormalizedTaskState::denormalize(conn,
                                                    submitted_user,
                                                    active_project.project_id,
-                                                   &task)
+                                                   task)
         .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok(TaskRun { run_id: Uuid::new_v4(), state })
+}
 
-    let run = TaskRun { state };
+async fn run_task_handler(
+    task_id: Uuid,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+    task_run_tx: broadcast::Sender<TaskRun>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let task = match Task::get(&mut conn, task_id) {
+        Some(task) => task,
+        None => {
+            return Err(warp::reject::custom(NotFoundError {}));
+        }
+    };
+    let run = build_task_run(&mut conn, auth.id(), &task)?;
+    OutboxEvent::record(&mut conn, TASK_RUN_BEAM, &run)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
     task_run_tx.send(run).ok();
 
     Ok((
@@ -545,17 +1164,25 @@ pub fn routes(
     let task_tx: broadcast::Sender<Task> = router.announce();
     let task_update_tx: broadcast::Sender<TaskStatePayload> = router.announce();
     let task_run_tx: broadcast::Sender<TaskRun> = router.announce();
+    let update_senders = TaskUpdateSenders::new(router);
+    let task_comments = comments::routes(idp.clone(), session.clone(), pool.clone(), router);
     let prompt_request_tx: mpsc::Sender<InitializePromptChannel> = router
         .get_address()
         .expect("No prompt request channel defined")
         .clone();
+    let embed_tx: mpsc::Sender<EmbedRequest> = router
+        .get_address()
+        .expect("No embedding channel defined")
+        .clone();
 
     let create_task = warp::post()
         .and(warp::body::json())
         .and(authenticate(idp.clone(), session.clone()))
         .and(with_db(pool.clone()))
         .and(with_channel(prompt_request_tx))
+        .and(with_channel(embed_tx.clone()))
         .and(with_broadcast(task_tx))
+        .and(with_update_senders(update_senders.clone()))
         .and_then(create_task_handler)
         .untuple_one()
         .and_then(store_auth_cookie);
@@ -566,10 +1193,31 @@ pub fn routes(
         .and(authenticate(idp.clone(), session.clone()))
         .and(with_db(pool.clone()))
         .and(with_broadcast(task_update_tx))
+        .and(with_update_senders(update_senders))
         .and_then(update_task_handler)
         .untuple_one()
         .and_then(store_auth_cookie);
 
+    let task_history = warp::get()
+        .and(warp::path::param())
+        .and(warp::path!("history"))
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(task_history_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let similar_tasks = warp::get()
+        .and(warp::path::param())
+        .and(warp::path!("similar"))
+        .and(warp::query::<SimilarParams>())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and(with_channel(embed_tx))
+        .and_then(similar_tasks_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
     let get_task = warp::get()
         .and(warp::path::param())
         .and(warp::query::<GetTaskQuery>())
@@ -588,6 +1236,15 @@ pub fn routes(
         .untuple_one()
         .and_then(store_auth_cookie);
 
+    let search_tasks = warp::path!("search")
+        .and(warp::get())
+        .and(warp::query::<SearchParams>())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(search_tasks_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
     let run_task = warp::path("run")
         .and(warp::path::param())
         .and(authenticate(idp.clone(), session.clone()))
@@ -599,9 +1256,13 @@ pub fn routes(
 
     warp::path("task").and(
         filter_tasks
+            .or(search_tasks)
+            .or(task_comments)
             .or(create_task)
             .or(update_task)
             .or(run_task)
+            .or(task_history)
+            .or(similar_tasks)
             .or(get_task),
     )
 }
@@ -2,23 +2,27 @@ use std::sync::Arc;
 
 use bytes::Buf;
 use chrono::NaiveDateTime;
-use diesel::{PgConnection, QueryResult};
+use diesel::{Connection, PgConnection, QueryResult};
 use futures::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use subseq_util::api::{InvalidConfigurationError, NotFoundError};
 use subseq_util::api::sessions::store_auth_cookie;
 use subseq_util::{
-    api::{authenticate, with_db, AuthenticatedUser, DatabaseError},
+    api::{authenticate, with_broadcast, with_db, AuthenticatedUser, DatabaseError},
     oidc::IdentityProvider,
     tables::{DbPool, UserTable},
+    Router,
 };
+use tokio::sync::broadcast;
 use uuid::Uuid;
 use warp::multipart::FormData;
 use warp::{http::Response, reject::Rejection, reply::Reply, Filter};
 use warp_sessions::{MemoryStore, SessionWithStore};
 
-use crate::tables::{User, UserIdAccount, UserMetadata, UserPortrait};
-use super::PAGE_SIZE;
+use crate::events::USER_UPDATED_BEAM;
+use crate::interop::{UserUpdate, USER_UPDATE_ORIGIN};
+use crate::tables::{OutboxEvent, PageRequest, User, UserIdAccount, UserMetadata, UserPortrait};
+use super::ValidationError;
 
 #[derive(Deserialize, Serialize)]
 pub struct StoredUserMeta {
@@ -98,8 +102,69 @@ pub async fn self_users_handler(
     Ok((warp::reply::json(&user), session))
 }
 
+#[derive(Deserialize)]
+pub struct UserProfilePayload {
+    username: Option<String>,
+    job_title: Option<String>,
+}
+
+pub async fn update_self_handler(
+    payload: UserProfilePayload,
+    auth_user: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+    sender: broadcast::Sender<UserUpdate>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let user = match User::get(&mut conn, auth_user.id()) {
+        Some(user) => user,
+        None => return Err(warp::reject::custom(NotFoundError{})),
+    };
+    let UserProfilePayload { username, job_title } = payload;
+    let update = UserUpdate {
+        user_id: user.id,
+        email: None,
+        username,
+        job_title,
+        origin: Some(USER_UPDATE_ORIGIN.to_string()),
+    };
+    conn.transaction(|transact| {
+        apply_user_update(transact, &update)?;
+        OutboxEvent::record(transact, USER_UPDATED_BEAM, &update)
+    })
+    .map_err(ValidationError::reject)?;
+    sender.send(update).ok();
+
+    let user = DenormalizedUser::denormalize(&mut conn, user)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::json(&user), session))
+}
+
+/// Stores the email, username and job title of a profile update
+pub fn apply_user_update(conn: &mut PgConnection, update: &UserUpdate) -> QueryResult<()> {
+    conn.transaction(|transact| {
+        if let Some(email) = &update.email {
+            let mut user = User::get(transact, update.user_id)
+                .ok_or(diesel::result::Error::NotFound)?;
+            user.set_email(transact, email)?;
+        }
+        if let Some(username) = &update.username {
+            UserIdAccount::set_username(transact, update.user_id, username)?;
+        }
+        if let Some(job_title) = &update.job_title {
+            let job_title = serde_json::to_value(job_title)
+                .map_err(|err| diesel::result::Error::SerializationError(Box::new(err)))?;
+            UserMetadata::set_field(transact, update.user_id, "job_title", job_title)?;
+        }
+        Ok(())
+    })
+}
+
 pub async fn list_users_handler(
-    page: u32,
+    page: PageRequest,
     _auth_user: AuthenticatedUser,
     session: SessionWithStore<MemoryStore>,
     db_pool: Arc<DbPool>,
@@ -108,13 +173,10 @@ pub async fn list_users_handler(
         Ok(conn) => conn,
         Err(_) => return Err(warp::reject::custom(DatabaseError {})),
     };
-    let users = User::list(&mut conn, page, PAGE_SIZE);
-    let mut denorm_users = Vec::new();
-    for user in users {
-        let denorm_user = DenormalizedUser::denormalize(&mut conn, user)
-            .map_err(|_| warp::reject::custom(DatabaseError {}))?;
-        denorm_users.push(denorm_user);
-    }
+    let users = User::page(&mut conn, &page).map_err(ValidationError::reject)?;
+    let denorm_users = users
+        .try_map(|user| DenormalizedUser::denormalize(&mut conn, user))
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
     Ok((warp::reply::json(&denorm_users), session))
 }
 
@@ -157,7 +219,20 @@ pub fn routes(
     idp: Option<Arc<IdentityProvider>>,
     session: MemoryStore,
     pool: Arc<DbPool>,
+    router: &mut Router,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let user_update_tx: broadcast::Sender<UserUpdate> = router.announce();
+
+    let update_me = warp::path!("user" / "me")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and(with_broadcast(user_update_tx))
+        .and_then(update_self_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
     let whoami = warp::path!("user" / "me")
         .and(authenticate(idp.clone(), session.clone()))
         .and(with_db(pool.clone()))
@@ -165,7 +240,8 @@ pub fn routes(
         .untuple_one()
         .and_then(store_auth_cookie);
 
-    let list_users = warp::path!("user" / "list" / u32)
+    let list_users = warp::path!("user" / "list")
+        .and(warp::query::<PageRequest>())
         .and(authenticate(idp.clone(), session.clone()))
         .and(with_db(pool.clone()))
         .and_then(list_users_handler)
@@ -190,5 +266,5 @@ pub fn routes(
         .untuple_one()
         .and_then(store_auth_cookie);
 
-    whoami.or(list_users).or(get_image).or(put_image)
+    update_me.or(whoami).or(list_users).or(get_image).or(put_image)
 }
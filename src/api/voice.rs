@@ -70,8 +70,10 @@ pub struct SpeechToTextResponse {
     pub finalized: bool,
 }
 
+/// Batches audio into utterances and sends their transcripts on, tagged with the context the
+/// utterance started in
 pub fn create_audio_timing_task(
-    text_tx: mpsc::Sender<SpeechToTextResponse>,
+    text_tx: mpsc::Sender<(AudioContext, SpeechToTextResponse)>,
     mut audio_rx: mpsc::Receiver<AudioDataChannel>,
     audio_stream: mpsc::Sender<AudioEventChannel>,
 ) {
@@ -80,7 +82,7 @@ pub fn create_audio_timing_task(
     spawn(async move {
         let mut n_messages: usize = 0;
         let mut stream: Option<Vec<u8>> = None;
-        let mut _context = AudioContext::Discover;
+        let mut context = AudioContext::Discover;
 
         loop {
             let conversation_id: Uuid = Uuid::new_v4();
@@ -105,9 +107,10 @@ pub fn create_audio_timing_task(
                         }
 
                         let text_tx = text_tx.clone();
+                        let context = context;
                         spawn(async move {
                             if let Ok(response) = rx.await {
-                                text_tx.send(response).await.ok();
+                                text_tx.send((context, response)).await.ok();
                             }
                         });
                     }
@@ -122,7 +125,7 @@ pub fn create_audio_timing_task(
                         Some(audio)
                     }
                     None => {
-                        _context = new_context;
+                        context = new_context;
                         Some(payload)
                     }
                 };
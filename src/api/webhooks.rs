@@ -0,0 +1,425 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use diesel::{Connection, PgConnection, QueryResult};
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use subseq_util::api::sessions::store_auth_cookie;
+use subseq_util::oidc::IdentityProvider;
+use subseq_util::{api::*, tables::DbPool};
+use tokio::net::lookup_host;
+use tokio::spawn;
+use tokio::time::interval;
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+use warp_sessions::{MemoryStore, SessionWithStore};
+
+use super::ValidationError;
+use crate::events::{
+    HELP_REQUESTED_BEAM, JOB_FINISHED_BEAM, TASK_CREATED_BEAM, TASK_STATE_BEAM, TASK_UPDATED_BEAM,
+};
+use crate::sinks::{EventSink, SinkError, SinkFuture};
+use crate::tables::{
+    is_public_ip, PageRequest, Project, ProjectWebhook, WebhookDelivery, WebhookEvent,
+};
+
+const DELIVERY_POLL_RATE: Duration = Duration::from_secs(5);
+const DELIVERY_BATCH_SIZE: i64 = 64;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_CONCURRENCY: usize = 8;
+
+pub const WEBHOOK_SINK_NAME: &str = "webhooks";
+
+pub const SIGNATURE_HEADER: &str = "X-Zini-Signature";
+pub const EVENT_HEADER: &str = "X-Zini-Event";
+pub const DELIVERY_HEADER: &str = "X-Zini-Delivery";
+
+/// The hex HMAC-SHA256 of a delivery body, sent as `sha256=<hex>` so receivers can check the
+/// delivery came from us
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// The body posted to a webhook
+#[derive(Serialize)]
+struct WebhookBody<'a> {
+    delivery_id: Uuid,
+    event: &'a str,
+    payload: &'a serde_json::Value,
+}
+
+fn enqueue<T: Serialize>(
+    conn: &mut PgConnection,
+    webhooks: Vec<ProjectWebhook>,
+    event: WebhookEvent,
+    msg: &T,
+) -> QueryResult<()> {
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+    let payload = serde_json::to_value(msg)
+        .map_err(|err| diesel::result::Error::SerializationError(Box::new(err)))?;
+    for webhook in webhooks {
+        WebhookDelivery::enqueue(conn, &webhook, event, payload.clone())?;
+    }
+    Ok(())
+}
+
+/// Which part of an event's payload names the task or project it is about
+enum Subject {
+    Task(&'static str),
+    Project(&'static str),
+}
+
+fn webhook_event(beam: &str) -> Option<(WebhookEvent, Subject)> {
+    match beam {
+        TASK_CREATED_BEAM => Some((WebhookEvent::TaskCreated, Subject::Task("/id"))),
+        TASK_UPDATED_BEAM => Some((WebhookEvent::TaskUpdated, Subject::Task("/task/id"))),
+        TASK_STATE_BEAM => Some((WebhookEvent::TaskStateChanged, Subject::Task("/task_id"))),
+        JOB_FINISHED_BEAM => Some((WebhookEvent::JobFinished, Subject::Project("/job/project_id"))),
+        HELP_REQUESTED_BEAM => {
+            Some((WebhookEvent::HelpRequested, Subject::Project("/job/project_id")))
+        }
+        _ => None,
+    }
+}
+
+/// Queues a delivery to every webhook registered for an event as it is read from the outbox
+pub struct WebhookSink {
+    db_pool: Arc<DbPool>,
+}
+
+impl WebhookSink {
+    pub fn new(db_pool: Arc<DbPool>) -> Self {
+        Self { db_pool }
+    }
+
+    fn queue(&self, beam: &str, photon: &serde_json::Value) -> Result<(), SinkError> {
+        let (event, subject) = match webhook_event(beam) {
+            Some(found) => found,
+            None => return Ok(()),
+        };
+        let pointer = match subject {
+            Subject::Task(pointer) | Subject::Project(pointer) => pointer,
+        };
+        let id: Uuid = photon
+            .pointer(pointer)
+            .and_then(|id| id.as_str())
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| SinkError(format!("{} event has no {}", beam, pointer)))?;
+        let mut conn = self
+            .db_pool
+            .get()
+            .map_err(|err| SinkError(err.to_string()))?;
+        // All or none of the deliveries are queued so a retried event isn't sent twice
+        conn.transaction(|transact| {
+            let webhooks = match subject {
+                Subject::Task(_) => ProjectWebhook::subscribed_for_task(transact, id, event)?,
+                Subject::Project(_) => ProjectWebhook::subscribed(transact, &[id], event)?,
+            };
+            enqueue(transact, webhooks, event, photon)
+        })
+        .map_err(|err| SinkError(err.to_string()))
+    }
+}
+
+impl EventSink for WebhookSink {
+    fn emit<'a>(&'a mut self, beam: &'a str, photon: &'a serde_json::Value) -> SinkFuture<'a> {
+        let queued = self.queue(beam, photon);
+        Box::pin(async move { queued })
+    }
+}
+
+/// Resolves webhook hosts, refusing any which resolve to a private or local address so a
+/// webhook can't reach inside the network. Checking at connect time stops a host from
+/// passing validation and then being pointed somewhere else.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} does not resolve to a public address", name.as_str()).into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+async fn attempt_delivery(
+    client: &reqwest::Client,
+    webhook: &ProjectWebhook,
+    delivery: &WebhookDelivery,
+) -> Result<i32, (Option<i32>, String)> {
+    let body = WebhookBody {
+        delivery_id: delivery.id,
+        event: &delivery.event,
+        payload: &delivery.payload,
+    };
+    let body = serde_json::to_vec(&body).map_err(|err| (None, err.to_string()))?;
+    // Addresses in the URL itself skip the resolver
+    let host = url::Url::parse(&webhook.url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.trim_matches(['[', ']']).to_string()));
+    if let Some(Ok(ip)) = host.map(|host| host.parse::<IpAddr>()) {
+        if !is_public_ip(ip) {
+            return Err((None, format!("{} is not a public address", ip)));
+        }
+    }
+    let response = client
+        .post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, &delivery.event)
+        .header(DELIVERY_HEADER, delivery.id.to_string())
+        .header(SIGNATURE_HEADER, sign(&webhook.secret, &body))
+        .body(body)
+        .send()
+        .await
+        .map_err(|err| (None, err.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(status.as_u16() as i32)
+    } else {
+        Err((Some(status.as_u16() as i32), status.to_string()))
+    }
+}
+
+async fn deliver(client: &reqwest::Client, db_pool: &DbPool, mut delivery: WebhookDelivery) {
+    let webhook = match db_pool.get() {
+        Ok(mut conn) => match ProjectWebhook::get(&mut conn, delivery.webhook_id) {
+            Some(webhook) => webhook,
+            None => return,
+        },
+        Err(_) => {
+            tracing::warn!("Database connection failed");
+            return;
+        }
+    };
+    let attempt = attempt_delivery(client, &webhook, &delivery).await;
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => {
+            tracing::warn!("Database connection failed");
+            return;
+        }
+    };
+    let recorded = match attempt {
+        Ok(status_code) => delivery.record_success(&mut conn, status_code),
+        Err((status_code, error)) => {
+            tracing::warn!("Webhook delivery {} failed: {}", delivery.id, error);
+            delivery.record_failure(&mut conn, status_code, &error)
+        }
+    };
+    if let Err(err) = recorded {
+        tracing::error!("Failed to record delivery {}: {:?}", delivery.id, err);
+    }
+}
+
+/// Posts queued deliveries as they come due, a few at a time, recording each attempt in the
+/// delivery log
+pub fn deliver_webhooks(db_pool: Arc<DbPool>) {
+    let client = reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()
+        .expect("Could not build HTTP client");
+
+    spawn(async move {
+        let mut poll = interval(DELIVERY_POLL_RATE);
+        loop {
+            poll.tick().await;
+            let due = match db_pool.get() {
+                Ok(mut conn) => {
+                    let now = chrono::Utc::now().naive_utc();
+                    WebhookDelivery::due(&mut conn, now, DELIVERY_BATCH_SIZE)
+                }
+                Err(_) => {
+                    tracing::warn!("Database connection failed");
+                    continue;
+                }
+            };
+            let due = match due {
+                Ok(due) => due,
+                Err(err) => {
+                    tracing::error!("Failed to load webhook deliveries: {:?}", err);
+                    continue;
+                }
+            };
+            futures::stream::iter(due)
+                .for_each_concurrent(DELIVERY_CONCURRENCY, |delivery| {
+                    deliver(&client, &db_pool, delivery)
+                })
+                .await;
+        }
+    });
+}
+
+#[derive(Deserialize)]
+pub struct WebhookPayload {
+    url: String,
+    events: Vec<WebhookEvent>,
+}
+
+/// A newly registered webhook. This is the only time its secret is returned.
+#[derive(Serialize)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    webhook: ProjectWebhook,
+    secret: String,
+}
+
+/// Fetches a project whose webhooks and routing rules the user is allowed to manage.
+pub(super) fn owned_project(conn: &mut PgConnection, user_id: Uuid, project_id: Uuid) -> Result<Project, Rejection> {
+    let project = match Project::get(conn, project_id) {
+        Some(project) => project,
+        None => return Err(warp::reject::custom(NotFoundError {})),
+    };
+    if project.owner_id != user_id {
+        tracing::warn!("User {} cannot manage project {}", user_id, project_id);
+        return Err(warp::reject::custom(InvalidConfigurationError {}));
+    }
+    Ok(project)
+}
+
+/// Fetches a webhook on a project the user owns.
+fn owned_webhook(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    project_id: Uuid,
+    webhook_id: Uuid,
+) -> Result<ProjectWebhook, Rejection> {
+    owned_project(conn, user_id, project_id)?;
+    match ProjectWebhook::get(conn, webhook_id) {
+        Some(webhook) if webhook.project_id == project_id => Ok(webhook),
+        _ => Err(warp::reject::custom(NotFoundError {})),
+    }
+}
+
+async fn create_webhook_handler(
+    project_id: Uuid,
+    payload: WebhookPayload,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let project = owned_project(&mut conn, auth.id(), project_id)?;
+    let webhook = ProjectWebhook::create(&mut conn, &project, &payload.url, &payload.events)
+        .map_err(ValidationError::reject)?;
+    let secret = webhook.secret.clone();
+    Ok((warp::reply::json(&CreatedWebhook { webhook, secret }), session))
+}
+
+async fn list_webhooks_handler(
+    project_id: Uuid,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    owned_project(&mut conn, auth.id(), project_id)?;
+    let webhooks = ProjectWebhook::list_for_project(&mut conn, project_id)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::json(&webhooks), session))
+}
+
+async fn delete_webhook_handler(
+    project_id: Uuid,
+    webhook_id: Uuid,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let webhook = owned_webhook(&mut conn, auth.id(), project_id, webhook_id)?;
+    webhook
+        .delete(&mut conn)
+        .map_err(|_| warp::reject::custom(DatabaseError {}))?;
+    Ok((warp::reply::reply(), session))
+}
+
+async fn list_deliveries_handler(
+    project_id: Uuid,
+    webhook_id: Uuid,
+    page: PageRequest,
+    auth: AuthenticatedUser,
+    session: SessionWithStore<MemoryStore>,
+    db_pool: Arc<DbPool>,
+) -> Result<(impl Reply, SessionWithStore<MemoryStore>), Rejection> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Err(warp::reject::custom(DatabaseError {})),
+    };
+    let webhook = owned_webhook(&mut conn, auth.id(), project_id, webhook_id)?;
+    let deliveries = WebhookDelivery::list_for_webhook(&mut conn, webhook.id, &page)
+        .map_err(ValidationError::reject)?;
+    Ok((warp::reply::json(&deliveries), session))
+}
+
+/// Routes under /project/{project_id}/webhook
+pub fn routes(
+    idp: Option<Arc<IdentityProvider>>,
+    session: MemoryStore,
+    pool: Arc<DbPool>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let create_webhook = warp::path!("project" / Uuid / "webhook")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(create_webhook_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let list_webhooks = warp::path!("project" / Uuid / "webhook")
+        .and(warp::get())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(list_webhooks_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let delete_webhook = warp::path!("project" / Uuid / "webhook" / Uuid)
+        .and(warp::delete())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(delete_webhook_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    let list_deliveries = warp::path!("project" / Uuid / "webhook" / Uuid / "delivery")
+        .and(warp::get())
+        .and(warp::query::<PageRequest>())
+        .and(authenticate(idp.clone(), session.clone()))
+        .and(with_db(pool.clone()))
+        .and_then(list_deliveries_handler)
+        .untuple_one()
+        .and_then(store_auth_cookie);
+
+    create_webhook
+        .or(list_webhooks)
+        .or(delete_webhook)
+        .or(list_deliveries)
+}
@@ -13,7 +13,9 @@ use subseq_util::{
 use warp::{reject::Rejection, Filter};
 
 use zini::api::{*, prompts::PromptChannelHandle};
+use zini::embeddings::{self, EmbeddingsConfig};
 use zini::events;
+use zini::sinks::EventsConfig;
 use zini::tables::User;
 
 #[derive(Parser, Debug)]
@@ -29,7 +31,18 @@ async fn main() {
     setup_tracing("zini");
     let args = Args::parse();
     let conf_file = File::open(args.conf).expect("Could not open file");
-    let conf: BaseConfig = serde_json::from_reader(conf_file).expect("Reading config failed");
+    let conf: serde_json::Value = serde_json::from_reader(conf_file).expect("Reading config failed");
+    let events_conf: EventsConfig = match conf.get("events") {
+        Some(events) => serde_json::from_value(events.clone()).expect("Invalid events config"),
+        None => EventsConfig::default(),
+    };
+    let embeddings_conf: EmbeddingsConfig = match conf.get("embeddings") {
+        Some(embeddings) => {
+            serde_json::from_value(embeddings.clone()).expect("Invalid embeddings config")
+        }
+        None => EmbeddingsConfig::default(),
+    };
+    let conf: BaseConfig = serde_json::from_value(conf).expect("Reading config failed");
     let conf: InnerConfig = conf
         .try_into()
         .expect("Could not fetch all secrets from environment");
@@ -73,9 +86,12 @@ async fn main() {
 
     jobs::handle_new_job(pool.clone(), &mut router);
     jobs::handle_new_job_results(pool.clone(), &mut router);
+    jobs::handle_job_progress(pool.clone(), &mut router);
     jobs::handle_job_request(pool.clone(), &mut router, prompt_channel.clone());
+    webhooks::deliver_webhooks(pool.clone());
+    embeddings::handle_embeddings(&embeddings_conf, pool.clone(), &mut router);
 
-    events::emit_events(&prism_url, &mut router, pool.clone());
+    events::emit_events(&prism_url, &events_conf, &mut router, pool.clone());
     prompts::instruction_channel_task(pool.clone(), &mut router, prompt_channel);
 
     let log_requests = warp::log::custom(|info| {
@@ -98,14 +114,17 @@ async fn main() {
     let logo = warp::path("subseq-logo.svg").and(warp::fs::file("dist/subseq-logo.svg"));
     let assets = warp::path("assets").and(warp::fs::dir("dist/assets"));
 
-    let routes = projects::routes(idp.clone(), session.clone(), pool.clone(), &mut router)
+    let routes = webhooks::routes(idp.clone(), session.clone(), pool.clone())
+        .or(routing::routes(idp.clone(), session.clone(), pool.clone()))
+        .or(instruct::routes(idp.clone(), session.clone(), pool.clone()))
+        .or(projects::routes(idp.clone(), session.clone(), pool.clone(), &mut router))
         .or(util_users::routes::<User>(
             idp.clone(),
             session.clone(),
             pool.clone(),
             &mut router,
         ))
-        .or(users::routes(idp.clone(), session.clone(), pool.clone()))
+        .or(users::routes(idp.clone(), session.clone(), pool.clone(), &mut router))
         .or(tasks::routes(
             idp.clone(),
             session.clone(),
@@ -119,7 +138,7 @@ async fn main() {
             pool.clone(),
             &mut router,
         ))
-        .or(socket::routes(idp.clone(), session.clone(), &mut router))
+        .or(socket::routes(idp.clone(), session.clone(), pool.clone(), &mut router))
         .or(probe)
         .or(frontend)
         .or(ico)
@@ -129,6 +148,7 @@ async fn main() {
     if let Some(idp) = idp {
         let routes = routes
             .or(sessions::routes(session, idp))
+            .recover(handle_validation_rejection)
             .recover(handle_rejection)
             .with(log_requests);
         let tls = tls.unwrap();
@@ -141,6 +161,7 @@ async fn main() {
     } else {
         let routes = routes
             .or(sessions::no_auth_routes(session))
+            .recover(handle_validation_rejection)
             .recover(handle_rejection)
             .with(log_requests);
         warp::serve(routes).run(([127, 0, 0, 1], ZINI_PORT)).await;
@@ -0,0 +1,340 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subseq_util::tables::DbPool;
+use subseq_util::Router;
+use tokio::spawn;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::timeout;
+
+use crate::api::tasks::TaskStatePayload;
+use crate::tables::{Task, TaskEmbedding};
+
+const EMBED_TIMEOUT: Duration = Duration::from_secs(10);
+const HTTP_EMBED_TIMEOUT: Duration = Duration::from_secs(10);
+const INDEX_BATCH_SIZE: i64 = 64;
+const DEFAULT_DIMENSIONS: usize = 256;
+
+/// Tasks scoring at least this against a new task are reported as likely duplicates
+pub const DUPLICATE_THRESHOLD: f32 = 0.75;
+
+/// The embedder could not produce an embedding
+#[derive(Debug)]
+pub struct EmbeddingError(pub String);
+
+pub type EmbeddingFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Vec<f32>, EmbeddingError>> + Send + 'a>>;
+
+/// Turns text into vectors which lie close together when the texts say similar things
+pub trait Embedder: Send + Sync {
+    /// Names the model. Embeddings are only ever compared with others from the same model.
+    fn model(&self) -> &str;
+    fn embed<'a>(&'a self, text: &'a str) -> EmbeddingFuture<'a>;
+}
+
+/// Hashes words and adjacent word pairs into a fixed number of buckets. It needs no service
+/// and always gives the same vector for the same text, so it suits tests and small installs,
+/// but it only finds tasks which share their wording.
+pub struct HashingEmbedder {
+    model: String,
+    dimensions: usize,
+}
+
+impl HashingEmbedder {
+    pub fn new(dimensions: usize) -> Self {
+        Self {
+            model: format!("hashing-{}", dimensions),
+            dimensions,
+        }
+    }
+
+    pub fn embed_text(&self, text: &str) -> Vec<f32> {
+        let words: Vec<String> = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_lowercase())
+            .collect();
+        let mut embedding = vec![0.0; self.dimensions];
+        for word in &words {
+            self.add_feature(&mut embedding, word, 1.0);
+        }
+        for pair in words.windows(2) {
+            self.add_feature(&mut embedding, &format!("{} {}", pair[0], pair[1]), 0.5);
+        }
+        let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|x| *x /= norm);
+        }
+        embedding
+    }
+
+    fn add_feature(&self, embedding: &mut [f32], feature: &str, weight: f32) {
+        let digest = Sha256::digest(feature.as_bytes());
+        let mut bucket = [0u8; 8];
+        bucket.copy_from_slice(&digest[..8]);
+        let index = (u64::from_le_bytes(bucket) % self.dimensions as u64) as usize;
+        // A hashed sign keeps collisions from always adding up
+        let sign = if digest[8] & 1 == 0 { 1.0 } else { -1.0 };
+        embedding[index] += sign * weight;
+    }
+}
+
+impl Embedder for HashingEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn embed<'a>(&'a self, text: &'a str) -> EmbeddingFuture<'a> {
+        let embedding = self.embed_text(text);
+        Box::pin(async move { Ok(embedding) })
+    }
+}
+
+#[derive(Serialize)]
+struct HttpEmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+}
+
+#[derive(Deserialize)]
+struct HttpEmbeddingData {
+    embedding: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+struct HttpEmbeddingResponse {
+    data: Vec<HttpEmbeddingData>,
+}
+
+/// Calls an OpenAI compatible embeddings endpoint
+pub struct HttpEmbedder {
+    url: String,
+    model: String,
+    api_key: Option<String>,
+    client: reqwest::Client,
+}
+
+impl HttpEmbedder {
+    pub fn new(url: String, model: String, api_key: Option<String>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(HTTP_EMBED_TIMEOUT)
+            .build()
+            .expect("Could not build HTTP client");
+        Self {
+            url,
+            model,
+            api_key,
+            client,
+        }
+    }
+}
+
+impl Embedder for HttpEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn embed<'a>(&'a self, text: &'a str) -> EmbeddingFuture<'a> {
+        Box::pin(async move {
+            let mut request = self.client.post(&self.url).json(&HttpEmbeddingRequest {
+                model: &self.model,
+                input: text,
+            });
+            if let Some(api_key) = self.api_key.as_ref() {
+                request = request.bearer_auth(api_key);
+            }
+            let response = request
+                .send()
+                .await
+                .map_err(|err| EmbeddingError(err.to_string()))?;
+            if !response.status().is_success() {
+                return Err(EmbeddingError(format!("{} returned {}", self.url, response.status())));
+            }
+            let response: HttpEmbeddingResponse = response
+                .json()
+                .await
+                .map_err(|err| EmbeddingError(err.to_string()))?;
+            response
+                .data
+                .into_iter()
+                .next()
+                .map(|data| data.embedding)
+                .ok_or_else(|| EmbeddingError("No embedding in response".to_string()))
+        })
+    }
+}
+
+fn default_dimensions() -> usize {
+    DEFAULT_DIMENSIONS
+}
+
+/// The `embeddings` section of the config file. Without one tasks are embedded by hashing.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EmbeddingsConfig {
+    Hashing {
+        #[serde(default = "default_dimensions")]
+        dimensions: usize,
+    },
+    /// The API key, if any, is read from the named environment variable
+    Http {
+        url: String,
+        model: String,
+        #[serde(default)]
+        api_key_env: Option<String>,
+    },
+}
+
+impl Default for EmbeddingsConfig {
+    fn default() -> Self {
+        Self::Hashing {
+            dimensions: DEFAULT_DIMENSIONS,
+        }
+    }
+}
+
+impl EmbeddingsConfig {
+    pub fn build(&self) -> Box<dyn Embedder> {
+        match self {
+            Self::Hashing { dimensions } => Box::new(HashingEmbedder::new(*dimensions)),
+            Self::Http {
+                url,
+                model,
+                api_key_env,
+            } => {
+                let api_key = api_key_env.as_ref().map(|var| {
+                    std::env::var(var).expect("Embeddings API key is not in the environment")
+                });
+                Box::new(HttpEmbedder::new(url.clone(), model.clone(), api_key))
+            }
+        }
+    }
+}
+
+/// An embedding and the model which made it
+#[derive(Clone, Debug)]
+pub struct TextEmbedding {
+    pub model: String,
+    pub embedding: Vec<f32>,
+}
+
+/// Asks the embedding task to embed some text
+pub struct EmbedRequest(pub String, pub oneshot::Sender<Option<TextEmbedding>>);
+
+/// The text a task is embedded from
+pub fn task_text(title: &str, description: &str) -> String {
+    format!("{}\n{}", title, description)
+}
+
+/// Embeds text through the embedding task, giving up after a while
+pub async fn embed(embed_tx: &mpsc::Sender<EmbedRequest>, text: String) -> Option<TextEmbedding> {
+    let (tx, rx) = oneshot::channel();
+    embed_tx.send(EmbedRequest(text, tx)).await.ok()?;
+    match timeout(EMBED_TIMEOUT, rx).await {
+        Ok(Ok(embedding)) => embedding,
+        Ok(Err(_)) => None,
+        Err(_) => {
+            tracing::warn!("Timed out waiting for an embedding");
+            None
+        }
+    }
+}
+
+async fn index_task(embedder: &dyn Embedder, db_pool: &DbPool, task: &Task) {
+    let embedding = match embedder.embed(&task_text(&task.title, &task.description)).await {
+        Ok(embedding) => embedding,
+        Err(err) => {
+            tracing::warn!("Could not embed task {}: {:?}", task.id, err);
+            return;
+        }
+    };
+    let result = db_pool
+        .get()
+        .map_err(|err| err.to_string())
+        .and_then(|mut conn| {
+            TaskEmbedding::upsert(&mut conn, task.id, embedder.model(), embedding)
+                .map_err(|err| err.to_string())
+        });
+    if let Err(err) = result {
+        tracing::warn!("Could not store embedding for task {}: {}", task.id, err);
+    }
+}
+
+/// Embeds every task which has no embedding from the configured model yet
+async fn index_missing(embedder: Arc<dyn Embedder>, db_pool: Arc<DbPool>) {
+    loop {
+        let missing = match db_pool
+            .get()
+            .map(|mut conn| TaskEmbedding::missing(&mut conn, embedder.model(), INDEX_BATCH_SIZE))
+        {
+            Ok(Ok(missing)) => missing,
+            _ => {
+                tracing::warn!("Could not look up tasks missing embeddings");
+                return;
+            }
+        };
+        if missing.is_empty() {
+            return;
+        }
+        for task in &missing {
+            index_task(embedder.as_ref(), &db_pool, task).await;
+        }
+        if (missing.len() as i64) < INDEX_BATCH_SIZE {
+            return;
+        }
+    }
+}
+
+/// Serves embedding requests and keeps task embeddings current as tasks are created and
+/// edited. Tasks from before the model was configured are embedded in the background.
+pub fn handle_embeddings(config: &EmbeddingsConfig, db_pool: Arc<DbPool>, router: &mut Router) {
+    let embedder: Arc<dyn Embedder> = Arc::from(config.build());
+    let mut request_rx: mpsc::Receiver<EmbedRequest> = router.create_channel();
+    let mut task_rx: broadcast::Receiver<Task> = router.subscribe();
+    let mut task_update_rx: broadcast::Receiver<TaskStatePayload> = router.subscribe();
+
+    spawn(index_missing(embedder.clone(), db_pool.clone()));
+    spawn(async move {
+        loop {
+            let task = tokio::select!(
+                request = request_rx.recv() => {
+                    let EmbedRequest(text, tx) = match request {
+                        Some(request) => request,
+                        None => break,
+                    };
+                    let embedder = embedder.clone();
+                    spawn(async move {
+                        let embedding = match embedder.embed(&text).await {
+                            Ok(embedding) => Some(TextEmbedding {
+                                model: embedder.model().to_string(),
+                                embedding,
+                            }),
+                            Err(err) => {
+                                tracing::warn!("Embedding failed: {:?}", err);
+                                None
+                            }
+                        };
+                        tx.send(embedding).ok();
+                    });
+                    continue;
+                }
+                msg = task_rx.recv() => match msg {
+                    Ok(task) => task,
+                    Err(_) => continue,
+                },
+                msg = task_update_rx.recv() => match msg {
+                    Ok(state) => state.task,
+                    Err(_) => continue,
+                },
+            );
+            let embedder = embedder.clone();
+            let db_pool = db_pool.clone();
+            spawn(async move { index_task(embedder.as_ref(), &db_pool, &task).await });
+        }
+        tracing::warn!("Embedding handler exited");
+    });
+}
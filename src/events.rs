@@ -1,8 +1,10 @@
+use std::collections::HashSet;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 
+use diesel::PgConnection;
 use futures::Future;
 use http::Uri;
 use prism_client::{AsyncClient, Wavelet};
@@ -11,21 +13,26 @@ use serde::Serialize;
 use subseq_util::router::Router;
 use subseq_util::tables::{DbPool, UserTable};
 use tokio::spawn;
-use tokio::time::sleep;
-use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{interval, sleep};
+use tokio::sync::{broadcast, mpsc, oneshot, Notify};
 
+use crate::api::jobs::{HelpRequested, JobFinished};
+use crate::api::projects::ProjectUpdated;
 use crate::api::prompts::{InitializePromptChannel, PromptRx, PromptTx};
-use crate::api::tasks::TaskRun;
+use crate::api::tasks::{TaskAssigneeChanged, TaskRun, TaskStateChanged};
 use crate::api::voice::{
     SpeechToText, SpeechToTextRequest, SpeechToTextResponse, VoiceResponseCollection,
 };
-use crate::interop::{JobRequest, JobResponse};
+use crate::api::users::apply_user_update;
+use crate::api::webhooks::{WebhookSink, WEBHOOK_SINK_NAME};
+use crate::interop::{JobProgress, JobRequest, JobResponse, UserUpdate};
+use crate::sinks::{run_sink, EventSink, EventsConfig, SinkError, SinkFuture};
 use crate::{
     api::prompts::PromptResponseCollection,
     api::jobs::JobRequestCollection,
     api::tasks::TaskStatePayload,
     interop::{DenormalizedJob, JobResult},
-    tables::{Flow, Graph, Project, Task, User},
+    tables::{Flow, FlowAction, Graph, OutboxEvent, Project, Task, TaskComment, User},
 };
 
 pub fn prism_url(host: &str, port: Option<u16>) -> String {
@@ -39,110 +46,142 @@ pub fn prism_url(host: &str, port: Option<u16>) -> String {
 
 // Users
 pub const USER_CREATED_BEAM: &str = "urn:subseq.io:oidc:user:created";
-const USER_UPDATED_BEAM: &str = "urn:subseq.io:oidc:user:updated";
+pub const USER_UPDATED_BEAM: &str = "urn:subseq.io:oidc:user:updated";
 
 // Builds from Sage
 const JOB_CREATED_BEAM: &str = "urn:subseq.io:builds:job:created";
 const JOB_PROGRESS_BEAM: &str = "urn:subseq.io:builds:job:progress";
 const JOB_REQUEST_BEAM: &str = "urn:subseq.io:builds:job:request";
 // Tasks from Sage
-const TASK_RUN_BEAM: &str = "urn:subseq.io:tasks:task:run";
+pub const TASK_RUN_BEAM: &str = "urn:subseq.io:tasks:task:run";
 const TASK_RESULT_BEAM: &str = "urn:subseq.io:tasks:task:result";
+// Job outcomes
+pub const JOB_FINISHED_BEAM: &str = "urn:subseq.io:tasks:job:finished";
+pub const HELP_REQUESTED_BEAM: &str = "urn:subseq.io:tasks:job:help:requested";
 
 // Task Broadcast
-const TASK_CREATED_BEAM: &str = "urn:subseq.io:tasks:task:created";
-const TASK_UPDATED_BEAM: &str = "urn:subseq.io:tasks:task:updated";
-const TASK_ASSIGNEE_BEAM: &str = "urn:subseq.io:tasks:task:assignee:changed";
-const TASK_STATE_BEAM: &str = "urn:subseq.io:tasks:task:state:changed";
+pub const TASK_CREATED_BEAM: &str = "urn:subseq.io:tasks:task:created";
+pub const TASK_UPDATED_BEAM: &str = "urn:subseq.io:tasks:task:updated";
+pub const TASK_ASSIGNEE_BEAM: &str = "urn:subseq.io:tasks:task:assignee:changed";
+pub const TASK_STATE_BEAM: &str = "urn:subseq.io:tasks:task:state:changed";
+pub const TASK_COMMENT_BEAM: &str = "urn:subseq.io:tasks:task:comment:created";
 
 // Projects
 pub const PROJECT_CREATED_BEAM: &str = "urn:subseq.io:projects:project:created";
-const PROJECT_UPDATED_BEAM: &str = "urn:subseq.io:projects:project:updated";
+pub const PROJECT_UPDATED_BEAM: &str = "urn:subseq.io:projects:project:updated";
 
 // Flows
-const FLOW_CREATED_BEAM: &str = "urn:subseq.io:tasks:workflow:created";
-const FLOW_UPDATED_BEAM: &str = "urn:subseq.io:tasks:workflow:updated";
+pub const FLOW_CREATED_BEAM: &str = "urn:subseq.io:tasks:workflow:created";
+pub const FLOW_UPDATED_BEAM: &str = "urn:subseq.io:tasks:workflow:updated";
 
 const DEFAULT_PING_RATE: Duration = Duration::from_secs(50);
-
-async fn setup_user_beams(client: &mut AsyncClient) {
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+const OUTBOX_BATCH_SIZE: i64 = 256;
+const OUTBOX_POLL_RATE: Duration = Duration::from_secs(5);
+const OUTBOX_RETENTION_DAYS: i64 = 7;
+/// Times Prism may refuse to add an event's beam before the event is marked failed
+const OUTBOX_MAX_ATTEMPTS: i32 = 5;
+
+/// The Prism connection was lost or refused
+#[derive(Debug)]
+struct Disconnected;
+
+async fn setup_user_beams(client: &mut AsyncClient) -> Result<(), Disconnected> {
     client
         .add_beam(USER_CREATED_BEAM)
         .await
-        .expect("Failed setting up client");
+        .map_err(|_| Disconnected)?;
     client
         .add_beam(USER_UPDATED_BEAM)
         .await
-        .expect("Failed setting up client");
+        .map_err(|_| Disconnected)?;
 
     client
         .subscribe(USER_CREATED_BEAM, None)
         .await
-        .expect("Failed subscribing");
+        .map_err(|_| Disconnected)?;
     client
         .subscribe(USER_UPDATED_BEAM, None)
         .await
-        .expect("Failed subscribing");
+        .map_err(|_| Disconnected)?;
+    Ok(())
 }
 
-async fn setup_job_beams(client: &mut AsyncClient) {
+async fn setup_job_beams(client: &mut AsyncClient) -> Result<(), Disconnected> {
+    client
+        .add_beam(JOB_FINISHED_BEAM)
+        .await
+        .map_err(|_| Disconnected)?;
+    client
+        .add_beam(HELP_REQUESTED_BEAM)
+        .await
+        .map_err(|_| Disconnected)?;
     client
         .subscribe(TASK_RESULT_BEAM, None)
         .await
-        .expect("Failed subscribing");
+        .map_err(|_| Disconnected)?;
     client
         .subscribe(JOB_CREATED_BEAM, None)
         .await
-        .expect("Failed subscribing");
+        .map_err(|_| Disconnected)?;
     client
         .subscribe(JOB_REQUEST_BEAM, None)
         .await
-        .expect("Failed subscribing");
+        .map_err(|_| Disconnected)?;
     client
         .subscribe(JOB_PROGRESS_BEAM, None)
         .await
-        .expect("Failed subscribing");
+        .map_err(|_| Disconnected)?;
+    Ok(())
 }
 
-async fn setup_task_beams(client: &mut AsyncClient) {
+async fn setup_task_beams(client: &mut AsyncClient) -> Result<(), Disconnected> {
     client
         .add_beam(TASK_CREATED_BEAM)
         .await
-        .expect("Failed setting up client");
+        .map_err(|_| Disconnected)?;
     client
         .add_beam(TASK_UPDATED_BEAM)
         .await
-        .expect("Failed setting up client");
+        .map_err(|_| Disconnected)?;
     client
         .add_beam(TASK_ASSIGNEE_BEAM)
         .await
-        .expect("Failed setting up client");
+        .map_err(|_| Disconnected)?;
     client
         .add_beam(TASK_STATE_BEAM)
         .await
-        .expect("Failed setting up client");
+        .map_err(|_| Disconnected)?;
+    client
+        .add_beam(TASK_COMMENT_BEAM)
+        .await
+        .map_err(|_| Disconnected)?;
+    Ok(())
 }
 
-async fn setup_project_beams(client: &mut AsyncClient) {
+async fn setup_project_beams(client: &mut AsyncClient) -> Result<(), Disconnected> {
     client
         .add_beam(PROJECT_CREATED_BEAM)
         .await
-        .expect("Failed setting up client");
+        .map_err(|_| Disconnected)?;
     client
         .add_beam(PROJECT_UPDATED_BEAM)
         .await
-        .expect("Failed setting up client");
+        .map_err(|_| Disconnected)?;
+    Ok(())
 }
 
-async fn setup_flow_beams(client: &mut AsyncClient) {
+async fn setup_flow_beams(client: &mut AsyncClient) -> Result<(), Disconnected> {
     client
         .add_beam(FLOW_CREATED_BEAM)
         .await
-        .expect("Failed setting up client");
+        .map_err(|_| Disconnected)?;
     client
         .add_beam(FLOW_UPDATED_BEAM)
         .await
-        .expect("Failed setting up client");
+        .map_err(|_| Disconnected)?;
+    Ok(())
 }
 
 fn gen_rand() -> String {
@@ -193,6 +232,42 @@ pub fn create_users_from_events(
     });
 }
 
+/// A profile update received from the OIDC service
+#[derive(Clone)]
+pub struct UserUpdated(pub UserUpdate);
+
+impl From<UserUpdate> for UserUpdated {
+    fn from(update: UserUpdate) -> Self {
+        Self(update)
+    }
+}
+
+pub fn update_users_from_events(
+    mut user_updated_rx: broadcast::Receiver<UserUpdated>,
+    db_pool: Arc<DbPool>,
+) {
+    spawn(async move {
+        while let Ok(updated_user) = user_updated_rx.recv().await {
+            let mut conn = match db_pool.get() {
+                Ok(conn) => conn,
+                Err(_) => return,
+            };
+            let update = updated_user.0;
+            // Our own updates are already applied, and applying them again could undo a newer one
+            if update.is_echo() {
+                continue;
+            }
+            if User::get(&mut conn, update.user_id).is_none() {
+                tracing::warn!("Update for unknown user {}", update.user_id);
+                continue;
+            }
+            if let Err(err) = apply_user_update(&mut conn, &update) {
+                tracing::warn!("Failed to update user {}: {:?}", update.user_id, err);
+            }
+        }
+    });
+}
+
 #[derive(Serialize)]
 struct PromptTxWrapper {
     prompt_tx: PromptTx,
@@ -202,7 +277,9 @@ struct PromptTxWrapper {
 struct WaveletHandler {
     job_result_tx: mpsc::Sender<JobResult>,
     job_created_tx: mpsc::Sender<DenormalizedJob>,
+    job_progress_tx: mpsc::Sender<JobProgress>,
     user_created_tx: broadcast::Sender<UserCreated>,
+    user_updated_tx: broadcast::Sender<UserUpdated>,
     job_requests: JobRequestCollection,
     prompt_requests: PromptResponseCollection,
     prompt_beam: String,
@@ -246,14 +323,54 @@ macro_rules! process_photons_spawn {
     };
 }
 
-macro_rules! emit_photon {
-    ($client:expr, $msg:expr, $beam:expr) => {
-        if let Ok(msg) = $msg {
-            tracing::info!("Emit {}", $beam);
-            let vec = serde_json::to_vec(&msg).unwrap();
-            if $client.emit($beam, vec).await.is_err() {
-                break;
+/// Wakes everything reading the outbox: the Prism emitter and each configured sink
+#[derive(Clone, Default)]
+struct OutboxWakers(Vec<Arc<Notify>>);
+
+impl OutboxWakers {
+    fn add(&mut self) -> Arc<Notify> {
+        let notify = Arc::new(Notify::new());
+        self.0.push(notify.clone());
+        notify
+    }
+
+    fn wake(&self) {
+        for notify in &self.0 {
+            notify.notify_one();
+        }
+    }
+}
+
+/// Writes an event to the outbox and wakes its readers
+fn store_event<T: Serialize>(db_pool: &DbPool, wakers: &OutboxWakers, beam: &str, msg: &T) {
+    let payload = match serde_json::to_value(msg) {
+        Ok(payload) => payload,
+        Err(err) => {
+            tracing::error!("Could not serialize event for {}: {:?}", beam, err);
+            return;
+        }
+    };
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => {
+            tracing::error!("Database unavailable, dropped event for {}", beam);
+            return;
+        }
+    };
+    match OutboxEvent::push(&mut conn, beam, payload) {
+        Ok(_) => wakers.wake(),
+        Err(err) => tracing::error!("Failed to store event for {}: {:?}", beam, err),
+    }
+}
+
+macro_rules! outbox_photon {
+    ($db_pool:expr, $wakers:expr, $msg:expr, $beam:expr) => {
+        match $msg {
+            Ok(msg) => store_event(&$db_pool, &$wakers, $beam, &msg),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                tracing::warn!("Lost {} events for {} before they reached the outbox", skipped, $beam);
             }
+            Err(broadcast::error::RecvError::Closed) => {}
         }
     };
 }
@@ -303,9 +420,15 @@ impl Future for WaveletHandler {
             JOB_CREATED_BEAM => {
                 process_photons_spawn!(JOB_CREATED_BEAM, photons, DenormalizedJob, this.job_created_tx);
             }
+            JOB_PROGRESS_BEAM => {
+                process_photons_spawn!(JOB_PROGRESS_BEAM, photons, JobProgress, this.job_progress_tx);
+            }
             USER_CREATED_BEAM => {
                 process_photons!(USER_CREATED_BEAM, photons, User, this.user_created_tx);
             }
+            USER_UPDATED_BEAM => {
+                process_photons!(USER_UPDATED_BEAM, photons, UserUpdate, this.user_updated_tx);
+            }
             b => {
                 if b == this.voice_beam {
                     process_dynamic_photons!(b,
@@ -330,17 +453,196 @@ impl Future for WaveletHandler {
 }
 
 
-pub fn emit_events(addr: &str, router: &mut Router, db_pool: Arc<DbPool>) {
-    // Tables
+/// Beams this service emits on without adding them first
+pub fn is_static_beam(beam: &str) -> bool {
+    matches!(
+        beam,
+        USER_CREATED_BEAM
+            | USER_UPDATED_BEAM
+            | TASK_RUN_BEAM
+            | JOB_FINISHED_BEAM
+            | HELP_REQUESTED_BEAM
+            | TASK_CREATED_BEAM
+            | TASK_UPDATED_BEAM
+            | TASK_ASSIGNEE_BEAM
+            | TASK_STATE_BEAM
+            | TASK_COMMENT_BEAM
+            | PROJECT_CREATED_BEAM
+            | PROJECT_UPDATED_BEAM
+            | FLOW_CREATED_BEAM
+            | FLOW_UPDATED_BEAM
+    )
+}
+
+/// Prism as an event sink. Beams named by flow actions are added the first time they are used
+/// on a connection.
+struct PrismSink<'c> {
+    client: &'c mut AsyncClient,
+    added_beams: &'c mut HashSet<String>,
+}
+
+impl PrismSink<'_> {
+    async fn ensure_beam(&mut self, beam: &str) -> Result<(), SinkError> {
+        if is_static_beam(beam) || self.added_beams.contains(beam) {
+            return Ok(());
+        }
+        self.client
+            .add_beam(beam)
+            .await
+            .map_err(|_| SinkError(format!("Could not add {}", beam)))?;
+        self.added_beams.insert(beam.to_string());
+        Ok(())
+    }
+}
+
+impl EventSink for PrismSink<'_> {
+    fn emit<'a>(&'a mut self, beam: &'a str, photon: &'a serde_json::Value) -> SinkFuture<'a> {
+        Box::pin(async move {
+            self.ensure_beam(beam).await?;
+            tracing::info!("Emit {}", beam);
+            let vec = serde_json::to_vec(photon).unwrap();
+            self.client
+                .emit(beam, vec)
+                .await
+                .map_err(|_| SinkError(format!("Could not emit {}", beam)))
+        })
+    }
+}
+
+/// Sets an event aside which Prism will never take, so it stops blocking the outbox
+fn dead_letter(conn: &mut PgConnection, event: &mut OutboxEvent) {
+    tracing::error!(
+        "Event {} on {} can not be emitted, marking it failed",
+        event.seq,
+        event.beam
+    );
+    if let Err(err) = event.mark_failed(conn) {
+        tracing::error!("Failed to mark event {} failed: {:?}", event.seq, err);
+    }
+}
+
+/// Emits every pending outbox event to Prism in order, stopping at the first one Prism refuses
+/// so it is retried after reconnecting. Events on invalid beams, or on beams Prism refused to
+/// add too many times, are marked failed instead.
+async fn drain_outbox(sink: &mut PrismSink<'_>, db_pool: &DbPool) -> Result<(), Disconnected> {
+    let mut conn = match db_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => {
+            tracing::warn!("Database unavailable, outbox not drained");
+            return Ok(());
+        }
+    };
+    loop {
+        let pending = match OutboxEvent::pending(&mut conn, OUTBOX_BATCH_SIZE) {
+            Ok(pending) => pending,
+            Err(err) => {
+                tracing::error!("Failed to read outbox: {:?}", err);
+                return Ok(());
+            }
+        };
+        if pending.is_empty() {
+            return Ok(());
+        }
+        for mut event in pending {
+            if !is_static_beam(&event.beam) && FlowAction::validate_beam(&event.beam).is_err() {
+                dead_letter(&mut conn, &mut event);
+                continue;
+            }
+            if let Err(err) = sink.ensure_beam(&event.beam).await {
+                tracing::warn!("{}", err.0);
+                match event.record_attempt(&mut conn) {
+                    Ok(attempts) if attempts >= OUTBOX_MAX_ATTEMPTS => {
+                        dead_letter(&mut conn, &mut event);
+                        continue;
+                    }
+                    _ => return Err(Disconnected),
+                }
+            }
+            if let Err(err) = sink.emit(&event.beam, &event.photon()).await {
+                tracing::warn!("{}", err.0);
+                return Err(Disconnected);
+            }
+            if let Err(err) = event.mark_emitted(&mut conn) {
+                tracing::error!("Failed to mark event {} emitted: {:?}", event.seq, err);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Wakes the outbox readers on each message, until every sender is gone. A lagged receiver
+/// still wakes them since the events it missed are already in the outbox.
+fn wake_on<T: Clone + Send + 'static>(mut rx: broadcast::Receiver<T>, wakers: OutboxWakers) {
+    spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => wakers.wake(),
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Stores created users in the outbox as they are broadcast, since they are made outside this
+/// service's handlers. Every other event is written to the outbox in the transaction making
+/// the change, and its broadcast only wakes the emitter.
+fn collect_outbox_events(router: &mut Router, db_pool: Arc<DbPool>, wakers: OutboxWakers) {
+    let user_update_rx: broadcast::Receiver<UserUpdate> = router.subscribe();
+    let task_rx: broadcast::Receiver<Task> = router.subscribe();
+    let task_update_rx: broadcast::Receiver<TaskStatePayload> = router.subscribe();
+    let task_state_rx: broadcast::Receiver<TaskStateChanged> = router.subscribe();
+    let task_assignee_rx: broadcast::Receiver<TaskAssigneeChanged> = router.subscribe();
+    let task_comment_rx: broadcast::Receiver<TaskComment> = router.subscribe();
+    let task_run_rx: broadcast::Receiver<TaskRun> = router.subscribe();
+    let project_rx: broadcast::Receiver<Project> = router.subscribe();
+    let project_update_rx: broadcast::Receiver<ProjectUpdated> = router.subscribe();
+    let flow_rx: broadcast::Receiver<Flow> = router.subscribe();
+    let graph_rx: broadcast::Receiver<Graph> = router.subscribe();
+    let job_finished_rx: broadcast::Receiver<JobFinished> = router.subscribe();
+    let help_requested_rx: broadcast::Receiver<HelpRequested> = router.subscribe();
+    wake_on(user_update_rx, wakers.clone());
+    wake_on(task_rx, wakers.clone());
+    wake_on(task_update_rx, wakers.clone());
+    wake_on(task_state_rx, wakers.clone());
+    wake_on(task_assignee_rx, wakers.clone());
+    wake_on(task_comment_rx, wakers.clone());
+    wake_on(task_run_rx, wakers.clone());
+    wake_on(project_rx, wakers.clone());
+    wake_on(project_update_rx, wakers.clone());
+    wake_on(flow_rx, wakers.clone());
+    wake_on(graph_rx, wakers.clone());
+    wake_on(job_finished_rx, wakers.clone());
+    wake_on(help_requested_rx, wakers.clone());
+
     let mut user_rx: broadcast::Receiver<User> = router.subscribe();
+    spawn(async move {
+        loop {
+            let msg = user_rx.recv().await;
+            if let Err(broadcast::error::RecvError::Closed) = msg {
+                tracing::warn!("User broadcasts closed, no longer storing created users");
+                break;
+            }
+            outbox_photon!(db_pool, wakers, msg, USER_CREATED_BEAM);
+        }
+    });
+}
+
+pub fn emit_events(addr: &str, config: &EventsConfig, router: &mut Router, db_pool: Arc<DbPool>) {
+    // Tables
     let user_created_rx: broadcast::Receiver<UserCreated> = router.subscribe();
-    create_users_from_events(user_created_rx, db_pool);
-    let mut task_rx: broadcast::Receiver<Task> = router.subscribe();
-    let mut task_update_rx: broadcast::Receiver<TaskStatePayload> = router.subscribe();
-    let mut task_run_rx: broadcast::Receiver<TaskRun> = router.subscribe();
-    let mut project_rx: broadcast::Receiver<Project> = router.subscribe();
-    let mut flow_rx: broadcast::Receiver<Flow> = router.subscribe();
-    let mut graph_rx: broadcast::Receiver<Graph> = router.subscribe();
+    create_users_from_events(user_created_rx, db_pool.clone());
+    let user_updated_rx: broadcast::Receiver<UserUpdated> = router.subscribe();
+    update_users_from_events(user_updated_rx, db_pool.clone());
+    let mut wakers = OutboxWakers::default();
+    let outbox_notify = wakers.add();
+    for sink_config in &config.sinks {
+        tracing::info!("Emitting events to sink {}", sink_config.name());
+        let notify = wakers.add();
+        run_sink(sink_config.name().to_string(), sink_config.build(), db_pool.clone(), notify);
+    }
+    let webhook_sink = Box::new(WebhookSink::new(db_pool.clone()));
+    run_sink(WEBHOOK_SINK_NAME.to_string(), webhook_sink, db_pool.clone(), wakers.add());
+    collect_outbox_events(router, db_pool.clone(), wakers);
 
     // Voice
     let mut voice_rx: mpsc::Receiver<(SpeechToText, oneshot::Sender<SpeechToTextResponse>)> =
@@ -352,10 +654,12 @@ pub fn emit_events(addr: &str, router: &mut Router, db_pool: Arc<DbPool>) {
     let job_tx: mpsc::Sender<DenormalizedJob> = router.get_address().cloned().expect("job_tx");
     let job_result_tx: mpsc::Sender<JobResult> = router.get_address().cloned().expect("job_result_tx");
     let job_request_tx: mpsc::Sender<JobRequest> = router.get_address().cloned().expect("job_request_tx");
+    let job_progress_tx: mpsc::Sender<JobProgress> = router.get_address().cloned().expect("job_progress_tx");
     let job_requests = JobRequestCollection::new(job_request_tx);
 
     let mut job_response_rx: broadcast::Receiver<JobResponse> = router.subscribe();
     let user_created_tx: broadcast::Sender<UserCreated> = router.announce();
+    let user_updated_tx: broadcast::Sender<UserUpdated> = router.announce();
 
     // Prompts
     let (prompt_request_tx, mut prompt_request_rx) = mpsc::channel::<PromptTxWrapper>(1024);
@@ -378,7 +682,9 @@ pub fn emit_events(addr: &str, router: &mut Router, db_pool: Arc<DbPool>) {
             job_result_tx: job_result_tx.clone(),
             job_requests: job_requests_wave.clone(),
             job_created_tx: job_tx.clone(),
+            job_progress_tx: job_progress_tx.clone(),
             user_created_tx: user_created_tx.clone(),
+            user_updated_tx: user_updated_tx.clone(),
             prompt_requests: handler_requests.clone(),
             prompt_beam: prompt_beam.clone(),
             voice_requests: handler_voice_requests.clone(),
@@ -386,124 +692,126 @@ pub fn emit_events(addr: &str, router: &mut Router, db_pool: Arc<DbPool>) {
             wavelet,
         };
 
-        let mut client = match AsyncClient::connect(uri, handle_tasks).await {
-            Ok(client) => client,
-            Err(_err) => {
-                tracing::warn!("Zini is running in standalone mode. No connection to prism.");
-                return;
+        let mut reconnect_delay = MIN_RECONNECT_DELAY;
+        loop {
+            let mut client = match AsyncClient::connect(uri.clone(), handle_tasks.clone()).await {
+                Ok(client) => client,
+                Err(_err) => {
+                    tracing::warn!(
+                        "No connection to prism, retrying in {}s",
+                        reconnect_delay.as_secs()
+                    );
+                    sleep(reconnect_delay).await;
+                    reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                    continue;
+                }
+            };
+            tracing::info!("Zini connected to prism!");
+
+            // Beams named by flow actions are only added the first time they are used
+            let mut added_beams: HashSet<String> = HashSet::new();
+            let setup = async {
+                client.add_beam(&voice_request_beam).await.map_err(|_| Disconnected)?;
+                client.subscribe(&voice_response_beam, None).await.map_err(|_| Disconnected)?;
+                client.add_beam(&prompt_request_beam).await.map_err(|_| Disconnected)?;
+                client.subscribe(&prompt_response_beam, None).await.map_err(|_| Disconnected)?;
+
+                setup_user_beams(&mut client).await?;
+                setup_job_beams(&mut client).await?;
+                setup_task_beams(&mut client).await?;
+                setup_project_beams(&mut client).await?;
+                setup_flow_beams(&mut client).await?;
+
+                // Replay everything stored while disconnected
+                let mut sink = PrismSink { client: &mut client, added_beams: &mut added_beams };
+                drain_outbox(&mut sink, &db_pool).await
+            };
+            if setup.await.is_err() {
+                tracing::warn!("Prism setup failed, reconnecting");
+                sleep(reconnect_delay).await;
+                reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                continue;
+            }
+            reconnect_delay = MIN_RECONNECT_DELAY;
+            if let Ok(mut conn) = db_pool.get() {
+                let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::days(OUTBOX_RETENTION_DAYS);
+                OutboxEvent::prune(&mut conn, cutoff).ok();
             }
-        };
-        tracing::info!("Zini connected to prism!");
-
-        client
-            .add_beam(&voice_request_beam)
-            .await
-            .expect("Failed setting up client");
-        client
-            .subscribe(&voice_response_beam, None)
-            .await
-            .expect("Failed subscribing");
-        client
-            .add_beam(&prompt_request_beam)
-            .await
-            .expect("Failed setting up client");
-        client
-            .subscribe(&prompt_response_beam, None)
-            .await
-            .expect("Failed subscribing");
-
-        setup_user_beams(&mut client).await;
-        setup_job_beams(&mut client).await;
-        setup_task_beams(&mut client).await;
-        setup_project_beams(&mut client).await;
-        setup_flow_beams(&mut client).await;
 
-        let mut remaining_time = DEFAULT_PING_RATE;
-        let mut instant = Instant::now();
-        loop {
-            remaining_time = remaining_time.checked_sub(instant.elapsed()).unwrap_or(Duration::ZERO);
-            let ping_timer = sleep(remaining_time);
-            tokio::select!(
-                _ = ping_timer => {
-                    if client.ping().await.is_err() {
-                        break;
+            let mut remaining_time = DEFAULT_PING_RATE;
+            let mut instant = Instant::now();
+            // Catches events committed by writers which did not broadcast afterwards
+            let mut outbox_poll = interval(OUTBOX_POLL_RATE);
+            loop {
+                remaining_time = remaining_time.checked_sub(instant.elapsed()).unwrap_or(Duration::ZERO);
+                let ping_timer = sleep(remaining_time);
+                tokio::select!(
+                    _ = ping_timer => {
+                        if client.ping().await.is_err() {
+                            break;
+                        }
+                        remaining_time = DEFAULT_PING_RATE;
+                        instant = Instant::now();
                     }
-                    remaining_time = DEFAULT_PING_RATE;
-                    instant = Instant::now();
-                }
-                msg = job_response_rx.recv() => {
-                    if let Ok(msg) = msg {
-                        let JobResponse{job_id, response} = msg;
-                        let beam = job_requests.remove(job_id);
-                        if let Some(beam) = beam {
-                            tracing::info!("Emit {}", beam);
-                            let vec = serde_json::to_vec(&response).unwrap();
-                            if client.emit(beam, vec).await.is_err() {
-                                break;
-                            }
+                    _ = outbox_poll.tick() => {
+                        let mut sink = PrismSink { client: &mut client, added_beams: &mut added_beams };
+                        if drain_outbox(&mut sink, &db_pool).await.is_err() {
+                            break;
                         }
                     }
-                }
-                msg = voice_rx.recv() => {
-                    if let Some((msg, tx)) = msg {
-                        voice_requests.insert(msg.conversation_id, msg.count, tx);
-                        let request = SpeechToTextRequest::extend_from(msg, voice_response_beam.clone());
-                        let vec = serde_cbor::to_vec(&request).unwrap();
-                        if client.emit(&voice_request_beam, vec).await.is_err() {
+                    _ = outbox_notify.notified() => {
+                        let mut sink = PrismSink { client: &mut client, added_beams: &mut added_beams };
+                        if drain_outbox(&mut sink, &db_pool).await.is_err() {
                             break;
                         }
                     }
-                }
-                msg = new_prompt_rx.recv() => {
-                    if let Some(InitializePromptChannel(stream_id, mut rx, tx)) = msg {
-                        prompt_requests.insert(stream_id, tx);
-                        let msg_tx = prompt_request_tx.clone();
-                        let prompt_response_beam = prompt_response_beam.clone();
-                        spawn(async move {
-                            while let Some(msg) = rx.recv().await {
-                                let wrapper = PromptTxWrapper{prompt_tx: msg, beam: prompt_response_beam.clone()};
-                                msg_tx.send(wrapper).await.ok();
+                    msg = job_response_rx.recv() => {
+                        if let Ok(msg) = msg {
+                            let JobResponse{job_id, response} = msg;
+                            let beam = job_requests.remove(job_id);
+                            if let Some(beam) = beam {
+                                tracing::info!("Emit {}", beam);
+                                let vec = serde_json::to_vec(&response).unwrap();
+                                if client.emit(beam, vec).await.is_err() {
+                                    break;
+                                }
                             }
-                        });
+                        }
                     }
-                }
-                msg = prompt_request_rx.recv() => {
-                    if let Some(msg) = msg {
-                        let vec = serde_json::to_vec(&msg).unwrap();
-                        if client.emit(prompt_request_beam.clone(), vec).await.is_err() {
-                            break;
+                    msg = voice_rx.recv() => {
+                        if let Some((msg, tx)) = msg {
+                            voice_requests.insert(msg.conversation_id, msg.count, tx);
+                            let request = SpeechToTextRequest::extend_from(msg, voice_response_beam.clone());
+                            let vec = serde_cbor::to_vec(&request).unwrap();
+                            if client.emit(&voice_request_beam, vec).await.is_err() {
+                                break;
+                            }
                         }
                     }
-                }
-                msg = user_rx.recv() => {
-                    emit_photon!(client, msg, USER_CREATED_BEAM);
-                }
-                msg = task_rx.recv() => {
-                    emit_photon!(client, msg, TASK_CREATED_BEAM);
-                }
-                msg = task_update_rx.recv() => {
-                    emit_photon!(client, msg, TASK_UPDATED_BEAM);
-                }
-                msg = task_run_rx.recv() => {
-                    if let Ok(msg) = msg {
-                        let msg = msg.state;
-                        let vec = serde_json::to_vec(&msg).unwrap();
-                        if client.emit(TASK_RUN_BEAM, vec).await.is_err() {
-                            break;
+                    msg = new_prompt_rx.recv() => {
+                        if let Some(InitializePromptChannel(stream_id, mut rx, tx)) = msg {
+                            prompt_requests.insert(stream_id, tx);
+                            let msg_tx = prompt_request_tx.clone();
+                            let prompt_response_beam = prompt_response_beam.clone();
+                            spawn(async move {
+                                while let Some(msg) = rx.recv().await {
+                                    let wrapper = PromptTxWrapper{prompt_tx: msg, beam: prompt_response_beam.clone()};
+                                    msg_tx.send(wrapper).await.ok();
+                                }
+                            });
                         }
                     }
-                }
-                msg = project_rx.recv() => {
-                    emit_photon!(client, msg, PROJECT_CREATED_BEAM);
-                }
-                msg = flow_rx.recv() => {
-                    emit_photon!(client, msg, FLOW_CREATED_BEAM);
-                }
-                msg = graph_rx.recv() => {
-                    emit_photon!(client, msg, FLOW_UPDATED_BEAM);
-                }
-            );
+                    msg = prompt_request_rx.recv() => {
+                        if let Some(msg) = msg {
+                            let vec = serde_json::to_vec(&msg).unwrap();
+                            if client.emit(prompt_request_beam.clone(), vec).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                );
+            }
+            tracing::warn!("Prism client closed, reconnecting");
         }
-        tracing::warn!("Prism client closed");
     });
 }
@@ -10,6 +10,16 @@ pub struct JobResult {
     pub job_log: String,
 }
 
+/// A step reported by a running job, before its JobResult arrives
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct JobProgress {
+    pub job_id: Uuid,
+    pub percent: f32,
+    pub stage: String,
+    #[serde(default)]
+    pub message: String,
+}
+
 #[derive(PartialEq, Clone, Debug, Deserialize, Serialize)]
 pub enum JobRequestType {
     Help(String)
@@ -59,4 +69,32 @@ pub struct DenormalizedJob {
     pub name: String,
     pub job_owners: JobOwners,
     pub task_id: Option<Uuid>,
+    /// The task run this job was created for, when Sage was asked for one
+    #[serde(default)]
+    pub run_id: Option<Uuid>,
+}
+
+/// Marks the user updates Zini sends, so they are not applied again when Prism echoes them back
+pub const USER_UPDATE_ORIGIN: &str = "zini";
+
+/// A change to a user's profile, shared with the OIDC service in both directions
+#[derive(PartialEq, Clone, Debug, Deserialize, Serialize)]
+pub struct UserUpdate {
+    pub user_id: Uuid,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub job_title: Option<String>,
+    /// The service which made the change. The OIDC service leaves it out.
+    #[serde(default)]
+    pub origin: Option<String>,
+}
+
+impl UserUpdate {
+    /// Whether the update was made here and came back from Prism
+    pub fn is_echo(&self) -> bool {
+        self.origin.as_deref() == Some(USER_UPDATE_ORIGIN)
+    }
 }
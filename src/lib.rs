@@ -1,5 +1,7 @@
 pub mod api;
+pub mod embeddings;
 pub mod events;
 pub mod interop;
 pub mod schema;
+pub mod sinks;
 pub mod tables;
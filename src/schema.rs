@@ -5,6 +5,26 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    event_outbox (seq) {
+        seq -> Int8,
+        beam -> Varchar,
+        payload -> Jsonb,
+        created -> Timestamp,
+        emitted -> Nullable<Timestamp>,
+        attempts -> Int4,
+        failed -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    event_sink_cursors (sink_name) {
+        sink_name -> Varchar,
+        last_seq -> Int8,
+        updated -> Timestamp,
+    }
+}
+
 diesel::table! {
     flow_assignments (flow_id, node_id) {
         flow_id -> Uuid,
@@ -20,9 +40,22 @@ diesel::table! {
 }
 
 diesel::table! {
-    flow_node_connections (from_node_id, to_node_id) {
+    flow_node_actions (id) {
+        id -> Uuid,
+        flow_id -> Uuid,
+        node_id -> Uuid,
+        created -> Timestamp,
+        on_enter -> Bool,
+        action -> Jsonb,
+    }
+}
+
+diesel::table! {
+    flow_node_connections (flow_id, from_node_id, to_node_id) {
         from_node_id -> Uuid,
         to_node_id -> Uuid,
+        guards -> Jsonb,
+        flow_id -> Uuid,
     }
 }
 
@@ -41,6 +74,33 @@ diesel::table! {
         flow_name -> Varchar,
         description -> Text,
         entry_node_id -> Uuid,
+        version -> Int4,
+        lineage_id -> Uuid,
+        superseded_by -> Nullable<Uuid>,
+    }
+}
+
+diesel::table! {
+    instruct_messages (session_id, seq) {
+        session_id -> Uuid,
+        seq -> Int4,
+        kind -> Varchar,
+        payload -> Jsonb,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    instruct_sessions (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        project_id -> Uuid,
+        stream_id -> Nullable<Uuid>,
+        title -> Varchar,
+        awaiting_reply -> Bool,
+        created -> Timestamp,
+        updated -> Timestamp,
+        closed -> Nullable<Timestamp>,
     }
 }
 
@@ -51,6 +111,17 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    project_webhooks (id) {
+        id -> Uuid,
+        project_id -> Uuid,
+        url -> Varchar,
+        secret -> Varchar,
+        events -> Jsonb,
+        created -> Timestamp,
+    }
+}
+
 diesel::table! {
     projects (id) {
         id -> Uuid,
@@ -70,12 +141,44 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    routing_rules (id) {
+        id -> Uuid,
+        project_id -> Uuid,
+        priority -> Int4,
+        pattern -> Nullable<Varchar>,
+        tag -> Nullable<Varchar>,
+        assignee_id -> Uuid,
+        created -> Timestamp,
+    }
+}
+
 diesel::table! {
     tags (name) {
         name -> Varchar,
     }
 }
 
+diesel::table! {
+    task_comments (id) {
+        id -> Uuid,
+        task_id -> Uuid,
+        author_id -> Uuid,
+        created -> Timestamp,
+        updated -> Nullable<Timestamp>,
+        body -> Text,
+    }
+}
+
+diesel::table! {
+    task_embeddings (task_id) {
+        task_id -> Uuid,
+        model -> Varchar,
+        embedding -> Array<Float4>,
+        updated -> Timestamp,
+    }
+}
+
 diesel::table! {
     task_flows (task_id, flow_id) {
         task_id -> Uuid,
@@ -85,6 +188,18 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    task_history (id) {
+        id -> Uuid,
+        task_id -> Uuid,
+        actor_id -> Uuid,
+        created -> Timestamp,
+        task_update -> Jsonb,
+        previous -> Nullable<Jsonb>,
+        reverted -> Bool,
+    }
+}
+
 diesel::table! {
     task_links (task_from_id, task_to_id) {
         task_from_id -> Uuid,
@@ -123,6 +238,22 @@ diesel::table! {
         description -> Text,
         author_id -> Uuid,
         assignee_id -> Nullable<Uuid>,
+        updated -> Timestamp,
+    }
+}
+
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Uuid,
+        webhook_id -> Uuid,
+        event -> Varchar,
+        payload -> Jsonb,
+        created -> Timestamp,
+        attempts -> Int4,
+        next_attempt -> Nullable<Timestamp>,
+        delivered -> Nullable<Timestamp>,
+        status_code -> Nullable<Int4>,
+        error -> Nullable<Text>,
     }
 }
 
@@ -157,6 +288,17 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    job_progress (id) {
+        id -> Uuid,
+        job_id -> Uuid,
+        created -> Timestamp,
+        percent -> Float4,
+        stage -> Varchar,
+        message -> Text,
+    }
+}
+
 diesel::table! {
     job_results (job_id) {
         job_id -> Uuid,
@@ -174,6 +316,7 @@ diesel::table! {
         name -> Varchar,
         created_id -> Uuid,
         assignee_id -> Uuid,
+        created -> Timestamp,
     }
 }
 
@@ -222,6 +365,9 @@ diesel::joinable!(default_project_tags -> projects (project_id));
 diesel::joinable!(default_project_tags -> tags (tag_name));
 diesel::joinable!(flow_assignments -> flow_nodes (node_id));
 diesel::joinable!(flow_assignments -> flows (flow_id));
+diesel::joinable!(flow_node_actions -> flow_nodes (node_id));
+diesel::joinable!(flow_node_actions -> flows (flow_id));
+diesel::joinable!(flow_node_connections -> flows (flow_id));
 diesel::joinable!(flow_exits -> flow_nodes (node_id));
 diesel::joinable!(flow_exits -> flows (flow_id));
 diesel::joinable!(flows -> flow_nodes (entry_node_id));
@@ -229,20 +375,33 @@ diesel::joinable!(flows -> users (owner_id));
 diesel::joinable!(help_resolution -> awaiting_help (help_id));
 diesel::joinable!(help_resolution_actions -> awaiting_help (help_id));
 diesel::joinable!(help_resolution_files -> help_resolution_actions (action_id));
+diesel::joinable!(job_progress -> jobs (job_id));
 diesel::joinable!(job_results -> jobs (job_id));
 diesel::joinable!(jobs -> projects (project_id));
 diesel::joinable!(jobs -> tasks (task_id));
 diesel::joinable!(jobs -> users (assignee_id));
+diesel::joinable!(instruct_messages -> instruct_sessions (session_id));
+diesel::joinable!(instruct_sessions -> projects (project_id));
+diesel::joinable!(instruct_sessions -> users (user_id));
+diesel::joinable!(project_webhooks -> projects (project_id));
 diesel::joinable!(projects -> users (owner_id));
+diesel::joinable!(routing_rules -> projects (project_id));
+diesel::joinable!(routing_rules -> users (assignee_id));
 diesel::joinable!(tasks -> users (author_id));
+diesel::joinable!(task_comments -> tasks (task_id));
+diesel::joinable!(task_comments -> users (author_id));
+diesel::joinable!(task_embeddings -> tasks (task_id));
 diesel::joinable!(task_flows -> flow_nodes (current_node_id));
 diesel::joinable!(task_flows -> flows (flow_id));
 diesel::joinable!(task_flows -> tasks (task_id));
+diesel::joinable!(task_history -> tasks (task_id));
+diesel::joinable!(task_history -> users (actor_id));
 diesel::joinable!(task_links -> link_types (link_type));
 diesel::joinable!(task_projects -> projects (project_id));
 diesel::joinable!(task_projects -> tasks (task_id));
 diesel::joinable!(task_tags -> tags (tag_name));
 diesel::joinable!(task_tags -> tasks (task_id));
+diesel::joinable!(webhook_deliveries -> project_webhooks (webhook_id));
 diesel::joinable!(task_watchers -> tasks (task_id));
 diesel::joinable!(task_watchers -> users (watcher_id));
 diesel::joinable!(user_id_accounts -> users (user_id));
@@ -251,25 +410,37 @@ diesel::allow_tables_to_appear_in_same_query!(
     active_projects,
     awaiting_help,
     default_project_tags,
+    event_outbox,
+    event_sink_cursors,
     flow_assignments,
     flow_exits,
+    flow_node_actions,
     flow_node_connections,
     flow_nodes,
     flows,
     help_resolution,
     help_resolution_actions,
     help_resolution_files,
+    instruct_messages,
+    instruct_sessions,
+    job_progress,
     job_results,
     jobs,
     link_types,
+    project_webhooks,
     projects,
+    routing_rules,
     tags,
+    task_comments,
+    task_embeddings,
     task_flows,
+    task_history,
     task_links,
     task_projects,
     task_tags,
     task_watchers,
     tasks,
+    webhook_deliveries,
     metadata,
     portraits,
     user_id_accounts,
@@ -0,0 +1,320 @@
+use std::ops::DerefMut;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use diesel::PgConnection;
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use subseq_util::tables::DbPool;
+use tokio::io::AsyncWriteExt;
+use tokio::spawn;
+use tokio::sync::Notify;
+use tokio::time::{interval, sleep};
+
+use crate::tables::{OutboxEvent, SinkCursor};
+
+const SINK_BATCH_SIZE: i64 = 256;
+const SINK_POLL_RATE: Duration = Duration::from_secs(5);
+const SINK_RETRY_DELAY: Duration = Duration::from_secs(10);
+const HTTP_SINK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Sequence numbers are taken when an event is inserted but appear when it commits, so a gap
+/// is a transaction still in flight until it is this old. Older gaps were rolled back.
+const SEQ_GAP_GRACE: Duration = Duration::from_secs(30);
+
+/// The sink refused or could not take an event; it is offered again later
+#[derive(Debug)]
+pub struct SinkError(pub String);
+
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = Result<(), SinkError>> + Send + 'a>>;
+
+/// Somewhere outbox events are delivered. Events arrive in sequence order and a sink sees an
+/// event again if it fails, so it should dedupe on the `event_seq` of the payload.
+pub trait EventSink: Send {
+    fn emit<'a>(&'a mut self, beam: &'a str, photon: &'a serde_json::Value) -> SinkFuture<'a>;
+}
+
+/// The line written to files and the body posted to webhooks
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SinkRecord {
+    pub beam: String,
+    pub payload: serde_json::Value,
+}
+
+/// Posts each event as JSON to a URL
+pub struct HttpSink {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpSink {
+    pub fn new(url: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(HTTP_SINK_TIMEOUT)
+            .build()
+            .expect("Could not build HTTP client");
+        Self { url, client }
+    }
+}
+
+impl EventSink for HttpSink {
+    fn emit<'a>(&'a mut self, beam: &'a str, photon: &'a serde_json::Value) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let record = SinkRecord {
+                beam: beam.to_string(),
+                payload: photon.clone(),
+            };
+            let response = self
+                .client
+                .post(&self.url)
+                .json(&record)
+                .send()
+                .await
+                .map_err(|err| SinkError(err.to_string()))?;
+            if !response.status().is_success() {
+                return Err(SinkError(format!("{} returned {}", self.url, response.status())));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Appends each event to a file as a line of JSON
+pub struct FileSink {
+    path: PathBuf,
+}
+
+impl FileSink {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl EventSink for FileSink {
+    fn emit<'a>(&'a mut self, beam: &'a str, photon: &'a serde_json::Value) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let record = SinkRecord {
+                beam: beam.to_string(),
+                payload: photon.clone(),
+            };
+            let mut line = serde_json::to_vec(&record).map_err(|err| SinkError(err.to_string()))?;
+            line.push(b'\n');
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await
+                .map_err(|err| SinkError(err.to_string()))?;
+            file.write_all(&line)
+                .await
+                .map_err(|err| SinkError(err.to_string()))
+        })
+    }
+}
+
+/// Keeps events in memory, for tests and for inspecting event behavior without Prism
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    records: Arc<Mutex<Vec<SinkRecord>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn records(&self) -> Vec<SinkRecord> {
+        self.records.lock().expect("poisoned").clone()
+    }
+}
+
+impl EventSink for MemorySink {
+    fn emit<'a>(&'a mut self, beam: &'a str, photon: &'a serde_json::Value) -> SinkFuture<'a> {
+        self.records.lock().expect("poisoned").push(SinkRecord {
+            beam: beam.to_string(),
+            payload: photon.clone(),
+        });
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A sink as named in the `events` section of the config file
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkConfig {
+    Http { name: String, url: String },
+    File { name: String, path: PathBuf },
+}
+
+impl SinkConfig {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Http { name, .. } | Self::File { name, .. } => name,
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn EventSink> {
+        match self {
+            Self::Http { url, .. } => Box::new(HttpSink::new(url.clone())),
+            Self::File { path, .. } => Box::new(FileSink::new(path.clone())),
+        }
+    }
+}
+
+/// The `events` section of the config file. Prism always receives events; these sinks receive
+/// them as well, each reading the outbox on its own.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct EventsConfig {
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+}
+
+/// Whether the sink can move on to this event: it directly follows the cursor, or the events
+/// in between have been missing long enough that they will never commit
+fn gap_settled(cursor: &SinkCursor, event: &OutboxEvent) -> bool {
+    if event.seq == cursor.last_seq + 1 {
+        return true;
+    }
+    let age = chrono::Utc::now().naive_utc() - event.created;
+    age.to_std().map(|age| age >= SEQ_GAP_GRACE).unwrap_or(false)
+}
+
+/// Delivers every outbox event after the sink's cursor, advancing the cursor as each one is
+/// accepted. Delivery stops at a gap which may still be filled by a commit in flight. Returns
+/// how many were delivered.
+pub async fn drain_to_sink(
+    sink: &mut dyn EventSink,
+    cursor: &mut SinkCursor,
+    db_pool: &DbPool,
+) -> Result<usize, SinkError> {
+    drain_with(sink, cursor, || db_pool.get().map_err(|err| SinkError(err.to_string()))).await
+}
+
+async fn drain_with<F, C>(
+    sink: &mut dyn EventSink,
+    cursor: &mut SinkCursor,
+    mut connect: F,
+) -> Result<usize, SinkError>
+where
+    F: FnMut() -> Result<C, SinkError>,
+    C: DerefMut<Target = PgConnection>,
+{
+    let mut delivered = 0;
+    loop {
+        let events = {
+            let mut conn = connect()?;
+            OutboxEvent::after(&mut conn, cursor.last_seq, SINK_BATCH_SIZE)
+                .map_err(|err| SinkError(err.to_string()))?
+        };
+        if events.is_empty() {
+            return Ok(delivered);
+        }
+        for event in events {
+            if !gap_settled(cursor, &event) {
+                return Ok(delivered);
+            }
+            sink.emit(&event.beam, &event.photon()).await?;
+            let mut conn = connect()?;
+            cursor
+                .advance(&mut conn, event.seq)
+                .map_err(|err| SinkError(err.to_string()))?;
+            delivered += 1;
+        }
+    }
+}
+
+/// Feeds a sink from the outbox for as long as the service runs
+pub fn run_sink(
+    name: String,
+    mut sink: Box<dyn EventSink>,
+    db_pool: Arc<DbPool>,
+    notify: Arc<Notify>,
+) {
+    spawn(async move {
+        let mut cursor = loop {
+            match db_pool.get().map(|mut conn| SinkCursor::get_or_create(&mut conn, &name)) {
+                Ok(Ok(cursor)) => break cursor,
+                _ => {
+                    tracing::warn!("Event sink {} has no cursor yet, retrying", name);
+                    sleep(SINK_RETRY_DELAY).await;
+                }
+            }
+        };
+        let mut poll = interval(SINK_POLL_RATE);
+        loop {
+            tokio::select!(
+                _ = poll.tick() => {}
+                _ = notify.notified() => {}
+            );
+            if let Err(err) = drain_to_sink(sink.as_mut(), &mut cursor, &db_pool).await {
+                tracing::warn!("Event sink {} failed: {:?}", name, err);
+                sleep(SINK_RETRY_DELAY).await;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tables::test::MIGRATIONS;
+    use diesel::prelude::*;
+    use function_name::named;
+    use std::cell::{RefCell, RefMut};
+    use subseq_util::tables::harness::{to_pg_db_name, DbHarness};
+
+    fn pg(conn: &mut PgConnection) -> &mut PgConnection {
+        conn
+    }
+
+    #[tokio::test]
+    #[named]
+    async fn test_drain_to_sink() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let conn = RefCell::new(harness.conn());
+        let connect = || Ok::<_, SinkError>(RefMut::map(conn.borrow_mut(), |conn| pg(conn)));
+        let push = |beam: &str, payload: serde_json::Value| {
+            OutboxEvent::push(&mut *conn.borrow_mut(), beam, payload).expect("push")
+        };
+
+        push("urn:test:old", serde_json::json!({}));
+        let mut cursor = SinkCursor::get_or_create(&mut *conn.borrow_mut(), "memory")
+            .expect("cursor");
+        let first = push("urn:test:first", serde_json::json!({"n": 1}));
+        let second = push("urn:test:second", serde_json::json!({"n": 2}));
+
+        let sink = MemorySink::new();
+        let mut emitter = sink.clone();
+        assert_eq!(drain_with(&mut emitter, &mut cursor, connect).await.expect("drain"), 2);
+        assert_eq!(
+            sink.records(),
+            vec![
+                SinkRecord { beam: first.beam.clone(), payload: first.photon() },
+                SinkRecord { beam: second.beam.clone(), payload: second.photon() },
+            ]
+        );
+        assert_eq!(cursor.last_seq, second.seq);
+        assert_eq!(drain_with(&mut emitter, &mut cursor, connect).await.expect("drain"), 0);
+
+        // A seq taken by a transaction which hasn't committed holds back the events after it
+        let in_flight = push("urn:test:in_flight", serde_json::json!({}));
+        diesel::delete(crate::schema::event_outbox::table.find(in_flight.seq))
+            .execute(&mut *conn.borrow_mut())
+            .expect("delete");
+        let after = push("urn:test:after", serde_json::json!({}));
+        assert_eq!(drain_with(&mut emitter, &mut cursor, connect).await.expect("drain"), 0);
+        assert_eq!(cursor.last_seq, second.seq);
+
+        // Once the gap is old it was rolled back, and the sink moves past it
+        let settled = chrono::Utc::now().naive_utc() - chrono::Duration::minutes(1);
+        diesel::update(crate::schema::event_outbox::table.find(after.seq))
+            .set(crate::schema::event_outbox::created.eq(settled))
+            .execute(&mut *conn.borrow_mut())
+            .expect("age");
+        assert_eq!(drain_with(&mut emitter, &mut cursor, connect).await.expect("drain"), 1);
+        assert_eq!(cursor.last_seq, after.seq);
+        assert_eq!(sink.records().len(), 3);
+    }
+}
@@ -0,0 +1,135 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::{Task, User, ValidationErrorMessage};
+
+/// A markdown comment left on a Task by a user
+#[derive(PartialEq, Queryable, Insertable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::task_comments)]
+pub struct TaskComment {
+    pub id: Uuid,
+    pub task_id: Uuid,
+    pub author_id: Uuid,
+    pub created: NaiveDateTime,
+    pub updated: Option<NaiveDateTime>,
+    pub body: String,
+}
+
+impl TaskComment {
+    fn validate_body(body: &str) -> QueryResult<()> {
+        if body.trim().is_empty() {
+            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+            let msg = Box::new(ValidationErrorMessage {
+                message: "Comment body is empty".to_string(),
+                column: "body".to_string(),
+                constraint_name: "body_not_empty".to_string(),
+            });
+            return Err(diesel::result::Error::DatabaseError(kind, msg));
+        }
+        Ok(())
+    }
+
+    pub fn create(
+        conn: &mut PgConnection,
+        task: &Task,
+        author: &User,
+        body: &str,
+    ) -> QueryResult<Self> {
+        Self::validate_body(body)?;
+        let comment = Self {
+            id: Uuid::new_v4(),
+            task_id: task.id,
+            author_id: author.id,
+            created: chrono::Utc::now().naive_utc(),
+            updated: None,
+            body: body.to_owned(),
+        };
+        diesel::insert_into(crate::schema::task_comments::table)
+            .values(&comment)
+            .execute(conn)?;
+        Ok(comment)
+    }
+
+    pub fn get(conn: &mut PgConnection, comment_id: Uuid) -> Option<Self> {
+        use crate::schema::task_comments::dsl;
+        dsl::task_comments
+            .find(comment_id)
+            .get_result::<Self>(conn)
+            .optional()
+            .ok()?
+    }
+
+    /// All comments on a task, oldest first
+    pub fn list_for_task(conn: &mut PgConnection, task_id: Uuid) -> QueryResult<Vec<Self>> {
+        use crate::schema::task_comments::dsl;
+        dsl::task_comments
+            .filter(dsl::task_id.eq(task_id))
+            .order(dsl::created.asc())
+            .load::<Self>(conn)
+    }
+
+    pub fn set_body(&mut self, conn: &mut PgConnection, body: &str) -> QueryResult<()> {
+        use crate::schema::task_comments::dsl;
+        Self::validate_body(body)?;
+        self.body = body.to_owned();
+        self.updated = Some(chrono::Utc::now().naive_utc());
+        diesel::update(dsl::task_comments.find(self.id))
+            .set((dsl::body.eq(&self.body), dsl::updated.eq(self.updated)))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn delete(&self, conn: &mut PgConnection) -> QueryResult<()> {
+        use crate::schema::task_comments::dsl;
+        diesel::delete(dsl::task_comments.find(self.id)).execute(conn)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tables::test::MIGRATIONS;
+    use crate::tables::{Flow, FlowNode, Project};
+    use function_name::named;
+    use subseq_util::tables::harness::{to_pg_db_name, DbHarness};
+    use subseq_util::tables::UserTable;
+
+    #[test]
+    #[named]
+    fn test_comment_handle() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let user = User::create(&mut conn, Uuid::new_v4(), "test@example.com", None).expect("user");
+
+        let entry_node = FlowNode::create(&mut conn, "OPEN").expect("open");
+        let exit_node = FlowNode::create(&mut conn, "CLOSED").expect("closed");
+        let flow = Flow::create(
+            &mut conn,
+            &user,
+            "Default".to_string(),
+            "This is the default flow".to_string(),
+            &entry_node,
+            vec![(&entry_node, &exit_node)],
+            vec![&exit_node],
+        ).expect("flow");
+        let mut proj = Project::create(&mut conn, Uuid::new_v4(), &user, "proj", "", &flow)
+            .expect("proj");
+        let task = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "Task 1", "", &user)
+            .expect("task");
+
+        assert!(TaskComment::create(&mut conn, &task, &user, "  ").is_err());
+        let mut comment = TaskComment::create(&mut conn, &task, &user, "**Looks good**")
+            .expect("comment");
+        comment.set_body(&mut conn, "_Needs work_").expect("edit");
+        let comment2 = TaskComment::get(&mut conn, comment.id).expect("comment2");
+        assert_eq!(comment2.body, "_Needs work_");
+        assert!(comment2.updated.is_some());
+
+        comment.delete(&mut conn).expect("delete");
+        assert!(TaskComment::list_for_task(&mut conn, task.id).expect("list").is_empty());
+    }
+}
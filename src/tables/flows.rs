@@ -1,9 +1,13 @@
+use std::collections::{HashMap, HashSet};
+
 use chrono::NaiveDateTime;
 use diesel::{connection::LoadConnection, pg::Pg, prelude::*};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use super::pagination::list_page;
 use super::*;
+use crate::events::FLOW_CREATED_BEAM;
 
 #[derive(Queryable, Insertable, Clone, Debug, Serialize)]
 #[diesel(table_name = crate::schema::flows)]
@@ -14,6 +18,9 @@ pub struct Flow {
     pub flow_name: String,
     pub description: String,
     pub entry_node_id: Uuid,
+    pub version: i32,
+    pub lineage_id: Uuid,
+    pub superseded_by: Option<Uuid>,
 }
 
 impl PartialEq for Flow {
@@ -24,6 +31,9 @@ impl PartialEq for Flow {
             && self.flow_name == other.flow_name
             && self.description == other.description
             && self.entry_node_id == other.entry_node_id
+            && self.version == other.version
+            && self.lineage_id == other.lineage_id
+            && self.superseded_by == other.superseded_by
     }
 }
 
@@ -40,37 +50,277 @@ impl Flow {
     where
         C: Connection<Backend = Pg> + LoadConnection,
     {
-        let sinks: Vec<&FlowNode> = graph.iter().map(|&(_, sink)| sink).collect();
-        let sources: Vec<&FlowNode> = graph.iter().map(|&(source, _)| source).collect();
-
-        let exits_not_in_graph = !exits.iter().any(|e: &&FlowNode| sinks.contains(e));
-        let entry_not_in_graph = !sources.contains(&entry_node);
-
-        if graph.is_empty() || exits_not_in_graph || entry_not_in_graph || exits.is_empty() {
+        if let Err(violation) = Self::validate_graph(entry_node, &graph, &exits) {
             let kind = diesel::result::DatabaseErrorKind::CheckViolation;
-            let msg = Box::new(ValidationErrorMessage {
-                message: "Invalid username".to_string(),
-                column: "username".to_string(),
-                constraint_name: "username_limits".to_string(),
-            });
-            return Err(diesel::result::Error::DatabaseError(kind, msg));
+            return Err(diesel::result::Error::DatabaseError(kind, Box::new(violation)));
         }
 
+        let id = Uuid::new_v4();
         let flow = Self {
-            id: Uuid::new_v4(),
+            id,
             owner_id: author.id,
             created: chrono::Utc::now().naive_utc(),
             flow_name: flow_name.to_ascii_uppercase(),
             description,
             entry_node_id: entry_node.id,
+            version: 1,
+            lineage_id: id,
+            superseded_by: None,
+        };
+
+        conn.transaction(|transact| {
+            diesel::insert_into(crate::schema::flows::table)
+                .values(&flow)
+                .execute(transact)?;
+            FlowConnection::connect_all(transact, flow.id, graph)?;
+            FlowExit::create_exits(transact, flow.id, exits)?;
+            OutboxEvent::record(transact, FLOW_CREATED_BEAM, &flow)
+        })?;
+        Ok(flow)
+    }
+
+    /// Checks that a graph forms a usable flow, returning the first violation found:
+    /// - every node is reachable from the entry
+    /// - every node can reach an exit
+    /// - exits have no outgoing edges
+    /// - node names are unique within the flow
+    pub fn validate_graph(
+        entry_node: &FlowNode,
+        graph: &[(&FlowNode, &FlowNode)],
+        exits: &[&FlowNode],
+    ) -> Result<(), ValidationErrorMessage> {
+        let violation = |message: String, constraint_name: &str| ValidationErrorMessage {
+            message,
+            column: "graph".to_string(),
+            constraint_name: constraint_name.to_string(),
         };
 
+        if graph.is_empty() {
+            return Err(violation("Flow has no connections".to_string(), "flow_graph_not_empty"));
+        }
+        if exits.is_empty() {
+            return Err(violation("Flow has no exit nodes".to_string(), "flow_exits_not_empty"));
+        }
+
+        let mut nodes: Vec<&FlowNode> = vec![];
+        let mut outgoing: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+        let mut incoming: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+        for &(source, sink) in graph {
+            for node in [source, sink] {
+                if !nodes.iter().any(|known| known.id == node.id) {
+                    nodes.push(node);
+                }
+            }
+            outgoing.entry(source.id).or_default().push(sink.id);
+            incoming.entry(sink.id).or_default().push(source.id);
+        }
+
+        let mut names: HashMap<&str, Uuid> = HashMap::new();
+        for node in nodes.iter() {
+            if let Some(other) = names.insert(node.node_name.as_str(), node.id) {
+                return Err(violation(
+                    format!("Node name {} is used by both {} and {}", node.node_name, other, node.id),
+                    "flow_node_names_unique",
+                ));
+            }
+        }
+
+        if !outgoing.contains_key(&entry_node.id) {
+            return Err(violation(
+                format!("Entry node {} has no outgoing connections", entry_node.node_name),
+                "flow_entry_in_graph",
+            ));
+        }
+        for exit in exits.iter() {
+            if !nodes.iter().any(|node| node.id == exit.id) {
+                return Err(violation(
+                    format!("Exit node {} is not connected to the flow", exit.node_name),
+                    "flow_exit_in_graph",
+                ));
+            }
+            if outgoing.contains_key(&exit.id) {
+                return Err(violation(
+                    format!("Exit node {} has outgoing connections", exit.node_name),
+                    "flow_exit_is_sink",
+                ));
+            }
+        }
+
+        let reachable = |start: Vec<Uuid>, edges: &HashMap<Uuid, Vec<Uuid>>| {
+            let mut seen: HashSet<Uuid> = start.iter().copied().collect();
+            let mut frontier = start;
+            while let Some(node_id) = frontier.pop() {
+                for next in edges.get(&node_id).into_iter().flatten() {
+                    if seen.insert(*next) {
+                        frontier.push(*next);
+                    }
+                }
+            }
+            seen
+        };
+
+        let from_entry = reachable(vec![entry_node.id], &outgoing);
+        if let Some(node) = nodes.iter().find(|node| !from_entry.contains(&node.id)) {
+            return Err(violation(
+                format!(
+                    "Node {} is not reachable from the entry node {}",
+                    node.node_name, entry_node.node_name
+                ),
+                "flow_nodes_reachable",
+            ));
+        }
+        let to_exit = reachable(exits.iter().map(|exit| exit.id).collect(), &incoming);
+        if let Some(node) = nodes.iter().find(|node| !to_exit.contains(&node.id)) {
+            return Err(violation(
+                format!("Node {} cannot reach an exit node", node.node_name),
+                "flow_nodes_exit",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fails unless this is the latest version of its flow
+    fn check_latest(&self) -> QueryResult<()> {
+        match self.superseded_by {
+            Some(next_id) => {
+                let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+                let msg = Box::new(ValidationErrorMessage {
+                    message: format!(
+                        "Version {} of flow {} has been superseded by {}",
+                        self.version, self.flow_name, next_id
+                    ),
+                    column: "superseded_by".to_string(),
+                    constraint_name: "flow_latest_version".to_string(),
+                });
+                Err(diesel::result::Error::DatabaseError(kind, msg))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Creates the next version of this flow with a new entry, exits and connections after
+    /// validating the new graph. Node actions are carried over for nodes which remain, while
+    /// tasks stay on this version until they are upgraded with `upgrade_tasks`.
+    pub fn new_version<C>(
+        &mut self,
+        conn: &mut C,
+        entry_node: &FlowNode,
+        graph: Vec<(&FlowNode, &FlowNode)>,
+        exits: Vec<&FlowNode>,
+    ) -> QueryResult<Self>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+    {
+        use crate::schema::flows::dsl;
+        self.check_latest()?;
+        if let Err(violation) = Self::validate_graph(entry_node, &graph, &exits) {
+            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+            return Err(diesel::result::Error::DatabaseError(kind, Box::new(violation)));
+        }
+
+        let mut next = Self {
+            id: Uuid::new_v4(),
+            owner_id: self.owner_id,
+            created: chrono::Utc::now().naive_utc(),
+            flow_name: self.flow_name.clone(),
+            description: self.description.clone(),
+            entry_node_id: entry_node.id,
+            version: self.version + 1,
+            lineage_id: self.lineage_id,
+            superseded_by: None,
+        };
         diesel::insert_into(crate::schema::flows::table)
-            .values(&flow)
+            .values(&next)
             .execute(conn)?;
-        FlowConnection::connect_all(conn, flow.id, graph)?;
-        FlowExit::create_exits(conn, flow.id, exits)?;
-        Ok(flow)
+        // The entry is assigned with the rest of the graph, it is always on an edge
+        FlowConnection::connect_all(conn, next.id, graph)?;
+        FlowExit::create_exits(conn, next.id, exits)?;
+        FlowNodeAction::copy_to(conn, self.id, next.id)?;
+
+        self.superseded_by = Some(next.id);
+        diesel::update(dsl::flows.find(self.id))
+            .set(dsl::superseded_by.eq(self.superseded_by))
+            .execute(conn)?;
+        Ok(next)
+    }
+
+    /// Moves every task pinned to an older version of this flow onto this version. Tasks keep
+    /// their node when it is still in the flow, otherwise they move to the node given by
+    /// `node_mapping`. Fails without moving anything if a task has no valid mapping.
+    pub fn upgrade_tasks<C>(
+        &self,
+        conn: &mut C,
+        node_mapping: &HashMap<Uuid, Uuid>,
+    ) -> QueryResult<Vec<TaskFlowMove>>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+    {
+        use crate::schema::flows;
+        use crate::schema::task_flows;
+        use crate::schema::tasks;
+
+        self.check_latest()?;
+        let node_ids: Vec<Uuid> = self.nodes(conn)?.into_iter().map(|node| node.id).collect();
+        let older_ids = flows::table
+            .filter(flows::dsl::lineage_id.eq(self.lineage_id))
+            .filter(flows::dsl::id.ne(self.id))
+            .select(flows::dsl::id);
+        let pinned = task_flows::table
+            .inner_join(tasks::table)
+            .filter(task_flows::dsl::flow_id.eq_any(older_ids))
+            .select((
+                task_flows::dsl::task_id,
+                tasks::dsl::slug,
+                task_flows::dsl::flow_id,
+                task_flows::dsl::current_node_id,
+            ))
+            .load::<(Uuid, String, Uuid, Option<Uuid>)>(conn)?;
+
+        let mut unmapped: Vec<String> = vec![];
+        let mut moves = vec![];
+        for (task_id, slug, from_flow_id, from_node_id) in pinned {
+            let to_node_id = match from_node_id {
+                Some(node_id) if node_ids.contains(&node_id) => Some(node_id),
+                Some(node_id) => match node_mapping.get(&node_id) {
+                    Some(to_node_id) if node_ids.contains(to_node_id) => Some(*to_node_id),
+                    _ => {
+                        unmapped.push(format!("{} (on {})", slug, node_id));
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            moves.push(TaskFlowMove {
+                task_id,
+                slug,
+                from_flow_id,
+                from_node_id,
+                to_node_id,
+            });
+        }
+        if !unmapped.is_empty() {
+            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+            let msg = Box::new(ValidationErrorMessage {
+                message: format!("Tasks left without a node in the flow: {}", unmapped.join(", ")),
+                column: "current_node_id".to_string(),
+                constraint_name: "flow_migration_mapped".to_string(),
+            });
+            return Err(diesel::result::Error::DatabaseError(kind, msg));
+        }
+
+        for task_move in moves.iter() {
+            diesel::update(
+                task_flows::table
+                    .filter(task_flows::dsl::task_id.eq(task_move.task_id))
+                    .filter(task_flows::dsl::flow_id.eq(task_move.from_flow_id)),
+            )
+            .set((
+                task_flows::dsl::flow_id.eq(self.id),
+                task_flows::dsl::current_node_id.eq(task_move.to_node_id),
+            ))
+            .execute(conn)?;
+        }
+        Ok(moves)
     }
 
     pub fn get<C>(conn: &mut C, flow_id: Uuid) -> Option<Self>
@@ -86,31 +336,92 @@ impl Flow {
         Some(task)
     }
 
-    pub fn default_flow<C>(conn: &mut C) -> Option<Self>
+    /// All nodes assigned to this flow
+    pub fn nodes<C>(&self, conn: &mut C) -> QueryResult<Vec<FlowNode>>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+    {
+        use crate::schema::flow_assignments;
+        use crate::schema::flow_nodes;
+        let node_ids = flow_assignments::table
+            .filter(flow_assignments::dsl::flow_id.eq(self.id))
+            .select(flow_assignments::dsl::node_id);
+        flow_nodes::table
+            .filter(flow_nodes::dsl::id.eq_any(node_ids))
+            .load::<FlowNode>(conn)
+    }
+
+    /// The newest version in this flow's lineage
+    pub fn latest_version<C>(&self, conn: &mut C) -> QueryResult<Self>
     where
         C: Connection<Backend = Pg> + LoadConnection,
     {
-        Self::list(conn, 0, 1).into_iter().next()
+        use crate::schema::flows::dsl;
+        if self.superseded_by.is_none() {
+            return Ok(self.clone());
+        }
+        dsl::flows
+            .filter(dsl::lineage_id.eq(self.lineage_id))
+            .filter(dsl::superseded_by.is_null())
+            .first::<Self>(conn)
     }
 
-    pub fn list<C>(conn: &mut C, page: u32, page_size: u32) -> Vec<Self>
+    /// Every version of a flow, oldest first
+    pub fn versions<C>(conn: &mut C, lineage_id: Uuid) -> QueryResult<Vec<Self>>
     where
         C: Connection<Backend = Pg> + LoadConnection,
     {
-        let offset = page.saturating_sub(1) * page_size;
-        match crate::schema::flows::table
-            .limit(page_size as i64)
-            .offset(offset as i64)
+        use crate::schema::flows::dsl;
+        dsl::flows
+            .filter(dsl::lineage_id.eq(lineage_id))
+            .order(dsl::version.asc())
             .load::<Self>(conn)
-        {
-            Ok(list) => list,
+    }
+
+    pub fn get_version<C>(conn: &mut C, lineage_id: Uuid, version: i32) -> Option<Self>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+    {
+        use crate::schema::flows::dsl;
+        dsl::flows
+            .filter(dsl::lineage_id.eq(lineage_id))
+            .filter(dsl::version.eq(version))
+            .first::<Self>(conn)
+            .optional()
+            .ok()?
+    }
+
+    pub fn default_flow<C>(conn: &mut C) -> Option<Self>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+    {
+        match Self::page(conn, &PageRequest::first(1)) {
+            Ok(page) => page.items.into_iter().next(),
             Err(err) => {
                 tracing::warn!("DB List Query Failed: {:?}", err);
-                vec![]
+                None
             }
         }
     }
 
+    /// A page of the latest version of each flow
+    pub fn page<C>(conn: &mut C, page: &PageRequest) -> QueryResult<Page<Self>>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+    {
+        use crate::schema::flows::dsl;
+        let total = dsl::flows
+            .filter(dsl::superseded_by.is_null())
+            .count()
+            .get_result::<i64>(conn)?;
+        let latest = dsl::flows.filter(dsl::superseded_by.is_null()).into_boxed();
+        Ok(list_page!(
+            conn, page, total, latest, Self,
+            created: dsl::created, name: dsl::flow_name, id: dsl::id,
+            cursor: |flow| (flow.created, flow.flow_name.clone(), flow.id)
+        ))
+    }
+
     pub fn set_flow_entry<C>(&mut self, conn: &mut C, node: &FlowNode) -> QueryResult<()>
     where
         C: Connection<Backend = Pg> + LoadConnection,
@@ -158,14 +469,67 @@ impl FlowNode {
     }
 }
 
+/// A condition which must hold for a task to move along a FlowConnection
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum FlowGuard {
+    /// The task must have an assignee
+    RequiresAssignee,
+    /// Every task this task DEPENDS ON must be closed
+    DependenciesClosed,
+    /// The task must carry this tag
+    RequiresTag { name: String },
+    /// Every task which is a SUBTASK OF this task must be closed
+    SubtasksClosed,
+}
+
 #[derive(Queryable, Insertable, Clone, Debug, Serialize)]
 #[diesel(table_name = crate::schema::flow_node_connections)]
 pub struct FlowConnection {
     from_node_id: Uuid,
     to_node_id: Uuid,
+    guards: serde_json::Value,
+    flow_id: Uuid,
 }
 
 impl FlowConnection {
+    /// The guards on the edge between two nodes, empty if the edge has none
+    pub fn guards<C>(
+        conn: &mut C,
+        flow_id: Uuid,
+        from_node_id: Uuid,
+        to_node_id: Uuid,
+    ) -> QueryResult<Vec<FlowGuard>>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+    {
+        use crate::schema::flow_node_connections::dsl;
+        let guards = dsl::flow_node_connections
+            .find((flow_id, from_node_id, to_node_id))
+            .select(dsl::guards)
+            .first::<serde_json::Value>(conn)?;
+        serde_json::from_value(guards)
+            .map_err(|err| diesel::result::Error::DeserializationError(Box::new(err)))
+    }
+
+    pub fn set_guards<C>(
+        conn: &mut C,
+        flow_id: Uuid,
+        from_node_id: Uuid,
+        to_node_id: Uuid,
+        guards: &[FlowGuard],
+    ) -> QueryResult<()>
+    where
+        C: Connection<Backend = Pg>,
+    {
+        use crate::schema::flow_node_connections::dsl;
+        let guards = serde_json::to_value(guards)
+            .map_err(|err| diesel::result::Error::SerializationError(Box::new(err)))?;
+        diesel::update(dsl::flow_node_connections.find((flow_id, from_node_id, to_node_id)))
+            .set(dsl::guards.eq(guards))
+            .execute(conn)?;
+        Ok(())
+    }
+
     pub fn edges<C>(conn: &mut C, node_id: Uuid) -> QueryResult<Vec<FlowNode>>
     where
         C: Connection<Backend = Pg> + LoadConnection,
@@ -186,6 +550,23 @@ impl FlowConnection {
         Ok(result)
     }
 
+    /// Like `edges`, but only follows connections within the given flow
+    pub fn flow_edges<C>(conn: &mut C, flow_id: Uuid, node_id: Uuid) -> QueryResult<Vec<FlowNode>>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+    {
+        use crate::schema::flow_node_connections;
+        use crate::schema::flow_nodes;
+
+        let to_node_ids = flow_node_connections::table
+            .filter(flow_node_connections::dsl::flow_id.eq(flow_id))
+            .filter(flow_node_connections::dsl::from_node_id.eq(node_id))
+            .select(flow_node_connections::dsl::to_node_id);
+        flow_nodes::table
+            .filter(flow_nodes::dsl::id.eq_any(to_node_ids))
+            .load::<FlowNode>(conn)
+    }
+
     pub fn connect_all<C>(
         conn: &mut C,
         flow_id: Uuid,
@@ -194,27 +575,20 @@ impl FlowConnection {
     where
         C: Connection<Backend = Pg> + LoadConnection,
     {
-        // 1. Get all nodes in this flow from FlowAssignment
-        let node_ids: Vec<Uuid> = crate::schema::flow_assignments::table
-            .filter(crate::schema::flow_assignments::flow_id.eq(flow_id))
-            .select(crate::schema::flow_assignments::node_id)
-            .load::<Uuid>(conn)?;
-
-        // 2. Delete all FlowConnections on those nodes, but only within this flow
+        // 1. Delete all FlowConnections within this flow
         diesel::delete(
-            crate::schema::flow_node_connections::table.filter(
-                crate::schema::flow_node_connections::from_node_id
-                    .eq_any(&node_ids)
-                    .and(crate::schema::flow_node_connections::to_node_id.eq_any(&node_ids)),
-            ),
+            crate::schema::flow_node_connections::table
+                .filter(crate::schema::flow_node_connections::flow_id.eq(flow_id)),
         )
         .execute(conn)?;
 
-        // 3. Create all new connections from nodes
+        // 2. Create all new connections from nodes
         for (from_node, to_node) in nodes {
             let new_connection = FlowConnection {
                 from_node_id: from_node.id,
                 to_node_id: to_node.id,
+                guards: serde_json::json!([]),
+                flow_id,
             };
             diesel::insert_into(crate::schema::flow_node_connections::table)
                 .values(&new_connection)
@@ -243,22 +617,26 @@ impl FlowAssignment {
             flow_id,
             node_id: flow_node.id,
         };
-        match diesel::insert_into(crate::schema::flow_assignments::table)
+        // Nodes are assigned again as each edge and exit is added. A failed insert would
+        // abort the surrounding transaction, so duplicates are skipped instead.
+        diesel::insert_into(crate::schema::flow_assignments::table)
             .values(&assignment)
-            .execute(conn)
-        {
-            Err(diesel::result::Error::DatabaseError(
-                diesel::result::DatabaseErrorKind::UniqueViolation,
-                _,
-            )) => {
-                Ok(0usize) // Unique errors mean already inserted, this is fine.
-            }
-            other => other,
-        }?;
+            .on_conflict_do_nothing()
+            .execute(conn)?;
         Ok(())
     }
 }
 
+/// A task moved from an older version of a flow onto the latest one
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskFlowMove {
+    pub task_id: Uuid,
+    pub slug: String,
+    pub from_flow_id: Uuid,
+    pub from_node_id: Option<Uuid>,
+    pub to_node_id: Option<Uuid>,
+}
+
 #[derive(Queryable, Insertable, Clone, Debug)]
 #[diesel(table_name = crate::schema::flow_exits)]
 pub struct FlowExit {
@@ -292,9 +670,203 @@ impl FlowExit {
     }
 }
 
+/// Something done to a task automatically when it enters or leaves a node
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum FlowAction {
+    AssignUser { user_id: Uuid },
+    AddTag { name: String },
+    RemoveTag { name: String },
+    AddWatcher { user_id: Uuid },
+    /// Starts a job for the task as if it were sent to /task/run
+    RunTask,
+    /// Emits the task state on a custom beam
+    EmitBeam { beam: String },
+}
+
+/// Every beam a flow action emits on is under this namespace
+pub const FLOW_BEAM_PREFIX: &str = "urn:subseq.io:";
+
+impl FlowAction {
+    /// Custom beams must be a `urn:subseq.io:` name made of non-empty `:` separated segments of
+    /// ascii letters, digits, `-`, `_` and `.`, and must not be one of the beams this service
+    /// emits itself.
+    pub fn validate_beam(beam: &str) -> Result<(), ValidationErrorMessage> {
+        let violation = |message: String| ValidationErrorMessage {
+            message,
+            column: "action".to_string(),
+            constraint_name: "flow_action_beam".to_string(),
+        };
+        let name = match beam.strip_prefix(FLOW_BEAM_PREFIX) {
+            Some(name) => name,
+            None => return Err(violation(format!("Beam must start with {}", FLOW_BEAM_PREFIX))),
+        };
+        let valid_segment = |segment: &str| {
+            !segment.is_empty()
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        };
+        if !name.split(':').all(valid_segment) {
+            return Err(violation(format!("{} is not a valid beam name", beam)));
+        }
+        if crate::events::is_static_beam(beam) {
+            return Err(violation(format!("{} is reserved", beam)));
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ValidationErrorMessage> {
+        match self {
+            Self::EmitBeam { beam } => Self::validate_beam(beam),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Queryable, Insertable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::flow_node_actions)]
+pub struct FlowNodeAction {
+    pub id: Uuid,
+    pub flow_id: Uuid,
+    pub node_id: Uuid,
+    pub created: NaiveDateTime,
+    pub on_enter: bool,
+    pub action: serde_json::Value,
+}
+
+impl FlowNodeAction {
+    pub fn create<C>(
+        conn: &mut C,
+        flow_id: Uuid,
+        node_id: Uuid,
+        on_enter: bool,
+        action: &FlowAction,
+    ) -> QueryResult<Self>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+    {
+        use crate::schema::flow_assignments;
+        // Actions belong to the version tasks are moving through, older versions are read only
+        Flow::get(conn, flow_id)
+            .ok_or(diesel::result::Error::NotFound)?
+            .check_latest()?;
+        let assigned = flow_assignments::table
+            .find((flow_id, node_id))
+            .count()
+            .get_result::<i64>(conn)?;
+        if assigned == 0 {
+            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+            let msg = Box::new(ValidationErrorMessage {
+                message: format!("Node {} is not in flow {}", node_id, flow_id),
+                column: "node_id".to_string(),
+                constraint_name: "flow_node_action_assigned".to_string(),
+            });
+            return Err(diesel::result::Error::DatabaseError(kind, msg));
+        }
+        if let Err(violation) = action.validate() {
+            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+            return Err(diesel::result::Error::DatabaseError(kind, Box::new(violation)));
+        }
+
+        let action = serde_json::to_value(action)
+            .map_err(|err| diesel::result::Error::SerializationError(Box::new(err)))?;
+        let node_action = Self {
+            id: Uuid::new_v4(),
+            flow_id,
+            node_id,
+            created: chrono::Utc::now().naive_utc(),
+            on_enter,
+            action,
+        };
+        diesel::insert_into(crate::schema::flow_node_actions::table)
+            .values(&node_action)
+            .execute(conn)?;
+        Ok(node_action)
+    }
+
+    pub fn decode_action(&self) -> Option<FlowAction> {
+        serde_json::from_value(self.action.clone()).ok()
+    }
+
+    /// The actions to run when a task enters (or leaves) a node, in the order they were added
+    pub fn triggered<C>(
+        conn: &mut C,
+        flow_id: Uuid,
+        node_id: Uuid,
+        on_enter: bool,
+    ) -> QueryResult<Vec<Self>>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+    {
+        use crate::schema::flow_node_actions::dsl;
+        dsl::flow_node_actions
+            .filter(dsl::flow_id.eq(flow_id))
+            .filter(dsl::node_id.eq(node_id))
+            .filter(dsl::on_enter.eq(on_enter))
+            .order(dsl::created.asc())
+            .load::<Self>(conn)
+    }
+
+    pub fn list_for_flow<C>(conn: &mut C, flow_id: Uuid) -> QueryResult<Vec<Self>>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+    {
+        use crate::schema::flow_node_actions::dsl;
+        dsl::flow_node_actions
+            .filter(dsl::flow_id.eq(flow_id))
+            .order(dsl::created.asc())
+            .load::<Self>(conn)
+    }
+
+    /// Copies the actions of one flow version onto the nodes the next version still has
+    pub fn copy_to<C>(conn: &mut C, from_flow_id: Uuid, to_flow_id: Uuid) -> QueryResult<()>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+    {
+        use crate::schema::flow_assignments;
+        let node_ids = flow_assignments::table
+            .filter(flow_assignments::dsl::flow_id.eq(to_flow_id))
+            .select(flow_assignments::dsl::node_id)
+            .load::<Uuid>(conn)?;
+        for node_action in Self::list_for_flow(conn, from_flow_id)? {
+            if !node_ids.contains(&node_action.node_id) {
+                continue;
+            }
+            let copied = Self {
+                id: Uuid::new_v4(),
+                flow_id: to_flow_id,
+                ..node_action
+            };
+            diesel::insert_into(crate::schema::flow_node_actions::table)
+                .values(&copied)
+                .execute(conn)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete<C>(conn: &mut C, flow_id: Uuid, action_id: Uuid) -> QueryResult<()>
+    where
+        C: Connection<Backend = Pg>,
+    {
+        use crate::schema::flow_node_actions::dsl;
+        let deleted = diesel::delete(
+            dsl::flow_node_actions
+                .filter(dsl::id.eq(action_id))
+                .filter(dsl::flow_id.eq(flow_id)),
+        )
+        .execute(conn)?;
+        if deleted == 0 {
+            return Err(diesel::result::Error::NotFound);
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Graph {
     flow_id: Uuid,
+    lineage_id: Uuid,
+    version: i32,
     entry_point: FlowNode,
     exit_points: Vec<FlowNode>,
     nodes: Vec<FlowNode>,
@@ -336,15 +908,13 @@ impl Graph {
 
         // Fetch all connections in the flow
         let connections = crate::schema::flow_node_connections::table
-            .filter(
-                crate::schema::flow_node_connections::from_node_id
-                    .eq_any(&node_ids)
-                    .or(crate::schema::flow_node_connections::to_node_id.eq_any(&node_ids)),
-            )
+            .filter(crate::schema::flow_node_connections::flow_id.eq(flow_id))
             .load::<FlowConnection>(conn)?;
 
         Ok(Graph {
             flow_id,
+            lineage_id: flow.lineage_id,
+            version: flow.version,
             entry_point,
             exit_points,
             nodes,
@@ -393,4 +963,212 @@ mod test {
         assert_eq!(flow, flow2);
         assert_eq!(flow.flow_name, "FLOW"); // Forced uppercase
     }
+
+    fn node(name: &str) -> FlowNode {
+        FlowNode {
+            id: Uuid::new_v4(),
+            node_name: name.to_string(),
+        }
+    }
+
+    fn constraint(result: Result<(), ValidationErrorMessage>) -> String {
+        match result {
+            Ok(()) => String::new(),
+            Err(violation) => violation.constraint_name,
+        }
+    }
+
+    #[test]
+    fn test_validate_graph() {
+        let open = node("OPEN");
+        let doing = node("IN PROGRESS");
+        let closed = node("CLOSED");
+        let orphan = node("ORPHAN");
+
+        let graph = vec![(&open, &doing), (&doing, &closed), (&open, &closed)];
+        assert!(Flow::validate_graph(&open, &graph, &[&closed]).is_ok());
+
+        assert_eq!(constraint(Flow::validate_graph(&open, &[], &[&closed])), "flow_graph_not_empty");
+        assert_eq!(constraint(Flow::validate_graph(&open, &graph, &[])), "flow_exits_not_empty");
+        assert_eq!(
+            constraint(Flow::validate_graph(&closed, &graph, &[&closed])),
+            "flow_entry_in_graph"
+        );
+        assert_eq!(
+            constraint(Flow::validate_graph(&open, &graph, &[&doing])),
+            "flow_exit_is_sink"
+        );
+        assert_eq!(
+            constraint(Flow::validate_graph(&open, &graph, &[&orphan])),
+            "flow_exit_in_graph"
+        );
+
+        let unreachable = vec![(&open, &closed), (&orphan, &closed)];
+        assert_eq!(
+            constraint(Flow::validate_graph(&open, &unreachable, &[&closed])),
+            "flow_nodes_reachable"
+        );
+
+        let dead_end = vec![(&open, &closed), (&open, &doing)];
+        assert_eq!(
+            constraint(Flow::validate_graph(&open, &dead_end, &[&closed])),
+            "flow_nodes_exit"
+        );
+
+        let duplicate = node("CLOSED");
+        let duplicated = vec![(&open, &closed), (&open, &duplicate)];
+        assert_eq!(
+            constraint(Flow::validate_graph(&open, &duplicated, &[&closed, &duplicate])),
+            "flow_node_names_unique"
+        );
+    }
+
+    #[test]
+    #[named]
+    fn test_flow_node_actions() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new(
+            "localhost",
+            "development",
+            &db_name,
+            Some(crate::tables::test::MIGRATIONS),
+        );
+        let mut conn = harness.conn();
+
+        let entry_node = FlowNode::create(&mut conn, "Open").expect("entry");
+        let exit_node = FlowNode::create(&mut conn, "Closed").expect("exit");
+        let stray_node = FlowNode::create(&mut conn, "Stray").expect("stray");
+        let user = User::create(&mut conn, Uuid::new_v4(), "test@example.com", None).expect("user");
+        let flow = Flow::create(
+            &mut conn,
+            &user,
+            "flow".to_string(),
+            "".to_string(),
+            &entry_node,
+            vec![(&entry_node, &exit_node)],
+            vec![&exit_node],
+        )
+        .expect("flow");
+
+        let action = FlowAction::AddTag {
+            name: "done".to_string(),
+        };
+        assert!(FlowNodeAction::create(&mut conn, flow.id, stray_node.id, true, &action).is_err());
+        let node_action = FlowNodeAction::create(&mut conn, flow.id, exit_node.id, true, &action)
+            .expect("action");
+
+        let on_enter = FlowNodeAction::triggered(&mut conn, flow.id, exit_node.id, true)
+            .expect("on enter");
+        assert_eq!(on_enter.len(), 1);
+        assert_eq!(on_enter[0].decode_action(), Some(action));
+        assert!(FlowNodeAction::triggered(&mut conn, flow.id, exit_node.id, false)
+            .expect("on exit")
+            .is_empty());
+
+        FlowNodeAction::delete(&mut conn, flow.id, node_action.id).expect("delete");
+        assert!(FlowNodeAction::list_for_flow(&mut conn, flow.id).expect("list").is_empty());
+
+        let emit = |beam: &str| FlowAction::EmitBeam { beam: beam.to_string() };
+        let unprefixed = emit("closed");
+        assert!(FlowNodeAction::create(&mut conn, flow.id, exit_node.id, true, &unprefixed).is_err());
+        let beam = emit("urn:subseq.io:team:task:closed");
+        FlowNodeAction::create(&mut conn, flow.id, exit_node.id, true, &beam).expect("beam action");
+    }
+
+    #[test]
+    fn test_validate_beam() {
+        assert!(FlowAction::validate_beam("urn:subseq.io:team:task:closed").is_ok());
+        assert!(FlowAction::validate_beam("urn:subseq.io:team-a:v1.2:done_now").is_ok());
+        assert!(FlowAction::validate_beam("urn:other:task:closed").is_err());
+        assert!(FlowAction::validate_beam("urn:subseq.io:").is_err());
+        assert!(FlowAction::validate_beam("urn:subseq.io:team::closed").is_err());
+        assert!(FlowAction::validate_beam("urn:subseq.io:team:task closed").is_err());
+        assert!(FlowAction::validate_beam(crate::events::TASK_CREATED_BEAM).is_err());
+    }
+
+    #[test]
+    #[named]
+    fn test_flow_versions() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new(
+            "localhost",
+            "development",
+            &db_name,
+            Some(crate::tables::test::MIGRATIONS),
+        );
+        let mut conn = harness.conn();
+
+        let open = FlowNode::create(&mut conn, "Open").expect("open");
+        let review = FlowNode::create(&mut conn, "Review").expect("review");
+        let closed = FlowNode::create(&mut conn, "Closed").expect("closed");
+        let user = User::create(&mut conn, Uuid::new_v4(), "test@example.com", None).expect("user");
+        let mut flow = Flow::create(
+            &mut conn,
+            &user,
+            "flow".to_string(),
+            "".to_string(),
+            &open,
+            vec![(&open, &review), (&review, &closed)],
+            vec![&closed],
+        )
+        .expect("flow");
+        let action = FlowAction::AddTag {
+            name: "done".to_string(),
+        };
+        FlowNodeAction::create(&mut conn, flow.id, closed.id, true, &action).expect("action");
+        let mut proj = Project::create(&mut conn, Uuid::new_v4(), &user, "proj", "", &flow)
+            .expect("proj");
+        let mut task = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "Task 1", "", &user)
+            .expect("task");
+        let to_review = TaskUpdate::Transition {
+            node_id: review.id,
+            flow_id: None,
+        };
+        task.update(&mut conn, user.id, to_review).expect("transition");
+
+        // Dropping REVIEW creates version 2 while the task stays pinned to version 1
+        let next = flow
+            .new_version(&mut conn, &open, vec![(&open, &closed)], vec![&closed])
+            .expect("new version");
+        assert_eq!(next.version, 2);
+        assert_eq!(next.lineage_id, flow.id);
+        assert_eq!(flow.superseded_by, Some(next.id));
+        assert!(flow
+            .new_version(&mut conn, &open, vec![(&open, &closed)], vec![&closed])
+            .is_err());
+        assert_eq!(flow.latest_version(&mut conn).expect("latest"), next);
+        assert_eq!(Flow::versions(&mut conn, flow.lineage_id).expect("versions").len(), 2);
+        assert_eq!(Flow::page(&mut conn, &PageRequest::first(10)).expect("page").items, vec![next.clone()]);
+        assert_eq!(
+            FlowNodeAction::list_for_flow(&mut conn, next.id).expect("copied").len(),
+            1
+        );
+        let old_graph = Graph::fetch(&mut conn, flow.id).expect("old graph");
+        assert_eq!(old_graph.connections.len(), 2);
+
+        let flows = task.flows(&mut conn).expect("flows");
+        assert_eq!(flows[0].flow_id, flow.id);
+        assert_eq!(flows[0].current_node_id, Some(review.id));
+
+        // REVIEW is gone, so the task cannot be upgraded unless it is mapped somewhere
+        assert!(next.upgrade_tasks(&mut conn, &HashMap::new()).is_err());
+        let mapping = HashMap::from([(review.id, closed.id)]);
+        let moved = next.upgrade_tasks(&mut conn, &mapping).expect("upgrade");
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].from_flow_id, flow.id);
+        assert_eq!(moved[0].to_node_id, Some(closed.id));
+        let flows = task.flows(&mut conn).expect("flows");
+        assert_eq!(flows[0].flow_id, next.id);
+        assert_eq!(flows[0].current_node_id, Some(closed.id));
+
+        // Undoing the transition made on version 1 moves the task on the version it is on now
+        task.update(&mut conn, user.id, TaskUpdate::Undo).expect("undo");
+        let flows = task.flows(&mut conn).expect("flows");
+        assert_eq!(flows[0].flow_id, next.id);
+        assert_eq!(flows[0].current_node_id, Some(open.id));
+
+        // Superseded versions don't take new actions
+        assert!(FlowNodeAction::create(&mut conn, flow.id, closed.id, true, &action).is_err());
+        FlowNodeAction::create(&mut conn, next.id, open.id, true, &action).expect("latest action");
+    }
 }
@@ -0,0 +1,124 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{TaskLinkType, TaskUpdate};
+
+/// The value a task held before a TaskUpdate was applied, which is what an Undo restores.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum TaskPrevious {
+    Assignee(Option<Uuid>),
+    Flow {
+        member: bool,
+        node_id: Option<Uuid>,
+    },
+    Title(String),
+    Description(String),
+    Node {
+        flow_id: Uuid,
+        node_id: Option<Uuid>,
+    },
+    Link(Option<TaskLinkType>),
+    Tagged(bool),
+}
+
+/// A single TaskUpdate applied to a task, who applied it and when
+#[derive(Queryable, Insertable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::task_history)]
+pub struct TaskHistory {
+    pub id: Uuid,
+    pub task_id: Uuid,
+    pub actor_id: Uuid,
+    pub created: NaiveDateTime,
+    pub task_update: serde_json::Value,
+    pub previous: Option<serde_json::Value>,
+    pub reverted: bool,
+}
+
+impl TaskHistory {
+    pub fn create(
+        conn: &mut PgConnection,
+        task_id: Uuid,
+        actor_id: Uuid,
+        update: &TaskUpdate,
+        previous: Option<&TaskPrevious>,
+    ) -> QueryResult<Self> {
+        let task_update = serde_json::to_value(update)
+            .map_err(|err| diesel::result::Error::SerializationError(Box::new(err)))?;
+        let previous = match previous {
+            Some(previous) => Some(
+                serde_json::to_value(previous)
+                    .map_err(|err| diesel::result::Error::SerializationError(Box::new(err)))?,
+            ),
+            None => None,
+        };
+
+        let entry = Self {
+            id: Uuid::new_v4(),
+            task_id,
+            actor_id,
+            created: chrono::Utc::now().naive_utc(),
+            task_update,
+            previous,
+            reverted: false,
+        };
+        diesel::insert_into(crate::schema::task_history::table)
+            .values(&entry)
+            .execute(conn)?;
+        Ok(entry)
+    }
+
+    pub fn decode_update(&self) -> Option<TaskUpdate> {
+        serde_json::from_value(self.task_update.clone()).ok()
+    }
+
+    pub fn decode_previous(&self) -> Option<TaskPrevious> {
+        serde_json::from_value(self.previous.clone()?).ok()
+    }
+
+    /// The flow, source node and destination node when this entry moved the task along a flow
+    pub fn transition(&self) -> Option<(Uuid, Option<Uuid>, Uuid)> {
+        match (self.decode_update()?, self.decode_previous()?) {
+            (TaskUpdate::Transition { node_id, .. }, TaskPrevious::Node { flow_id, node_id: from }) => {
+                Some((flow_id, from, node_id))
+            }
+            _ => None,
+        }
+    }
+
+    /// All changes made to a task, oldest first
+    pub fn list(conn: &mut PgConnection, task_id: Uuid) -> QueryResult<Vec<Self>> {
+        use crate::schema::task_history::dsl;
+        dsl::task_history
+            .filter(dsl::task_id.eq(task_id))
+            .order(dsl::created.asc())
+            .load::<Self>(conn)
+    }
+
+    /// The most recent change by this actor which can still be reverted
+    pub fn last_reversible(
+        conn: &mut PgConnection,
+        task_id: Uuid,
+        actor_id: Uuid,
+    ) -> QueryResult<Option<Self>> {
+        use crate::schema::task_history::dsl;
+        dsl::task_history
+            .filter(dsl::task_id.eq(task_id))
+            .filter(dsl::actor_id.eq(actor_id))
+            .filter(dsl::reverted.eq(false))
+            .filter(dsl::previous.is_not_null())
+            .order(dsl::created.desc())
+            .first::<Self>(conn)
+            .optional()
+    }
+
+    pub fn mark_reverted(&mut self, conn: &mut PgConnection) -> QueryResult<()> {
+        use crate::schema::task_history::dsl;
+        self.reverted = true;
+        diesel::update(dsl::task_history.find(self.id))
+            .set(dsl::reverted.eq(true))
+            .execute(conn)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::pagination::{list_page, Page, PageRequest};
+
+/// Session titles are cut to this many characters of the request which started them
+pub const SESSION_TITLE_LENGTH: usize = 80;
+
+/// A conversation over the instruct channel, kept so it can be listed, replayed and resumed.
+/// `stream_id` is the Prism stream the conversation runs on once it has started.
+#[derive(PartialEq, Queryable, Insertable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::instruct_sessions)]
+pub struct InstructSession {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub project_id: Uuid,
+    pub stream_id: Option<Uuid>,
+    pub title: String,
+    pub awaiting_reply: bool,
+    pub created: NaiveDateTime,
+    pub updated: NaiveDateTime,
+    pub closed: Option<NaiveDateTime>,
+}
+
+/// One step of a session. The payload's shape depends on its kind: a chat message, a tool call
+/// or a tool result.
+#[derive(PartialEq, Queryable, Insertable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::instruct_messages)]
+pub struct InstructMessage {
+    pub session_id: Uuid,
+    pub seq: i32,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub created: NaiveDateTime,
+}
+
+fn session_title(request: &str) -> String {
+    let first_line = request.trim().lines().next().unwrap_or_default().trim();
+    first_line.chars().take(SESSION_TITLE_LENGTH).collect()
+}
+
+impl InstructSession {
+    pub fn create(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        project_id: Uuid,
+        request: &str,
+    ) -> QueryResult<Self> {
+        let now = chrono::Utc::now().naive_utc();
+        let session = Self {
+            id: Uuid::new_v4(),
+            user_id,
+            project_id,
+            stream_id: None,
+            title: session_title(request),
+            awaiting_reply: false,
+            created: now,
+            updated: now,
+            closed: None,
+        };
+        diesel::insert_into(crate::schema::instruct_sessions::table)
+            .values(&session)
+            .execute(conn)?;
+        Ok(session)
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.closed.is_none()
+    }
+
+    fn touch(&mut self, conn: &mut PgConnection) -> QueryResult<()> {
+        use crate::schema::instruct_sessions::dsl;
+        self.updated = chrono::Utc::now().naive_utc();
+        diesel::update(dsl::instruct_sessions.find(self.id))
+            .set((
+                dsl::project_id.eq(self.project_id),
+                dsl::stream_id.eq(self.stream_id),
+                dsl::awaiting_reply.eq(self.awaiting_reply),
+                dsl::updated.eq(self.updated),
+                dsl::closed.eq(self.closed),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn set_stream(&mut self, conn: &mut PgConnection, stream_id: Uuid) -> QueryResult<()> {
+        self.stream_id = Some(stream_id);
+        self.touch(conn)
+    }
+
+    pub fn set_project(&mut self, conn: &mut PgConnection, project_id: Uuid) -> QueryResult<()> {
+        self.project_id = project_id;
+        self.touch(conn)
+    }
+
+    /// Whether the assistant is waiting on the user's reply before it carries on
+    pub fn set_awaiting_reply(
+        &mut self,
+        conn: &mut PgConnection,
+        awaiting: bool,
+    ) -> QueryResult<()> {
+        self.awaiting_reply = awaiting;
+        self.touch(conn)
+    }
+
+    pub fn close(&mut self, conn: &mut PgConnection) -> QueryResult<()> {
+        self.closed = Some(chrono::Utc::now().naive_utc());
+        self.awaiting_reply = false;
+        self.touch(conn)
+    }
+
+    /// The session's steps in the order they happened
+    pub fn messages(&self, conn: &mut PgConnection) -> QueryResult<Vec<InstructMessage>> {
+        use crate::schema::instruct_messages::dsl;
+        dsl::instruct_messages
+            .filter(dsl::session_id.eq(self.id))
+            .order_by(dsl::seq.asc())
+            .load::<InstructMessage>(conn)
+    }
+
+    /// A page of the user's sessions. Sorting by name sorts by title.
+    pub fn page(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        page: &PageRequest,
+    ) -> QueryResult<Page<Self>> {
+        use crate::schema::instruct_sessions::dsl;
+        let total = dsl::instruct_sessions
+            .filter(dsl::user_id.eq(user_id))
+            .count()
+            .get_result::<i64>(conn)?;
+        let query = dsl::instruct_sessions
+            .filter(dsl::user_id.eq(user_id))
+            .into_boxed();
+        Ok(list_page!(
+            conn, page, total, query, Self,
+            created: dsl::created, name: dsl::title, id: dsl::id,
+            cursor: |session| (session.created, session.title.clone(), session.id)
+        ))
+    }
+}
+
+subseq_util::setup_table_crud!(
+    InstructSession,
+    crate::schema::instruct_sessions::dsl::instruct_sessions
+);
+
+impl InstructMessage {
+    /// Adds a step to the end of a session. The session row is locked while the next seq is
+    /// picked so concurrent appends queue up instead of colliding.
+    pub fn append(
+        conn: &mut PgConnection,
+        session_id: Uuid,
+        kind: &str,
+        payload: serde_json::Value,
+    ) -> QueryResult<Self> {
+        use crate::schema::{instruct_messages, instruct_sessions};
+        conn.transaction(|transact| {
+            instruct_sessions::table
+                .find(session_id)
+                .select(instruct_sessions::id)
+                .for_update()
+                .get_result::<Uuid>(transact)?;
+            let last_seq: Option<i32> = instruct_messages::table
+                .filter(instruct_messages::session_id.eq(session_id))
+                .select(diesel::dsl::max(instruct_messages::seq))
+                .get_result(transact)?;
+            let message = Self {
+                session_id,
+                seq: last_seq.unwrap_or(0) + 1,
+                kind: kind.to_string(),
+                payload,
+                created: chrono::Utc::now().naive_utc(),
+            };
+            diesel::insert_into(instruct_messages::table)
+                .values(&message)
+                .execute(transact)?;
+            diesel::update(instruct_sessions::table.find(session_id))
+                .set(instruct_sessions::updated.eq(message.created))
+                .execute(transact)?;
+            Ok(message)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tables::test::{fixture, Fixture, MIGRATIONS};
+    use crate::tables::{Project, User};
+    use function_name::named;
+    use subseq_util::tables::harness::{to_pg_db_name, DbHarness};
+    use subseq_util::tables::UserTable;
+
+    #[test]
+    #[named]
+    fn test_instruct_sessions() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let Fixture { user, flow, proj } = fixture(&mut conn);
+        let other_user = User::create(&mut conn, Uuid::new_v4(), "other@example.com", None)
+            .expect("other user");
+        let other = Project::create(&mut conn, Uuid::new_v4(), &user, "other", "", &flow)
+            .expect("other");
+
+        let long_request = format!("  Plan the release\n{}", "details ".repeat(20));
+        let mut session = InstructSession::create(&mut conn, user.id, proj.id, &long_request)
+            .expect("session");
+        assert_eq!(session.title, "Plan the release");
+        assert!(session.is_open());
+        let wordy = InstructSession::create(&mut conn, user.id, proj.id, &"word ".repeat(40))
+            .expect("wordy");
+        assert_eq!(wordy.title.chars().count(), SESSION_TITLE_LENGTH);
+        InstructSession::create(&mut conn, other_user.id, proj.id, "Not yours").expect("theirs");
+
+        let first = InstructMessage::append(
+            &mut conn,
+            session.id,
+            "chat",
+            serde_json::json!({"role": "user", "content": {"text": "Plan the release"}}),
+        ).expect("first");
+        let second =
+            InstructMessage::append(&mut conn, session.id, "tool_call", serde_json::json!({}))
+                .expect("second");
+        InstructMessage::append(&mut conn, wordy.id, "chat", serde_json::json!({})).expect("wordy");
+        assert_eq!((first.seq, second.seq), (1, 2));
+        let messages = session.messages(&mut conn).expect("messages");
+        assert_eq!(messages, vec![first, second]);
+        let stored = InstructSession::get(&mut conn, session.id).expect("stored");
+        assert!(stored.updated >= session.updated);
+
+        let stream_id = Uuid::new_v4();
+        session.set_stream(&mut conn, stream_id).expect("stream");
+        session.set_project(&mut conn, other.id).expect("project");
+        session.set_awaiting_reply(&mut conn, true).expect("awaiting");
+        let stored = InstructSession::get(&mut conn, session.id).expect("stored");
+        assert_eq!(stored.stream_id, Some(stream_id));
+        assert_eq!(stored.project_id, other.id);
+        assert!(stored.awaiting_reply);
+
+        session.close(&mut conn).expect("close");
+        let stored = InstructSession::get(&mut conn, session.id).expect("stored");
+        assert!(!stored.is_open());
+        assert!(!stored.awaiting_reply);
+
+        // Users only see their own sessions
+        let page = InstructSession::page(&mut conn, user.id, &PageRequest::first(1)).expect("page");
+        assert_eq!(page.total, 2);
+        assert_eq!(page.items.len(), 1);
+        let next = PageRequest {
+            cursor: page.next_cursor.clone(),
+            ..PageRequest::first(1)
+        };
+        let rest = InstructSession::page(&mut conn, user.id, &next).expect("rest");
+        assert_eq!(rest.items.len(), 1);
+        assert_eq!(rest.next_cursor, None);
+        let mut ids = vec![page.items[0].id, rest.items[0].id];
+        ids.sort();
+        let mut expected = vec![session.id, wordy.id];
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+}
@@ -6,6 +6,7 @@ use diesel::prelude::*;
 use serde::Serialize;
 use uuid::Uuid;
 
+use super::pagination::{list_page, Page, PageRequest};
 use super::User;
 use subseq_util::tables::UserTable;
 
@@ -18,6 +19,7 @@ pub struct Job {
     pub name: String,
     pub created_id: Uuid,
     pub assignee_id: Uuid,
+    pub created: NaiveDateTime,
 }
 
 impl Job {
@@ -35,7 +37,8 @@ impl Job {
             task_id,
             name,
             created_id,
-            assignee_id
+            assignee_id,
+            created: chrono::Utc::now().naive_utc(),
         };
         diesel::insert_into(jobs::table)
             .values(&job)
@@ -46,60 +49,77 @@ impl Job {
     pub fn query(conn: &mut PgConnection,
                  user_id: Uuid,
                  query_dict: &HashMap<String, String>,
-                 page: u32,
-                 page_size: u32) -> QueryResult<Vec<(Self, Option<JobResult>)>> {
+                 page: &PageRequest) -> QueryResult<Page<(Self, Option<JobResult>)>> {
         use crate::schema::jobs::dsl::*;
         use crate::schema::job_results;
 
-        let mut query = crate::schema::jobs::table
-            .left_join(crate::schema::job_results::table)
-            .into_boxed();
-
-        for (key, value) in query_dict {
-            match key.as_str() {
-                "project_id" => if let Ok(value) = Uuid::try_parse(value) {
-                    query = query.filter(project_id.eq(value));
-                } else if value == "active" {
-                    if let Some(user) = User::get(conn, user_id) {
-                        if let Some(project) = user.get_active_project(conn) {
-                            query = query.filter(project_id.eq(project.id));
-                        }
+        let active_project_id = if query_dict.get("project_id").is_some_and(|value| value == "active") {
+            User::get(conn, user_id)
+                .and_then(|user| user.get_active_project(conn))
+                .map(|project| project.id)
+        } else {
+            None
+        };
+
+        let filtered = || {
+            let mut query = crate::schema::jobs::table
+                .left_join(crate::schema::job_results::table)
+                .into_boxed();
+
+            for (key, value) in query_dict {
+                match key.as_str() {
+                    "project_id" => if let Ok(value) = Uuid::try_parse(value) {
+                        query = query.filter(project_id.eq(value));
+                    } else if let Some(active_project_id) = active_project_id {
+                        query = query.filter(project_id.eq(active_project_id));
                     }
-                }
-                "created_id" => if let Ok(value) = Uuid::try_parse(value) {
-                    query = query.filter(created_id.eq(value));
-                } else if value == "self" {
-                    query = query.filter(created_id.eq(user_id))
-                }
-                "running" => {
-                    if let Ok(value) = bool::from_str(&value) {
-                        if value {
-                            query = query.filter(job_results::dsl::job_id.is_null());
-                        } else {
-                            query = query.filter(job_results::dsl::job_id.is_not_null());
+                    "created_id" => if let Ok(value) = Uuid::try_parse(value) {
+                        query = query.filter(created_id.eq(value));
+                    } else if value == "self" {
+                        query = query.filter(created_id.eq(user_id))
+                    }
+                    "running" => {
+                        if let Ok(value) = bool::from_str(value) {
+                            if value {
+                                query = query.filter(job_results::dsl::job_id.is_null());
+                            } else {
+                                query = query.filter(job_results::dsl::job_id.is_not_null());
+                            }
                         }
                     }
-                }
-                "assignee_id" => if let Ok(value) = Uuid::try_parse(value) {
-                        query = query.filter(assignee_id.eq(value));
-                    } else if value == "self" {
-                        query = query.filter(assignee_id.eq(user_id))
-                }
-                "task_id" => {
-                    if let Ok(value) = Uuid::try_parse(value) {
-                        query = query.filter(task_id.eq(value));
+                    "assignee_id" => if let Ok(value) = Uuid::try_parse(value) {
+                            query = query.filter(assignee_id.eq(value));
+                        } else if value == "self" {
+                            query = query.filter(assignee_id.eq(user_id))
                     }
+                    "task_id" => {
+                        if let Ok(value) = Uuid::try_parse(value) {
+                            query = query.filter(task_id.eq(value));
+                        }
+                    }
+                    "name" => query = query.filter(name.ilike(format!("%{}%", value))),
+                    _ => {} // Ignore unknown keys or log them if necessary
                 }
-                "name" => query = query.filter(name.ilike(format!("%{}%", value))),
-                _ => {} // Ignore unknown keys or log them if necessary
             }
-        }
+            query
+        };
 
-        let offset = page.saturating_sub(1) * page_size;
-        Ok(query
-            .limit(page_size as i64)
-            .offset(offset as i64)
-            .load::<(Job, Option<JobResult>)>(conn)?)
+        let total = filtered().count().get_result::<i64>(conn)?;
+        Ok(list_page!(
+            conn, page, total, filtered(), (Job, Option<JobResult>),
+            created: created, name: name, id: id,
+            cursor: |(job, _)| (job.created, job.name.clone(), job.id)
+        ))
+    }
+
+    pub fn list_for_task(conn: &mut PgConnection,
+                         task_id: Uuid) -> QueryResult<Vec<(Self, Option<JobResult>)>> {
+        use crate::schema::jobs;
+        jobs::table
+            .left_join(crate::schema::job_results::table)
+            .filter(jobs::task_id.eq(task_id))
+            .order(jobs::created.asc())
+            .load::<(Self, Option<JobResult>)>(conn)
     }
 
     pub fn get(conn: &mut PgConnection,
@@ -142,6 +162,48 @@ impl JobResult {
     }
 }
 
+#[derive(PartialEq, Queryable, Insertable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::job_progress)]
+pub struct JobProgress {
+    pub id: Uuid,
+    pub job_id: Uuid,
+    pub created: NaiveDateTime,
+    pub percent: f32,
+    pub stage: String,
+    pub message: String,
+}
+
+impl JobProgress {
+    pub fn create(conn: &mut PgConnection,
+                  job: &Job,
+                  percent: f32,
+                  stage: String,
+                  message: String) -> QueryResult<Self> {
+        use crate::schema::job_progress;
+        let progress = Self {
+            id: Uuid::new_v4(),
+            job_id: job.id,
+            created: chrono::Utc::now().naive_utc(),
+            percent: percent.clamp(0.0, 100.0),
+            stage,
+            message
+        };
+        diesel::insert_into(job_progress::table)
+            .values(&progress)
+            .execute(conn)?;
+        Ok(progress)
+    }
+
+    /// The progress reported by a job, oldest first
+    pub fn list_for_job(conn: &mut PgConnection, job_id: Uuid) -> QueryResult<Vec<Self>> {
+        use crate::schema::job_progress;
+        job_progress::table
+            .filter(job_progress::job_id.eq(job_id))
+            .order(job_progress::created.asc())
+            .load::<Self>(conn)
+    }
+}
+
 #[derive(PartialEq, Queryable, Insertable, Clone, Debug, Serialize)]
 #[diesel(table_name = crate::schema::awaiting_help)]
 pub struct AwaitingHelp {
@@ -1,13 +1,42 @@
+mod comments;
 mod flows;
+mod history;
+mod instruct;
 mod jobs;
+mod outbox;
+mod pagination;
 mod projects;
+mod routing;
+mod task_embeddings;
+mod task_query;
+mod task_search;
 mod tasks;
 mod users;
+mod webhooks;
 
-pub use self::flows::{Flow, FlowAssignment, FlowConnection, FlowExit, FlowNode, Graph};
+pub use self::comments::TaskComment;
+pub use self::flows::{
+    Flow, FlowAction, FlowAssignment, FlowConnection, FlowExit, FlowGuard, FlowNode,
+    FlowNodeAction, Graph, TaskFlowMove, FLOW_BEAM_PREFIX,
+};
+pub use self::history::{TaskHistory, TaskPrevious};
+pub use self::instruct::{InstructMessage, InstructSession, SESSION_TITLE_LENGTH};
+pub use self::outbox::{OutboxEvent, SinkCursor};
+pub use self::pagination::{Cursor, ListSort, Page, PageRequest, SortValue};
 pub use self::projects::{ActiveProject, Project};
-pub use self::tasks::{Tag, Task, TaskFlow, TaskLink, TaskLinkType, TaskUpdate};
+pub use self::routing::{RoutingRule, RoutingRuleFields};
+pub use self::task_embeddings::{cosine_similarity, SimilarTask, TaskEmbedding};
+pub use self::task_query::{
+    Comparison, NodeRef, OrderField, ProjectRef, QueryError, QueryExpr, TaskFilter, TaskFlag,
+    TaskOrder, TaskQuery, TaskRef, TextMatch, UserRef,
+};
+pub use self::task_search::{TaskSearchHit, MAX_SEARCH_RESULTS};
+pub use self::tasks::{
+    Tag, Task, TaskFlow, TaskFlowState, TaskLink, TaskLinkType, TaskUpdate, UnmetGuardsMessage,
+    TRANSITION_GUARDS_CONSTRAINT,
+};
 pub use self::users::{User, UserIdAccount, UserMetadata, UserPortrait};
+pub use self::webhooks::{is_public_ip, ProjectWebhook, WebhookDelivery, WebhookEvent};
 pub use self::jobs::{
     AwaitingHelp,
     DenormalizedHelpAction,
@@ -15,12 +44,44 @@ pub use self::jobs::{
     HelpResolutionAction,
     HelpResolutionFiles,
     Job,
+    JobProgress,
     JobResult,
 };
 pub use subseq_util::tables::{DbPool, ValidationErrorMessage};
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
+    use diesel::PgConnection;
     use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+    use subseq_util::tables::UserTable;
+    use uuid::Uuid;
+
+    use super::{Flow, FlowNode, Project, User};
+
     pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/");
+
+    /// A user with a project on the default OPEN -> CLOSED flow
+    pub struct Fixture {
+        pub user: User,
+        pub flow: Flow,
+        pub proj: Project,
+    }
+
+    pub fn fixture(conn: &mut PgConnection) -> Fixture {
+        let user = User::create(conn, Uuid::new_v4(), "test@example.com", None).expect("user");
+        let open = FlowNode::create(conn, "OPEN").expect("open");
+        let closed = FlowNode::create(conn, "CLOSED").expect("closed");
+        let flow = Flow::create(
+            conn,
+            &user,
+            "Default".to_string(),
+            "This is the default flow".to_string(),
+            &open,
+            vec![(&open, &closed)],
+            vec![&closed],
+        )
+        .expect("flow");
+        let proj = Project::create(conn, Uuid::new_v4(), &user, "proj", "", &flow).expect("proj");
+        Fixture { user, flow, proj }
+    }
 }
@@ -0,0 +1,287 @@
+use chrono::NaiveDateTime;
+use diesel::{connection::LoadConnection, pg::Pg, prelude::*};
+use serde::Serialize;
+
+/// An event waiting to be emitted, kept until Prism has accepted it. Events Prism refuses too
+/// many times are marked failed and skipped rather than retried forever.
+#[derive(PartialEq, Queryable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::event_outbox)]
+pub struct OutboxEvent {
+    pub seq: i64,
+    pub beam: String,
+    pub payload: serde_json::Value,
+    pub created: NaiveDateTime,
+    pub emitted: Option<NaiveDateTime>,
+    pub attempts: i32,
+    pub failed: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::event_outbox)]
+struct NewOutboxEvent<'a> {
+    beam: &'a str,
+    payload: serde_json::Value,
+    created: NaiveDateTime,
+}
+
+impl OutboxEvent {
+    pub fn push<C>(conn: &mut C, beam: &str, payload: serde_json::Value) -> QueryResult<Self>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+    {
+        let event = NewOutboxEvent {
+            beam,
+            payload,
+            created: chrono::Utc::now().naive_utc(),
+        };
+        diesel::insert_into(crate::schema::event_outbox::table)
+            .values(&event)
+            .get_result::<Self>(conn)
+    }
+
+    /// Serializes a message and pushes it. Call this inside the transaction making the change
+    /// the message describes, so the event exists if and only if the change was committed.
+    pub fn record<C, T>(conn: &mut C, beam: &str, msg: &T) -> QueryResult<Self>
+    where
+        C: Connection<Backend = Pg> + LoadConnection,
+        T: Serialize,
+    {
+        let payload = serde_json::to_value(msg)
+            .map_err(|err| diesel::result::Error::SerializationError(Box::new(err)))?;
+        Self::push(conn, beam, payload)
+    }
+
+    /// The payload as emitted, carrying the event's sequence number so consumers can drop
+    /// events they have already seen after a replay
+    pub fn photon(&self) -> serde_json::Value {
+        let mut photon = self.payload.clone();
+        if let serde_json::Value::Object(fields) = &mut photon {
+            fields.insert("event_seq".to_string(), serde_json::Value::from(self.seq));
+        }
+        photon
+    }
+
+    /// The oldest events which have not been emitted or failed yet, in the order they were
+    /// pushed
+    pub fn pending(conn: &mut PgConnection, limit: i64) -> QueryResult<Vec<Self>> {
+        use crate::schema::event_outbox::dsl;
+        dsl::event_outbox
+            .filter(dsl::emitted.is_null())
+            .filter(dsl::failed.is_null())
+            .order(dsl::seq.asc())
+            .limit(limit)
+            .load::<Self>(conn)
+    }
+
+    /// Events pushed after the given sequence number, whether or not Prism has emitted them
+    pub fn after(conn: &mut PgConnection, seq: i64, limit: i64) -> QueryResult<Vec<Self>> {
+        use crate::schema::event_outbox::dsl;
+        dsl::event_outbox
+            .filter(dsl::seq.gt(seq))
+            .order(dsl::seq.asc())
+            .limit(limit)
+            .load::<Self>(conn)
+    }
+
+    pub fn mark_emitted(&mut self, conn: &mut PgConnection) -> QueryResult<()> {
+        use crate::schema::event_outbox::dsl;
+        let emitted = chrono::Utc::now().naive_utc();
+        diesel::update(dsl::event_outbox.find(self.seq))
+            .set(dsl::emitted.eq(emitted))
+            .execute(conn)?;
+        self.emitted = Some(emitted);
+        Ok(())
+    }
+
+    /// Counts a refused attempt to emit this event, returning the attempts so far
+    pub fn record_attempt(&mut self, conn: &mut PgConnection) -> QueryResult<i32> {
+        use crate::schema::event_outbox::dsl;
+        self.attempts = diesel::update(dsl::event_outbox.find(self.seq))
+            .set(dsl::attempts.eq(dsl::attempts + 1))
+            .returning(dsl::attempts)
+            .get_result(conn)?;
+        Ok(self.attempts)
+    }
+
+    /// Sets the event aside so it no longer blocks the events after it
+    pub fn mark_failed(&mut self, conn: &mut PgConnection) -> QueryResult<()> {
+        use crate::schema::event_outbox::dsl;
+        let failed = chrono::Utc::now().naive_utc();
+        diesel::update(dsl::event_outbox.find(self.seq))
+            .set(dsl::failed.eq(failed))
+            .execute(conn)?;
+        self.failed = Some(failed);
+        Ok(())
+    }
+
+    /// Removes events which were emitted before the cutoff and which every sink has read
+    pub fn prune(conn: &mut PgConnection, emitted_before: NaiveDateTime) -> QueryResult<usize> {
+        use crate::schema::event_outbox::dsl;
+        use crate::schema::event_sink_cursors::dsl as cursors;
+        let read_by_all = cursors::event_sink_cursors
+            .select(diesel::dsl::min(cursors::last_seq))
+            .get_result::<Option<i64>>(conn)?
+            .unwrap_or(i64::MAX);
+        diesel::delete(
+            dsl::event_outbox
+                .filter(dsl::emitted.lt(emitted_before))
+                .filter(dsl::seq.le(read_by_all)),
+        )
+        .execute(conn)
+    }
+}
+
+/// The last outbox event a sink other than Prism has accepted
+#[derive(PartialEq, Queryable, Insertable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::event_sink_cursors)]
+pub struct SinkCursor {
+    pub sink_name: String,
+    pub last_seq: i64,
+    pub updated: NaiveDateTime,
+}
+
+impl SinkCursor {
+    /// A sink seen for the first time starts after the newest event, rather than replaying
+    /// the whole outbox
+    pub fn get_or_create(conn: &mut PgConnection, sink_name: &str) -> QueryResult<Self> {
+        use crate::schema::event_outbox::dsl as outbox;
+        use crate::schema::event_sink_cursors::dsl;
+        if let Some(cursor) = dsl::event_sink_cursors
+            .find(sink_name)
+            .get_result::<Self>(conn)
+            .optional()?
+        {
+            return Ok(cursor);
+        }
+        let last_seq = outbox::event_outbox
+            .select(diesel::dsl::max(outbox::seq))
+            .get_result::<Option<i64>>(conn)?
+            .unwrap_or(0);
+        let cursor = Self {
+            sink_name: sink_name.to_owned(),
+            last_seq,
+            updated: chrono::Utc::now().naive_utc(),
+        };
+        diesel::insert_into(crate::schema::event_sink_cursors::table)
+            .values(&cursor)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        Ok(cursor)
+    }
+
+    pub fn advance(&mut self, conn: &mut PgConnection, seq: i64) -> QueryResult<()> {
+        use crate::schema::event_sink_cursors::dsl;
+        let updated = chrono::Utc::now().naive_utc();
+        diesel::update(dsl::event_sink_cursors.find(&self.sink_name))
+            .set((dsl::last_seq.eq(seq), dsl::updated.eq(updated)))
+            .execute(conn)?;
+        self.last_seq = seq;
+        self.updated = updated;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::events::{FLOW_CREATED_BEAM, PROJECT_CREATED_BEAM};
+    use crate::tables::test::{fixture, Fixture, MIGRATIONS};
+    use crate::tables::Project;
+    use function_name::named;
+    use subseq_util::tables::harness::{to_pg_db_name, DbHarness};
+    use uuid::Uuid;
+
+    #[test]
+    #[named]
+    fn test_outbox_order() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+
+        let first = OutboxEvent::push(&mut conn, "urn:test:first", serde_json::json!({"n": 1}))
+            .expect("first");
+        let second = OutboxEvent::push(&mut conn, "urn:test:second", serde_json::json!({"n": 2}))
+            .expect("second");
+        assert!(first.seq < second.seq);
+        let first_seq = first.seq;
+
+        let mut pending = OutboxEvent::pending(&mut conn, 10).expect("pending");
+        assert_eq!(pending, vec![first, second.clone()]);
+        pending[0].mark_emitted(&mut conn).expect("emitted");
+        assert_eq!(OutboxEvent::pending(&mut conn, 10).expect("pending"), vec![second]);
+
+        assert_eq!(pending[0].photon(), serde_json::json!({"n": 1, "event_seq": first_seq}));
+
+        let cutoff = chrono::Utc::now().naive_utc() + chrono::Duration::seconds(1);
+        assert_eq!(OutboxEvent::prune(&mut conn, cutoff).expect("prune"), 1);
+    }
+
+    #[test]
+    #[named]
+    fn test_outbox_failed() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+
+        let mut refused = OutboxEvent::push(&mut conn, "urn:test:refused", serde_json::json!({}))
+            .expect("refused");
+        let next = OutboxEvent::push(&mut conn, "urn:test:next", serde_json::json!({}))
+            .expect("next");
+        assert_eq!(refused.record_attempt(&mut conn).expect("attempt"), 1);
+        assert_eq!(refused.record_attempt(&mut conn).expect("attempt"), 2);
+        assert_eq!(OutboxEvent::pending(&mut conn, 1).expect("pending"), vec![refused.clone()]);
+
+        refused.mark_failed(&mut conn).expect("failed");
+        assert_eq!(OutboxEvent::pending(&mut conn, 1).expect("pending"), vec![next]);
+    }
+
+    #[test]
+    #[named]
+    fn test_outbox_records_creates() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let Fixture { user, flow, proj } = fixture(&mut conn);
+
+        // A failed create leaves no event behind
+        assert!(Project::create(&mut conn, Uuid::new_v4(), &user, "", "Test", &flow).is_err());
+
+        let pending = OutboxEvent::pending(&mut conn, 10).expect("pending");
+        let beams: Vec<&str> = pending.iter().map(|event| event.beam.as_str()).collect();
+        assert_eq!(beams, vec![FLOW_CREATED_BEAM, PROJECT_CREATED_BEAM]);
+        assert_eq!(pending[1].payload["id"], serde_json::json!(proj.id));
+    }
+
+    #[test]
+    #[named]
+    fn test_sink_cursor() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+
+        let old = OutboxEvent::push(&mut conn, "urn:test:old", serde_json::json!({})).expect("old");
+        let mut cursor = SinkCursor::get_or_create(&mut conn, "file").expect("cursor");
+        assert_eq!(cursor.last_seq, old.seq);
+
+        let new = OutboxEvent::push(&mut conn, "urn:test:new", serde_json::json!({})).expect("new");
+        let unread = OutboxEvent::after(&mut conn, cursor.last_seq, 10).expect("after");
+        assert_eq!(unread, vec![new.clone()]);
+
+        cursor.advance(&mut conn, new.seq).expect("advance");
+        let cursor = SinkCursor::get_or_create(&mut conn, "file").expect("cursor");
+        assert_eq!(cursor.last_seq, new.seq);
+        assert!(OutboxEvent::after(&mut conn, cursor.last_seq, 10).expect("after").is_empty());
+
+        // Emitted events are only pruned once every sink has read them
+        let unread = OutboxEvent::push(&mut conn, "urn:test:unread", serde_json::json!({}))
+            .expect("unread");
+        for mut event in [old, new, unread.clone()] {
+            event.mark_emitted(&mut conn).expect("emitted");
+        }
+        let cutoff = chrono::Utc::now().naive_utc() + chrono::Duration::seconds(1);
+        assert_eq!(OutboxEvent::prune(&mut conn, cutoff).expect("prune"), 2);
+        let kept = OutboxEvent::after(&mut conn, 0, 10).expect("after");
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].seq, unread.seq);
+    }
+}
@@ -0,0 +1,266 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use subseq_util::tables::ValidationErrorMessage;
+use uuid::Uuid;
+
+pub const DEFAULT_PAGE_SIZE: u32 = 20;
+pub const MAX_PAGE_SIZE: u32 = 200;
+
+/// The value of one sort key in the last row of a page
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SortValue {
+    Time(NaiveDateTime),
+    Number(i64),
+    Text(String),
+}
+
+/// Where the next page starts: the sort keys and id of the last row handed out. Clients get
+/// it as an opaque string and send it back unchanged.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Cursor {
+    pub keys: Vec<SortValue>,
+    pub id: Uuid,
+}
+
+fn invalid_cursor(message: &str) -> diesel::result::Error {
+    let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+    let msg = Box::new(ValidationErrorMessage {
+        message: message.to_string(),
+        column: "cursor".to_string(),
+        constraint_name: "valid_cursor".to_string(),
+    });
+    diesel::result::Error::DatabaseError(kind, msg)
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        hex::encode(serde_json::to_vec(self).expect("cursor serializes"))
+    }
+
+    pub fn decode(cursor: &str) -> QueryResult<Self> {
+        let bytes = hex::decode(cursor).map_err(|_| invalid_cursor("Malformed cursor"))?;
+        serde_json::from_slice(&bytes).map_err(|_| invalid_cursor("Malformed cursor"))
+    }
+
+    /// Fails unless the cursor came from a list sorted on this many keys
+    pub fn expect_keys(&self, count: usize) -> QueryResult<()> {
+        if self.keys.len() != count {
+            return Err(invalid_cursor("Cursor does not match the sort order"));
+        }
+        Ok(())
+    }
+
+    pub fn key(&self, index: usize) -> QueryResult<&SortValue> {
+        self.keys
+            .get(index)
+            .ok_or_else(|| invalid_cursor("Cursor does not match the sort order"))
+    }
+
+    pub fn time(&self, index: usize) -> QueryResult<NaiveDateTime> {
+        match self.key(index)? {
+            SortValue::Time(time) => Ok(*time),
+            _ => Err(invalid_cursor("Cursor does not match the sort order")),
+        }
+    }
+
+    pub fn number(&self, index: usize) -> QueryResult<i64> {
+        match self.key(index)? {
+            SortValue::Number(number) => Ok(*number),
+            _ => Err(invalid_cursor("Cursor does not match the sort order")),
+        }
+    }
+
+    pub fn text(&self, index: usize) -> QueryResult<String> {
+        match self.key(index)? {
+            SortValue::Text(text) => Ok(text.clone()),
+            _ => Err(invalid_cursor("Cursor does not match the sort order")),
+        }
+    }
+}
+
+/// The sort options shared by the plain lists
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ListSort {
+    #[default]
+    Created,
+    Name,
+}
+
+/// Which page of a list to return and in what order
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct PageRequest {
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub page_size: Option<u32>,
+    #[serde(default)]
+    pub sort: ListSort,
+    #[serde(default)]
+    pub descending: bool,
+}
+
+impl PageRequest {
+    pub fn first(page_size: u32) -> Self {
+        Self {
+            page_size: Some(page_size),
+            ..Self::default()
+        }
+    }
+
+    pub fn limit(&self) -> i64 {
+        page_limit(self.page_size)
+    }
+
+    pub fn after(&self) -> QueryResult<Option<Cursor>> {
+        self.cursor.as_deref().map(Cursor::decode).transpose()
+    }
+}
+
+/// The requested page size, kept within 1..=MAX_PAGE_SIZE
+pub fn page_limit(page_size: Option<u32>) -> i64 {
+    page_size
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE) as i64
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+    pub total: i64,
+}
+
+impl<T> Page<T> {
+    /// Builds a page from up to `limit + 1` rows, the extra row only signalling that more follow
+    pub fn from_rows<F>(mut rows: Vec<T>, limit: i64, total: i64, cursor: F) -> Self
+    where
+        F: Fn(&T) -> Cursor,
+    {
+        let more = rows.len() as i64 > limit;
+        rows.truncate(limit as usize);
+        let next_cursor = if more {
+            rows.last().map(|row| cursor(row).encode())
+        } else {
+            None
+        };
+        Self {
+            items: rows,
+            next_cursor,
+            total,
+        }
+    }
+
+    pub fn try_map<U, E, F>(self, f: F) -> Result<Page<U>, E>
+    where
+        F: FnMut(T) -> Result<U, E>,
+    {
+        Ok(Page {
+            items: self.items.into_iter().map(f).collect::<Result<_, _>>()?,
+            next_cursor: self.next_cursor,
+            total: self.total,
+        })
+    }
+}
+
+/// Orders a boxed query by a sort key and then id, starting after `$after` when it holds the
+/// key and id of the last row of the previous page.
+macro_rules! keyset {
+    ($query:expr, $key:expr, $id:expr, $after:expr, $descending:expr) => {
+        match ($after, $descending) {
+            (Some((key, after_id)), true) => $query
+                .filter($key.lt(key.clone()).or($key.eq(key).and($id.lt(after_id))))
+                .order_by(($key.desc(), $id.desc())),
+            (Some((key, after_id)), false) => $query
+                .filter($key.gt(key.clone()).or($key.eq(key).and($id.gt(after_id))))
+                .order_by(($key.asc(), $id.asc())),
+            (None, true) => $query.order_by(($key.desc(), $id.desc())),
+            (None, false) => $query.order_by(($key.asc(), $id.asc())),
+        }
+    };
+}
+
+pub(crate) use keyset;
+
+/// Loads one page of a boxed query for a `PageRequest`, sorting by `created` or `name` as the
+/// request asks and then by `id`. `cursor` gives the created time, name and id of a row, for
+/// the cursor handed out after the last one.
+macro_rules! list_page {
+    (
+        $conn:expr, $page:expr, $total:expr, $query:expr, $row:ty,
+        created: $created:expr, name: $name:expr, id: $id:expr,
+        cursor: |$item:pat_param| ($created_key:expr, $name_key:expr, $item_id:expr)
+    ) => {{
+        use $crate::tables::pagination::{keyset, Cursor, ListSort, Page, SortValue};
+        let request: &$crate::tables::pagination::PageRequest = $page;
+        let after = request.after()?;
+        let limit = request.limit();
+        let rows = match request.sort {
+            ListSort::Created => {
+                let after = match &after {
+                    Some(cursor) => Some((cursor.time(0)?, cursor.id)),
+                    None => None,
+                };
+                keyset!($query, $created, $id, after, request.descending)
+                    .limit(limit + 1)
+                    .load::<$row>($conn)?
+            }
+            ListSort::Name => {
+                let after = match &after {
+                    Some(cursor) => Some((cursor.text(0)?, cursor.id)),
+                    None => None,
+                };
+                keyset!($query, $name, $id, after, request.descending)
+                    .limit(limit + 1)
+                    .load::<$row>($conn)?
+            }
+        };
+        Page::from_rows(rows, limit, $total, |$item: &$row| Cursor {
+            keys: vec![match request.sort {
+                ListSort::Created => SortValue::Time($created_key),
+                ListSort::Name => SortValue::Text($name_key),
+            }],
+            id: $item_id,
+        })
+    }};
+}
+
+pub(crate) use list_page;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = Cursor {
+            keys: vec![SortValue::Text("Fix login".to_string()), SortValue::Number(12)],
+            id: Uuid::new_v4(),
+        };
+        let decoded = Cursor::decode(&cursor.encode()).expect("decode");
+        assert_eq!(decoded, cursor);
+        assert_eq!(decoded.text(0).expect("text"), "Fix login");
+        assert_eq!(decoded.number(1).expect("number"), 12);
+        assert!(decoded.time(0).is_err());
+        assert!(decoded.key(2).is_err());
+        assert!(Cursor::decode("not a cursor").is_err());
+    }
+
+    #[test]
+    fn test_page_from_rows() {
+        let cursor = |n: &i64| Cursor {
+            keys: vec![SortValue::Number(*n)],
+            id: Uuid::nil(),
+        };
+        let page = Page::from_rows(vec![1, 2, 3], 2, 3, cursor);
+        assert_eq!(page.items, vec![1, 2]);
+        let next = Cursor::decode(page.next_cursor.as_deref().expect("next")).expect("cursor");
+        assert_eq!(next.number(0).expect("number"), 2);
+
+        let page = Page::from_rows(vec![1, 2], 2, 2, cursor);
+        assert_eq!(page.next_cursor, None);
+        assert_eq!(page_limit(Some(0)), 1);
+        assert_eq!(page_limit(Some(10_000)), MAX_PAGE_SIZE as i64);
+    }
+}
@@ -4,7 +4,9 @@ use serde::Serialize;
 use subseq_util::tables::ValidationErrorMessage;
 use uuid::Uuid;
 
-use super::{Flow, User};
+use super::pagination::{list_page, Page, PageRequest};
+use super::{Flow, OutboxEvent, User};
+use crate::events::PROJECT_CREATED_BEAM;
 
 #[derive(Queryable, Insertable, Clone, Debug, Serialize)]
 #[diesel(table_name = crate::schema::projects)]
@@ -31,6 +33,19 @@ impl PartialEq for Project {
 }
 
 impl Project {
+    fn validate_name(name: &str) -> QueryResult<()> {
+        if name.is_empty() || name.len() > 64 {
+            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+            let msg = Box::new(ValidationErrorMessage {
+                message: "Invalid project name".to_string(),
+                column: "name".to_string(),
+                constraint_name: "name_limits".to_string(),
+            });
+            return Err(diesel::result::Error::DatabaseError(kind, msg));
+        }
+        Ok(())
+    }
+
     pub fn create(
         conn: &mut PgConnection,
         id: Uuid,
@@ -49,22 +64,57 @@ impl Project {
             default_flow_id: flow.id,
         };
 
-        if project.name.len() > 64 {
-            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
-            let msg = Box::new(ValidationErrorMessage {
-                message: "Invalid project name".to_string(),
-                column: "name".to_string(),
-                constraint_name: "name_limits".to_string(),
-            });
-            return Err(diesel::result::Error::DatabaseError(kind, msg));
-        }
+        Self::validate_name(&project.name)?;
 
-        diesel::insert_into(crate::schema::projects::table)
-            .values(&project)
-            .execute(conn)?;
+        conn.transaction(|transact| {
+            diesel::insert_into(crate::schema::projects::table)
+                .values(&project)
+                .execute(transact)?;
+            OutboxEvent::record(transact, PROJECT_CREATED_BEAM, &project)
+        })?;
         Ok(project)
     }
 
+    /// Renames the project. Existing task slugs keep the name they were created with.
+    pub fn set_name(&mut self, conn: &mut PgConnection, name: &str) -> QueryResult<()> {
+        use crate::schema::projects::dsl;
+        let name = name.to_ascii_uppercase();
+        Self::validate_name(&name)?;
+        diesel::update(dsl::projects.find(self.id))
+            .set(dsl::name.eq(&name))
+            .execute(conn)?;
+        self.name = name;
+        Ok(())
+    }
+
+    pub fn set_description(&mut self, conn: &mut PgConnection, description: &str) -> QueryResult<()> {
+        use crate::schema::projects::dsl;
+        diesel::update(dsl::projects.find(self.id))
+            .set(dsl::description.eq(description))
+            .execute(conn)?;
+        self.description = description.to_owned();
+        Ok(())
+    }
+
+    /// The flow new tasks are added to. Existing tasks stay in the flows they are in.
+    pub fn set_default_flow(&mut self, conn: &mut PgConnection, flow: &Flow) -> QueryResult<()> {
+        use crate::schema::projects::dsl;
+        diesel::update(dsl::projects.find(self.id))
+            .set(dsl::default_flow_id.eq(flow.id))
+            .execute(conn)?;
+        self.default_flow_id = flow.id;
+        Ok(())
+    }
+
+    pub fn set_owner(&mut self, conn: &mut PgConnection, owner: &User) -> QueryResult<()> {
+        use crate::schema::projects::dsl;
+        diesel::update(dsl::projects.find(self.id))
+            .set(dsl::owner_id.eq(owner.id))
+            .execute(conn)?;
+        self.owner_id = owner.id;
+        Ok(())
+    }
+
     pub fn set_active_project(&self, conn: &mut PgConnection, uid: Uuid) -> QueryResult<()> {
         use crate::schema::active_projects::dsl::*;
         let pid = self.id;
@@ -80,6 +130,16 @@ impl Project {
             .execute(conn)?;
         Ok(())
     }
+
+    pub fn page(conn: &mut PgConnection, page: &PageRequest) -> QueryResult<Page<Self>> {
+        use crate::schema::projects::dsl;
+        let total = dsl::projects.count().get_result::<i64>(conn)?;
+        Ok(list_page!(
+            conn, page, total, dsl::projects.into_boxed(), Self,
+            created: dsl::created, name: dsl::name, id: dsl::id,
+            cursor: |project| (project.created, project.name.clone(), project.id)
+        ))
+    }
 }
 
 subseq_util::setup_table_crud!(Project, crate::schema::projects::dsl::projects);
@@ -100,7 +160,7 @@ subseq_util::setup_table_crud!(
 mod test {
     use super::*;
     use crate::tables::test::MIGRATIONS;
-    use crate::tables::{Flow, FlowNode};
+    use crate::tables::{Flow, FlowNode, ListSort};
     use function_name::named;
     use subseq_util::tables::harness::{to_pg_db_name, DbHarness};
     use subseq_util::tables::UserTable;
@@ -134,4 +194,94 @@ mod test {
         assert_eq!(proj, proj2);
         assert_eq!(proj.name, "TEST_PROJ"); // Forced uppercase
     }
+
+    #[test]
+    #[named]
+    fn test_proj_update() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let user = User::create(&mut conn, Uuid::new_v4(), "test@example.com", None).expect("user");
+        let other = User::create(&mut conn, Uuid::new_v4(), "other@example.com", None)
+            .expect("other");
+
+        let entry_node = FlowNode::create(&mut conn, "OPEN").expect("open");
+        let exit_node = FlowNode::create(&mut conn, "CLOSED").expect("closed");
+        let flow = Flow::create(
+            &mut conn,
+            &user,
+            "Default".to_string(),
+            "".to_string(),
+            &entry_node,
+            vec![(&entry_node, &exit_node)],
+            vec![&exit_node],
+        ).expect("flow");
+        let review_node = FlowNode::create(&mut conn, "REVIEW").expect("review");
+        let done_node = FlowNode::create(&mut conn, "DONE").expect("done");
+        let review_flow = Flow::create(
+            &mut conn,
+            &user,
+            "Review".to_string(),
+            "".to_string(),
+            &review_node,
+            vec![(&review_node, &done_node)],
+            vec![&done_node],
+        ).expect("review flow");
+
+        let mut proj = Project::create(&mut conn, Uuid::new_v4(), &user, "proj", "", &flow)
+            .expect("proj");
+        assert!(proj.set_name(&mut conn, &"x".repeat(65)).is_err());
+        proj.set_name(&mut conn, "renamed").expect("rename");
+        proj.set_description(&mut conn, "Now with a description").expect("description");
+        proj.set_default_flow(&mut conn, &review_flow).expect("default flow");
+        proj.set_owner(&mut conn, &other).expect("owner");
+
+        let proj2 = Project::get(&mut conn, proj.id).expect("proj2");
+        assert_eq!(proj, proj2);
+        assert_eq!(proj2.name, "RENAMED");
+        assert_eq!(proj2.default_flow_id, review_flow.id);
+        assert_eq!(proj2.owner_id, other.id);
+    }
+
+    #[test]
+    #[named]
+    fn test_proj_pages() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let user = User::create(&mut conn, Uuid::new_v4(), "test@example.com", None).expect("user");
+        let entry_node = FlowNode::create(&mut conn, "OPEN").expect("open");
+        let exit_node = FlowNode::create(&mut conn, "CLOSED").expect("closed");
+        let flow = Flow::create(
+            &mut conn,
+            &user,
+            "Default".to_string(),
+            "".to_string(),
+            &entry_node,
+            vec![(&entry_node, &exit_node)],
+            vec![&exit_node],
+        ).expect("flow");
+        for name in ["beta", "alpha", "gamma"] {
+            Project::create(&mut conn, Uuid::new_v4(), &user, name, "", &flow).expect("proj");
+        }
+
+        let mut request = PageRequest {
+            page_size: Some(2),
+            sort: ListSort::Name,
+            ..PageRequest::default()
+        };
+        let first = Project::page(&mut conn, &request).expect("first");
+        assert_eq!(first.total, 3);
+        let names: Vec<_> = first.items.iter().map(|proj| proj.name.as_str()).collect();
+        assert_eq!(names, vec!["ALPHA", "BETA"]);
+
+        request.cursor = first.next_cursor;
+        let second = Project::page(&mut conn, &request).expect("second");
+        let names: Vec<_> = second.items.iter().map(|proj| proj.name.as_str()).collect();
+        assert_eq!(names, vec!["GAMMA"]);
+        assert!(second.next_cursor.is_none());
+
+        request.sort = ListSort::Created;
+        assert!(Project::page(&mut conn, &request).is_err());
+    }
 }
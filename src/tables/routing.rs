@@ -0,0 +1,225 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{Project, Task, ValidationErrorMessage};
+
+/// Assigns new tasks on a project to a user. A rule matches a task whose title or description
+/// contains its pattern, ignoring case, and which carries its tag; a rule without one of the
+/// two doesn't check it. Of the rules matching a task, the one with the lowest priority wins.
+#[derive(PartialEq, Queryable, Insertable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::routing_rules)]
+pub struct RoutingRule {
+    pub id: Uuid,
+    pub project_id: Uuid,
+    pub priority: i32,
+    pub pattern: Option<String>,
+    pub tag: Option<String>,
+    pub assignee_id: Uuid,
+    pub created: NaiveDateTime,
+}
+
+/// The parts of a rule which can be set through the API
+#[derive(Deserialize, Clone, Debug)]
+pub struct RoutingRuleFields {
+    pub priority: i32,
+    #[serde(default)]
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub tag: Option<String>,
+    pub assignee_id: Uuid,
+}
+
+/// Whether a task tag is the rule's tag, or a JSON tag such as `{"component": "Frontend"}`
+/// holding it as a value
+fn tag_matches(rule_tag: &str, tag: &str) -> bool {
+    if tag == rule_tag {
+        return true;
+    }
+    match serde_json::from_str::<serde_json::Value>(tag) {
+        Ok(serde_json::Value::Object(fields)) => {
+            fields.values().any(|value| value.as_str() == Some(rule_tag))
+        }
+        _ => false,
+    }
+}
+
+impl RoutingRule {
+    fn validate(conn: &mut PgConnection, fields: &RoutingRuleFields) -> QueryResult<()> {
+        use crate::schema::users::dsl;
+        let violation = |message: &str, column: &str, constraint_name: &str| {
+            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+            let msg = Box::new(ValidationErrorMessage {
+                message: message.to_string(),
+                column: column.to_string(),
+                constraint_name: constraint_name.to_string(),
+            });
+            diesel::result::Error::DatabaseError(kind, msg)
+        };
+        let blank = |value: &Option<String>| value.as_deref().map_or(true, |v| v.trim().is_empty());
+        if blank(&fields.pattern) && blank(&fields.tag) {
+            return Err(violation(
+                "Routing rule needs a pattern or a tag",
+                "pattern",
+                "routing_rule_matches",
+            ));
+        }
+        let assignee_exists: bool = diesel::select(diesel::dsl::exists(
+            dsl::users.filter(dsl::id.eq(fields.assignee_id)),
+        ))
+        .get_result(conn)?;
+        if !assignee_exists {
+            return Err(violation("No such user", "assignee_id", "routing_rule_assignee"));
+        }
+        Ok(())
+    }
+
+    fn clean(value: &Option<String>) -> Option<String> {
+        value
+            .as_deref()
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(String::from)
+    }
+
+    pub fn create(
+        conn: &mut PgConnection,
+        project: &Project,
+        fields: &RoutingRuleFields,
+    ) -> QueryResult<Self> {
+        Self::validate(conn, fields)?;
+        let rule = Self {
+            id: Uuid::new_v4(),
+            project_id: project.id,
+            priority: fields.priority,
+            pattern: Self::clean(&fields.pattern),
+            tag: Self::clean(&fields.tag),
+            assignee_id: fields.assignee_id,
+            created: chrono::Utc::now().naive_utc(),
+        };
+        diesel::insert_into(crate::schema::routing_rules::table)
+            .values(&rule)
+            .execute(conn)?;
+        Ok(rule)
+    }
+
+    pub fn update(&mut self, conn: &mut PgConnection, fields: &RoutingRuleFields) -> QueryResult<()> {
+        use crate::schema::routing_rules::dsl;
+        Self::validate(conn, fields)?;
+        self.priority = fields.priority;
+        self.pattern = Self::clean(&fields.pattern);
+        self.tag = Self::clean(&fields.tag);
+        self.assignee_id = fields.assignee_id;
+        diesel::update(dsl::routing_rules.find(self.id))
+            .set((
+                dsl::priority.eq(self.priority),
+                dsl::pattern.eq(&self.pattern),
+                dsl::tag.eq(&self.tag),
+                dsl::assignee_id.eq(self.assignee_id),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn delete(&self, conn: &mut PgConnection) -> QueryResult<()> {
+        use crate::schema::routing_rules::dsl;
+        diesel::delete(dsl::routing_rules.find(self.id)).execute(conn)?;
+        Ok(())
+    }
+
+    /// The project's rules in the order they are tried
+    pub fn list_for_project(conn: &mut PgConnection, project_id: Uuid) -> QueryResult<Vec<Self>> {
+        use crate::schema::routing_rules::dsl;
+        dsl::routing_rules
+            .filter(dsl::project_id.eq(project_id))
+            .order_by((dsl::priority.asc(), dsl::created.asc()))
+            .load::<Self>(conn)
+    }
+
+    pub fn matches(&self, text: &str, tags: &[String]) -> bool {
+        let pattern_matches = match self.pattern.as_deref() {
+            Some(pattern) => text.to_lowercase().contains(&pattern.to_lowercase()),
+            None => true,
+        };
+        let tag_matches = match self.tag.as_deref() {
+            Some(rule_tag) => tags.iter().any(|tag| tag_matches(rule_tag, tag)),
+            None => true,
+        };
+        pattern_matches && tag_matches
+    }
+
+    /// Who the project's rules assign the task to, if any rule matches it
+    pub fn route(conn: &mut PgConnection, project_id: Uuid, task: &Task) -> QueryResult<Option<Uuid>> {
+        let rules = Self::list_for_project(conn, project_id)?;
+        if rules.is_empty() {
+            return Ok(None);
+        }
+        let text = format!("{}\n{}", task.title, task.description);
+        let tags = task.tags(conn)?;
+        Ok(rules
+            .iter()
+            .find(|rule| rule.matches(&text, &tags))
+            .map(|rule| rule.assignee_id))
+    }
+}
+
+subseq_util::setup_table_crud!(RoutingRule, crate::schema::routing_rules::dsl::routing_rules);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tables::test::{fixture, Fixture, MIGRATIONS};
+    use crate::tables::User;
+    use function_name::named;
+    use subseq_util::tables::harness::{to_pg_db_name, DbHarness};
+    use subseq_util::tables::UserTable;
+
+    #[test]
+    #[named]
+    fn test_routing_rules() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let Fixture { user, mut proj, .. } = fixture(&mut conn);
+        let frontend = User::create(&mut conn, Uuid::new_v4(), "frontend@example.com", Some("FRONTEND"))
+            .expect("frontend");
+        let backend = User::create(&mut conn, Uuid::new_v4(), "backend@example.com", Some("BACKEND"))
+            .expect("backend");
+
+        let fields = |priority: i32, pattern: Option<&str>, tag: Option<&str>, assignee_id: Uuid| {
+            RoutingRuleFields {
+                priority,
+                pattern: pattern.map(String::from),
+                tag: tag.map(String::from),
+                assignee_id,
+            }
+        };
+        assert!(RoutingRule::create(&mut conn, &proj, &fields(1, None, Some(" "), frontend.id)).is_err());
+        assert!(RoutingRule::create(&mut conn, &proj, &fields(1, Some("css"), None, Uuid::new_v4())).is_err());
+
+        let mut css = RoutingRule::create(&mut conn, &proj, &fields(2, Some("CSS"), None, frontend.id))
+            .expect("css");
+        RoutingRule::create(&mut conn, &proj, &fields(1, None, Some("Backend"), backend.id))
+            .expect("backend");
+        let rules = RoutingRule::list_for_project(&mut conn, proj.id).expect("rules");
+        assert_eq!(rules.iter().map(|rule| rule.priority).collect::<Vec<_>>(), vec![1, 2]);
+
+        let styling = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "Fix the css", "", &user)
+            .expect("styling");
+        assert_eq!(RoutingRule::route(&mut conn, proj.id, &styling).expect("route"), Some(frontend.id));
+
+        // The tag rule comes first, and JSON tags match on their values
+        styling.add_tag(&mut conn, r#"{"component":"Backend"}"#).expect("tag");
+        assert_eq!(RoutingRule::route(&mut conn, proj.id, &styling).expect("route"), Some(backend.id));
+
+        let other = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "Write docs", "", &user)
+            .expect("other");
+        assert_eq!(RoutingRule::route(&mut conn, proj.id, &other).expect("route"), None);
+
+        css.update(&mut conn, &fields(2, Some("docs"), None, frontend.id)).expect("update");
+        assert_eq!(RoutingRule::route(&mut conn, proj.id, &other).expect("route"), Some(frontend.id));
+        css.delete(&mut conn).expect("delete");
+        assert_eq!(RoutingRule::route(&mut conn, proj.id, &other).expect("route"), None);
+    }
+}
@@ -0,0 +1,232 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::Task;
+
+/// The embedding of a task's title and description, tagged with the model which produced it
+/// since embeddings from different models can't be compared
+#[derive(PartialEq, Queryable, Insertable, Clone, Debug)]
+#[diesel(table_name = crate::schema::task_embeddings)]
+pub struct TaskEmbedding {
+    pub task_id: Uuid,
+    pub model: String,
+    pub embedding: Vec<f32>,
+    pub updated: NaiveDateTime,
+}
+
+/// A task and how close it is to what it was compared with, from -1 to 1
+#[derive(Clone, Debug, Serialize)]
+pub struct SimilarTask {
+    pub task: Task,
+    pub score: f32,
+}
+
+/// Cosine similarity, or 0 when either vector is empty or the lengths differ
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+impl TaskEmbedding {
+    pub fn upsert(
+        conn: &mut PgConnection,
+        task_id: Uuid,
+        model: &str,
+        embedding: Vec<f32>,
+    ) -> QueryResult<Self> {
+        use crate::schema::task_embeddings::dsl;
+        let row = Self {
+            task_id,
+            model: model.to_string(),
+            embedding,
+            updated: chrono::Utc::now().naive_utc(),
+        };
+        diesel::insert_into(dsl::task_embeddings)
+            .values(&row)
+            .on_conflict(dsl::task_id)
+            .do_update()
+            .set((
+                dsl::model.eq(&row.model),
+                dsl::embedding.eq(&row.embedding),
+                dsl::updated.eq(row.updated),
+            ))
+            .get_result::<Self>(conn)
+    }
+
+    pub fn get(conn: &mut PgConnection, task_id: Uuid, model: &str) -> Option<Self> {
+        use crate::schema::task_embeddings::dsl;
+        dsl::task_embeddings
+            .filter(dsl::task_id.eq(task_id))
+            .filter(dsl::model.eq(model))
+            .get_result::<Self>(conn)
+            .optional()
+            .ok()
+            .flatten()
+    }
+
+    /// The task's stored embedding, from whichever model last embedded it
+    pub fn for_task(conn: &mut PgConnection, task_id: Uuid) -> Option<Self> {
+        use crate::schema::task_embeddings::dsl;
+        dsl::task_embeddings
+            .find(task_id)
+            .get_result::<Self>(conn)
+            .optional()
+            .ok()
+            .flatten()
+    }
+
+    /// The oldest tasks with no embedding from this model
+    pub fn missing(conn: &mut PgConnection, model: &str, limit: i64) -> QueryResult<Vec<Task>> {
+        use crate::schema::{task_embeddings, tasks};
+        let embedded = task_embeddings::table
+            .filter(task_embeddings::model.eq(model))
+            .select(task_embeddings::task_id);
+        tasks::table
+            .filter(diesel::dsl::not(tasks::id.eq_any(embedded)))
+            .order_by((tasks::created.asc(), tasks::id.asc()))
+            .limit(limit)
+            .load::<Task>(conn)
+    }
+}
+
+impl Task {
+    /// Tasks whose embeddings from `model` are closest to `embedding`, most similar first,
+    /// dropping any scoring under `min_score`. Scores are computed here rather than in
+    /// Postgres, which keeps the database free of vector extensions; `project_ids` bounds how
+    /// many embeddings are loaded.
+    pub fn similar(
+        conn: &mut PgConnection,
+        embedding: &[f32],
+        model: &str,
+        project_ids: Option<&[Uuid]>,
+        exclude: Option<Uuid>,
+        min_score: f32,
+        limit: usize,
+    ) -> QueryResult<Vec<SimilarTask>> {
+        use crate::schema::{task_embeddings, task_projects, tasks};
+        let mut query = task_embeddings::table
+            .inner_join(tasks::table)
+            .filter(task_embeddings::model.eq(model))
+            .select((task_embeddings::embedding, tasks::all_columns))
+            .into_boxed();
+        if let Some(project_ids) = project_ids {
+            let in_project = task_projects::table
+                .filter(task_projects::project_id.eq_any(project_ids))
+                .select(task_projects::task_id);
+            query = query.filter(tasks::id.eq_any(in_project));
+        }
+        if let Some(exclude) = exclude {
+            query = query.filter(tasks::id.ne(exclude));
+        }
+        let candidates = query.load::<(Vec<f32>, Task)>(conn)?;
+
+        let mut similar: Vec<SimilarTask> = candidates
+            .into_iter()
+            .map(|(other, task)| SimilarTask {
+                score: cosine_similarity(embedding, &other),
+                task,
+            })
+            .filter(|similar| similar.score >= min_score)
+            .collect();
+        similar.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.task.id.cmp(&b.task.id)));
+        similar.truncate(limit);
+        Ok(similar)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::embeddings::{HashingEmbedder, DUPLICATE_THRESHOLD};
+    use crate::tables::test::{fixture, Fixture, MIGRATIONS};
+    use crate::tables::Project;
+    use function_name::named;
+    use subseq_util::tables::harness::{to_pg_db_name, DbHarness};
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]) + 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[1.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn test_hashing_embedder() {
+        let embedder = HashingEmbedder::new(256);
+        let task = embedder.embed_text("Fix the login redirect bug");
+        assert_eq!(task.len(), 256);
+        assert_eq!(task, embedder.embed_text("fix the LOGIN redirect bug!"));
+        let reworded = embedder.embed_text("Fix login redirect bug");
+        assert!(cosine_similarity(&task, &reworded) >= DUPLICATE_THRESHOLD);
+        let unrelated = embedder.embed_text("Add a dark mode to the settings page");
+        assert!(cosine_similarity(&task, &unrelated) < DUPLICATE_THRESHOLD);
+        assert!(embedder.embed_text("").iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    #[named]
+    fn test_similar_tasks() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let Fixture { user, flow, mut proj } = fixture(&mut conn);
+        let mut other = Project::create(&mut conn, Uuid::new_v4(), &user, "other", "", &flow)
+            .expect("other");
+
+        let task_a = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "A", "", &user).expect("a");
+        let task_b = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "B", "", &user).expect("b");
+        let task_c = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "C", "", &user).expect("c");
+        let task_d = Task::create(&mut conn, Uuid::new_v4(), &mut other, "D", "", &user).expect("d");
+
+        let missing = TaskEmbedding::missing(&mut conn, "test", 10).expect("missing");
+        assert_eq!(missing.len(), 4);
+
+        TaskEmbedding::upsert(&mut conn, task_a.id, "test", vec![1.0, 0.0, 0.0]).expect("a");
+        TaskEmbedding::upsert(&mut conn, task_b.id, "test", vec![0.9, 0.1, 0.0]).expect("b");
+        TaskEmbedding::upsert(&mut conn, task_c.id, "test", vec![0.0, 0.0, 1.0]).expect("c");
+        TaskEmbedding::upsert(&mut conn, task_d.id, "test", vec![1.0, 0.0, 0.0]).expect("d");
+        let missing = TaskEmbedding::missing(&mut conn, "test", 10).expect("missing");
+        assert!(missing.is_empty());
+        let missing = TaskEmbedding::missing(&mut conn, "other-model", 10).expect("missing");
+        assert_eq!(missing.len(), 4);
+
+        let query = [1.0, 0.0, 0.0];
+        let similar = Task::similar(&mut conn, &query, "test", Some(&[proj.id]), None, 0.5, 10)
+            .expect("similar");
+        let ids: Vec<Uuid> = similar.iter().map(|similar| similar.task.id).collect();
+        assert_eq!(ids, vec![task_a.id, task_b.id]);
+
+        let similar = Task::similar(&mut conn, &query, "test", Some(&[proj.id]), Some(task_a.id), 0.5, 10)
+            .expect("similar");
+        assert_eq!(similar.len(), 1);
+        assert_eq!(similar[0].task.id, task_b.id);
+
+        let similar = Task::similar(&mut conn, &query, "test", None, None, -1.0, 2)
+            .expect("similar");
+        assert_eq!(similar.len(), 2);
+        assert!(similar.iter().all(|similar| similar.task.id != task_c.id));
+
+        let similar = Task::similar(&mut conn, &query, "other-model", None, None, -1.0, 10)
+            .expect("similar");
+        assert!(similar.is_empty());
+
+        // Re-embedding under another model replaces the stored embedding
+        TaskEmbedding::upsert(&mut conn, task_b.id, "other-model", vec![1.0]).expect("b");
+        assert!(TaskEmbedding::get(&mut conn, task_b.id, "test").is_none());
+        let stored = TaskEmbedding::get(&mut conn, task_b.id, "other-model").expect("stored");
+        assert_eq!(stored.embedding, vec![1.0]);
+        assert_eq!(TaskEmbedding::for_task(&mut conn, task_b.id), Some(stored));
+    }
+}
@@ -0,0 +1,1021 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::Bool;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::pagination::{Cursor, SortValue};
+use super::{ActiveProject, Task, TaskLinkType};
+use crate::schema::tasks;
+
+/// Why a query string was refused, and where in it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueryError {
+    pub message: String,
+    pub position: usize,
+}
+
+impl QueryError {
+    fn new(message: impl Into<String>, position: usize) -> Self {
+        Self {
+            message: message.into(),
+            position,
+        }
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProjectRef {
+    Id(Uuid),
+    Active,
+    Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserRef {
+    Id(Uuid),
+    Current,
+    Nobody,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextMatch {
+    Contains(String),
+    Exact(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Before,
+    AtOrBefore,
+    After,
+    AtOrAfter,
+    /// Within the day starting at the given time
+    OnDay,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeRef {
+    Id(Uuid),
+    Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskRef {
+    Id(Uuid),
+    Slug(String),
+}
+
+/// The `is:` conditions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskFlag {
+    /// Some flow has not reached an exit node
+    Open,
+    /// Every flow sits on an exit node
+    Closed,
+    /// Depends on a task that is still open
+    Blocked,
+    /// Has a job without a result
+    Running,
+}
+
+/// A single condition on a task
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskFilter {
+    Project(ProjectRef),
+    Assignee(UserRef),
+    Author(UserRef),
+    Watcher(UserRef),
+    State(NodeRef),
+    Is(TaskFlag),
+    Tag(String),
+    SubtaskOf(TaskRef),
+    Title(TextMatch),
+    Description(TextMatch),
+    Slug(TextMatch),
+    Created(Comparison, NaiveDateTime),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryExpr {
+    Filter(TaskFilter),
+    And(Box<QueryExpr>, Box<QueryExpr>),
+    Or(Box<QueryExpr>, Box<QueryExpr>),
+    Not(Box<QueryExpr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OrderField {
+    Created,
+    Updated,
+    Title,
+    Slug,
+    /// The number at the end of the slug, so ZINI-9 comes before ZINI-10
+    Number,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TaskOrder {
+    pub field: OrderField,
+    pub descending: bool,
+}
+
+/// A parsed task query such as
+/// `project:ZINI assignee:self (title:"auth" or description:oidc) created>2024-01-01 order by created desc`
+///
+/// Adjacent conditions are joined with `and`. Text fields match substrings with `:` and whole
+/// values with `=`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TaskQuery {
+    pub filter: Option<QueryExpr>,
+    pub order: Vec<TaskOrder>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Colon,
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Op(Op),
+    LParen,
+    RParen,
+    Comma,
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, QueryError> {
+    let mut tokens = vec![];
+    let mut chars = input.char_indices().peekable();
+    while let Some(&(pos, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | ',' | ':' | '=' => {
+                chars.next();
+                let token = match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    ',' => Token::Comma,
+                    ':' => Token::Op(Op::Colon),
+                    _ => Token::Op(Op::Eq),
+                };
+                tokens.push((pos, token));
+            }
+            '<' | '>' => {
+                chars.next();
+                let or_equal = matches!(chars.peek(), Some(&(_, '=')));
+                if or_equal {
+                    chars.next();
+                }
+                let op = match (c, or_equal) {
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    ('>', false) => Op::Gt,
+                    _ => Op::Ge,
+                };
+                tokens.push((pos, Token::Op(op)));
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                let mut closed = false;
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '"' => {
+                            closed = true;
+                            break;
+                        }
+                        '\\' => match chars.next() {
+                            Some((_, escaped)) => value.push(escaped),
+                            None => break,
+                        },
+                        c => value.push(c),
+                    }
+                }
+                if !closed {
+                    return Err(QueryError::new("Unterminated string", pos));
+                }
+                tokens.push((pos, Token::Quoted(value)));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_whitespace() || "():=<>,\"".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push((pos, Token::Word(word)));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_user(value: &str, position: usize, allow_nobody: bool) -> Result<UserRef, QueryError> {
+    match value.to_ascii_lowercase().as_str() {
+        "self" | "me" => Ok(UserRef::Current),
+        "none" if allow_nobody => Ok(UserRef::Nobody),
+        _ => Uuid::try_parse(value).map(UserRef::Id).map_err(|_| {
+            QueryError::new(
+                format!("Expected a user id or self, got {}", value),
+                position,
+            )
+        }),
+    }
+}
+
+fn parse_time(value: &str, position: usize) -> Result<NaiveDateTime, QueryError> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).expect("midnight is valid"));
+    }
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S").map_err(|_| {
+        QueryError::new(
+            format!("Expected a date like 2024-01-31, got {}", value),
+            position,
+        )
+    })
+}
+
+fn parse_filter(
+    field: &str,
+    field_position: usize,
+    op: Op,
+    value: String,
+    position: usize,
+) -> Result<TaskFilter, QueryError> {
+    let equality = |filter: TaskFilter| match op {
+        Op::Colon | Op::Eq => Ok(filter),
+        _ => Err(QueryError::new(
+            format!("{} only supports : and =", field),
+            position,
+        )),
+    };
+    let text = |value: String| match op {
+        Op::Colon => Ok(TextMatch::Contains(value)),
+        Op::Eq => Ok(TextMatch::Exact(value)),
+        _ => Err(QueryError::new(
+            format!("{} only supports : and =", field),
+            position,
+        )),
+    };
+    match field.to_ascii_lowercase().as_str() {
+        "project" => {
+            let project = if value.eq_ignore_ascii_case("active") {
+                ProjectRef::Active
+            } else if let Ok(id) = Uuid::try_parse(&value) {
+                ProjectRef::Id(id)
+            } else {
+                ProjectRef::Name(value.to_ascii_uppercase())
+            };
+            equality(TaskFilter::Project(project))
+        }
+        "assignee" => equality(TaskFilter::Assignee(parse_user(&value, position, true)?)),
+        "author" => equality(TaskFilter::Author(parse_user(&value, position, false)?)),
+        "watcher" => equality(TaskFilter::Watcher(parse_user(&value, position, false)?)),
+        "state" => {
+            let node = match Uuid::try_parse(&value) {
+                Ok(id) => NodeRef::Id(id),
+                Err(_) => NodeRef::Name(value.to_ascii_uppercase()),
+            };
+            equality(TaskFilter::State(node))
+        }
+        "is" => {
+            let flag = match value.to_ascii_lowercase().as_str() {
+                "open" => TaskFlag::Open,
+                "closed" => TaskFlag::Closed,
+                "blocked" => TaskFlag::Blocked,
+                "running" => TaskFlag::Running,
+                _ => {
+                    return Err(QueryError::new(
+                        format!("Expected open, closed, blocked or running, got {}", value),
+                        position,
+                    ))
+                }
+            };
+            equality(TaskFilter::Is(flag))
+        }
+        "tag" => equality(TaskFilter::Tag(value)),
+        "subtask" => {
+            let parent = match Uuid::try_parse(&value) {
+                Ok(id) => TaskRef::Id(id),
+                Err(_) => TaskRef::Slug(value.to_ascii_uppercase()),
+            };
+            equality(TaskFilter::SubtaskOf(parent))
+        }
+        "title" => Ok(TaskFilter::Title(text(value)?)),
+        "description" => Ok(TaskFilter::Description(text(value)?)),
+        "slug" => Ok(TaskFilter::Slug(text(value.to_ascii_uppercase())?)),
+        "created" => {
+            let comparison = match op {
+                Op::Colon | Op::Eq => Comparison::OnDay,
+                Op::Lt => Comparison::Before,
+                Op::Le => Comparison::AtOrBefore,
+                Op::Gt => Comparison::After,
+                Op::Ge => Comparison::AtOrAfter,
+            };
+            Ok(TaskFilter::Created(
+                comparison,
+                parse_time(&value, position)?,
+            ))
+        }
+        _ => Err(QueryError::new(
+            format!("Unknown field {}", field),
+            field_position,
+        )),
+    }
+}
+
+/// How deeply conditions may nest, counting parentheses, `not` and each `and`/`or`. Parsing and
+/// compiling recurse once per level, so this keeps client queries from exhausting the stack.
+pub const MAX_DEPTH: usize = 64;
+
+/// An expression with the depth of its deepest condition
+type Parsed = (QueryExpr, usize);
+
+fn too_deep(position: usize) -> QueryError {
+    QueryError::new(
+        format!("Query nests more than {} levels deep", MAX_DEPTH),
+        position,
+    )
+}
+
+/// Joins two expressions with `and` or `or`, refusing trees deeper than MAX_DEPTH
+fn join(
+    op: fn(Box<QueryExpr>, Box<QueryExpr>) -> QueryExpr,
+    (lhs, lhs_depth): Parsed,
+    (rhs, rhs_depth): Parsed,
+    position: usize,
+) -> Result<Parsed, QueryError> {
+    let depth = lhs_depth.max(rhs_depth) + 1;
+    if depth > MAX_DEPTH {
+        return Err(too_deep(position));
+    }
+    Ok((op(Box::new(lhs), Box::new(rhs)), depth))
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    index: usize,
+    end: usize,
+    /// Parentheses and `not`s open at the current token
+    nesting: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.index)
+            .map(|(pos, _)| *pos)
+            .unwrap_or(self.end)
+    }
+
+    fn next(&mut self) -> Option<(usize, Token)> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), QueryError> {
+        if self.peek_keyword(keyword) {
+            self.index += 1;
+            Ok(())
+        } else {
+            Err(QueryError::new(
+                format!("Expected {}", keyword),
+                self.position(),
+            ))
+        }
+    }
+
+    fn at_expr_end(&self) -> bool {
+        matches!(self.peek(), None | Some(Token::RParen))
+            || self.peek_keyword("or")
+            || self.peek_keyword("order")
+    }
+
+    fn parse_query(&mut self) -> Result<TaskQuery, QueryError> {
+        let filter = if self.at_expr_end() {
+            None
+        } else {
+            Some(self.parse_or()?.0)
+        };
+        let mut order = vec![];
+        if self.peek_keyword("order") {
+            self.index += 1;
+            self.expect_keyword("by")?;
+            loop {
+                order.push(self.parse_order()?);
+                if matches!(self.peek(), Some(Token::Comma)) {
+                    self.index += 1;
+                } else {
+                    break;
+                }
+            }
+        }
+        if self.peek().is_some() {
+            return Err(QueryError::new("Unexpected input", self.position()));
+        }
+        Ok(TaskQuery { filter, order })
+    }
+
+    fn parse_order(&mut self) -> Result<TaskOrder, QueryError> {
+        let position = self.position();
+        let field = match self.next() {
+            Some((_, Token::Word(word))) => match word.to_ascii_lowercase().as_str() {
+                "created" => OrderField::Created,
+                "updated" => OrderField::Updated,
+                "title" => OrderField::Title,
+                "slug" => OrderField::Slug,
+                "number" => OrderField::Number,
+                _ => {
+                    return Err(QueryError::new(
+                        format!("Cannot order by {}", word),
+                        position,
+                    ))
+                }
+            },
+            _ => return Err(QueryError::new("Expected a field to order by", position)),
+        };
+        let descending = if self.peek_keyword("desc") {
+            self.index += 1;
+            true
+        } else {
+            if self.peek_keyword("asc") {
+                self.index += 1;
+            }
+            false
+        };
+        Ok(TaskOrder { field, descending })
+    }
+
+    fn parse_or(&mut self) -> Result<Parsed, QueryError> {
+        let mut expr = self.parse_and()?;
+        while self.peek_keyword("or") {
+            let position = self.position();
+            self.index += 1;
+            let rhs = self.parse_and()?;
+            expr = join(QueryExpr::Or, expr, rhs, position)?;
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Parsed, QueryError> {
+        let mut expr = self.parse_unary()?;
+        loop {
+            let position = self.position();
+            if self.peek_keyword("and") {
+                self.index += 1;
+            } else if self.at_expr_end() {
+                break;
+            }
+            let rhs = self.parse_unary()?;
+            expr = join(QueryExpr::And, expr, rhs, position)?;
+        }
+        Ok(expr)
+    }
+
+    /// Enters a parenthesis or `not`, refusing to nest deeper than MAX_DEPTH
+    fn enter(&mut self, position: usize) -> Result<(), QueryError> {
+        self.nesting += 1;
+        if self.nesting > MAX_DEPTH {
+            return Err(too_deep(position));
+        }
+        Ok(())
+    }
+
+    fn parse_unary(&mut self) -> Result<Parsed, QueryError> {
+        let position = self.position();
+        if self.peek_keyword("not") {
+            self.index += 1;
+            self.enter(position)?;
+            let (inner, depth) = self.parse_unary()?;
+            self.nesting -= 1;
+            if depth + 1 > MAX_DEPTH {
+                return Err(too_deep(position));
+            }
+            return Ok((QueryExpr::Not(Box::new(inner)), depth + 1));
+        }
+        match self.next() {
+            Some((_, Token::LParen)) => {
+                self.enter(position)?;
+                let expr = self.parse_or()?;
+                self.nesting -= 1;
+                match self.next() {
+                    Some((_, Token::RParen)) => Ok(expr),
+                    _ => Err(QueryError::new("Expected )", position)),
+                }
+            }
+            Some((_, Token::Word(field))) => {
+                let op = match self.next() {
+                    Some((_, Token::Op(op))) => op,
+                    _ => {
+                        return Err(QueryError::new(
+                            format!("Expected an operator after {}", field),
+                            position,
+                        ))
+                    }
+                };
+                let value_position = self.position();
+                let value = match self.next() {
+                    Some((_, Token::Word(value))) | Some((_, Token::Quoted(value))) => value,
+                    _ => {
+                        return Err(QueryError::new(
+                            format!("Expected a value for {}", field),
+                            value_position,
+                        ))
+                    }
+                };
+                let filter = parse_filter(&field, position, op, value, value_position)?;
+                Ok((QueryExpr::Filter(filter), 1))
+            }
+            Some(_) => Err(QueryError::new("Expected a condition", position)),
+            None => Err(QueryError::new("Unexpected end of query", position)),
+        }
+    }
+}
+
+type BoxedCondition = Box<dyn BoxableExpression<tasks::table, Pg, SqlType = Bool>>;
+
+impl TaskQuery {
+    pub fn parse(input: &str) -> Result<Self, QueryError> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser {
+            tokens,
+            index: 0,
+            end: input.len(),
+            nesting: 0,
+        };
+        parser.parse_query()
+    }
+
+    /// A query from field/value pairs, each matched as `field:"value"`
+    pub fn from_fields(fields: &HashMap<String, String>) -> Result<Self, QueryError> {
+        let mut filter: Option<Parsed> = None;
+        for (field, value) in fields {
+            let condition = QueryExpr::Filter(parse_filter(field, 0, Op::Colon, value.clone(), 0)?);
+            filter = Some(match filter {
+                Some(expr) => join(QueryExpr::And, expr, (condition, 1), 0)?,
+                None => (condition, 1),
+            });
+        }
+        Ok(Self {
+            filter: filter.map(|(expr, _)| expr),
+            order: vec![],
+        })
+    }
+
+    /// The order results come back in, newest first unless the query gives one. Ties are
+    /// broken by id.
+    pub fn sort_order(&self) -> Vec<TaskOrder> {
+        if self.order.is_empty() {
+            vec![TaskOrder {
+                field: OrderField::Created,
+                descending: true,
+            }]
+        } else {
+            self.order.clone()
+        }
+    }
+
+    /// This query restricted to tasks that also match the filter
+    pub fn and(self, filter: TaskFilter) -> Self {
+        let condition = QueryExpr::Filter(filter);
+        let filter = match self.filter {
+            Some(expr) => QueryExpr::And(Box::new(condition), Box::new(expr)),
+            None => condition,
+        };
+        Self {
+            filter: Some(filter),
+            order: self.order,
+        }
+    }
+
+    pub(crate) fn condition(
+        &self,
+        conn: &mut PgConnection,
+        user_id: Uuid,
+    ) -> Option<BoxedCondition> {
+        self.filter
+            .as_ref()
+            .map(|expr| compile(expr, conn, user_id))
+    }
+}
+
+/// Matches a text column by substring, ignoring case, or by the whole value
+macro_rules! text_match {
+    ($column:expr, $text:expr) => {
+        match $text {
+            TextMatch::Contains(value) => {
+                Box::new($column.ilike(format!("%{}%", value))) as BoxedCondition
+            }
+            TextMatch::Exact(value) => Box::new($column.eq(value.clone())),
+        }
+    };
+}
+
+fn compile(expr: &QueryExpr, conn: &mut PgConnection, user_id: Uuid) -> BoxedCondition {
+    match expr {
+        QueryExpr::And(lhs, rhs) => {
+            Box::new(compile(lhs, conn, user_id).and(compile(rhs, conn, user_id)))
+        }
+        QueryExpr::Or(lhs, rhs) => {
+            Box::new(compile(lhs, conn, user_id).or(compile(rhs, conn, user_id)))
+        }
+        QueryExpr::Not(inner) => Box::new(diesel::dsl::not(compile(inner, conn, user_id))),
+        QueryExpr::Filter(filter) => compile_filter(filter, conn, user_id),
+    }
+}
+
+/// The ids of tasks with a flow that has not reached one of its exit nodes
+macro_rules! open_task_ids {
+    () => {
+        task_flows::table
+            .left_join(
+                flow_exits::table.on(flow_exits::flow_id
+                    .eq(task_flows::flow_id)
+                    .and(flow_exits::node_id.nullable().eq(task_flows::current_node_id))),
+            )
+            .filter(flow_exits::node_id.is_null())
+            .select(task_flows::task_id)
+    };
+}
+
+fn compile_filter(filter: &TaskFilter, conn: &mut PgConnection, user_id: Uuid) -> BoxedCondition {
+    use crate::schema::{
+        flow_exits, flow_nodes, job_results, jobs, projects, task_flows, task_links,
+        task_projects, task_tags, task_watchers,
+    };
+    match filter {
+        TaskFilter::Project(project) => {
+            let in_projects = task_projects::table.select(task_projects::task_id);
+            match project {
+                ProjectRef::Id(id) => Box::new(
+                    tasks::id.eq_any(in_projects.filter(task_projects::project_id.eq(*id))),
+                ),
+                ProjectRef::Name(name) => Box::new(
+                    tasks::id.eq_any(
+                        in_projects.filter(
+                            task_projects::project_id.eq_any(
+                                projects::table
+                                    .select(projects::id)
+                                    .filter(projects::name.eq(name.clone())),
+                            ),
+                        ),
+                    ),
+                ),
+                ProjectRef::Active => {
+                    // No active project matches nothing
+                    let active: Vec<Uuid> = ActiveProject::get(conn, user_id)
+                        .map(|active| active.project_id)
+                        .into_iter()
+                        .collect();
+                    Box::new(
+                        tasks::id
+                            .eq_any(in_projects.filter(task_projects::project_id.eq_any(active))),
+                    )
+                }
+            }
+        }
+        TaskFilter::Assignee(user) => match user {
+            // Unassigned tasks compare as false rather than null so `not assignee:self` keeps them
+            UserRef::Id(id) => Box::new(tasks::assignee_id.is_not_distinct_from(*id)),
+            UserRef::Current => Box::new(tasks::assignee_id.is_not_distinct_from(user_id)),
+            UserRef::Nobody => Box::new(tasks::assignee_id.is_null()),
+        },
+        TaskFilter::Author(user) => match user {
+            UserRef::Id(id) => Box::new(tasks::author_id.eq(*id)),
+            UserRef::Current => Box::new(tasks::author_id.eq(user_id)),
+            // Every task has an author, and the parser refuses author:none
+            UserRef::Nobody => Box::new(diesel::dsl::sql::<Bool>("FALSE")),
+        },
+        TaskFilter::Watcher(user) => {
+            // The parser refuses watcher:none
+            let watcher_id = match user {
+                UserRef::Id(id) => *id,
+                _ => user_id,
+            };
+            Box::new(
+                tasks::id.eq_any(
+                    task_watchers::table
+                        .select(task_watchers::task_id)
+                        .filter(task_watchers::watcher_id.eq(watcher_id)),
+                ),
+            )
+        }
+        TaskFilter::State(node) => {
+            let in_flows = task_flows::table.select(task_flows::task_id);
+            match node {
+                NodeRef::Id(node_id) => Box::new(
+                    tasks::id.eq_any(in_flows.filter(task_flows::current_node_id.eq(*node_id))),
+                ),
+                NodeRef::Name(name) => Box::new(
+                    tasks::id.eq_any(
+                        in_flows.filter(
+                            task_flows::current_node_id.eq_any(
+                                flow_nodes::table
+                                    .select(flow_nodes::id.nullable())
+                                    .filter(flow_nodes::node_name.eq(name.clone())),
+                            ),
+                        ),
+                    ),
+                ),
+            }
+        }
+        TaskFilter::Is(TaskFlag::Open) => Box::new(tasks::id.eq_any(open_task_ids!())),
+        TaskFilter::Is(TaskFlag::Closed) => {
+            Box::new(diesel::dsl::not(tasks::id.eq_any(open_task_ids!())))
+        }
+        TaskFilter::Is(TaskFlag::Blocked) => Box::new(
+            tasks::id.eq_any(
+                task_links::table
+                    .select(task_links::task_from_id)
+                    .filter(task_links::link_type.eq(i32::from(TaskLinkType::DependsOn)))
+                    .filter(task_links::task_to_id.eq_any(open_task_ids!())),
+            ),
+        ),
+        TaskFilter::Is(TaskFlag::Running) => Box::new(
+            tasks::id.eq_any(
+                jobs::table
+                    .left_join(job_results::table)
+                    .filter(job_results::job_id.is_null())
+                    .select(jobs::task_id),
+            ),
+        ),
+        TaskFilter::SubtaskOf(parent) => {
+            let subtasks = task_links::table
+                .select(task_links::task_from_id)
+                .filter(task_links::link_type.eq(i32::from(TaskLinkType::SubtaskOf)));
+            match parent {
+                TaskRef::Id(parent_id) => Box::new(
+                    tasks::id.eq_any(subtasks.filter(task_links::task_to_id.eq(*parent_id))),
+                ),
+                TaskRef::Slug(slug) => {
+                    // Resolved up front since the subselect would shadow the outer tasks table
+                    let parents: Vec<Uuid> = tasks::table
+                        .select(tasks::id)
+                        .filter(tasks::slug.eq(slug.clone()))
+                        .load(conn)
+                        .unwrap_or_default();
+                    Box::new(
+                        tasks::id.eq_any(subtasks.filter(task_links::task_to_id.eq_any(parents))),
+                    )
+                }
+            }
+        }
+        TaskFilter::Tag(tag) => Box::new(
+            tasks::id.eq_any(
+                task_tags::table
+                    .select(task_tags::task_id)
+                    .filter(task_tags::tag_name.eq(tag.clone())),
+            ),
+        ),
+        TaskFilter::Title(text) => text_match!(tasks::title, text),
+        TaskFilter::Description(text) => text_match!(tasks::description, text),
+        TaskFilter::Slug(text) => text_match!(tasks::slug, text),
+        TaskFilter::Created(comparison, time) => match comparison {
+            Comparison::Before => Box::new(tasks::created.lt(*time)),
+            Comparison::AtOrBefore => Box::new(tasks::created.le(*time)),
+            Comparison::After => Box::new(tasks::created.gt(*time)),
+            Comparison::AtOrAfter => Box::new(tasks::created.ge(*time)),
+            Comparison::OnDay => Box::new(
+                tasks::created
+                    .ge(*time)
+                    .and(tasks::created.lt(*time + chrono::Duration::days(1))),
+            ),
+        },
+    }
+}
+
+const SLUG_NUMBER_SQL: &str = "COALESCE(CAST(substring(tasks.slug from '[0-9]+$') AS BIGINT), 0)";
+
+fn slug_number(slug: &str) -> i64 {
+    let digits = slug.len() - slug.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    slug[slug.len() - digits..].parse().unwrap_or(0)
+}
+
+impl OrderField {
+    fn value(&self, task: &Task) -> SortValue {
+        match self {
+            Self::Created => SortValue::Time(task.created),
+            Self::Updated => SortValue::Time(task.updated),
+            Self::Title => SortValue::Text(task.title.clone()),
+            Self::Slug => SortValue::Text(task.slug.clone()),
+            Self::Number => SortValue::Number(slug_number(&task.slug)),
+        }
+    }
+}
+
+/// Where the page after this task starts
+pub(crate) fn task_cursor(order: &[TaskOrder], task: &Task) -> Cursor {
+    Cursor {
+        keys: order.iter().map(|order| order.field.value(task)).collect(),
+        id: task.id,
+    }
+}
+
+pub(crate) fn order_tasks(
+    mut query: tasks::BoxedQuery<'static, Pg>,
+    order: &[TaskOrder],
+) -> tasks::BoxedQuery<'static, Pg> {
+    use diesel::dsl::sql;
+    use diesel::sql_types::BigInt;
+    for order in order {
+        query = match (order.field, order.descending) {
+            (OrderField::Created, false) => query.then_order_by(tasks::created.asc()),
+            (OrderField::Created, true) => query.then_order_by(tasks::created.desc()),
+            (OrderField::Updated, false) => query.then_order_by(tasks::updated.asc()),
+            (OrderField::Updated, true) => query.then_order_by(tasks::updated.desc()),
+            (OrderField::Title, false) => query.then_order_by(tasks::title.asc()),
+            (OrderField::Title, true) => query.then_order_by(tasks::title.desc()),
+            (OrderField::Slug, false) => query.then_order_by(tasks::slug.asc()),
+            (OrderField::Slug, true) => query.then_order_by(tasks::slug.desc()),
+            (OrderField::Number, false) => {
+                query.then_order_by(sql::<BigInt>(SLUG_NUMBER_SQL).asc())
+            }
+            (OrderField::Number, true) => {
+                query.then_order_by(sql::<BigInt>(SLUG_NUMBER_SQL).desc())
+            }
+        };
+    }
+    query.then_order_by(tasks::id.asc())
+}
+
+#[derive(Clone, Copy)]
+enum KeyOp {
+    Eq,
+    Lt,
+    Gt,
+}
+
+macro_rules! compare_key {
+    ($column:expr, $op:expr, $value:expr) => {
+        match $op {
+            KeyOp::Eq => Box::new($column.eq($value)) as BoxedCondition,
+            KeyOp::Lt => Box::new($column.lt($value)),
+            KeyOp::Gt => Box::new($column.gt($value)),
+        }
+    };
+}
+
+fn compare_field(
+    field: OrderField,
+    op: KeyOp,
+    cursor: &Cursor,
+    index: usize,
+) -> QueryResult<BoxedCondition> {
+    use diesel::dsl::sql;
+    use diesel::sql_types::BigInt;
+    Ok(match field {
+        OrderField::Created => compare_key!(tasks::created, op, cursor.time(index)?),
+        OrderField::Updated => compare_key!(tasks::updated, op, cursor.time(index)?),
+        OrderField::Title => compare_key!(tasks::title, op, cursor.text(index)?),
+        OrderField::Slug => compare_key!(tasks::slug, op, cursor.text(index)?),
+        OrderField::Number => {
+            compare_key!(sql::<BigInt>(SLUG_NUMBER_SQL), op, cursor.number(index)?)
+        }
+    })
+}
+
+/// Tasks that come after the cursor in the given order. Each sort key only decides the order
+/// when every key before it is equal, and the id decides when all of them are.
+pub(crate) fn after_cursor(order: &[TaskOrder], cursor: &Cursor) -> QueryResult<BoxedCondition> {
+    cursor.expect_keys(order.len())?;
+    let mut after: Option<BoxedCondition> = None;
+    for index in 0..=order.len() {
+        let mut term: BoxedCondition = match order.get(index) {
+            Some(order) => {
+                let op = if order.descending { KeyOp::Lt } else { KeyOp::Gt };
+                compare_field(order.field, op, cursor, index)?
+            }
+            None => Box::new(tasks::id.gt(cursor.id)),
+        };
+        for (prior, order) in order.iter().enumerate().take(index) {
+            term = Box::new(compare_field(order.field, KeyOp::Eq, cursor, prior)?.and(term));
+        }
+        after = Some(match after {
+            Some(after) => Box::new(after.or(term)),
+            None => term,
+        });
+    }
+    Ok(after.expect("the id always decides"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_query() {
+        let query = TaskQuery::parse(
+            r#"project:ZINI assignee:self and (title:"auth flow" or description:oidc) created>2024-01-01 order by created desc, slug"#,
+        )
+        .expect("query");
+        let project = QueryExpr::Filter(TaskFilter::Project(ProjectRef::Name("ZINI".to_string())));
+        let assignee = QueryExpr::Filter(TaskFilter::Assignee(UserRef::Current));
+        let text = QueryExpr::Or(
+            Box::new(QueryExpr::Filter(TaskFilter::Title(TextMatch::Contains(
+                "auth flow".to_string(),
+            )))),
+            Box::new(QueryExpr::Filter(TaskFilter::Description(
+                TextMatch::Contains("oidc".to_string()),
+            ))),
+        );
+        let created = QueryExpr::Filter(TaskFilter::Created(
+            Comparison::After,
+            NaiveDate::from_ymd_opt(2024, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+        ));
+        let expected = QueryExpr::And(
+            Box::new(QueryExpr::And(
+                Box::new(QueryExpr::And(Box::new(project), Box::new(assignee))),
+                Box::new(text),
+            )),
+            Box::new(created),
+        );
+        assert_eq!(query.filter, Some(expected));
+        assert_eq!(
+            query.order,
+            vec![
+                TaskOrder {
+                    field: OrderField::Created,
+                    descending: true
+                },
+                TaskOrder {
+                    field: OrderField::Slug,
+                    descending: false
+                },
+            ]
+        );
+        assert_eq!(TaskQuery::parse("").expect("empty"), TaskQuery::default());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = TaskQuery::parse("colour:red").unwrap_err();
+        assert_eq!(err.position, 0);
+        assert_eq!(TaskQuery::parse("title:\"auth").unwrap_err().position, 6);
+        assert_eq!(TaskQuery::parse("(state:OPEN").unwrap_err().position, 0);
+        assert_eq!(TaskQuery::parse("assignee:bob").unwrap_err().position, 9);
+        assert_eq!(
+            TaskQuery::parse("created:yesterday").unwrap_err().position,
+            8
+        );
+        assert_eq!(TaskQuery::parse("title>auth").unwrap_err().position, 6);
+        assert_eq!(TaskQuery::parse("is:stuck").unwrap_err().position, 3);
+        assert_eq!(TaskQuery::parse("watcher:none").unwrap_err().position, 8);
+        assert_eq!(
+            TaskQuery::parse("order by assignee").unwrap_err().position,
+            9
+        );
+    }
+
+    #[test]
+    fn test_parse_depth() {
+        let nested = |depth: usize| {
+            format!("{}state:OPEN{}", "(".repeat(depth), ")".repeat(depth))
+        };
+        assert!(TaskQuery::parse(&nested(MAX_DEPTH)).is_ok());
+        let err = TaskQuery::parse(&nested(100_000)).unwrap_err();
+        assert_eq!(err.position, MAX_DEPTH);
+
+        assert!(TaskQuery::parse(&format!("{}state:OPEN", "not ".repeat(MAX_DEPTH - 1))).is_ok());
+        assert!(TaskQuery::parse(&format!("{}state:OPEN", "not ".repeat(100_000))).is_err());
+
+        // Long chains build deep trees without any parentheses
+        let chain = |n: usize| vec!["state:OPEN"; n].join(" and ");
+        assert!(TaskQuery::parse(&chain(MAX_DEPTH)).is_ok());
+        assert!(TaskQuery::parse(&chain(MAX_DEPTH + 1)).is_err());
+        assert!(TaskQuery::parse(&vec!["state:OPEN"; MAX_DEPTH + 1].join(" or ")).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Float4, Nullable, Text, Uuid as SqlUuid};
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::Task;
+
+pub const MAX_SEARCH_RESULTS: i64 = 50;
+
+// The tsvector column is not in the diesel schema since diesel has no type for it, so it is
+// written and read with SQL here. Titles rank above descriptions.
+const INDEX_SQL: &str = "UPDATE tasks SET search_vector = \
+    setweight(to_tsvector('english', title), 'A') || \
+    setweight(to_tsvector('english', description), 'B') \
+    WHERE id = $1";
+
+// Matches are marked with the STX and ETX control characters rather than HTML, so the text can
+// be escaped before the marks are turned into tags. Those characters are removed from the text
+// first so a task can't open or close marks of its own.
+const SEARCH_SQL: &str = "SELECT tasks.id, \
+    ts_rank(tasks.search_vector, query) AS rank, \
+    ts_headline('english', translate(tasks.title, chr(2) || chr(3), ''), query, \
+        'StartSel=' || chr(2) || ', StopSel=' || chr(3) || ', HighlightAll=true') AS title, \
+    ts_headline('english', translate(tasks.description, chr(2) || chr(3), ''), query, \
+        'StartSel=' || chr(2) || ', StopSel=' || chr(3) || \
+        ', MaxFragments=2, MaxWords=24, MinWords=8') AS snippet \
+    FROM tasks, websearch_to_tsquery('english', $1) AS query \
+    WHERE tasks.search_vector @@ query \
+    AND ($2 IS NULL OR tasks.id IN \
+        (SELECT task_id FROM task_projects WHERE project_id = $2)) \
+    ORDER BY rank DESC, tasks.id \
+    LIMIT $3";
+
+const MARK_START: char = '\u{2}';
+const MARK_STOP: char = '\u{3}';
+
+/// Escapes a headline for HTML and turns its match marks into `<mark>` tags
+fn highlight(headline: &str) -> String {
+    let mut html = String::with_capacity(headline.len());
+    for c in headline.chars() {
+        match c {
+            MARK_START => html.push_str("<mark>"),
+            MARK_STOP => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#x27;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+#[derive(QueryableByName)]
+struct SearchRow {
+    #[diesel(sql_type = SqlUuid)]
+    id: Uuid,
+    #[diesel(sql_type = Float4)]
+    rank: f32,
+    #[diesel(sql_type = Text)]
+    title: String,
+    #[diesel(sql_type = Text)]
+    snippet: String,
+}
+
+/// A task matching a search. The title and snippet are HTML escaped, with matched words wrapped
+/// in `<mark>` tags.
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskSearchHit {
+    pub task: Task,
+    pub rank: f32,
+    pub title: String,
+    pub snippet: String,
+}
+
+impl Task {
+    /// Brings the search index up to date with the title and description
+    pub(crate) fn index_search(&self, conn: &mut PgConnection) -> QueryResult<()> {
+        diesel::sql_query(INDEX_SQL)
+            .bind::<SqlUuid, _>(self.id)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Tasks matching a web style search such as `login -oauth "session token"`, best match
+    /// first
+    pub fn search(
+        conn: &mut PgConnection,
+        text: &str,
+        project_id: Option<Uuid>,
+        limit: i64,
+    ) -> QueryResult<Vec<TaskSearchHit>> {
+        let rows: Vec<SearchRow> = diesel::sql_query(SEARCH_SQL)
+            .bind::<Text, _>(text)
+            .bind::<Nullable<SqlUuid>, _>(project_id)
+            .bind::<BigInt, _>(limit.clamp(1, MAX_SEARCH_RESULTS))
+            .load(conn)?;
+        let mut hits = Vec::with_capacity(rows.len());
+        for row in rows {
+            let task = Task::get_result(conn, row.id)?;
+            hits.push(TaskSearchHit {
+                task,
+                rank: row.rank,
+                title: highlight(&row.title),
+                snippet: highlight(&row.snippet),
+            });
+        }
+        Ok(hits)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tables::test::{fixture, Fixture, MIGRATIONS};
+    use crate::tables::{Project, TaskUpdate};
+    use function_name::named;
+    use subseq_util::tables::harness::{to_pg_db_name, DbHarness};
+
+    #[test]
+    #[named]
+    fn test_task_search() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let Fixture { user, flow, mut proj } = fixture(&mut conn);
+        let mut other = Project::create(&mut conn, Uuid::new_v4(), &user, "other", "", &flow)
+            .expect("other");
+
+        let login = Task::create(
+            &mut conn,
+            Uuid::new_v4(),
+            &mut proj,
+            "Fix login redirect",
+            "Users are sent to the wrong page",
+            &user,
+        ).expect("login");
+        let session = Task::create(
+            &mut conn,
+            Uuid::new_v4(),
+            &mut proj,
+            "Expire sessions",
+            "Sessions should end after a failed login",
+            &user,
+        ).expect("session");
+        let elsewhere = Task::create(
+            &mut conn,
+            Uuid::new_v4(),
+            &mut other,
+            "Login page copy",
+            "",
+            &user,
+        ).expect("elsewhere");
+
+        // Title matches rank above description matches
+        let hits = Task::search(&mut conn, "login", Some(proj.id), 10).expect("search");
+        let ids: Vec<Uuid> = hits.iter().map(|hit| hit.task.id).collect();
+        assert_eq!(ids, vec![login.id, session.id]);
+        assert_eq!(hits[0].title, "Fix <mark>login</mark> redirect");
+        assert!(hits[1].snippet.contains("<mark>login</mark>"));
+
+        let hits = Task::search(&mut conn, "login", None, 10).expect("search");
+        assert_eq!(hits.len(), 3);
+        assert!(hits.iter().any(|hit| hit.task.id == elsewhere.id));
+
+        let hits = Task::search(&mut conn, "login -redirect", Some(proj.id), 10).expect("search");
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].task.id, session.id);
+
+        // Edits keep the index current
+        let mut login = login;
+        login
+            .update(&mut conn, user.id, TaskUpdate::ChangeTitle { title: "Fix signup redirect".to_string() })
+            .expect("title");
+        let hits = Task::search(&mut conn, "signup", None, 10).expect("search");
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].task.id, login.id);
+        let mut session = session;
+        session
+            .update(
+                &mut conn,
+                user.id,
+                TaskUpdate::ChangeDescription { description: "Timeouts".to_string() },
+            )
+            .expect("description");
+        assert!(Task::search(&mut conn, "login", Some(proj.id), 10).expect("search").is_empty());
+
+        // Task text is escaped, only the marks are markup
+        Task::create(
+            &mut conn,
+            Uuid::new_v4(),
+            &mut proj,
+            "<img src=x onerror=alert(1)> upload",
+            "Broken \u{2}upload\u{3} & <script>",
+            &user,
+        ).expect("markup");
+        let hits = Task::search(&mut conn, "upload", Some(proj.id), 10).expect("search");
+        assert_eq!(hits.len(), 1);
+        assert!(hits[0].title.starts_with("&lt;img src=x onerror=alert(1)&gt;"));
+        assert!(hits[0].title.ends_with("<mark>upload</mark>"));
+        assert!(hits[0].snippet.contains("Broken <mark>upload</mark>"));
+        assert!(!hits[0].snippet.contains("<script>"));
+    }
+
+    #[test]
+    fn test_highlight() {
+        assert_eq!(highlight("a \u{2}b\u{3} <c>"), "a <mark>b</mark> &lt;c&gt;");
+        assert_eq!(highlight("\"it's\" & co"), "&quot;it&#x27;s&quot; &amp; co");
+    }
+}
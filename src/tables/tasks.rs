@@ -1,13 +1,70 @@
-use std::collections::HashMap;
-
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
+use diesel::result::DatabaseErrorInformation;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use super::{Flow, FlowConnection, FlowNode, Project, User, ValidationErrorMessage};
+use super::{
+    Flow, FlowConnection, FlowGuard, FlowNode, OutboxEvent, Project, TaskHistory, TaskPrevious,
+    TaskQuery, User, ValidationErrorMessage,
+};
+use super::pagination::{page_limit, Cursor, Page};
+use super::task_query::{after_cursor, order_tasks, task_cursor};
+use crate::events::TASK_CREATED_BEAM;
 use subseq_util::tables::UserTable;
 
+/// The constraint a transition fails with when the task does not meet the edge's guards
+pub const TRANSITION_GUARDS_CONSTRAINT: &str = "transition_guards";
+
+/// A transition refused for unmet guards. The guards are listed as JSON in the error details
+/// so each one can be handed back to the caller.
+#[derive(Debug)]
+pub struct UnmetGuardsMessage {
+    details: String,
+}
+
+impl UnmetGuardsMessage {
+    pub fn new(unmet: &[String]) -> Self {
+        Self {
+            details: serde_json::to_string(unmet).unwrap_or_default(),
+        }
+    }
+
+    /// The guards listed by a refused transition, empty for any other error
+    pub fn unmet(info: &(dyn DatabaseErrorInformation + Send + Sync)) -> Vec<String> {
+        if info.constraint_name() != Some(TRANSITION_GUARDS_CONSTRAINT) {
+            return vec![];
+        }
+        info.details()
+            .and_then(|details| serde_json::from_str(details).ok())
+            .unwrap_or_default()
+    }
+}
+
+impl DatabaseErrorInformation for UnmetGuardsMessage {
+    fn message(&self) -> &str {
+        "Unmet transition guards"
+    }
+    fn details(&self) -> Option<&str> {
+        Some(&self.details)
+    }
+    fn hint(&self) -> Option<&str> {
+        None
+    }
+    fn table_name(&self) -> Option<&str> {
+        Some("task_flows")
+    }
+    fn column_name(&self) -> Option<&str> {
+        Some("current_node_id")
+    }
+    fn constraint_name(&self) -> Option<&str> {
+        Some(TRANSITION_GUARDS_CONSTRAINT)
+    }
+    fn statement_position(&self) -> Option<i32> {
+        None
+    }
+}
+
 #[derive(PartialEq, Queryable, Insertable, Clone, Debug, Serialize)]
 #[diesel(table_name = crate::schema::task_projects)]
 pub struct TaskProject {
@@ -25,6 +82,7 @@ pub struct Task {
     pub description: String,
     pub author_id: Uuid,
     pub assignee_id: Option<Uuid>,
+    pub updated: NaiveDateTime,
 }
 
 impl PartialEq for Task {
@@ -50,15 +108,17 @@ impl Task {
     ) -> QueryResult<Self> {
         project.n_tasks += 1;
         let slug = format!("{}-{}", project.name, project.n_tasks);
+        let now = chrono::Utc::now().naive_utc();
 
         let task = Self {
             id: task_id,
             slug,
-            created: chrono::Utc::now().naive_utc(),
+            created: now,
             title: title.to_owned(),
             description: description.to_owned(),
             author_id: author.id,
             assignee_id: None,
+            updated: now,
         };
 
         let task_project = TaskProject {
@@ -67,12 +127,14 @@ impl Task {
         };
 
         conn.transaction(|transact| {
-            let default_flow_id = project.default_flow_id;
-            let flow = Flow::get(transact, default_flow_id);
+            // New tasks are pinned to the latest version of the project's flow
+            let flow = Flow::get(transact, project.default_flow_id)
+                .ok_or(diesel::result::Error::NotFound)?
+                .latest_version(transact)?;
             let task_flow = TaskFlow {
                 task_id: task.id,
-                flow_id: default_flow_id,
-                current_node_id: flow.map(|f| f.entry_node_id),
+                flow_id: flow.id,
+                current_node_id: Some(flow.entry_node_id),
                 order_added: 0,
             };
             let watcher = TaskWatcher {
@@ -83,6 +145,7 @@ impl Task {
             diesel::insert_into(crate::schema::tasks::table)
                 .values(&task)
                 .execute(transact)?;
+            task.index_search(transact)?;
             diesel::insert_into(crate::schema::task_watchers::table)
                 .values(&watcher)
                 .execute(transact)?;
@@ -95,6 +158,7 @@ impl Task {
             diesel::update(crate::schema::projects::table.find(&project.id))
                 .set(crate::schema::projects::n_tasks.eq(project.n_tasks))
                 .execute(transact)?;
+            OutboxEvent::record(transact, TASK_CREATED_BEAM, &task)?;
             QueryResult::Ok(())
         })?;
         Ok(task)
@@ -131,14 +195,14 @@ impl Task {
         use crate::schema::tags;
         use crate::schema::task_tags;
 
-        // Ignore errors if the tag already exists.
+        // The tag may already exist from another task.
         let tag = Tag {
             name: String::from(tag),
         };
         diesel::insert_into(tags::table)
             .values(&tag)
-            .execute(conn)
-            .ok();
+            .on_conflict_do_nothing()
+            .execute(conn)?;
 
         let task_tag = TaskTag {
             task_id: self.id,
@@ -153,6 +217,7 @@ impl Task {
     pub fn rm_tag(&self, conn: &mut PgConnection, tag: &str) -> QueryResult<()> {
         use crate::schema::task_tags;
         diesel::delete(task_tags::table)
+            .filter(task_tags::dsl::task_id.eq(self.id))
             .filter(task_tags::dsl::tag_name.eq(tag))
             .execute(conn)?;
         Ok(())
@@ -200,75 +265,77 @@ impl Task {
         Ok(users)
     }
 
+    /// A page of the tasks matching a query, in the query's order, starting after the cursor
     pub fn query(
         conn: &mut PgConnection,
         user_id: Uuid,
-        query_dict: &HashMap<String, String>,
-        page: u32,
-        page_size: u32,
-    ) -> Vec<Self> {
-        use crate::schema::task_projects;
-        use crate::schema::tasks::dsl::*;
-
-        let mut query = tasks
-            .into_boxed()
-            .inner_join(task_projects::dsl::task_projects.on(task_projects::dsl::task_id.eq(id)));
-
-        for (key, value) in query_dict {
-            match key.as_str() {
-                "slug" => query = query.filter(slug.like(format!("%{}%", value))),
-                "project" => {
-                    if let Ok(value) = Uuid::try_parse(value) {
-                        query = query.filter(task_projects::dsl::project_id.eq(value));
-                    } else if value == "active" {
-                        if let Some(user) = User::get(conn, user_id) {
-                            if let Some(project) = user.get_active_project(conn) {
-                                query = query.filter(task_projects::dsl::project_id.eq(project.id));
-                            }
-                        }
-                    } else {
-                        tracing::warn!("Invalid uuid project_id");
-                        continue;
-                    }
-                }
-                "title" => query = query.filter(title.ilike(format!("%{}%", value))),
-                "description" => query = query.filter(description.ilike(format!("%{}%", value))),
-                "author" => {
-                    if let Ok(value) = Uuid::try_parse(value) {
-                        query = query.filter(author_id.eq(value))
-                    } else if value == "self" {
-                        query = query.filter(author_id.eq(user_id))
-                    } else {
-                        tracing::warn!("Invalid uuid author");
-                        continue;
-                    }
-                }
-                "assignee" => {
-                    if let Ok(value) = Uuid::try_parse(value) {
-                        query = query.filter(assignee_id.eq(value))
-                    } else if value == "self" {
-                        query = query.filter(assignee_id.eq(user_id))
-                    } else {
-                        tracing::warn!("Invalid uuid assignee");
-                        continue;
-                    }
-                }
-                _ => {} // Ignore unknown keys or log them if necessary
-            }
+        task_query: &TaskQuery,
+        cursor: Option<&str>,
+        page_size: Option<u32>,
+    ) -> QueryResult<Page<Self>> {
+        let order = task_query.sort_order();
+        let limit = page_limit(page_size);
+        let total = Self::filtered(conn, user_id, task_query)
+            .count()
+            .get_result::<i64>(conn)?;
+
+        let mut query = order_tasks(Self::filtered(conn, user_id, task_query), &order);
+        if let Some(cursor) = cursor {
+            query = query.filter(after_cursor(&order, &Cursor::decode(cursor)?)?);
         }
+        let rows = query.limit(limit + 1).load::<Task>(conn)?;
+        Ok(Page::from_rows(rows, limit, total, |task| {
+            task_cursor(&order, task)
+        }))
+    }
 
-        let offset = page.saturating_sub(1) * page_size;
+    /// Whether this task would be in the results of a query
+    pub fn matches_query(
+        &self,
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        task_query: &TaskQuery,
+    ) -> bool {
+        use crate::schema::tasks;
+        Self::filtered(conn, user_id, task_query)
+            .filter(tasks::id.eq(self.id))
+            .count()
+            .get_result::<i64>(conn)
+            .map(|count| count > 0)
+            .unwrap_or(false)
+    }
 
-        match query
-            .limit(page_size as i64)
-            .offset(offset as i64)
-            .load::<Task>(conn)
-        {
-            Ok(list) => list,
-            Err(_) => vec![],
+    fn filtered(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        task_query: &TaskQuery,
+    ) -> crate::schema::tasks::BoxedQuery<'static, diesel::pg::Pg> {
+        let query = crate::schema::tasks::table.into_boxed();
+        match task_query.condition(conn, user_id) {
+            Some(condition) => query.filter(condition),
+            None => query,
         }
     }
 
+    pub fn in_project(&self, conn: &mut PgConnection, project_id: Uuid) -> QueryResult<bool> {
+        use crate::schema::task_projects::dsl;
+        let count: i64 = dsl::task_projects
+            .filter(dsl::task_id.eq(self.id))
+            .filter(dsl::project_id.eq(project_id))
+            .count()
+            .get_result(conn)?;
+        Ok(count > 0)
+    }
+
+    /// Every project the task is in
+    pub fn project_ids(&self, conn: &mut PgConnection) -> QueryResult<Vec<Uuid>> {
+        use crate::schema::task_projects::dsl;
+        dsl::task_projects
+            .filter(dsl::task_id.eq(self.id))
+            .select(dsl::project_id)
+            .load::<Uuid>(conn)
+    }
+
     pub fn flows(&self, conn: &mut PgConnection) -> QueryResult<Vec<TaskFlow>> {
         use crate::schema::task_flows;
         let mut flows = task_flows::table
@@ -308,56 +375,440 @@ impl Task {
         Ok(())
     }
 
-    pub fn transition(&self, conn: &mut PgConnection, node_id: Uuid) -> QueryResult<()> {
+    /// Moves the task along an edge to `node_id`, returning the TaskFlow as it was before the move.
+    /// When `flow_id` is not given the edge must be unambiguous across the task's flows.
+    pub fn transition(
+        &self,
+        conn: &mut PgConnection,
+        flow_id: Option<Uuid>,
+        node_id: Uuid,
+    ) -> QueryResult<TaskFlow> {
+        let mut candidates = vec![];
         for flow in self.flows(conn)? {
+            if flow_id.is_some_and(|flow_id| flow_id != flow.flow_id) {
+                continue;
+            }
             if let Some(current_node_id) = flow.current_node_id {
-                let valid_transitions = FlowConnection::edges(conn, current_node_id)?;
-                let contains_id = valid_transitions.iter().any(|node| node.id == node_id);
-                if contains_id {
-                    use crate::schema::task_flows::dsl::*;
-                    diesel::update(
-                        task_flows
-                            .filter(task_id.eq(self.id))
-                            .filter(flow_id.eq(flow.flow_id)),
-                    )
-                    .set(current_node_id.eq(Some(node_id)))
-                    .execute(conn)?;
-                    return Ok(());
+                let valid_transitions = FlowConnection::flow_edges(conn, flow.flow_id, current_node_id)?;
+                if valid_transitions.iter().any(|node| node.id == node_id) {
+                    candidates.push(flow);
+                }
+            }
+        }
+
+        if candidates.len() > 1 {
+            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+            let msg = Box::new(ValidationErrorMessage {
+                message: format!("Transition to {} is ambiguous, a flow_id is required", node_id),
+                column: "flow_id".to_string(),
+                constraint_name: "valid_transition_flow_id".to_string(),
+            });
+            return Err(diesel::result::Error::DatabaseError(kind, msg));
+        }
+
+        match candidates.pop() {
+            Some(flow) => {
+                if let Some(current_node_id) = flow.current_node_id {
+                    let guards = FlowConnection::guards(conn, flow.flow_id, current_node_id, node_id)?;
+                    let unmet = self.unmet_guards(conn, &guards)?;
+                    if !unmet.is_empty() {
+                        let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+                        let msg = Box::new(UnmetGuardsMessage::new(&unmet));
+                        return Err(diesel::result::Error::DatabaseError(kind, msg));
+                    }
+                }
+                self.set_node(conn, flow.flow_id, Some(node_id))?;
+                Ok(flow)
+            }
+            None => {
+                let message = match flow_id {
+                    Some(flow_id) => {
+                        format!("No valid transition to {} found in flow {}", node_id, flow_id)
+                    }
+                    None => format!("No valid transition to {} found", node_id),
+                };
+                let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+                let msg = Box::new(ValidationErrorMessage {
+                    message,
+                    column: "current_node_id".to_string(),
+                    constraint_name: "valid_transition_current_node_id".to_string(),
+                });
+                Err(diesel::result::Error::DatabaseError(kind, msg))
+            }
+        }
+    }
+
+    /// Describes each guard the task does not currently satisfy.
+    pub fn unmet_guards(
+        &self,
+        conn: &mut PgConnection,
+        guards: &[FlowGuard],
+    ) -> QueryResult<Vec<String>> {
+        let mut unmet = vec![];
+        for guard in guards {
+            match guard {
+                FlowGuard::RequiresAssignee => {
+                    if self.assignee_id.is_none() {
+                        unmet.push("Task requires an assignee".to_string());
+                    }
+                }
+                FlowGuard::DependenciesClosed => {
+                    for link in TaskLink::get_outgoing(conn, self)? {
+                        if link.link_type != TaskLinkType::DependsOn {
+                            continue;
+                        }
+                        let dependency = Task::get_result(conn, link.task_to_id)?;
+                        if !dependency.is_closed(conn)? {
+                            unmet.push(format!("Dependency {} is not closed", dependency.slug));
+                        }
+                    }
+                }
+                FlowGuard::RequiresTag { name } => {
+                    if !self.tags(conn)?.contains(name) {
+                        unmet.push(format!("Task requires the tag {}", name));
+                    }
                 }
+                FlowGuard::SubtasksClosed => {
+                    for link in TaskLink::get_incoming(conn, self)? {
+                        if link.link_type != TaskLinkType::SubtaskOf {
+                            continue;
+                        }
+                        let subtask = Task::get_result(conn, link.task_from_id)?;
+                        if !subtask.is_closed(conn)? {
+                            unmet.push(format!("Subtask {} is not closed", subtask.slug));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(unmet)
+    }
+
+    /// A task is closed once it sits on an exit node in every one of its flows.
+    pub fn is_closed(&self, conn: &mut PgConnection) -> QueryResult<bool> {
+        use crate::schema::flow_exits;
+        for flow in self.flows(conn)? {
+            let node_id = match flow.current_node_id {
+                Some(node_id) => node_id,
+                None => return Ok(false),
+            };
+            let exits = flow_exits::table
+                .filter(flow_exits::dsl::flow_id.eq(flow.flow_id))
+                .filter(flow_exits::dsl::node_id.eq(node_id))
+                .count()
+                .get_result::<i64>(conn)?;
+            if exits == 0 {
+                return Ok(false);
             }
         }
+        Ok(true)
+    }
+
+    /// Places the task at the entry node of another flow.
+    pub fn add_flow(&self, conn: &mut PgConnection, flow: &Flow) -> QueryResult<()> {
+        self.insert_flow(conn, flow.id, Some(flow.entry_node_id))
+    }
+
+    fn insert_flow(
+        &self,
+        conn: &mut PgConnection,
+        flow_id: Uuid,
+        node_id: Option<Uuid>,
+    ) -> QueryResult<()> {
+        let order_added = self
+            .flows(conn)?
+            .iter()
+            .map(|flow| flow.order_added + 1)
+            .max()
+            .unwrap_or(0);
+        let task_flow = TaskFlow {
+            task_id: self.id,
+            flow_id,
+            current_node_id: node_id,
+            order_added,
+        };
+        diesel::insert_into(crate::schema::task_flows::table)
+            .values(&task_flow)
+            .execute(conn)?;
+        Ok(())
+    }
 
-        let kind = diesel::result::DatabaseErrorKind::CheckViolation;
-        let msg = Box::new(ValidationErrorMessage {
-            message: format!("No valid transition to {} found", node_id),
-            column: "current_node_id".to_string(),
-            constraint_name: "valid_transition_current_node_id".to_string(),
-        });
-        Err(diesel::result::Error::DatabaseError(kind, msg))
+    /// Takes the task out of a flow, returning the TaskFlow as it was before removal.
+    /// A task must always remain in at least one flow.
+    pub fn rm_flow(&self, conn: &mut PgConnection, flow_id: Uuid) -> QueryResult<TaskFlow> {
+        use crate::schema::task_flows::dsl;
+        let flows = self.flows(conn)?;
+        let remaining = flows.len();
+        let flow = flows
+            .into_iter()
+            .find(|flow| flow.flow_id == flow_id)
+            .ok_or(diesel::result::Error::NotFound)?;
+        if remaining <= 1 {
+            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+            let msg = Box::new(ValidationErrorMessage {
+                message: "Task is missing a flow".to_string(),
+                column: "task_id".to_string(),
+                constraint_name: "task_flow_required".to_string(),
+            });
+            return Err(diesel::result::Error::DatabaseError(kind, msg));
+        }
+        diesel::delete(
+            dsl::task_flows
+                .filter(dsl::task_id.eq(self.id))
+                .filter(dsl::flow_id.eq(flow_id)),
+        )
+        .execute(conn)?;
+        Ok(flow)
     }
 
+    fn link_type(&self, conn: &mut PgConnection, task_id: Uuid) -> QueryResult<Option<TaskLinkType>> {
+        use crate::schema::task_links;
+        let link = task_links::table
+            .filter(task_links::dsl::task_from_id.eq(self.id))
+            .filter(task_links::dsl::task_to_id.eq(task_id))
+            .first::<TaskLinkData>(conn)
+            .optional()?;
+        Ok(link.map(|link| TaskLink::from(link).link_type))
+    }
+
+    fn set_node(&self, conn: &mut PgConnection, flow_id: Uuid, node_id: Option<Uuid>) -> QueryResult<()> {
+        use crate::schema::task_flows::dsl;
+        let updated = diesel::update(
+            dsl::task_flows
+                .filter(dsl::task_id.eq(self.id))
+                .filter(dsl::flow_id.eq(flow_id)),
+        )
+        .set(dsl::current_node_id.eq(node_id))
+        .execute(conn)?;
+        if updated == 0 {
+            return Err(diesel::result::Error::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Puts the task back on a node of a flow it was on. The task may have been upgraded to a
+    /// newer version of the flow since, so the version it is on now is the one changed, as long
+    /// as that version still has the node.
+    fn restore_node(
+        &self,
+        conn: &mut PgConnection,
+        flow_id: Uuid,
+        node_id: Option<Uuid>,
+    ) -> QueryResult<()> {
+        use crate::schema::{flow_assignments, flows, task_flows};
+        let lineage_id = flows::table
+            .find(flow_id)
+            .select(flows::dsl::lineage_id)
+            .get_result::<Uuid>(conn)?;
+        let current_flow_id = task_flows::table
+            .inner_join(flows::table)
+            .filter(task_flows::dsl::task_id.eq(self.id))
+            .filter(flows::dsl::lineage_id.eq(lineage_id))
+            .select(task_flows::dsl::flow_id)
+            .first::<Uuid>(conn)?;
+        if let Some(node_id) = node_id {
+            let assigned = flow_assignments::table
+                .find((current_flow_id, node_id))
+                .count()
+                .get_result::<i64>(conn)?;
+            if assigned == 0 {
+                let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+                let msg = Box::new(ValidationErrorMessage {
+                    message: format!(
+                        "Node {} is no longer in the version of the flow the task is on",
+                        node_id
+                    ),
+                    column: "node_id".to_string(),
+                    constraint_name: "task_history_reversible".to_string(),
+                });
+                return Err(diesel::result::Error::DatabaseError(kind, msg));
+            }
+        }
+        self.set_node(conn, current_flow_id, node_id)
+    }
+
+    /// Applies an update and records it in the task history, along with
+    /// the previous value when the update can be undone.
     pub fn update(
         &mut self,
         conn: &mut PgConnection,
         user_id: Uuid,
         update: TaskUpdate,
-    ) -> QueryResult<()> {
+    ) -> QueryResult<TaskHistory> {
+        conn.transaction(|transact| {
+            let previous = self.apply_update(transact, user_id, &update)?;
+            let history =
+                TaskHistory::create(transact, self.id, user_id, &update, previous.as_ref())?;
+            self.touch(transact, history.created)?;
+            Ok(history)
+        })
+    }
+
+    fn touch(&mut self, conn: &mut PgConnection, updated: NaiveDateTime) -> QueryResult<()> {
+        use crate::schema::tasks;
+        diesel::update(tasks::table.find(self.id))
+            .set(tasks::updated.eq(updated))
+            .execute(conn)?;
+        self.updated = updated;
+        Ok(())
+    }
+
+    fn apply_update(
+        &mut self,
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        update: &TaskUpdate,
+    ) -> QueryResult<Option<TaskPrevious>> {
         match update {
-            TaskUpdate::AssignOther { user_id } => self.assign_user(conn, user_id),
-            TaskUpdate::AssignSelf => self.assign_user(conn, user_id),
+            TaskUpdate::AddFlow { flow_id } => {
+                let flow = Flow::get(conn, *flow_id)
+                    .ok_or(diesel::result::Error::NotFound)?
+                    .latest_version(conn)?;
+                self.add_flow(conn, &flow)?;
+                Ok(Some(TaskPrevious::Flow {
+                    member: false,
+                    node_id: None,
+                }))
+            }
+            TaskUpdate::AssignOther { user_id } => {
+                let previous = TaskPrevious::Assignee(self.assignee_id);
+                self.assign_user(conn, *user_id)?;
+                Ok(Some(previous))
+            }
+            TaskUpdate::AssignSelf => {
+                let previous = TaskPrevious::Assignee(self.assignee_id);
+                self.assign_user(conn, user_id)?;
+                Ok(Some(previous))
+            }
             TaskUpdate::ChangeDescription { description } => {
+                let previous = TaskPrevious::Description(self.description.clone());
+                self.set_description(conn, description)?;
+                Ok(Some(previous))
+            }
+            TaskUpdate::ChangeTitle { title } => {
+                let previous = TaskPrevious::Title(self.title.clone());
+                self.set_title(conn, title)?;
+                Ok(Some(previous))
+            }
+            TaskUpdate::Link { task_id, link_type } => {
+                self.add_link(conn, *task_id, *link_type)?;
+                Ok(Some(TaskPrevious::Link(None)))
+            }
+            TaskUpdate::RemoveFlow { flow_id } => {
+                let flow = self.rm_flow(conn, *flow_id)?;
+                Ok(Some(TaskPrevious::Flow {
+                    member: true,
+                    node_id: flow.current_node_id,
+                }))
+            }
+            TaskUpdate::StopWatchingTask => {
+                self.rm_watcher(conn, user_id)?;
+                Ok(None)
+            }
+            TaskUpdate::Tag { name } => {
+                self.add_tag(conn, name)?;
+                Ok(Some(TaskPrevious::Tagged(false)))
+            }
+            TaskUpdate::Transition { node_id, flow_id } => {
+                let flow = self.transition(conn, *flow_id, *node_id)?;
+                Ok(Some(TaskPrevious::Node {
+                    flow_id: flow.flow_id,
+                    node_id: flow.current_node_id,
+                }))
+            }
+            TaskUpdate::Unassign => {
+                let previous = TaskPrevious::Assignee(self.assignee_id);
+                self.unassign_user(conn)?;
+                Ok(Some(previous))
+            }
+            TaskUpdate::WatchTask => {
+                self.add_watcher(conn, user_id)?;
+                Ok(None)
+            }
+            TaskUpdate::Undo => {
+                self.undo(conn, user_id)?;
+                Ok(None)
+            }
+            TaskUpdate::Unlink { task_id } => {
+                // Only a link which existed can be restored.
+                let previous = self.link_type(conn, *task_id)?;
+                self.rm_link(conn, *task_id)?;
+                Ok(previous.map(|link_type| TaskPrevious::Link(Some(link_type))))
+            }
+            TaskUpdate::Untag { name } => {
+                let tagged = self.tags(conn)?.contains(name);
+                self.rm_tag(conn, name)?;
+                Ok(tagged.then_some(TaskPrevious::Tagged(true)))
+            }
+        }
+    }
+
+    /// Reverts the caller's most recent reversible change to this task.
+    fn undo(&mut self, conn: &mut PgConnection, user_id: Uuid) -> QueryResult<()> {
+        let mut entry = match TaskHistory::last_reversible(conn, self.id, user_id)? {
+            Some(entry) => entry,
+            None => {
+                let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+                let msg = Box::new(ValidationErrorMessage {
+                    message: "Nothing to undo".to_string(),
+                    column: "task_id".to_string(),
+                    constraint_name: "task_history_reversible".to_string(),
+                });
+                return Err(diesel::result::Error::DatabaseError(kind, msg));
+            }
+        };
+        self.revert(conn, &entry)?;
+        entry.mark_reverted(conn)
+    }
+
+    fn revert(&mut self, conn: &mut PgConnection, entry: &TaskHistory) -> QueryResult<()> {
+        let (update, previous) = match (entry.decode_update(), entry.decode_previous()) {
+            (Some(update), Some(previous)) => (update, previous),
+            _ => {
+                let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+                let msg = Box::new(ValidationErrorMessage {
+                    message: format!("History entry {} cannot be reverted", entry.id),
+                    column: "previous".to_string(),
+                    constraint_name: "task_history_decodable".to_string(),
+                });
+                return Err(diesel::result::Error::DatabaseError(kind, msg));
+            }
+        };
+
+        match (update, previous) {
+            (_, TaskPrevious::Assignee(Some(user_id))) => self.assign_user(conn, user_id),
+            (_, TaskPrevious::Assignee(None)) => self.unassign_user(conn),
+            (_, TaskPrevious::Title(title)) => self.set_title(conn, &title),
+            (_, TaskPrevious::Description(description)) => {
                 self.set_description(conn, &description)
             }
-            TaskUpdate::ChangeTitle { title } => self.set_title(conn, &title),
-            TaskUpdate::Link { task_id, link_type } => self.add_link(conn, task_id, link_type),
-            TaskUpdate::StopWatchingTask => self.rm_watcher(conn, user_id),
-            TaskUpdate::Tag { name } => self.add_tag(conn, &name),
-            TaskUpdate::Transition { node_id } => self.transition(conn, node_id),
-            TaskUpdate::Unassign => self.unassign_user(conn),
-            TaskUpdate::WatchTask => self.add_watcher(conn, user_id),
-            TaskUpdate::Undo => Ok(()), // TODO
-            TaskUpdate::Unlink { task_id } => self.rm_link(conn, task_id),
-            TaskUpdate::Untag { name } => self.rm_tag(conn, &name),
+            (_, TaskPrevious::Node { flow_id, node_id }) => {
+                self.restore_node(conn, flow_id, node_id)
+            }
+            (TaskUpdate::AddFlow { flow_id }, TaskPrevious::Flow { member: false, .. }) => {
+                self.rm_flow(conn, flow_id).map(|_| ())
+            }
+            (TaskUpdate::RemoveFlow { flow_id }, TaskPrevious::Flow { member: true, node_id }) => {
+                self.insert_flow(conn, flow_id, node_id)
+            }
+            (TaskUpdate::Tag { name }, TaskPrevious::Tagged(false)) => self.rm_tag(conn, &name),
+            (TaskUpdate::Untag { name }, TaskPrevious::Tagged(true)) => self.add_tag(conn, &name),
+            (TaskUpdate::Link { task_id, .. }, TaskPrevious::Link(None)) => {
+                self.rm_link(conn, task_id)
+            }
+            (TaskUpdate::Unlink { task_id }, TaskPrevious::Link(Some(link_type))) => {
+                self.add_link(conn, task_id, link_type)
+            }
+            (update, previous) => {
+                tracing::warn!("Mismatched history entry {:?} {:?}", update, previous);
+                let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+                let msg = Box::new(ValidationErrorMessage {
+                    message: format!("History entry {} cannot be undone", entry.id),
+                    column: "previous".to_string(),
+                    constraint_name: "task_history_reversible".to_string(),
+                });
+                Err(diesel::result::Error::DatabaseError(kind, msg))
+            }
         }
     }
 
@@ -385,7 +836,7 @@ impl Task {
         diesel::update(dsl::tasks.filter(dsl::id.eq(self.id)))
             .set(dsl::description.eq(description))
             .execute(conn)?;
-        Ok(())
+        self.index_search(conn)
     }
     fn set_title(&mut self, conn: &mut PgConnection, title: &str) -> QueryResult<()> {
         use crate::schema::tasks::dsl;
@@ -393,13 +844,16 @@ impl Task {
         diesel::update(dsl::tasks.filter(dsl::id.eq(self.id)))
             .set(dsl::title.eq(title))
             .execute(conn)?;
-        Ok(())
+        self.index_search(conn)
     }
 }
 subseq_util::setup_table_crud!(Task, crate::schema::tasks::dsl::tasks);
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum TaskUpdate {
+    AddFlow {
+        flow_id: Uuid,
+    },
     AssignOther {
         user_id: Uuid,
     },
@@ -414,12 +868,17 @@ pub enum TaskUpdate {
         task_id: Uuid,
         link_type: TaskLinkType,
     },
+    RemoveFlow {
+        flow_id: Uuid,
+    },
     StopWatchingTask,
     Tag {
         name: String,
     },
     Transition {
         node_id: Uuid,
+        #[serde(default)]
+        flow_id: Option<Uuid>,
     },
     Unassign,
     Undo,
@@ -466,38 +925,34 @@ pub struct TaskFlow {
     pub order_added: i32,
 }
 
-/// Gets the FlowNode active from a list of TaskFlows
-impl TaskFlow {
-    pub fn get_active_node(conn: &mut PgConnection, flows: &[Self]) -> QueryResult<FlowNode> {
-        // TODO: check other TaskFlows
-        let flow = match flows.first() {
-            Some(flow) => flow,
-            None => {
-                let kind = diesel::result::DatabaseErrorKind::CheckViolation;
-                let msg = Box::new(ValidationErrorMessage {
-                    message: "Task is missing a flow".to_string(),
-                    column: "task_id".to_string(),
-                    constraint_name: "task_flow_required".to_string(),
-                });
-                return Err(diesel::result::Error::DatabaseError(kind, msg));
-            }
-        };
+/// The current node of a task within one flow, and the nodes it can move to next
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskFlowState {
+    pub flow_id: Uuid,
+    pub flow_name: String,
+    pub flow_version: i32,
+    pub state: Option<FlowNode>,
+    pub valid_transitions: Vec<FlowNode>,
+}
 
-        let node_id = match flow.current_node_id {
+impl TaskFlow {
+    pub fn state(&self, conn: &mut PgConnection) -> QueryResult<TaskFlowState> {
+        let flow = Flow::get(conn, self.flow_id).ok_or(diesel::result::Error::NotFound)?;
+        let node_id = match self.current_node_id {
             Some(node_id) => node_id,
             None => {
-                let kind = diesel::result::DatabaseErrorKind::CheckViolation;
-                let msg = Box::new(ValidationErrorMessage {
-                    message: "TaskFlow has no valid node id".to_string(),
-                    column: "current_node_id".to_string(),
-                    constraint_name: "task_flow_current_node_id_null".to_string(),
-                });
-                return Err(diesel::result::Error::DatabaseError(kind, msg));
+                return Ok(TaskFlowState {
+                    flow_id: flow.id,
+                    flow_name: flow.flow_name,
+                    flow_version: flow.version,
+                    state: None,
+                    valid_transitions: vec![],
+                })
             }
         };
 
-        match FlowNode::get(conn, node_id) {
-            Some(node) => Ok(node),
+        let node = match FlowNode::get(conn, node_id) {
+            Some(node) => node,
             None => {
                 let kind = diesel::result::DatabaseErrorKind::CheckViolation;
                 let msg = Box::new(ValidationErrorMessage {
@@ -505,9 +960,31 @@ impl TaskFlow {
                     column: "current_node_id".to_string(),
                     constraint_name: "task_flow_current_node_id_exists".to_string(),
                 });
-                Err(diesel::result::Error::DatabaseError(kind, msg))
+                return Err(diesel::result::Error::DatabaseError(kind, msg));
             }
+        };
+        let valid_transitions = FlowConnection::flow_edges(conn, flow.id, node.id)?;
+        Ok(TaskFlowState {
+            flow_id: flow.id,
+            flow_name: flow.flow_name,
+            flow_version: flow.version,
+            state: Some(node),
+            valid_transitions,
+        })
+    }
+
+    /// Gets the state of every flow a task is in, in the order they were added
+    pub fn states(conn: &mut PgConnection, flows: &[Self]) -> QueryResult<Vec<TaskFlowState>> {
+        if flows.is_empty() {
+            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+            let msg = Box::new(ValidationErrorMessage {
+                message: "Task is missing a flow".to_string(),
+                column: "task_id".to_string(),
+                constraint_name: "task_flow_required".to_string(),
+            });
+            return Err(diesel::result::Error::DatabaseError(kind, msg));
         }
+        flows.iter().map(|flow| flow.state(conn)).collect()
     }
 }
 
@@ -631,7 +1108,9 @@ impl TaskLink {
 mod test {
     use super::*;
     use crate::tables::test::MIGRATIONS;
+    use crate::tables::Job;
     use function_name::named;
+    use std::collections::HashMap;
     use subseq_util::tables::harness::{to_pg_db_name, DbHarness};
     use subseq_util::tables::UserTable;
     use uuid::Uuid;
@@ -682,4 +1161,359 @@ mod test {
         assert_eq!(task, task2);
         assert_eq!(proj.n_tasks, 1);
     }
+
+    #[test]
+    #[named]
+    fn test_task_undo() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+
+        let user = User::create(
+            &mut conn,
+            Uuid::new_v4(),
+            "test@example.com",
+            Some("test_user"),
+        )
+        .expect("user");
+
+        let entry_node = FlowNode::create(&mut conn, "OPEN").expect("open");
+        let exit_node = FlowNode::create(&mut conn, "CLOSED").expect("closed");
+        let flow = Flow::create(
+            &mut conn,
+            &user,
+            "Default".to_string(),
+            "This is the default flow".to_string(),
+            &entry_node,
+            vec![(&entry_node, &exit_node)],
+            vec![&exit_node],
+        ).expect("flow");
+
+        let mut proj = Project::create(&mut conn, Uuid::new_v4(), &user, "proj", "", &flow)
+            .expect("proj");
+        let mut task = Task::create(
+            &mut conn,
+            Uuid::new_v4(),
+            &mut proj,
+            "Task 1",
+            "Do this",
+            &user,
+        )
+        .expect("task");
+
+        assert!(task.update(&mut conn, user.id, TaskUpdate::Undo).is_err());
+
+        task.update(&mut conn, user.id, TaskUpdate::ChangeTitle { title: "Task 2".to_string() })
+            .expect("title");
+        task.update(
+            &mut conn,
+            user.id,
+            TaskUpdate::Transition {
+                node_id: exit_node.id,
+                flow_id: None,
+            },
+        )
+        .expect("transition");
+
+        task.update(&mut conn, user.id, TaskUpdate::Undo).expect("undo transition");
+        let flows = task.flows(&mut conn).expect("flows");
+        assert_eq!(flows[0].current_node_id, Some(entry_node.id));
+
+        task.update(&mut conn, user.id, TaskUpdate::Undo).expect("undo title");
+        let task2 = Task::get(&mut conn, task.id).expect("task2");
+        assert_eq!(task2.title, "Task 1");
+        assert_eq!(TaskHistory::list(&mut conn, task.id).expect("history").len(), 4);
+
+        // An entry whose previous value doesn't fit its update is refused, not marked reverted
+        let mismatched = TaskHistory::create(
+            &mut conn,
+            task.id,
+            user.id,
+            &TaskUpdate::Tag { name: "bug".to_string() },
+            Some(&TaskPrevious::Link(None)),
+        )
+        .expect("mismatched");
+        assert!(task.update(&mut conn, user.id, TaskUpdate::Undo).is_err());
+        let pending = TaskHistory::last_reversible(&mut conn, task.id, user.id)
+            .expect("reversible")
+            .expect("still pending");
+        assert_eq!(pending.id, mismatched.id);
+        assert!(!pending.reverted);
+    }
+
+    #[test]
+    #[named]
+    fn test_task_multi_flow() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+
+        let user = User::create(&mut conn, Uuid::new_v4(), "test@example.com", None)
+            .expect("user");
+        let open = FlowNode::create(&mut conn, "OPEN").expect("open");
+        let closed = FlowNode::create(&mut conn, "CLOSED").expect("closed");
+        let flow = Flow::create(
+            &mut conn,
+            &user,
+            "Default".to_string(),
+            "".to_string(),
+            &open,
+            vec![(&open, &closed)],
+            vec![&closed],
+        ).expect("flow");
+        let in_review = FlowNode::create(&mut conn, "IN REVIEW").expect("in review");
+        let review = Flow::create(
+            &mut conn,
+            &user,
+            "Review".to_string(),
+            "".to_string(),
+            &in_review,
+            vec![(&in_review, &closed)],
+            vec![&closed],
+        ).expect("review");
+
+        let mut proj = Project::create(&mut conn, Uuid::new_v4(), &user, "proj", "", &flow)
+            .expect("proj");
+        let mut task = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "Task 1", "", &user)
+            .expect("task");
+        assert!(task
+            .update(&mut conn, user.id, TaskUpdate::RemoveFlow { flow_id: flow.id })
+            .is_err());
+        // A flow the task isn't in is missing, however many flows the task has
+        let missing = task.update(&mut conn, user.id, TaskUpdate::RemoveFlow { flow_id: review.id });
+        assert!(matches!(missing, Err(diesel::result::Error::NotFound)));
+        task.update(&mut conn, user.id, TaskUpdate::AddFlow { flow_id: review.id })
+            .expect("add flow");
+
+        let ambiguous = TaskUpdate::Transition {
+            node_id: closed.id,
+            flow_id: None,
+        };
+        assert!(task.update(&mut conn, user.id, ambiguous).is_err());
+        let targeted = TaskUpdate::Transition {
+            node_id: closed.id,
+            flow_id: Some(review.id),
+        };
+        task.update(&mut conn, user.id, targeted).expect("transition");
+
+        let flows = task.flows(&mut conn).expect("flows");
+        let states = TaskFlow::states(&mut conn, &flows).expect("states");
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].state, Some(open.clone()));
+        assert_eq!(states[0].valid_transitions, vec![closed.clone()]);
+        assert_eq!(states[1].state, Some(closed));
+        assert!(states[1].valid_transitions.is_empty());
+
+        task.update(&mut conn, user.id, TaskUpdate::RemoveFlow { flow_id: review.id })
+            .expect("remove flow");
+        task.update(&mut conn, user.id, TaskUpdate::Undo).expect("undo");
+        assert_eq!(task.flows(&mut conn).expect("flows").len(), 2);
+    }
+
+    #[test]
+    #[named]
+    fn test_transition_guards() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+
+        let user = User::create(&mut conn, Uuid::new_v4(), "test@example.com", None)
+            .expect("user");
+        let open = FlowNode::create(&mut conn, "OPEN").expect("open");
+        let closed = FlowNode::create(&mut conn, "CLOSED").expect("closed");
+        let flow = Flow::create(
+            &mut conn,
+            &user,
+            "Default".to_string(),
+            "".to_string(),
+            &open,
+            vec![(&open, &closed)],
+            vec![&closed],
+        ).expect("flow");
+        let guards = vec![FlowGuard::RequiresAssignee, FlowGuard::DependenciesClosed];
+        FlowConnection::set_guards(&mut conn, flow.id, open.id, closed.id, &guards).expect("guards");
+
+        let mut proj = Project::create(&mut conn, Uuid::new_v4(), &user, "proj", "", &flow)
+            .expect("proj");
+        let mut task = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "Task 1", "", &user)
+            .expect("task");
+        let mut blocker = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "Task 2", "", &user)
+            .expect("blocker");
+        TaskLink::create(&mut conn, &task, &blocker, TaskLinkType::DependsOn).expect("link");
+
+        let unmet = task.unmet_guards(&mut conn, &guards).expect("unmet");
+        assert_eq!(unmet.len(), 2);
+        let close = TaskUpdate::Transition {
+            node_id: closed.id,
+            flow_id: None,
+        };
+        match task.update(&mut conn, user.id, close.clone()) {
+            Err(diesel::result::Error::DatabaseError(_, info)) => {
+                assert_eq!(
+                    UnmetGuardsMessage::unmet(info.as_ref()),
+                    vec![
+                        "Task requires an assignee".to_string(),
+                        format!("Dependency {} is not closed", blocker.slug),
+                    ]
+                );
+            }
+            other => panic!("Expected unmet guards, got {:?}", other),
+        }
+
+        task.update(&mut conn, user.id, TaskUpdate::AssignSelf).expect("assign");
+        blocker.update(&mut conn, user.id, TaskUpdate::AssignSelf).expect("assign blocker");
+        blocker.update(&mut conn, user.id, close.clone()).expect("close blocker");
+        assert!(blocker.is_closed(&mut conn).expect("closed"));
+        task.update(&mut conn, user.id, close).expect("close");
+    }
+
+    #[test]
+    #[named]
+    fn test_task_matches_query() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let user = User::create(&mut conn, Uuid::new_v4(), "test@example.com", None).expect("user");
+        let open = FlowNode::create(&mut conn, "OPEN").expect("open");
+        let closed = FlowNode::create(&mut conn, "CLOSED").expect("closed");
+        let flow = Flow::create(
+            &mut conn,
+            &user,
+            "Default".to_string(),
+            "This is the default flow".to_string(),
+            &open,
+            vec![(&open, &closed)],
+            vec![&closed],
+        ).expect("flow");
+        let mut proj = Project::create(&mut conn, Uuid::new_v4(), &user, "proj", "", &flow)
+            .expect("proj");
+        let mut other = Project::create(&mut conn, Uuid::new_v4(), &user, "other", "", &flow)
+            .expect("other");
+        let task = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "Fix login", "", &user)
+            .expect("task");
+        let other_task = Task::create(&mut conn, Uuid::new_v4(), &mut other, "Fix logout", "", &user)
+            .expect("other task");
+
+        assert!(task.in_project(&mut conn, proj.id).expect("in project"));
+        assert!(!task.in_project(&mut conn, other.id).expect("in project"));
+
+        let mut fields = HashMap::new();
+        fields.insert("title".to_string(), "login".to_string());
+        let query = TaskQuery::from_fields(&fields).expect("fields");
+        assert!(task.matches_query(&mut conn, user.id, &query));
+        assert!(!other_task.matches_query(&mut conn, user.id, &query));
+        fields.insert("project".to_string(), other.id.to_string());
+        let query = TaskQuery::from_fields(&fields).expect("fields");
+        assert!(!task.matches_query(&mut conn, user.id, &query));
+
+        let query = TaskQuery::parse("project:OTHER or (title:login and not assignee:self)")
+            .expect("query");
+        assert!(task.matches_query(&mut conn, user.id, &query));
+        assert!(other_task.matches_query(&mut conn, user.id, &query));
+        let query = TaskQuery::parse("title:fix order by title desc").expect("query");
+        let found = Task::query(&mut conn, user.id, &query, None, Some(10)).expect("query");
+        let found: Vec<Uuid> = found.items.into_iter().map(|task| task.id).collect();
+        assert_eq!(found, vec![other_task.id, task.id]);
+    }
+
+    #[test]
+    #[named]
+    fn test_task_query_relations() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let user = User::create(&mut conn, Uuid::new_v4(), "test@example.com", None).expect("user");
+        let open = FlowNode::create(&mut conn, "OPEN").expect("open");
+        let closed = FlowNode::create(&mut conn, "CLOSED").expect("closed");
+        let flow = Flow::create(
+            &mut conn,
+            &user,
+            "Default".to_string(),
+            "This is the default flow".to_string(),
+            &open,
+            vec![(&open, &closed)],
+            vec![&closed],
+        ).expect("flow");
+        let mut proj = Project::create(&mut conn, Uuid::new_v4(), &user, "proj", "", &flow)
+            .expect("proj");
+        let parent = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "Parent", "", &user)
+            .expect("parent");
+        let blocked = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "Blocked", "", &user)
+            .expect("blocked");
+        blocked.add_link(&mut conn, parent.id, TaskLinkType::SubtaskOf).expect("subtask");
+        blocked.add_link(&mut conn, parent.id, TaskLinkType::DependsOn).expect("depends");
+        blocked.add_tag(&mut conn, "backend").expect("tag");
+        blocked.add_watcher(&mut conn, user.id).expect("watcher");
+
+        let matches = |conn: &mut PgConnection, task: &Task, query: &str| {
+            let query = TaskQuery::parse(query).expect("query");
+            task.matches_query(conn, user.id, &query)
+        };
+        assert!(matches(&mut conn, &blocked, "is:blocked is:open tag:backend watcher:self"));
+        assert!(matches(&mut conn, &blocked, &format!("subtask:{}", parent.slug)));
+        assert!(matches(&mut conn, &blocked, &format!("subtask:{}", parent.id)));
+        assert!(!matches(&mut conn, &parent, "is:blocked or watcher:self or is:running"));
+        assert!(matches(&mut conn, &parent, "state:open"));
+
+        parent.transition(&mut conn, None, closed.id).expect("close");
+        assert!(matches(&mut conn, &parent, "is:closed"));
+        assert!(matches(&mut conn, &parent, &format!("state:{}", closed.id)));
+        assert!(!matches(&mut conn, &blocked, "is:blocked"));
+
+        Job::create(&mut conn, Uuid::new_v4(), proj.id, blocked.id, "job".to_string(), user.id, user.id)
+            .expect("job");
+        assert!(matches(&mut conn, &blocked, "is:running"));
+    }
+
+    #[test]
+    #[named]
+    fn test_task_query_pages() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let user = User::create(&mut conn, Uuid::new_v4(), "test@example.com", None).expect("user");
+        let open = FlowNode::create(&mut conn, "OPEN").expect("open");
+        let closed = FlowNode::create(&mut conn, "CLOSED").expect("closed");
+        let flow = Flow::create(
+            &mut conn,
+            &user,
+            "Default".to_string(),
+            "This is the default flow".to_string(),
+            &open,
+            vec![(&open, &closed)],
+            vec![&closed],
+        ).expect("flow");
+        let mut proj = Project::create(&mut conn, Uuid::new_v4(), &user, "proj", "", &flow)
+            .expect("proj");
+        let mut created = vec![];
+        for n in 0..11 {
+            let task = Task::create(&mut conn, Uuid::new_v4(), &mut proj, &format!("Task {}", n), "", &user)
+                .expect("task");
+            created.push(task.id);
+        }
+
+        let query = TaskQuery::parse("order by number desc").expect("query");
+        let mut found = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = Task::query(&mut conn, user.id, &query, cursor.as_deref(), Some(4))
+                .expect("page");
+            assert_eq!(page.total, 11);
+            found.extend(page.items.into_iter().map(|task| task.id));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        created.reverse();
+        assert_eq!(found, created);
+
+        // A cursor only continues the order it came from
+        let page = Task::query(&mut conn, user.id, &query, None, Some(4)).expect("page");
+        for other in ["order by title", "order by number, created"] {
+            let other = TaskQuery::parse(other).expect("query");
+            assert!(Task::query(&mut conn, user.id, &other, page.next_cursor.as_deref(), Some(4))
+                .is_err());
+        }
+    }
 }
@@ -1,8 +1,10 @@
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
+use email_address::EmailAddress;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use super::pagination::{list_page, Page, PageRequest};
 use super::projects::{ActiveProject, Project};
 use subseq_util::tables::{UserTable, ValidationErrorMessage};
 
@@ -22,6 +24,40 @@ impl UserIdAccount {
             .execute(conn)?;
         Ok(id)
     }
+
+    fn validate_username(username: &str) -> QueryResult<()> {
+        let mut chars = username.chars();
+        let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && username.len() <= 64;
+        if !valid {
+            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+            let msg = Box::new(ValidationErrorMessage {
+                message: format!("Invalid username {}", username),
+                column: "username".to_string(),
+                constraint_name: "username_limits".to_string(),
+            });
+            return Err(diesel::result::Error::DatabaseError(kind, msg));
+        }
+        Ok(())
+    }
+
+    /// Sets the username of a user, adding an account for them if they have none
+    pub fn set_username(conn: &mut PgConnection, user_id: Uuid, username: &str) -> QueryResult<Self> {
+        use crate::schema::auth::user_id_accounts::dsl;
+        Self::validate_username(username)?;
+        let account = Self {
+            user_id,
+            username: username.to_owned(),
+            account_type: None,
+        };
+        diesel::insert_into(dsl::user_id_accounts)
+            .values(&account)
+            .on_conflict(dsl::user_id)
+            .do_update()
+            .set(dsl::username.eq(username))
+            .get_result::<Self>(conn)
+    }
 }
 
 impl UserMetadata {
@@ -35,6 +71,34 @@ impl UserMetadata {
             .execute(conn)?;
         Ok(meta)
     }
+
+    /// Sets one field of a user's metadata, keeping every other field as it was
+    pub fn set_field(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        field: &str,
+        value: serde_json::Value,
+    ) -> QueryResult<Self> {
+        use crate::schema::auth::metadata::dsl;
+        conn.transaction(|transact| {
+            let mut data = match Self::get(transact, user_id) {
+                Some(Self { data: serde_json::Value::Object(data), .. }) => data,
+                _ => serde_json::Map::new(),
+            };
+            data.insert(field.to_string(), value);
+            let meta = Self {
+                user_id,
+                data: serde_json::Value::Object(data),
+            };
+            diesel::insert_into(dsl::metadata)
+                .values(&meta)
+                .on_conflict(dsl::user_id)
+                .do_update()
+                .set(dsl::data.eq(&meta.data))
+                .execute(transact)?;
+            Ok(meta)
+        })
+    }
 }
 
 impl UserPortrait {
@@ -53,6 +117,40 @@ subseq_util::setup_table_crud!(
 );
 
 impl User {
+    fn validate_email(email: &str) -> QueryResult<()> {
+        if !EmailAddress::is_valid(email) {
+            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+            let msg = Box::new(ValidationErrorMessage {
+                message: format!("Invalid email {}", email),
+                column: "email".to_string(),
+                constraint_name: "email_format".to_string(),
+            });
+            return Err(diesel::result::Error::DatabaseError(kind, msg));
+        }
+        Ok(())
+    }
+
+    pub fn set_email(&mut self, conn: &mut PgConnection, email: &str) -> QueryResult<()> {
+        use crate::schema::auth::users::dsl;
+        Self::validate_email(email)?;
+        diesel::update(dsl::users.find(self.id))
+            .set(dsl::email.eq(email))
+            .execute(conn)?;
+        self.email = email.to_owned();
+        Ok(())
+    }
+
+    /// A page of users, where sorting by name sorts by email
+    pub fn page(conn: &mut PgConnection, page: &PageRequest) -> QueryResult<Page<Self>> {
+        use crate::schema::auth::users::dsl;
+        let total = dsl::users.count().get_result::<i64>(conn)?;
+        Ok(list_page!(
+            conn, page, total, dsl::users.into_boxed(), Self,
+            created: dsl::created, name: dsl::email, id: dsl::id,
+            cursor: |user| (user.created, user.email.clone(), user.id)
+        ))
+    }
+
     pub fn get_active_project(&self, conn: &mut PgConnection) -> Option<Project> {
         let active_project = ActiveProject::get(conn, self.id);
         if let Some(active_project) = active_project {
@@ -95,4 +193,33 @@ mod test {
         .is_err());
         assert!(User::create(&mut conn, Uuid::new_v4(), "bad_email", Some("bad_user")).is_err());
     }
+
+    #[test]
+    #[named]
+    fn test_user_profile() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let mut user = User::create(&mut conn, Uuid::new_v4(), "test@example.com", None)
+            .expect("user");
+
+        assert!(user.set_email(&mut conn, "not an email").is_err());
+        assert_eq!(user.email, "test@example.com");
+        user.set_email(&mut conn, "renamed@example.com").expect("email");
+        let stored = User::get(&mut conn, user.id).expect("stored");
+        assert_eq!(stored.email, "renamed@example.com");
+
+        assert!(UserIdAccount::set_username(&mut conn, user.id, "2bad_user").is_err());
+        UserIdAccount::set_username(&mut conn, user.id, "first_name").expect("username");
+        UserIdAccount::set_username(&mut conn, user.id, "second_name").expect("rename");
+        let account = UserIdAccount::get(&mut conn, user.id).expect("account");
+        assert_eq!(account.username, "second_name");
+
+        UserMetadata::create(&mut conn, user.id, serde_json::json!({"team": "core"}))
+            .expect("meta");
+        UserMetadata::set_field(&mut conn, user.id, "job_title", serde_json::json!("Engineer"))
+            .expect("job title");
+        let meta = UserMetadata::get(&mut conn, user.id).expect("meta");
+        assert_eq!(meta.data, serde_json::json!({"team": "core", "job_title": "Engineer"}));
+    }
 }
@@ -0,0 +1,421 @@
+use std::net::IpAddr;
+
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::pagination::{list_page, Page, PageRequest};
+use super::{Project, ValidationErrorMessage};
+
+const SECRET_LENGTH: usize = 40;
+const MAX_DELIVERY_ATTEMPTS: i32 = 8;
+const FIRST_RETRY_SECS: i64 = 30;
+const MAX_RETRY_SECS: i64 = 3600;
+
+/// The events a webhook can be registered for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    TaskCreated,
+    TaskUpdated,
+    TaskStateChanged,
+    JobFinished,
+    HelpRequested,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::TaskCreated => "task_created",
+            Self::TaskUpdated => "task_updated",
+            Self::TaskStateChanged => "task_state_changed",
+            Self::JobFinished => "job_finished",
+            Self::HelpRequested => "help_requested",
+        }
+    }
+}
+
+/// Whether an address is on the public internet, rather than this host or a private network
+/// which a webhook could be used to reach from inside
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            let shared = first == 100 && (second & 0xc0) == 64;
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || shared
+                || first == 0)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            let unique_local = (first & 0xfe00) == 0xfc00;
+            let link_local = (first & 0xffc0) == 0xfe80;
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || unique_local || link_local)
+        }
+    }
+}
+
+/// A URL events on a project are posted to. The secret signs each delivery and is only
+/// returned when the webhook is created.
+#[derive(PartialEq, Queryable, Insertable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::project_webhooks)]
+pub struct ProjectWebhook {
+    pub id: Uuid,
+    pub project_id: Uuid,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub events: serde_json::Value,
+    pub created: NaiveDateTime,
+}
+
+impl ProjectWebhook {
+    fn validate(url: &str, events: &[WebhookEvent]) -> QueryResult<()> {
+        let violation = |message: &str, column: &str, constraint_name: &str| {
+            let kind = diesel::result::DatabaseErrorKind::CheckViolation;
+            let msg = Box::new(ValidationErrorMessage {
+                message: message.to_string(),
+                column: column.to_string(),
+                constraint_name: constraint_name.to_string(),
+            });
+            diesel::result::Error::DatabaseError(kind, msg)
+        };
+        let parsed = match url::Url::parse(url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => parsed,
+            _ => return Err(violation("Webhook URL must be http or https", "url", "webhook_url")),
+        };
+        // Hosts are checked again when they are resolved for each delivery
+        let public = match parsed.host() {
+            Some(url::Host::Domain(domain)) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                domain != "localhost" && !domain.ends_with(".localhost")
+            }
+            Some(url::Host::Ipv4(ip)) => is_public_ip(IpAddr::V4(ip)),
+            Some(url::Host::Ipv6(ip)) => is_public_ip(IpAddr::V6(ip)),
+            None => false,
+        };
+        if !public {
+            return Err(violation(
+                "Webhook URL must be a public host",
+                "url",
+                "webhook_url_public",
+            ));
+        }
+        if events.is_empty() {
+            return Err(violation("Webhook has no events", "events", "webhook_events_not_empty"));
+        }
+        Ok(())
+    }
+
+    pub fn create(
+        conn: &mut PgConnection,
+        project: &Project,
+        url: &str,
+        events: &[WebhookEvent],
+    ) -> QueryResult<Self> {
+        Self::validate(url, events)?;
+        let secret = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(SECRET_LENGTH)
+            .map(char::from)
+            .collect();
+        let events = serde_json::to_value(events)
+            .map_err(|err| diesel::result::Error::SerializationError(Box::new(err)))?;
+        let webhook = Self {
+            id: Uuid::new_v4(),
+            project_id: project.id,
+            url: url.to_owned(),
+            secret,
+            events,
+            created: chrono::Utc::now().naive_utc(),
+        };
+        diesel::insert_into(crate::schema::project_webhooks::table)
+            .values(&webhook)
+            .execute(conn)?;
+        Ok(webhook)
+    }
+
+    pub fn decode_events(&self) -> Vec<WebhookEvent> {
+        serde_json::from_value(self.events.clone()).unwrap_or_default()
+    }
+
+    pub fn list_for_project(conn: &mut PgConnection, project_id: Uuid) -> QueryResult<Vec<Self>> {
+        use crate::schema::project_webhooks::dsl;
+        dsl::project_webhooks
+            .filter(dsl::project_id.eq(project_id))
+            .order(dsl::created.asc())
+            .load::<Self>(conn)
+    }
+
+    /// The webhooks on any of the projects which asked for this event
+    pub fn subscribed(
+        conn: &mut PgConnection,
+        project_ids: &[Uuid],
+        event: WebhookEvent,
+    ) -> QueryResult<Vec<Self>> {
+        use crate::schema::project_webhooks::dsl;
+        let webhooks = dsl::project_webhooks
+            .filter(dsl::project_id.eq_any(project_ids))
+            .load::<Self>(conn)?;
+        Ok(webhooks
+            .into_iter()
+            .filter(|webhook| webhook.decode_events().contains(&event))
+            .collect())
+    }
+
+    /// The webhooks on the projects a task belongs to which asked for this event
+    pub fn subscribed_for_task(
+        conn: &mut PgConnection,
+        task_id: Uuid,
+        event: WebhookEvent,
+    ) -> QueryResult<Vec<Self>> {
+        use crate::schema::task_projects::dsl;
+        let project_ids = dsl::task_projects
+            .filter(dsl::task_id.eq(task_id))
+            .select(dsl::project_id)
+            .load::<Uuid>(conn)?;
+        Self::subscribed(conn, &project_ids, event)
+    }
+
+    pub fn delete(&self, conn: &mut PgConnection) -> QueryResult<()> {
+        use crate::schema::project_webhooks::dsl;
+        diesel::delete(dsl::project_webhooks.find(self.id)).execute(conn)?;
+        Ok(())
+    }
+}
+
+subseq_util::setup_table_crud!(ProjectWebhook, crate::schema::project_webhooks::dsl::project_webhooks);
+
+/// One event sent, or still to be sent, to a webhook. A delivery is retried with backoff until
+/// it succeeds or runs out of attempts, at which point next_attempt is cleared.
+#[derive(PartialEq, Queryable, Insertable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::webhook_deliveries)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub webhook_id: Uuid,
+    pub event: String,
+    pub payload: serde_json::Value,
+    pub created: NaiveDateTime,
+    pub attempts: i32,
+    pub next_attempt: Option<NaiveDateTime>,
+    pub delivered: Option<NaiveDateTime>,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+}
+
+impl WebhookDelivery {
+    pub fn enqueue(
+        conn: &mut PgConnection,
+        webhook: &ProjectWebhook,
+        event: WebhookEvent,
+        payload: serde_json::Value,
+    ) -> QueryResult<Self> {
+        let now = chrono::Utc::now().naive_utc();
+        let delivery = Self {
+            id: Uuid::new_v4(),
+            webhook_id: webhook.id,
+            event: event.as_str().to_string(),
+            payload,
+            created: now,
+            attempts: 0,
+            next_attempt: Some(now),
+            delivered: None,
+            status_code: None,
+            error: None,
+        };
+        diesel::insert_into(crate::schema::webhook_deliveries::table)
+            .values(&delivery)
+            .execute(conn)?;
+        Ok(delivery)
+    }
+
+    /// Deliveries whose next attempt is at or before `now`, oldest first
+    pub fn due(conn: &mut PgConnection, now: NaiveDateTime, limit: i64) -> QueryResult<Vec<Self>> {
+        use crate::schema::webhook_deliveries::dsl;
+        dsl::webhook_deliveries
+            .filter(dsl::next_attempt.le(now))
+            .order(dsl::next_attempt.asc())
+            .limit(limit)
+            .load::<Self>(conn)
+    }
+
+    /// A page of the deliveries to a webhook. Sorting by name sorts by event.
+    pub fn list_for_webhook(
+        conn: &mut PgConnection,
+        webhook_id: Uuid,
+        page: &PageRequest,
+    ) -> QueryResult<Page<Self>> {
+        use crate::schema::webhook_deliveries::dsl;
+        let total = dsl::webhook_deliveries
+            .filter(dsl::webhook_id.eq(webhook_id))
+            .count()
+            .get_result::<i64>(conn)?;
+        let query = dsl::webhook_deliveries
+            .filter(dsl::webhook_id.eq(webhook_id))
+            .into_boxed();
+        Ok(list_page!(
+            conn, page, total, query, Self,
+            created: dsl::created, name: dsl::event, id: dsl::id,
+            cursor: |delivery| (delivery.created, delivery.event.clone(), delivery.id)
+        ))
+    }
+
+    pub fn record_success(&mut self, conn: &mut PgConnection, status_code: i32) -> QueryResult<()> {
+        use crate::schema::webhook_deliveries::dsl;
+        let now = chrono::Utc::now().naive_utc();
+        diesel::update(dsl::webhook_deliveries.find(self.id))
+            .set((
+                dsl::attempts.eq(self.attempts + 1),
+                dsl::next_attempt.eq(None::<NaiveDateTime>),
+                dsl::delivered.eq(now),
+                dsl::status_code.eq(status_code),
+                dsl::error.eq(None::<String>),
+            ))
+            .execute(conn)?;
+        self.attempts += 1;
+        self.next_attempt = None;
+        self.delivered = Some(now);
+        self.status_code = Some(status_code);
+        self.error = None;
+        Ok(())
+    }
+
+    /// Schedules the next attempt, doubling the delay each time, or gives up after the last
+    pub fn record_failure(
+        &mut self,
+        conn: &mut PgConnection,
+        status_code: Option<i32>,
+        error: &str,
+    ) -> QueryResult<()> {
+        use crate::schema::webhook_deliveries::dsl;
+        let attempts = self.attempts + 1;
+        let next_attempt = (attempts < MAX_DELIVERY_ATTEMPTS).then(|| {
+            let delay = (FIRST_RETRY_SECS << (attempts - 1)).min(MAX_RETRY_SECS);
+            chrono::Utc::now().naive_utc() + chrono::Duration::seconds(delay)
+        });
+        diesel::update(dsl::webhook_deliveries.find(self.id))
+            .set((
+                dsl::attempts.eq(attempts),
+                dsl::next_attempt.eq(next_attempt),
+                dsl::status_code.eq(status_code),
+                dsl::error.eq(error),
+            ))
+            .execute(conn)?;
+        self.attempts = attempts;
+        self.next_attempt = next_attempt;
+        self.status_code = status_code;
+        self.error = Some(error.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tables::test::{fixture, Fixture, MIGRATIONS};
+    use crate::tables::Task;
+    use function_name::named;
+    use subseq_util::tables::harness::{to_pg_db_name, DbHarness};
+
+    #[test]
+    #[named]
+    fn test_webhook_deliveries() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let Fixture { user, mut proj, .. } = fixture(&mut conn);
+        let task = Task::create(&mut conn, Uuid::new_v4(), &mut proj, "Task", "Body", &user)
+            .expect("task");
+
+        assert!(ProjectWebhook::create(&mut conn, &proj, "ftp://example.com", &[WebhookEvent::TaskCreated]).is_err());
+        assert!(ProjectWebhook::create(&mut conn, &proj, "https://example.com", &[]).is_err());
+        let webhook = ProjectWebhook::create(
+            &mut conn,
+            &proj,
+            "https://example.com/hook",
+            &[WebhookEvent::TaskCreated, WebhookEvent::JobFinished],
+        ).expect("webhook");
+        assert_eq!(webhook.decode_events(), vec![WebhookEvent::TaskCreated, WebhookEvent::JobFinished]);
+
+        let subscribed = ProjectWebhook::subscribed_for_task(&mut conn, task.id, WebhookEvent::TaskCreated)
+            .expect("subscribed");
+        let ids: Vec<Uuid> = subscribed.iter().map(|webhook| webhook.id).collect();
+        assert_eq!(ids, vec![webhook.id]);
+        let subscribed = ProjectWebhook::subscribed_for_task(&mut conn, task.id, WebhookEvent::TaskUpdated)
+            .expect("subscribed");
+        assert!(subscribed.is_empty());
+
+        let mut delivery = WebhookDelivery::enqueue(
+            &mut conn, &webhook, WebhookEvent::TaskCreated, serde_json::json!({"id": task.id})
+        ).expect("delivery");
+        let now = chrono::Utc::now().naive_utc();
+        let due = WebhookDelivery::due(&mut conn, now, 10).expect("due");
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, delivery.id);
+
+        delivery.record_failure(&mut conn, Some(500), "Internal Server Error").expect("failure");
+        assert_eq!(delivery.attempts, 1);
+        assert!(WebhookDelivery::due(&mut conn, now, 10).expect("due").is_empty());
+        let later = now + chrono::Duration::seconds(FIRST_RETRY_SECS + 1);
+        assert_eq!(WebhookDelivery::due(&mut conn, later, 10).expect("due").len(), 1);
+
+        delivery.record_success(&mut conn, 200).expect("success");
+        assert!(WebhookDelivery::due(&mut conn, later, 10).expect("due").is_empty());
+        let log = WebhookDelivery::list_for_webhook(&mut conn, webhook.id, &PageRequest::first(20))
+            .expect("log");
+        assert_eq!(log.total, 1);
+        assert_eq!(log.items[0].attempts, 2);
+        assert_eq!(log.items[0].status_code, Some(200));
+
+        let second = WebhookDelivery::enqueue(
+            &mut conn, &webhook, WebhookEvent::JobFinished, serde_json::json!({})
+        ).expect("second");
+        let newest = PageRequest { descending: true, ..PageRequest::first(1) };
+        let log = WebhookDelivery::list_for_webhook(&mut conn, webhook.id, &newest).expect("log");
+        assert_eq!(log.items[0].id, second.id);
+        let older = PageRequest { cursor: log.next_cursor.clone(), ..newest };
+        let log = WebhookDelivery::list_for_webhook(&mut conn, webhook.id, &older).expect("log");
+        assert_eq!(log.items[0].id, delivery.id);
+        assert_eq!(log.next_cursor, None);
+    }
+
+    #[test]
+    #[named]
+    fn test_webhook_hosts() {
+        let db_name = to_pg_db_name(function_name!());
+        let harness = DbHarness::new("localhost", "development", &db_name, Some(MIGRATIONS));
+        let mut conn = harness.conn();
+        let Fixture { proj, .. } = fixture(&mut conn);
+
+        let events = [WebhookEvent::TaskCreated];
+        for url in [
+            "http://localhost:8080/hook",
+            "http://api.localhost/hook",
+            "http://127.0.0.1/hook",
+            "http://2130706433/hook",
+            "http://10.0.0.5/hook",
+            "http://192.168.1.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[::ffff:10.0.0.1]/hook",
+        ] {
+            assert!(ProjectWebhook::create(&mut conn, &proj, url, &events).is_err(), "{}", url);
+        }
+        ProjectWebhook::create(&mut conn, &proj, "https://93.184.216.34/hook", &events)
+            .expect("public ip");
+        assert!(!is_public_ip("100.64.0.1".parse().expect("ip")));
+        assert!(is_public_ip("2606:4700::1111".parse().expect("ip")));
+    }
+}